@@ -0,0 +1,163 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const ADDRESS_BOOK_FILE: &str = "address_book.json";
+
+// Newest addresses kept per peer; older ones are usually stale NAT mappings
+const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+// What we remember about a chat peer between runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddressBookEntry {
+    // Newest first
+    pub addresses: Vec<String>,
+    pub last_seen: String,
+    pub nickname: Option<String>,
+    // Room the peer was last seen in, so its members can be redialed on rejoin
+    pub last_room: Option<String>,
+    // Kept in every room mesh we share and dialed at startup; never pruned
+    pub explicit: bool,
+}
+
+// One entry as returned by `get_address_book`
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressBookPeer {
+    pub peer_id: String,
+    #[serde(flatten)]
+    pub entry: AddressBookEntry,
+}
+
+// Chat peers by peer ID, backed by a JSON file in the app data directory.
+// Without a path the book only lives in memory.
+#[derive(Default)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    entries: HashMap<String, AddressBookEntry>,
+    // Set on every change so the periodic save can skip untouched books
+    dirty: bool,
+}
+
+impl AddressBook {
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable address book {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path: Some(path), entries, dirty: false }
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        // Write to a temp file first so a crash can't leave half a file
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&self.entries)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Drop peers not seen within the TTL; returns how many were removed
+    pub fn prune(&mut self, ttl: Duration) -> usize {
+        let Ok(ttl) = chrono::Duration::from_std(ttl) else {
+            return 0;
+        };
+        let cutoff = (chrono::Utc::now() - ttl).to_rfc3339();
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.explicit || entry.last_seen > cutoff);
+
+        let removed = before - self.entries.len();
+        if removed > 0 {
+            info!("Pruned {} stale address book entries", removed);
+            self.dirty = true;
+        }
+        removed
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.entries.contains_key(&peer_id.to_string())
+    }
+
+    // Mark the peer as seen now, adding any new addresses in front
+    pub fn record_seen<'a>(&mut self, peer_id: &PeerId, addrs: impl IntoIterator<Item = &'a Multiaddr>) {
+        let entry = self.entry(peer_id);
+        for addr in addrs {
+            let addr = addr.to_string();
+            entry.addresses.retain(|a| *a != addr);
+            entry.addresses.insert(0, addr);
+        }
+        entry.addresses.truncate(MAX_ADDRESSES_PER_PEER);
+    }
+
+    pub fn set_room(&mut self, peer_id: &PeerId, room: &str) {
+        self.entry(peer_id).last_room = Some(room.to_string());
+    }
+
+    // Only updates peers already in the book
+    pub fn set_nickname(&mut self, peer_id: &PeerId, nickname: Option<String>) {
+        if let Some(entry) = self.entries.get_mut(&peer_id.to_string()) {
+            entry.nickname = nickname;
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_explicit(&mut self, peer_id: &PeerId, explicit: bool) {
+        if explicit {
+            self.entry(peer_id).explicit = true;
+        } else if let Some(entry) = self.entries.get_mut(&peer_id.to_string()) {
+            entry.explicit = false;
+            self.dirty = true;
+        }
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, &AddressBookEntry)> {
+        self.entries
+            .iter()
+            .filter_map(|(peer_id, entry)| Some((peer_id.parse().ok()?, entry)))
+    }
+
+    // Most recently seen members of a room, newest first
+    pub fn recent_room_peers(&self, room: &str, limit: usize) -> Vec<PeerId> {
+        let mut members: Vec<(PeerId, &AddressBookEntry)> = self
+            .peers()
+            .filter(|(_, entry)| entry.last_room.as_deref() == Some(room) && !entry.addresses.is_empty())
+            .collect();
+        members.sort_by(|(_, a), (_, b)| b.last_seen.cmp(&a.last_seen));
+        members.into_iter().take(limit).map(|(peer_id, _)| peer_id).collect()
+    }
+
+    pub fn snapshot(&self) -> Vec<AddressBookPeer> {
+        let mut peers: Vec<AddressBookPeer> = self
+            .entries
+            .iter()
+            .map(|(peer_id, entry)| AddressBookPeer {
+                peer_id: peer_id.clone(),
+                entry: entry.clone(),
+            })
+            .collect();
+        peers.sort_by(|a, b| b.entry.last_seen.cmp(&a.entry.last_seen));
+        peers
+    }
+
+    fn entry(&mut self, peer_id: &PeerId) -> &mut AddressBookEntry {
+        self.dirty = true;
+        let entry = self.entries.entry(peer_id.to_string()).or_default();
+        entry.last_seen = chrono::Utc::now().to_rfc3339();
+        entry
+    }
+}
@@ -0,0 +1,162 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+// Fetches the bytes of attachments too large to send inline
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/file/1.0.0");
+// Attachments up to this size travel inside the message itself
+pub const INLINE_LIMIT: usize = 16 * 1024;
+// Cap on attachments sent inline on request, e.g. small images; base64 of
+// this still fits in the default 64 KiB gossipsub message
+pub const MAX_INLINE_SIZE: usize = 32 * 1024;
+// Larger files are refused; the file protocol answers with one response
+pub const MAX_ATTACHMENT_SIZE: usize = 4 * 1024 * 1024;
+// Types we can't make sense of are offered as plain downloads
+pub const GENERIC_MIME: &str = "application/octet-stream";
+const MAX_NAME_LEN: usize = 128;
+
+// A file attached to a room message. Small files carry their bytes in
+// `data`; the rest are fetched from `source` with `FILE_PROTOCOL`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub mime: String,
+    // Hex SHA-256 of the bytes, checked before anything is saved
+    pub sha256: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Base64 bytes of inline attachments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    // Local only: the peer to fetch from, set from the message's author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    // Local only: where the bytes are cached once we have them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl Attachment {
+    // `inline` keeps the bytes in the message even above INLINE_LIMIT; the
+    // caller enforces MAX_INLINE_SIZE
+    pub fn new(data: &[u8], mime: &str, name: Option<String>, inline: bool) -> Self {
+        Self {
+            mime: normalize_mime(mime),
+            sha256: sha256_hex(data),
+            size: data.len() as u64,
+            name: name.map(|name| sanitize_name(&name)).filter(|name| !name.is_empty()),
+            data: (inline || data.len() <= INLINE_LIMIT).then(|| BASE64.encode(data)),
+            source: None,
+            path: None,
+        }
+    }
+
+    // What goes on the wire: no local fields
+    pub fn for_wire(&self) -> Self {
+        Self {
+            source: None,
+            path: None,
+            ..self.clone()
+        }
+    }
+
+    // Cleans up fields from a received envelope; None if it can't be used
+    pub fn received(mut self, source: Option<String>) -> Option<Self> {
+        if !is_sha256_hex(&self.sha256) || self.size > MAX_ATTACHMENT_SIZE as u64 {
+            return None;
+        }
+        self.mime = normalize_mime(&self.mime);
+        self.name = self.name.map(|name| sanitize_name(&name)).filter(|name| !name.is_empty());
+        self.source = source;
+        self.path = None;
+        Some(self)
+    }
+
+    // Text older clients show in place of the attachment
+    pub fn placeholder(&self) -> String {
+        format!("📎 {} ({} bytes)", self.name.as_deref().unwrap_or(&self.mime), self.size)
+    }
+}
+
+// Content-addressed cache of attachment bytes in the app data directory
+pub struct AttachmentStore {
+    dir: PathBuf,
+}
+
+impl AttachmentStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path_for(&self, sha256: &str) -> PathBuf {
+        self.dir.join(sha256)
+    }
+
+    pub fn cached(&self, sha256: &str) -> Option<PathBuf> {
+        let path = self.path_for(sha256);
+        (is_sha256_hex(sha256) && path.is_file()).then_some(path)
+    }
+
+    pub fn load(&self, sha256: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.cached(sha256)?).ok()
+    }
+
+    // Saves bytes under their hash after checking they match it
+    pub fn save(&self, sha256: &str, data: &[u8]) -> Result<PathBuf, String> {
+        if sha256_hex(data) != sha256 {
+            return Err("content doesn't match its hash".to_string());
+        }
+        if let Some(path) = self.cached(sha256) {
+            return Ok(path);
+        }
+        std::fs::create_dir_all(&self.dir).map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let path = self.path_for(sha256);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).map_err(|e| format!("Failed to save attachment: {}", e))?;
+        std::fs::rename(&tmp_path, &path).map_err(|e| format!("Failed to save attachment: {}", e))?;
+        Ok(path)
+    }
+}
+
+// Asks the author of a message for an attachment's bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileResponse {
+    // Base64 bytes, or None if the peer doesn't have the file
+    pub data: Option<String>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// `type/subtype` in lowercase, parameters dropped; anything else is generic
+pub fn normalize_mime(mime: &str) -> String {
+    let mime = mime.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let valid = mime.split_once('/').is_some_and(|(kind, subtype)| {
+        let token = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b));
+        token(kind) && token(subtype)
+    });
+    if valid {
+        mime
+    } else {
+        GENERIC_MIME.to_string()
+    }
+}
+
+// File names are only shown and offered as a download name, never used as a path
+fn sanitize_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    name.chars().filter(|c| !c.is_control()).take(MAX_NAME_LEN).collect::<String>().trim().to_string()
+}
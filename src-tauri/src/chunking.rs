@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Most parts a single message may be split into
+pub const MAX_CHUNKS: u32 = 64;
+// Incomplete messages are dropped by `expire` once this long has passed
+// since their first part arrived
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+// Parts buffered across all incomplete messages; the oldest are dropped past this
+pub const MAX_BUFFERED_BYTES: usize = 8 * 1024 * 1024;
+
+// Position of one part of a message too large for a single gossipsub
+// message. Parts share the message ID of their envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkHeader {
+    pub index: u32,
+    pub total: u32,
+}
+
+// Cut content into parts of at most `max_len` bytes, never inside a character
+pub fn split(content: &str, max_len: usize) -> Vec<&str> {
+    let max_len = max_len.max(4);
+    let mut parts = Vec::new();
+    let mut rest = content;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(max_len);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    parts
+}
+
+struct PartialMessage {
+    parts: Vec<Option<String>>,
+    received: u32,
+    bytes: usize,
+    started: Instant,
+}
+
+// Collects message parts, which may arrive in any order and more than once,
+// until every part of a message is in
+pub struct Reassembler {
+    partial: HashMap<String, PartialMessage>,
+    buffered: usize,
+    max_buffered: usize,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(MAX_BUFFERED_BYTES, REASSEMBLY_TIMEOUT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(max_buffered: usize, timeout: Duration) -> Self {
+        Self {
+            partial: HashMap::new(),
+            buffered: 0,
+            max_buffered,
+            timeout,
+        }
+    }
+
+    // `key` identifies the message, e.g. its author and ID. Returns the whole
+    // content once the last missing part arrives.
+    pub fn insert(&mut self, key: &str, header: ChunkHeader, part: String) -> Result<Option<String>, String> {
+        if header.total == 0 || header.total > MAX_CHUNKS || header.index >= header.total {
+            return Err(format!("invalid part {} of {}", header.index, header.total));
+        }
+
+        if let Some(message) = self.partial.get(key) {
+            if message.parts.len() != header.total as usize {
+                self.remove(key);
+                return Err("parts disagree on the message length".to_string());
+            }
+            if message.parts[header.index as usize].is_some() {
+                return Ok(None);
+            }
+        }
+
+        // Make room by dropping the oldest other messages; give up on this
+        // one if it can't fit on its own
+        while self.buffered + part.len() > self.max_buffered {
+            let oldest = self
+                .partial
+                .iter()
+                .filter(|(k, _)| k.as_str() != key)
+                .min_by_key(|(_, message)| message.started)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(oldest) => {
+                    info!("Dropping incomplete message {} to stay under the reassembly limit", oldest);
+                    self.remove(&oldest);
+                }
+                None => {
+                    self.remove(key);
+                    return Err(format!("message exceeds the {} byte reassembly limit", self.max_buffered));
+                }
+            }
+        }
+
+        let message = self.partial.entry(key.to_string()).or_insert_with(|| PartialMessage {
+            parts: vec![None; header.total as usize],
+            received: 0,
+            bytes: 0,
+            started: Instant::now(),
+        });
+        self.buffered += part.len();
+        message.bytes += part.len();
+        message.received += 1;
+        message.parts[header.index as usize] = Some(part);
+
+        if message.received < header.total {
+            return Ok(None);
+        }
+        let message = self.remove(key).expect("message was just inserted");
+        Ok(Some(message.parts.into_iter().flatten().collect()))
+    }
+
+    // Drop messages whose parts stopped arriving; returns how many were dropped
+    pub fn expire(&mut self) -> usize {
+        let expired: Vec<String> = self
+            .partial
+            .iter()
+            .filter(|(_, message)| message.started.elapsed() >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            info!("Dropping incomplete message {}", key);
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    fn remove(&mut self, key: &str) -> Option<PartialMessage> {
+        let message = self.partial.remove(key)?;
+        self.buffered -= message.bytes;
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(index: u32, total: u32) -> ChunkHeader {
+        ChunkHeader { index, total }
+    }
+
+    #[test]
+    fn split_never_cuts_a_character() {
+        let text = "héllo wörld ✓ 日本語 🦀 ".repeat(40);
+        for max_len in [4, 5, 7, 50] {
+            let parts = split(&text, max_len);
+            assert!(parts.iter().all(|p| p.len() <= max_len && !p.is_empty()), "{}", max_len);
+            assert_eq!(parts.concat(), text);
+        }
+        assert!(split("", 10).is_empty());
+    }
+
+    #[test]
+    fn parts_in_any_order_reassemble() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert("k", part(2, 3), "c".into()), Ok(None));
+        assert_eq!(reassembler.insert("k", part(0, 3), "a".into()), Ok(None));
+        assert_eq!(reassembler.insert("k", part(1, 3), "b".into()), Ok(Some("abc".into())));
+        assert_eq!(reassembler.buffered, 0);
+        // Done messages start over rather than being remembered
+        assert_eq!(reassembler.insert("k", part(0, 1), "x".into()), Ok(Some("x".into())));
+    }
+
+    #[test]
+    fn duplicate_parts_are_ignored() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert("k", part(0, 2), "a".into()), Ok(None));
+        assert_eq!(reassembler.insert("k", part(0, 2), "zzz".into()), Ok(None));
+        assert_eq!(reassembler.buffered, 1);
+        assert_eq!(reassembler.insert("k", part(1, 2), "b".into()), Ok(Some("ab".into())));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.insert("k", part(0, 0), "a".into()).is_err());
+        assert!(reassembler.insert("k", part(2, 2), "a".into()).is_err());
+        assert!(reassembler.insert("k", part(0, MAX_CHUNKS + 1), "a".into()).is_err());
+
+        // Parts disagreeing on the total drop the whole message
+        assert_eq!(reassembler.insert("k", part(0, 3), "a".into()), Ok(None));
+        assert!(reassembler.insert("k", part(1, 2), "b".into()).is_err());
+        assert_eq!(reassembler.buffered, 0);
+        assert_eq!(reassembler.insert("k", part(1, 3), "b".into()), Ok(None));
+    }
+
+    #[test]
+    fn oldest_messages_are_evicted_past_the_memory_cap() {
+        let mut reassembler = Reassembler::new(10, REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembler.insert("old", part(0, 2), "12345".into()), Ok(None));
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(reassembler.insert("new", part(0, 2), "12345".into()), Ok(None));
+        assert_eq!(reassembler.insert("newest", part(0, 2), "123".into()), Ok(None));
+        assert!(!reassembler.partial.contains_key("old"));
+        assert_eq!(reassembler.buffered, 8);
+
+        // A message too large for the cap on its own is given up on
+        assert!(reassembler.insert("huge", part(0, 2), "12345678901".into()).is_err());
+        assert!(!reassembler.partial.contains_key("huge"));
+        assert!(reassembler.buffered <= 10);
+    }
+
+    #[test]
+    fn expire_drops_stalled_messages() {
+        let mut reassembler = Reassembler::new(MAX_BUFFERED_BYTES, Duration::ZERO);
+        assert_eq!(reassembler.insert("a", part(0, 2), "x".into()), Ok(None));
+        assert_eq!(reassembler.insert("b", part(1, 3), "y".into()), Ok(None));
+        assert_eq!(reassembler.expire(), 2);
+        assert_eq!(reassembler.buffered, 0);
+        assert_eq!(reassembler.expire(), 0);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert("a", part(0, 2), "x".into()), Ok(None));
+        assert_eq!(reassembler.expire(), 0);
+    }
+}
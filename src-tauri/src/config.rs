@@ -0,0 +1,581 @@
+use crate::p2p_node::AGENT_VERSION;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Registration lifetimes accepted by rendezvous servers
+pub const RENDEZVOUS_MIN_TTL_SECS: u64 = 2 * 60 * 60;
+pub const RENDEZVOUS_MAX_TTL_SECS: u64 = 72 * 60 * 60;
+
+// Low-power mode searches for room peers this many times less often
+const LOW_POWER_DISCOVERY_FACTOR: u64 = 4;
+// Longer intervals leave a room without new peers for too long to be useful
+const MAX_DISCOVERY_INTERVAL_SECS: u64 = 24 * 60 * 60;
+// Addresses older than this are unlikely to still work
+const MAX_ADDRESS_BOOK_TTL_DAYS: u64 = 10 * 365;
+// A peer gone for longer than this has left, whether or not it comes back
+const MAX_RECONNECT_GRACE_SECS: u64 = 5 * 60;
+// Each search is scheduled up to this fraction early or late so clients that
+// started together don't query in lockstep
+const DISCOVERY_JITTER: f64 = 0.1;
+// Identify messages are small; a long agent string only adds noise
+const MAX_AGENT_VERSION_LEN: usize = 128;
+
+// Options accepted by `init_p2p`; any field left out falls back to its default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct P2PConfig {
+    // Seconds between automatic provider searches in the current room
+    pub discovery_interval_secs: u64,
+    // Periodic searches are skipped while this many room members are connected
+    pub discovery_target_peers: usize,
+    pub gossipsub: GossipsubSettings,
+    pub kad: KadSettings,
+    pub transport: TransportSettings,
+    pub connection_limits: ConnectionLimitSettings,
+    // Local network discovery; some networks flag mDNS traffic
+    pub mdns_enabled: bool,
+    // Path to a swarm.key file; when set only peers with the same key can connect
+    pub swarm_key_path: Option<String>,
+    // Extra WebSocket listener for browser peers, e.g. "/ip6/::/tcp/8081/ws"
+    pub ws_listen_addr: Option<String>,
+    // Relay circuits for NATed peers; only meant for always-on public nodes
+    pub relay_server: bool,
+    pub relay_limits: RelayLimits,
+    // Rendezvous server to register rooms with, e.g. "/ip4/1.2.3.4/tcp/62649/p2p/12D3Koo..."
+    pub rendezvous_server: Option<String>,
+    pub rendezvous_ttl_secs: u64,
+    // Identical system messages repeated within this many seconds are
+    // collapsed into one; 0 shows every copy
+    pub system_message_dedup_secs: u64,
+    // Stored messages are deleted this many seconds after they arrive;
+    // 0 keeps them forever
+    pub message_ttl_secs: u64,
+    // Peers in the address book not seen for this many days are forgotten
+    pub address_book_ttl_days: u64,
+    // Redial recently seen members when joining a room again
+    pub auto_dial_known_peers: bool,
+    // Client name and version peers see over identify, e.g. "p2p-rust/0.1.0"
+    pub agent_version: String,
+    // Messages sent with nobody in the room are retried for this many seconds
+    // before they're reported as failed
+    pub outbox_max_age_secs: u64,
+    // Connections nothing uses are closed after this many seconds; room
+    // members are kept connected regardless
+    pub idle_connection_timeout_secs: u64,
+    // A peer that reconnects within this many seconds isn't reported as
+    // disconnected and reconnected; 0 reports every change
+    pub reconnect_grace_secs: u64,
+    // Loaded from the persisted settings rather than passed by the caller
+    #[serde(skip)]
+    pub power: PowerSettings,
+    // Where the DHT store is snapshotted, set from the app data directory;
+    // None keeps it in memory only
+    #[serde(skip)]
+    pub kad_store_path: Option<PathBuf>,
+}
+
+impl Default for P2PConfig {
+    fn default() -> Self {
+        Self {
+            discovery_interval_secs: 30,
+            discovery_target_peers: 8,
+            gossipsub: GossipsubSettings::default(),
+            kad: KadSettings::default(),
+            transport: TransportSettings::default(),
+            connection_limits: ConnectionLimitSettings::default(),
+            mdns_enabled: true,
+            swarm_key_path: None,
+            ws_listen_addr: None,
+            relay_server: false,
+            relay_limits: RelayLimits::default(),
+            rendezvous_server: None,
+            rendezvous_ttl_secs: RENDEZVOUS_MIN_TTL_SECS,
+            system_message_dedup_secs: 10,
+            message_ttl_secs: 0,
+            address_book_ttl_days: 30,
+            auto_dial_known_peers: true,
+            agent_version: AGENT_VERSION.to_string(),
+            outbox_max_age_secs: 24 * 60 * 60,
+            idle_connection_timeout_secs: 60,
+            reconnect_grace_secs: 10,
+            power: PowerSettings::default(),
+            kad_store_path: None,
+        }
+    }
+}
+
+impl P2PConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_DISCOVERY_INTERVAL_SECS).contains(&self.discovery_interval_secs) {
+            return Err(format!("discovery_interval_secs must be between 1 and {}", MAX_DISCOVERY_INTERVAL_SECS));
+        }
+        if !(1..=MAX_ADDRESS_BOOK_TTL_DAYS).contains(&self.address_book_ttl_days) {
+            return Err(format!("address_book_ttl_days must be between 1 and {}", MAX_ADDRESS_BOOK_TTL_DAYS));
+        }
+        if self.reconnect_grace_secs > MAX_RECONNECT_GRACE_SECS {
+            return Err(format!("reconnect_grace_secs must be at most {}", MAX_RECONNECT_GRACE_SECS));
+        }
+        if self.idle_connection_timeout_secs == 0 {
+            return Err("idle_connection_timeout_secs must be at least 1".to_string());
+        }
+        if self.agent_version.trim().is_empty() || self.agent_version.len() > MAX_AGENT_VERSION_LEN {
+            return Err(format!("agent_version must be 1 to {} bytes", MAX_AGENT_VERSION_LEN));
+        }
+        if self.outbox_max_age_secs == 0 {
+            return Err("outbox_max_age_secs must be at least 1".to_string());
+        }
+        if self.discovery_target_peers == 0 {
+            return Err("discovery_target_peers must be at least 1".to_string());
+        }
+        if let Some(addr) = &self.ws_listen_addr {
+            let multiaddr: Multiaddr = addr
+                .parse()
+                .map_err(|e| format!("Invalid ws_listen_addr {}: {}", addr, e))?;
+            if !multiaddr.iter().any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_))) {
+                return Err(format!("ws_listen_addr {} must end in /ws or /wss", addr));
+            }
+        }
+        if let Some(addr) = &self.rendezvous_server {
+            let multiaddr: Multiaddr = addr
+                .parse()
+                .map_err(|e| format!("Invalid rendezvous_server {}: {}", addr, e))?;
+            if !matches!(multiaddr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(format!("rendezvous_server {} must end in /p2p/<peer id>", addr));
+            }
+        }
+        if !(RENDEZVOUS_MIN_TTL_SECS..=RENDEZVOUS_MAX_TTL_SECS).contains(&self.rendezvous_ttl_secs) {
+            return Err(format!(
+                "rendezvous_ttl_secs must be between {} and {}",
+                RENDEZVOUS_MIN_TTL_SECS, RENDEZVOUS_MAX_TTL_SECS
+            ));
+        }
+        self.relay_limits.validate()?;
+        self.transport.validate()?;
+        self.connection_limits.validate()?;
+        self.kad.validate()?;
+        self.gossipsub.validate()
+    }
+
+    pub fn discovery_interval(&self, mode: PowerMode) -> Duration {
+        match mode {
+            PowerMode::Normal => Duration::from_secs(self.discovery_interval_secs),
+            PowerMode::LowPower => Duration::from_secs(self.discovery_interval_secs.saturating_mul(LOW_POWER_DISCOVERY_FACTOR)),
+        }
+    }
+
+    pub fn outbox_max_age(&self) -> Duration {
+        Duration::from_secs(self.outbox_max_age_secs)
+    }
+
+    pub fn address_book_ttl(&self) -> Duration {
+        Duration::from_secs(self.address_book_ttl_days.saturating_mul(24 * 60 * 60))
+    }
+
+    pub fn message_ttl(&self) -> Option<Duration> {
+        (self.message_ttl_secs > 0).then(|| Duration::from_secs(self.message_ttl_secs))
+    }
+
+    // Delay until the next periodic search, with jitter applied
+    pub fn discovery_delay(&self, mode: PowerMode) -> Duration {
+        let jitter = rand::thread_rng().gen_range(1.0 - DISCOVERY_JITTER..=1.0 + DISCOVERY_JITTER);
+        self.discovery_interval(mode).mul_f64(jitter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerMode {
+    #[default]
+    Normal,
+    // Fewer background queries for metered or battery-powered connections
+    LowPower,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerSettings {
+    pub mode: PowerMode,
+    // Also ignore mDNS discoveries while in low-power mode
+    pub pause_mdns: bool,
+}
+
+// Smallest per-stream window yamux allows (256 KiB, from the yamux spec)
+const YAMUX_MIN_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+// Multiplexer and TCP tuning. Leaving the yamux fields unset keeps yamux's
+// auto-tuned windows; setting any of them switches to fixed per-stream
+// windows (256 KiB window, 1 MiB buffer and 8192 streams unless overridden).
+// Larger windows help bulk transfers, smaller ones save memory on phones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportSettings {
+    pub yamux_receive_window: Option<u32>,
+    pub yamux_max_buffer_size: Option<usize>,
+    pub yamux_max_num_streams: Option<usize>,
+    // Dial from our listening port, which helps NAT traversal; off uses a
+    // fresh ephemeral port for every outgoing connection
+    pub tcp_port_reuse: bool,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            yamux_receive_window: None,
+            yamux_max_buffer_size: None,
+            yamux_max_num_streams: None,
+            tcp_port_reuse: true,
+        }
+    }
+}
+
+impl TransportSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(window) = self.yamux_receive_window {
+            if window < YAMUX_MIN_RECEIVE_WINDOW {
+                return Err(format!("yamux_receive_window must be at least {} bytes", YAMUX_MIN_RECEIVE_WINDOW));
+            }
+            if self.yamux_max_buffer_size.is_some_and(|buffer| buffer < window as usize) {
+                return Err("yamux_max_buffer_size must not be smaller than yamux_receive_window".to_string());
+            }
+        }
+        if self.yamux_max_buffer_size.is_some_and(|buffer| buffer < YAMUX_MIN_RECEIVE_WINDOW as usize) {
+            return Err(format!("yamux_max_buffer_size must be at least {} bytes", YAMUX_MIN_RECEIVE_WINDOW));
+        }
+        if self.yamux_max_num_streams == Some(0) {
+            return Err("yamux_max_num_streams must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Bounds on how many sockets the swarm keeps open; connections past these
+// are refused before the handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimitSettings {
+    pub max_established: u32,
+    pub max_established_per_peer: u32,
+    // Applies to incoming and outgoing handshakes separately
+    pub max_pending: u32,
+    // Discovered peers dialed at once; the rest wait for those dials to
+    // resolve, so a room with many providers doesn't open a burst of sockets
+    pub max_concurrent_dials: usize,
+}
+
+impl Default for ConnectionLimitSettings {
+    fn default() -> Self {
+        Self {
+            max_established: 100,
+            max_established_per_peer: 2,
+            max_pending: 16,
+            max_concurrent_dials: 4,
+        }
+    }
+}
+
+impl ConnectionLimitSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_established == 0
+            || self.max_established_per_peer == 0
+            || self.max_pending == 0
+            || self.max_concurrent_dials == 0
+        {
+            return Err("connection limits must be at least 1".to_string());
+        }
+        if self.max_established_per_peer > self.max_established {
+            return Err(format!(
+                "max_established_per_peer ({}) must not exceed max_established ({})",
+                self.max_established_per_peer, self.max_established
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Caps on what a relay server hands out to other peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayLimits {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration_secs: u64,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration_secs: u64,
+    // Bytes relayed per circuit before it is closed
+    pub max_circuit_bytes: u64,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration_secs: 60 * 60,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration_secs: 2 * 60,
+            max_circuit_bytes: 1 << 17,
+        }
+    }
+}
+
+impl RelayLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_reservations_per_peer == 0 || self.max_circuits_per_peer == 0 {
+            return Err("relay per-peer limits must be at least 1".to_string());
+        }
+        if self.reservation_duration_secs == 0 || self.max_circuit_duration_secs == 0 {
+            return Err("relay durations must be at least 1 second".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Bounds for `max_transmit_size`: below the minimum even short messages would
+// need splitting, above the maximum a single peer can push megabytes at us
+const MIN_TRANSMIT_SIZE: usize = 4 * 1024;
+const MAX_TRANSMIT_SIZE: usize = 1024 * 1024;
+
+// Kademlia records expire no sooner than this; the routing table replicates
+// them hourly. Ones kept for over a week outlive the peers behind them.
+const MIN_KAD_RECORD_TTL_SECS: u64 = 60 * 60;
+const MAX_KAD_RECORD_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+// A query still running after this long isn't going to find anything
+const MAX_KAD_QUERY_TIMEOUT_SECS: u64 = 10 * 60;
+
+// Kademlia tuning; a shorter query timeout answers sooner on good networks,
+// a higher replication factor keeps provider records alive longer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KadSettings {
+    pub query_timeout_secs: u64,
+    // Peer searches started by joining a room or by the user end after this
+    // many seconds, so the UI gets a definite answer; periodic searches run
+    // for the full query timeout
+    pub interactive_search_timeout_secs: u64,
+    // Closest peers each provider record is stored on
+    pub replication_factor: usize,
+    // Requests a query keeps in flight at once
+    pub parallelism: usize,
+    // Lifetimes of stored values and of provider records such as room
+    // announcements; both are republished well before they run out
+    pub record_ttl_secs: u64,
+    pub provider_record_ttl_secs: u64,
+    // Records and provider keys the DHT store holds each; the least
+    // recently used are evicted past this
+    pub max_records: usize,
+}
+
+impl Default for KadSettings {
+    fn default() -> Self {
+        Self {
+            query_timeout_secs: 60,
+            interactive_search_timeout_secs: 15,
+            replication_factor: 20,
+            parallelism: 3,
+            record_ttl_secs: 48 * 60 * 60,
+            provider_record_ttl_secs: 48 * 60 * 60,
+            max_records: 1024,
+        }
+    }
+}
+
+impl KadSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_KAD_QUERY_TIMEOUT_SECS).contains(&self.query_timeout_secs) {
+            return Err(format!("kad query_timeout_secs must be between 1 and {}", MAX_KAD_QUERY_TIMEOUT_SECS));
+        }
+        if !(1..=MAX_KAD_QUERY_TIMEOUT_SECS).contains(&self.interactive_search_timeout_secs) {
+            return Err(format!(
+                "kad interactive_search_timeout_secs must be between 1 and {}",
+                MAX_KAD_QUERY_TIMEOUT_SECS
+            ));
+        }
+        if self.replication_factor == 0 {
+            return Err("kad replication_factor must be at least 1".to_string());
+        }
+        if self.parallelism == 0 {
+            return Err("kad parallelism must be at least 1".to_string());
+        }
+        let ttls = MIN_KAD_RECORD_TTL_SECS..=MAX_KAD_RECORD_TTL_SECS;
+        if !ttls.contains(&self.record_ttl_secs) || !ttls.contains(&self.provider_record_ttl_secs) {
+            return Err(format!(
+                "kad record TTLs must be between {} and {} seconds",
+                MIN_KAD_RECORD_TTL_SECS, MAX_KAD_RECORD_TTL_SECS
+            ));
+        }
+        if self.max_records == 0 {
+            return Err("kad max_records must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_secs(self.query_timeout_secs)
+    }
+
+    // Never longer than the query itself may run
+    pub fn interactive_search_timeout(&self) -> Duration {
+        Duration::from_secs(self.interactive_search_timeout_secs.min(self.query_timeout_secs))
+    }
+
+    pub fn record_ttl(&self) -> Duration {
+        Duration::from_secs(self.record_ttl_secs)
+    }
+
+    pub fn provider_record_ttl(&self) -> Duration {
+        Duration::from_secs(self.provider_record_ttl_secs)
+    }
+}
+
+// Low-power mode runs the gossipsub heartbeat this many times less often
+const LOW_POWER_HEARTBEAT_FACTOR: u64 = 2;
+// With slower heartbeats meshes take minutes to repair after peers leave
+const MAX_HEARTBEAT_SECS: u64 = 60;
+
+// Gossipsub tuning; a longer heartbeat and smaller mesh save bandwidth on
+// mobile or metered connections at the cost of slower propagation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipsubSettings {
+    pub heartbeat_secs: u64,
+    // Heartbeats of message history kept for IWANT requests
+    pub history_length: usize,
+    // Heartbeats of history advertised in IHAVE gossip
+    pub history_gossip: usize,
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub flood_publish: bool,
+    // Largest gossipsub message sent or accepted, framing included. Longer
+    // chat messages are split into parts that fit.
+    pub max_transmit_size: usize,
+}
+
+impl Default for GossipsubSettings {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: 1,
+            history_length: 5,
+            history_gossip: 3,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            flood_publish: true,
+            max_transmit_size: 64 * 1024,
+        }
+    }
+}
+
+impl GossipsubSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_HEARTBEAT_SECS).contains(&self.heartbeat_secs) {
+            return Err(format!("gossipsub heartbeat_secs must be between 1 and {}", MAX_HEARTBEAT_SECS));
+        }
+        if self.history_length == 0 {
+            return Err("gossipsub history_length must be at least 1".to_string());
+        }
+        if self.history_gossip > self.history_length {
+            return Err(format!(
+                "gossipsub history_gossip ({}) must not exceed history_length ({})",
+                self.history_gossip, self.history_length
+            ));
+        }
+        if self.mesh_n_low == 0 {
+            return Err("gossipsub mesh_n_low must be at least 1".to_string());
+        }
+        if !(self.mesh_n_low <= self.mesh_n && self.mesh_n <= self.mesh_n_high) {
+            return Err(format!(
+                "gossipsub mesh sizes must satisfy mesh_n_low ≤ mesh_n ≤ mesh_n_high (got {} ≤ {} ≤ {})",
+                self.mesh_n_low, self.mesh_n, self.mesh_n_high
+            ));
+        }
+        if !(MIN_TRANSMIT_SIZE..=MAX_TRANSMIT_SIZE).contains(&self.max_transmit_size) {
+            return Err(format!(
+                "gossipsub max_transmit_size must be between {} and {} bytes",
+                MIN_TRANSMIT_SIZE, MAX_TRANSMIT_SIZE
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self, mode: PowerMode) -> Duration {
+        match mode {
+            PowerMode::Normal => Duration::from_secs(self.heartbeat_secs),
+            PowerMode::LowPower => Duration::from_secs(self.heartbeat_secs.saturating_mul(LOW_POWER_HEARTBEAT_FACTOR)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Setter = fn(&mut P2PConfig, u64);
+
+    // Every bounded field with its accepted range
+    fn bounded_fields() -> Vec<(&'static str, Setter, u64, u64)> {
+        vec![
+            ("discovery_interval_secs", |c, v| c.discovery_interval_secs = v, 1, MAX_DISCOVERY_INTERVAL_SECS),
+            ("address_book_ttl_days", |c, v| c.address_book_ttl_days = v, 1, MAX_ADDRESS_BOOK_TTL_DAYS),
+            ("reconnect_grace_secs", |c, v| c.reconnect_grace_secs = v, 0, MAX_RECONNECT_GRACE_SECS),
+            ("rendezvous_ttl_secs", |c, v| c.rendezvous_ttl_secs = v, RENDEZVOUS_MIN_TTL_SECS, RENDEZVOUS_MAX_TTL_SECS),
+            ("kad.query_timeout_secs", |c, v| c.kad.query_timeout_secs = v, 1, MAX_KAD_QUERY_TIMEOUT_SECS),
+            ("kad.interactive_search_timeout_secs", |c, v| c.kad.interactive_search_timeout_secs = v, 1, MAX_KAD_QUERY_TIMEOUT_SECS),
+            ("kad.record_ttl_secs", |c, v| c.kad.record_ttl_secs = v, MIN_KAD_RECORD_TTL_SECS, MAX_KAD_RECORD_TTL_SECS),
+            ("kad.provider_record_ttl_secs", |c, v| c.kad.provider_record_ttl_secs = v, MIN_KAD_RECORD_TTL_SECS, MAX_KAD_RECORD_TTL_SECS),
+            ("gossipsub.heartbeat_secs", |c, v| c.gossipsub.heartbeat_secs = v, 1, MAX_HEARTBEAT_SECS),
+            ("gossipsub.max_transmit_size", |c, v| c.gossipsub.max_transmit_size = v as usize, MIN_TRANSMIT_SIZE as u64, MAX_TRANSMIT_SIZE as u64),
+        ]
+    }
+
+    #[test]
+    fn bounded_fields_reject_values_out_of_range() {
+        for (name, set, min, max) in bounded_fields() {
+            for value in [min, max] {
+                let mut config = P2PConfig::default();
+                set(&mut config, value);
+                assert_eq!(config.validate(), Ok(()), "{} = {}", name, value);
+            }
+            for value in min.checked_sub(1).into_iter().chain([max + 1, u64::MAX]) {
+                let mut config = P2PConfig::default();
+                set(&mut config, value);
+                assert!(config.validate().is_err(), "{} = {}", name, value);
+            }
+        }
+    }
+
+    #[test]
+    fn largest_accepted_values_make_usable_deadlines() {
+        let mut config = P2PConfig::default();
+        for (_, set, _, max) in bounded_fields() {
+            set(&mut config, max);
+        }
+        assert_eq!(config.validate(), Ok(()));
+        let now = std::time::Instant::now();
+        for duration in [
+            config.discovery_interval(PowerMode::LowPower),
+            config.address_book_ttl(),
+            config.kad.query_timeout(),
+            config.kad.interactive_search_timeout(),
+            config.kad.record_ttl() * 11 / 24,
+            config.kad.provider_record_ttl(),
+            Duration::from_secs(config.reconnect_grace_secs),
+            config.gossipsub.heartbeat_interval(PowerMode::LowPower),
+        ] {
+            assert!(now.checked_add(duration).is_some());
+        }
+    }
+
+    #[test]
+    fn unvalidated_durations_saturate_instead_of_overflowing() {
+        let mut config = P2PConfig { discovery_interval_secs: u64::MAX, address_book_ttl_days: u64::MAX, ..Default::default() };
+        config.gossipsub.heartbeat_secs = u64::MAX;
+        assert_eq!(config.discovery_interval(PowerMode::LowPower), Duration::from_secs(u64::MAX));
+        assert_eq!(config.address_book_ttl(), Duration::from_secs(u64::MAX));
+        assert_eq!(config.gossipsub.heartbeat_interval(PowerMode::LowPower), Duration::from_secs(u64::MAX));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::settings::Settings;
+use serde_json::Value;
+use std::io::Write;
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const REDACTED: &str = "<redacted>";
+// Settings fields whose values are secret wherever they appear. Room
+// passphrases are never saved, but a field holding one would be caught here.
+const SECRET_FIELD_HINTS: &[&str] = &["passphrase", "password", "secret", "private_key"];
+
+// Settings as JSON with secrets replaced, safe to attach to a bug report.
+// The owner signing keys are dropped but the rooms they belong to stay.
+pub fn redact_settings(settings: &Settings) -> Value {
+    let mut value = serde_json::to_value(settings).unwrap_or(Value::Null);
+    if let Some(Value::Object(owned_rooms)) = value.get_mut("owned_rooms") {
+        for key in owned_rooms.values_mut() {
+            *key = Value::String(REDACTED.to_string());
+        }
+    }
+    redact_secret_fields(&mut value);
+    value
+}
+
+fn redact_secret_fields(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                let name = name.to_lowercase();
+                if SECRET_FIELD_HINTS.iter().any(|hint| name.contains(hint)) && !field.is_null() {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact_secret_fields(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_secret_fields),
+        _ => {}
+    }
+}
+
+// Writes each (file name, contents) pair into a new zip at `path`
+pub fn write_bundle(path: &Path, files: &[(&str, String)]) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents) in files {
+        zip.start_file(*name, options)
+            .map_err(|e| format!("Failed to add {}: {}", name, e))?;
+        zip.write_all(contents.as_bytes())
+            .map_err(|e| format!("Failed to add {}: {}", name, e))?;
+    }
+    zip.finish().map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn owner_keys_are_redacted_but_rooms_kept() {
+        let mut settings = Settings::default();
+        settings.owned_rooms.insert("lobby".to_string(), "secret-owner-key".to_string());
+        settings.room_owners.insert("other".to_string(), "public-owner-key".to_string());
+        settings.nickname = Some("alice".to_string());
+        settings.muted_peers.push("12D3KooW".to_string());
+
+        let redacted = redact_settings(&settings);
+        assert_eq!(redacted["owned_rooms"], json!({ "lobby": REDACTED }));
+        assert!(!redacted.to_string().contains("secret-owner-key"));
+        // Public keys and ordinary fields pass through
+        assert_eq!(redacted["room_owners"], json!({ "other": "public-owner-key" }));
+        assert_eq!(redacted["nickname"], json!("alice"));
+        assert_eq!(redacted["muted_peers"], json!(["12D3KooW"]));
+    }
+
+    #[test]
+    fn secret_fields_are_redacted_at_any_depth() {
+        let mut value = json!({
+            "room": "lobby",
+            "passphrase": "hunter22",
+            "nested": {
+                "Room_Password": "hunter22",
+                "list": [{ "private_key": [1, 2, 3], "port": 4001 }],
+                "client_secret": null,
+            },
+        });
+        redact_secret_fields(&mut value);
+        assert_eq!(value, json!({
+            "room": "lobby",
+            "passphrase": REDACTED,
+            "nested": {
+                "Room_Password": REDACTED,
+                "list": [{ "private_key": REDACTED, "port": 4001 }],
+                "client_secret": null,
+            },
+        }));
+    }
+}
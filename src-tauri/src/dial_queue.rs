@@ -0,0 +1,162 @@
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+// Discovered peers waiting to be dialed and the dials under way. Only a
+// few run at once so a room with many providers doesn't open a burst of
+// sockets; the rest wait for a running dial to connect or fail.
+#[derive(Default)]
+pub struct DialQueue {
+    queued: HashSet<PeerId>,
+    in_flight: HashSet<PeerId>,
+    // When an address was last learned for each peer, to dial the freshest first
+    learned_at: HashMap<PeerId, Instant>,
+}
+
+impl DialQueue {
+    // False if the peer is already queued or being dialed
+    pub fn queue(&mut self, peer_id: PeerId) -> bool {
+        !self.in_flight.contains(&peer_id) && self.queued.insert(peer_id)
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.queued.contains(peer_id) || self.in_flight.contains(peer_id)
+    }
+
+    pub fn address_learned(&mut self, peer_id: PeerId) {
+        self.learned_at.insert(peer_id, Instant::now());
+    }
+
+    // Takes the next peer to dial while fewer than `max_concurrent` dials
+    // run: the one we learned an address for most recently, as it's the
+    // likeliest to answer. Peers with no address of their own come last;
+    // they usually need a DHT lookup. A peer taken but not dialed (e.g. it
+    // connected meanwhile) doesn't use up a slot.
+    pub fn next_due(&mut self, max_concurrent: usize) -> Option<PeerId> {
+        if self.in_flight.len() >= max_concurrent {
+            return None;
+        }
+        let peer_id = *self.queued.iter().max_by_key(|peer_id| self.learned_at.get(peer_id))?;
+        self.queued.remove(&peer_id);
+        Some(peer_id)
+    }
+
+    pub fn started(&mut self, peer_id: PeerId) {
+        self.in_flight.insert(peer_id);
+    }
+
+    // A connection frees the peer's dial slot, and a dial still queued for
+    // it is no longer needed, e.g. when the peer dialed us first
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        self.in_flight.remove(peer_id);
+        self.queued.remove(peer_id);
+    }
+
+    // Frees the slot; the peer is queued again when it's next discovered
+    pub fn failed(&mut self, peer_id: &PeerId) {
+        self.in_flight.remove(peer_id);
+    }
+
+    // Dials started on a network we've since left won't complete
+    pub fn clear_in_flight(&mut self) {
+        self.in_flight.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.in_flight.clear();
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn in_flight_len(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Starts every dial that's due, as `process_pending_dials` does
+    fn start_due(queue: &mut DialQueue, max_concurrent: usize) -> Vec<PeerId> {
+        let mut started = Vec::new();
+        while let Some(peer_id) = queue.next_due(max_concurrent) {
+            queue.started(peer_id);
+            started.push(peer_id);
+        }
+        started
+    }
+
+    #[test]
+    fn at_most_max_concurrent_dials_run() {
+        let mut queue = DialQueue::default();
+        let peers: Vec<PeerId> = (0..10).map(|_| PeerId::random()).collect();
+        for peer_id in &peers {
+            assert!(queue.queue(*peer_id));
+        }
+        assert!(!queue.queue(peers[0]));
+
+        let started = start_due(&mut queue, 4);
+        assert_eq!(started.len(), 4);
+        assert_eq!(queue.in_flight_len(), 4);
+        assert_eq!(queue.queued_len(), 6);
+        // Being dialed counts as queued for new discoveries
+        assert!(!queue.queue(started[0]));
+        assert!(start_due(&mut queue, 4).is_empty());
+    }
+
+    #[test]
+    fn queue_drains_as_dials_connect_or_fail() {
+        let mut queue = DialQueue::default();
+        for _ in 0..6 {
+            queue.queue(PeerId::random());
+        }
+        let started = start_due(&mut queue, 2);
+        assert_eq!(started.len(), 2);
+
+        queue.connected(&started[0]);
+        assert_eq!(start_due(&mut queue, 2).len(), 1);
+        queue.failed(&started[1]);
+        assert_eq!(start_due(&mut queue, 2).len(), 1);
+        assert_eq!(queue.queued_len(), 2);
+
+        // Connections we didn't dial leave the running dials alone
+        queue.connected(&PeerId::random());
+        queue.failed(&PeerId::random());
+        assert!(start_due(&mut queue, 2).is_empty());
+        assert_eq!(queue.in_flight_len(), 2);
+    }
+
+    #[test]
+    fn connected_peers_leave_the_queue() {
+        let mut queue = DialQueue::default();
+        let peer_id = PeerId::random();
+        queue.queue(peer_id);
+        queue.connected(&peer_id);
+        assert!(!queue.contains(&peer_id));
+        assert_eq!(queue.next_due(4), None);
+
+        // A failed dial can be queued again
+        queue.queue(peer_id);
+        assert_eq!(queue.next_due(4), Some(peer_id));
+        queue.started(peer_id);
+        queue.failed(&peer_id);
+        assert!(queue.queue(peer_id));
+    }
+
+    #[test]
+    fn freshest_addresses_are_dialed_first() {
+        let mut queue = DialQueue::default();
+        let (no_address, older, newer) = (PeerId::random(), PeerId::random(), PeerId::random());
+        queue.address_learned(older);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        queue.address_learned(newer);
+        for peer_id in [no_address, older, newer] {
+            queue.queue(peer_id);
+        }
+        assert_eq!(start_due(&mut queue, 4), vec![newer, older, no_address]);
+    }
+}
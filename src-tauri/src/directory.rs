@@ -0,0 +1,112 @@
+use crate::moderation;
+use crate::p2p_node::sanitize_room_name;
+use libp2p::{identity, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+pub const DIRECTORY_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/directory/1.0.0");
+// Everyone listing a public room provides this key in the DHT
+pub const DIRECTORY_KEY: &str = "p2p-chat:directory";
+// Most rooms returned by one directory lookup
+pub const MAX_PUBLIC_ROOMS: usize = 50;
+// Listings claiming more members than this are spam
+const MAX_MEMBER_ESTIMATE: u32 = 10_000;
+// Listings older than this are stale; peers sign a fresh one per request
+const LISTING_MAX_AGE_SECS: i64 = 5 * 60;
+
+// Asks a directory provider for the public rooms it is in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryResponse {
+    pub rooms: Vec<RoomListing>,
+}
+
+// A public room as advertised by one of its members, signed with their
+// peer key so it can't be passed off as coming from someone else
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomListing {
+    pub room: String,
+    pub members: u32,
+    // Unix seconds
+    pub issued_at: i64,
+    // Base58 protobuf encoding of the lister's public key
+    pub public_key: String,
+    // Base58 signature over `signed_bytes`
+    pub signature: String,
+}
+
+impl RoomListing {
+    pub fn sign(room: &str, members: u32, keypair: &identity::Keypair) -> Result<Self, String> {
+        let issued_at = chrono::Utc::now().timestamp();
+        let signature = keypair
+            .sign(&signed_bytes(room, members, issued_at))
+            .map_err(|e| format!("Failed to sign room listing: {}", e))?;
+        Ok(Self {
+            room: room.to_string(),
+            members,
+            issued_at,
+            public_key: moderation::encode_public_key(&keypair.public()),
+            signature: bs58::encode(signature).into_string(),
+        })
+    }
+
+    // The listing must be signed by the peer that sent it, recent, and sane
+    pub fn verify(&self, sender: &PeerId) -> Result<(), String> {
+        let key = moderation::decode_public_key(&self.public_key)?;
+        if key.to_peer_id() != *sender {
+            return Err("listing was signed by another peer".to_string());
+        }
+        let signature = bs58::decode(&self.signature)
+            .into_vec()
+            .map_err(|_| "signature is not valid base58".to_string())?;
+        if !key.verify(&signed_bytes(&self.room, self.members, self.issued_at), &signature) {
+            return Err("bad signature".to_string());
+        }
+
+        let age = chrono::Utc::now().timestamp() - self.issued_at;
+        if !(-60..=LISTING_MAX_AGE_SECS).contains(&age) {
+            return Err(format!("listing is {} seconds old", age));
+        }
+        if self.members == 0 || self.members > MAX_MEMBER_ESTIMATE {
+            return Err(format!("implausible member count {}", self.members));
+        }
+        if sanitize_room_name(&self.room).as_deref() != Ok(self.room.as_str()) {
+            return Err("invalid room name".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn signed_bytes(room: &str, members: u32, issued_at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("p2p-chat/directory", room, members, issued_at)).expect("listing serialization cannot fail")
+}
+
+// One entry of `list_public_rooms`, merged from every member listing the room
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicRoom {
+    pub room: String,
+    // The largest estimate any lister reported
+    pub members: u32,
+    // Peers that listed the room
+    pub listed_by: usize,
+}
+
+// Merge listings per room, drop hidden rooms, and keep the biggest rooms
+pub fn merge_listings(listings: &[RoomListing], hidden: &HashSet<String>) -> Vec<PublicRoom> {
+    let mut rooms: HashMap<&str, PublicRoom> = HashMap::new();
+    for listing in listings.iter().filter(|listing| !hidden.contains(&listing.room)) {
+        let room = rooms.entry(listing.room.as_str()).or_insert_with(|| PublicRoom {
+            room: listing.room.clone(),
+            members: 0,
+            listed_by: 0,
+        });
+        room.members = room.members.max(listing.members);
+        room.listed_by += 1;
+    }
+    let mut rooms: Vec<PublicRoom> = rooms.into_values().collect();
+    rooms.sort_by(|a, b| b.listed_by.cmp(&a.listed_by).then(b.members.cmp(&a.members)).then(a.room.cmp(&b.room)));
+    rooms.truncate(MAX_PUBLIC_ROOMS);
+    rooms
+}
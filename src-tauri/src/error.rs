@@ -0,0 +1,171 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::fmt;
+
+// Error returned by every Tauri command. Serialized as an object with a
+// stable `code` the frontend can match on, a readable `message`, and the
+// variant's own fields, e.g.
+// `{"code":"invalid_address","message":"Invalid address: ...","reason":"..."}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    NotInitialized,
+    AlreadyInitialized,
+    InvalidAddress { reason: String },
+    // Bad room names, peer IDs, config values and other arguments
+    InvalidInput { reason: String },
+    RoomNotJoined,
+    PublishFailed { reason: String },
+    RateLimited { retry_after_secs: u64 },
+    RelayReservationFailed { reason: String },
+    // An attachment couldn't be downloaded or didn't match its hash
+    AttachmentUnavailable { reason: String },
+    // An exported identity that doesn't open with the given passphrase
+    WrongPassphrase,
+    // A file that isn't an exported identity, or is damaged
+    InvalidIdentityFile { reason: String },
+    Timeout,
+    Internal { message: String },
+}
+
+impl CommandError {
+    // Wraps errors from storage, settings and the like that the UI can only display
+    pub fn internal(e: impl fmt::Display) -> Self {
+        CommandError::Internal { message: e.to_string() }
+    }
+
+    pub fn invalid_input(e: impl fmt::Display) -> Self {
+        CommandError::InvalidInput { reason: e.to_string() }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::NotInitialized => "not_initialized",
+            CommandError::AlreadyInitialized => "already_initialized",
+            CommandError::InvalidAddress { .. } => "invalid_address",
+            CommandError::InvalidInput { .. } => "invalid_input",
+            CommandError::RoomNotJoined => "room_not_joined",
+            CommandError::PublishFailed { .. } => "publish_failed",
+            CommandError::RateLimited { .. } => "rate_limited",
+            CommandError::RelayReservationFailed { .. } => "relay_reservation_failed",
+            CommandError::AttachmentUnavailable { .. } => "attachment_unavailable",
+            CommandError::WrongPassphrase => "wrong_passphrase",
+            CommandError::InvalidIdentityFile { .. } => "invalid_identity_file",
+            CommandError::Timeout => "timeout",
+            CommandError::Internal { .. } => "internal",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::NotInitialized => write!(f, "P2P node not running"),
+            CommandError::AlreadyInitialized => write!(f, "P2P node already initialized"),
+            CommandError::InvalidAddress { reason } => write!(f, "Invalid address: {}", reason),
+            CommandError::InvalidInput { reason } => write!(f, "{}", reason),
+            CommandError::RoomNotJoined => write!(f, "Join a room first (Ctrl+J)"),
+            CommandError::PublishFailed { reason } => write!(f, "Failed to publish message: {}", reason),
+            CommandError::RateLimited { retry_after_secs } => {
+                write!(f, "Too many changes, try again in {} seconds", retry_after_secs)
+            }
+            CommandError::RelayReservationFailed { reason } => write!(f, "Relay reservation failed: {}", reason),
+            CommandError::AttachmentUnavailable { reason } => write!(f, "Couldn't fetch attachment: {}", reason),
+            CommandError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            CommandError::InvalidIdentityFile { reason } => write!(f, "Not a valid identity file: {}", reason),
+            CommandError::Timeout => write!(f, "Timed out, try again"),
+            CommandError::Internal { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl Serialize for CommandError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", self.code())?;
+        map.serialize_entry("message", &self.to_string())?;
+        match self {
+            CommandError::InvalidAddress { reason }
+            | CommandError::InvalidInput { reason }
+            | CommandError::PublishFailed { reason }
+            | CommandError::RelayReservationFailed { reason }
+            | CommandError::AttachmentUnavailable { reason }
+            | CommandError::InvalidIdentityFile { reason } => map.serialize_entry("reason", reason)?,
+            CommandError::RateLimited { retry_after_secs } => map.serialize_entry("retry_after_secs", retry_after_secs)?,
+            _ => {}
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn to_json(error: CommandError) -> Value {
+        serde_json::to_value(error).unwrap()
+    }
+
+    #[test]
+    fn variants_without_fields_have_code_and_message_only() {
+        let cases = [
+            (CommandError::NotInitialized, "not_initialized", "P2P node not running"),
+            (CommandError::AlreadyInitialized, "already_initialized", "P2P node already initialized"),
+            (CommandError::RoomNotJoined, "room_not_joined", "Join a room first (Ctrl+J)"),
+            (CommandError::WrongPassphrase, "wrong_passphrase", "Wrong passphrase"),
+            (CommandError::Timeout, "timeout", "Timed out, try again"),
+        ];
+        for (error, code, message) in cases {
+            assert_eq!(to_json(error), json!({ "code": code, "message": message }));
+        }
+    }
+
+    #[test]
+    fn variants_with_a_reason_carry_it() {
+        let reason = || "bad thing".to_string();
+        let cases = [
+            (CommandError::InvalidAddress { reason: reason() }, "invalid_address", "Invalid address: bad thing"),
+            (CommandError::InvalidInput { reason: reason() }, "invalid_input", "bad thing"),
+            (CommandError::PublishFailed { reason: reason() }, "publish_failed", "Failed to publish message: bad thing"),
+            (
+                CommandError::RelayReservationFailed { reason: reason() },
+                "relay_reservation_failed",
+                "Relay reservation failed: bad thing",
+            ),
+            (
+                CommandError::AttachmentUnavailable { reason: reason() },
+                "attachment_unavailable",
+                "Couldn't fetch attachment: bad thing",
+            ),
+            (
+                CommandError::InvalidIdentityFile { reason: reason() },
+                "invalid_identity_file",
+                "Not a valid identity file: bad thing",
+            ),
+        ];
+        for (error, code, message) in cases {
+            assert_eq!(to_json(error), json!({ "code": code, "message": message, "reason": "bad thing" }));
+        }
+    }
+
+    #[test]
+    fn rate_limited_carries_the_retry_delay() {
+        assert_eq!(
+            to_json(CommandError::RateLimited { retry_after_secs: 12 }),
+            json!({
+                "code": "rate_limited",
+                "message": "Too many changes, try again in 12 seconds",
+                "retry_after_secs": 12,
+            })
+        );
+    }
+
+    #[test]
+    fn internal_errors_show_their_message() {
+        assert_eq!(
+            to_json(CommandError::internal("disk full")),
+            json!({ "code": "internal", "message": "disk full" })
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+
+// Entries kept for `get_event_log`; the oldest are dropped past this
+pub const MAX_LOG_ENTRIES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub timestamp: String,
+    pub level: LogLevel,
+    // What the entry is about, e.g. "dial", "bootstrap" or "provider_search"
+    pub kind: &'static str,
+    pub message: String,
+}
+
+// Recent network events and errors for a diagnostics view, so the UI
+// doesn't have to scrape the tracing output
+#[derive(Default)]
+pub struct EventLog {
+    entries: VecDeque<LogEntry>,
+}
+
+impl EventLog {
+    pub fn push(&mut self, level: LogLevel, kind: &'static str, message: String) {
+        if self.entries.len() >= MAX_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LogEntry {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            level,
+            kind,
+            message,
+        });
+    }
+
+    // The newest `limit` entries, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<LogEntry> {
+        let skip = self.entries.len().saturating_sub(limit);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+}
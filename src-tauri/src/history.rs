@@ -0,0 +1,631 @@
+use crate::attachments::Attachment;
+use crate::p2p_node::{normalize_room_name, ChatMessage, ContentType, OutboxEntry};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Bump when the schema changes and add a migration step in `migrate`
+const SCHEMA_VERSION: i32 = 9;
+
+// Bump when the export line format changes
+const EXPORT_VERSION: u32 = 1;
+
+const INSERT_MESSAGE: &str = "INSERT OR IGNORE INTO messages
+    (id, room, sender, content, timestamp, is_self, is_direct, content_type, expires_at, attachment, lamport, recovered)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)";
+
+const MESSAGE_COLUMNS: &str = "id, room, sender, content, timestamp, is_self, is_direct, content_type, attachment, lamport, recovered";
+
+// Upper bound on search results regardless of the requested limit
+const MAX_SEARCH_RESULTS: u32 = 200;
+
+// How often messages past their expiry are deleted
+pub const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+// First line of every export file
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportHeader {
+    version: u32,
+    exported_at: String,
+    room: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportSummary {
+    pub exported: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub corrupt: usize,
+}
+
+// Payload of the `message-expired` event
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiredMessage {
+    pub id: String,
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub message: ChatMessage,
+    pub snippet: String,
+}
+
+// Local message store backed by SQLite
+pub struct MessageStore {
+    conn: Connection,
+}
+
+impl MessageStore {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        let mut store = Self { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&mut self) -> rusqlite::Result<()> {
+        let version: i32 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        info!("Migrating message store from schema {} to {}", version, SCHEMA_VERSION);
+        let tx = self.conn.transaction()?;
+
+        if version < 1 {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id TEXT PRIMARY KEY,
+                    room TEXT,
+                    sender TEXT NOT NULL,
+                    content TEXT NOT NULL,
+                    timestamp TEXT NOT NULL,
+                    is_self INTEGER NOT NULL,
+                    is_direct INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_room ON messages (room, timestamp);",
+            )?;
+        }
+
+        if version < 2 {
+            // Full-text index over message content, kept in sync by triggers
+            tx.execute_batch(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                    content,
+                    content = 'messages',
+                    content_rowid = 'rowid'
+                );
+                CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+                END;
+                CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                        VALUES ('delete', old.rowid, old.content);
+                END;
+                CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                        VALUES ('delete', old.rowid, old.content);
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+                END;
+                -- Index existing rows now; the update trigger can't remove
+                -- rows from the index that were never added
+                INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+            )?;
+        }
+
+        if version < 3 {
+            tx.execute_batch(
+                "ALTER TABLE messages ADD COLUMN content_type TEXT NOT NULL DEFAULT 'text/plain';",
+            )?;
+        }
+
+        if version < 4 {
+            // Room names are now stored normalized; fold older spellings into one room
+            let rooms: Vec<String> = tx
+                .prepare("SELECT DISTINCT room FROM messages WHERE room IS NOT NULL")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            for room in rooms {
+                let normalized = normalize_room_name(&room);
+                if normalized != room {
+                    tx.execute("UPDATE messages SET room = ?1 WHERE room = ?2", params![normalized, room])?;
+                }
+            }
+        }
+
+        if version < 5 {
+            // Set for messages stored while a message TTL was configured
+            tx.execute_batch(
+                "ALTER TABLE messages ADD COLUMN expires_at TEXT;
+                CREATE INDEX IF NOT EXISTS messages_expiry ON messages (expires_at)
+                    WHERE expires_at IS NOT NULL;",
+            )?;
+        }
+
+        if version < 6 {
+            // Room messages that couldn't be published yet
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS outbox (
+                    id TEXT PRIMARY KEY,
+                    room TEXT NOT NULL,
+                    content TEXT NOT NULL,
+                    content_type TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                );",
+            )?;
+        }
+
+        if version < 7 {
+            // JSON metadata of a message's attachment
+            tx.execute_batch("ALTER TABLE messages ADD COLUMN attachment TEXT;")?;
+        }
+
+        if version < 8 {
+            // Lamport clock of each message; older ones sort first, by timestamp
+            tx.execute_batch(
+                "ALTER TABLE messages ADD COLUMN lamport INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE outbox ADD COLUMN lamport INTEGER NOT NULL DEFAULT 0;
+                CREATE INDEX IF NOT EXISTS messages_room_order ON messages (room, lamport, timestamp);",
+            )?;
+        }
+
+        if version < 9 {
+            // Set on messages fetched from their author after we missed them
+            tx.execute_batch("ALTER TABLE messages ADD COLUMN recovered INTEGER NOT NULL DEFAULT 0;")?;
+        }
+
+        // Rebuild the search index so it covers rows written by older schemas
+        tx.execute("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')", [])?;
+
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()
+    }
+
+    // Returns false if a message with the same ID was already stored. With a
+    // TTL the message is deleted by `expire` once that much time has passed.
+    pub fn store(&self, msg: &ChatMessage, ttl: Option<Duration>) -> rusqlite::Result<bool> {
+        let expires_at = ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| (chrono::Utc::now() + ttl).to_rfc3339());
+        let inserted = self.conn.execute(
+            INSERT_MESSAGE,
+            params![
+                msg.id,
+                msg.room.as_deref().map(normalize_room_name),
+                msg.from,
+                msg.content,
+                msg.timestamp,
+                msg.is_self,
+                msg.is_direct,
+                msg.content_type.as_str(),
+                expires_at,
+                attachment_json(msg),
+                msg.lamport as i64,
+                msg.recovered
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    // Delete messages whose expiry has passed, returning what was removed
+    pub fn expire(&self) -> rusqlite::Result<Vec<ExpiredMessage>> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let mut stmt = self
+            .conn
+            .prepare("DELETE FROM messages WHERE expires_at <= ?1 RETURNING id, room")?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok(ExpiredMessage {
+                id: row.get(0)?,
+                room: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn queue_outgoing(&self, entry: &OutboxEntry) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO outbox (id, room, content, content_type, created_at, lamport)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.id,
+                entry.room,
+                entry.content,
+                entry.content_type.as_str(),
+                entry.created_at,
+                entry.lamport as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Messages still waiting to be published, oldest first
+    pub fn outbox(&self) -> rusqlite::Result<Vec<OutboxEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, room, content, content_type, created_at, lamport FROM outbox ORDER BY lamport, created_at, id")?;
+        let rows = stmt.query_map([], |row| {
+            let content_type: String = row.get(3)?;
+            Ok(OutboxEntry {
+                id: row.get(0)?,
+                room: row.get(1)?,
+                content: row.get(2)?,
+                content_type: ContentType::from_mime(&content_type),
+                created_at: row.get(4)?,
+                lamport: row.get::<_, i64>(5)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn remove_outgoing(&self, id: &str) -> rusqlite::Result<()> {
+        self.conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    // All stored messages in the order they're shown (ChatMessage::sort_key),
+    // optionally limited to one room
+    pub fn messages(&self, room: Option<&str>) -> rusqlite::Result<Vec<ChatMessage>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE ?1 IS NULL OR room = ?1
+             ORDER BY lamport, timestamp, id",
+            MESSAGE_COLUMNS
+        ))?;
+
+        let room = room.map(normalize_room_name);
+        let rows = stmt.query_map(params![room], |row| message_from_row(row, 0))?;
+        rows.collect()
+    }
+
+    // Names of all rooms we have stored messages for
+    pub fn rooms(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT room FROM messages WHERE room IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    // Messages from other peers in a room newer than the given timestamp
+    pub fn count_received_since(&self, room: &str, since: Option<&str>) -> rusqlite::Result<u32> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM messages
+             WHERE room = ?1 AND is_self = 0 AND (?2 IS NULL OR timestamp > ?2)",
+            params![normalize_room_name(room), since],
+            |row| row.get(0),
+        )
+    }
+
+    // ID and timestamp of the newest message in a room
+    pub fn latest_in_room(&self, room: &str) -> rusqlite::Result<Option<(String, String)>> {
+        self.conn
+            .query_row(
+                "SELECT id, timestamp FROM messages WHERE room = ?1
+                 ORDER BY lamport DESC, timestamp DESC, id DESC LIMIT 1",
+                params![normalize_room_name(room)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    // Highest Lamport time of any stored or queued message
+    pub fn max_lamport(&self) -> rusqlite::Result<u64> {
+        self.conn
+            .query_row(
+                "SELECT MAX((SELECT COALESCE(MAX(lamport), 0) FROM messages),
+                            (SELECT COALESCE(MAX(lamport), 0) FROM outbox))",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|lamport| lamport as u64)
+    }
+
+    // Full-text search over message content, newest first
+    pub fn search(&self, query: &str, room: Option<&str>, limit: u32) -> Result<Vec<SearchHit>, Box<dyn Error>> {
+        let fts_query = fts_query(query).ok_or("Search query cannot be empty")?;
+        let limit = limit.clamp(1, MAX_SEARCH_RESULTS);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT snippet(messages_fts, 0, '[', ']', '…', 12),
+                    m.id, m.room, m.sender, m.content, m.timestamp, m.is_self, m.is_direct, m.content_type, m.attachment, m.lamport, m.recovered
+             FROM messages_fts
+             JOIN messages m ON m.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.room = ?2)
+             ORDER BY m.timestamp DESC
+             LIMIT ?3",
+        )?;
+
+        let room = room.map(normalize_room_name);
+        let rows = stmt.query_map(params![fts_query, room, limit], |row| {
+            Ok(SearchHit {
+                snippet: row.get(0)?,
+                message: message_from_row(row, 1)?,
+            })
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    // Write messages as newline-delimited JSON, preceded by a version header
+    pub fn export(&self, room: Option<&str>, path: &Path) -> Result<ExportSummary, Box<dyn Error>> {
+        let messages = self.messages(room)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let header = ExportHeader {
+            version: EXPORT_VERSION,
+            exported_at: chrono::Utc::now().to_rfc3339(),
+            room: room.map(normalize_room_name),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+
+        for msg in &messages {
+            writeln!(writer, "{}", serde_json::to_string(msg)?)?;
+        }
+        writer.flush()?;
+
+        info!("Exported {} messages to {}", messages.len(), path.display());
+        Ok(ExportSummary { exported: messages.len() })
+    }
+
+    // Merge an export file into the store, skipping messages we already have
+    // and lines that fail to parse
+    pub fn import(&mut self, path: &Path) -> Result<ImportSummary, Box<dyn Error>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let header: ExportHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)
+                .map_err(|e| format!("Not a chat history export: {}", e))?,
+            None => return Err("History file is empty".into()),
+        };
+        if header.version > EXPORT_VERSION {
+            return Err(format!("Unsupported history export version {}", header.version).into());
+        }
+
+        let mut summary = ImportSummary { imported: 0, skipped: 0, corrupt: 0 };
+        let tx = self.conn.transaction()?;
+
+        for (index, line) in lines.enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    warn!("Failed to read history line {}: {}", index + 2, e);
+                    summary.corrupt += 1;
+                    continue;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let msg: ChatMessage = match serde_json::from_str(&line) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Skipping corrupt history line {}: {}", index + 2, e);
+                    summary.corrupt += 1;
+                    continue;
+                }
+            };
+
+            let inserted = tx.execute(
+                INSERT_MESSAGE,
+                params![
+                    msg.id,
+                    msg.room.as_deref().map(normalize_room_name),
+                    msg.from,
+                    msg.content,
+                    msg.timestamp,
+                    msg.is_self,
+                    msg.is_direct,
+                    msg.content_type.as_str(),
+                    None::<String>,
+                    attachment_json(&msg),
+                    msg.lamport as i64,
+                    msg.recovered
+                ],
+            )?;
+            if inserted > 0 {
+                summary.imported += 1;
+            } else {
+                summary.skipped += 1;
+            }
+        }
+
+        tx.commit()?;
+
+        info!(
+            "Imported {} messages from {} ({} skipped, {} corrupt)",
+            summary.imported,
+            path.display(),
+            summary.skipped,
+            summary.corrupt
+        );
+        Ok(summary)
+    }
+}
+
+// Read a message from MESSAGE_COLUMNS starting at column `first`
+fn message_from_row(row: &Row, first: usize) -> rusqlite::Result<ChatMessage> {
+    let content_type: String = row.get(first + 7)?;
+    Ok(ChatMessage {
+        id: row.get(first)?,
+        room: row.get(first + 1)?,
+        from: row.get(first + 2)?,
+        content: row.get(first + 3)?,
+        timestamp: row.get(first + 4)?,
+        is_self: row.get(first + 5)?,
+        is_direct: row.get(first + 6)?,
+        content_type: ContentType::from_mime(&content_type),
+        delivered_to: None,
+        recipients_estimate: None,
+        attachment: row
+            .get::<_, Option<String>>(first + 8)?
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        lamport: row.get::<_, i64>(first + 9)? as u64,
+        recovered: row.get(first + 10)?,
+    })
+}
+
+// Attachment metadata as stored; the bytes themselves live in the attachment store
+fn attachment_json(msg: &ChatMessage) -> Option<String> {
+    let attachment = msg.attachment.as_ref().map(|attachment| Attachment {
+        data: None,
+        ..attachment.clone()
+    })?;
+    serde_json::to_string(&attachment).ok()
+}
+
+// Turn free-form user input into an FTS5 query that matches all terms as
+// prefixes, quoting each term so punctuation can't produce a syntax error
+fn fts_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p_node::sort_for_display;
+
+    fn memory_store() -> MessageStore {
+        MessageStore::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn message(id: &str, room: &str, timestamp: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            from: "peer".to_string(),
+            content: format!("content of {}", id),
+            timestamp: timestamp.to_string(),
+            is_self: false,
+            is_direct: false,
+            room: Some(room.to_string()),
+            content_type: ContentType::Plain,
+            delivered_to: None,
+            recipients_estimate: None,
+            attachment: None,
+            lamport: 0,
+            recovered: false,
+        }
+    }
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("p2p-history-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn export_round_trips_and_skips_corrupt_lines() {
+        let source = memory_store();
+        for i in 0..3 {
+            source.store(&message(&format!("m{}", i), "lobby", &format!("2026-01-01T00:00:0{}Z", i)), None).unwrap();
+        }
+        let path = temp_file("export.jsonl");
+        assert_eq!(source.export(None, &path).unwrap().exported, 3);
+
+        // A line cut off mid-write and a blank line, which isn't counted
+        let mut text = std::fs::read_to_string(&path).unwrap();
+        text.push_str("{\"id\": \"broken\n\n");
+        std::fs::write(&path, text).unwrap();
+
+        let mut target = memory_store();
+        let summary = target.import(&path).unwrap();
+        assert_eq!((summary.imported, summary.skipped, summary.corrupt), (3, 0, 1));
+        let summary = target.import(&path).unwrap();
+        assert_eq!((summary.imported, summary.skipped, summary.corrupt), (0, 3, 1));
+
+        let ids: Vec<String> = target.messages(Some("lobby")).unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, ["m0", "m1", "m2"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migration_folds_old_room_spellings_into_one_room() {
+        let path = temp_file("v1.db");
+        {
+            // The schema as version 1 left it, with rooms stored as typed
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE messages (
+                    id TEXT PRIMARY KEY,
+                    room TEXT,
+                    sender TEXT NOT NULL,
+                    content TEXT NOT NULL,
+                    timestamp TEXT NOT NULL,
+                    is_self INTEGER NOT NULL,
+                    is_direct INTEGER NOT NULL
+                );
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+            for (id, room) in [("a", "Caf\u{e9}"), ("b", "cafe\u{301}"), ("c", " CAFÉ "), ("d", "other")] {
+                conn.execute(
+                    "INSERT INTO messages VALUES (?1, ?2, 'peer', 'hello', ?1, 0, 0)",
+                    params![id, room],
+                )
+                .unwrap();
+            }
+        }
+
+        let store = MessageStore::open(&path).unwrap();
+        let mut rooms = store.rooms().unwrap();
+        rooms.sort();
+        assert_eq!(rooms, ["caf\u{e9}", "other"]);
+        let ids: Vec<String> = store.messages(Some("CAFE\u{301}")).unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(store.search("hello", None, 10).unwrap().len(), 4);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn import_rejects_files_without_a_header() {
+        let path = temp_file("no-header.jsonl");
+        std::fs::write(&path, "not json\n").unwrap();
+        assert!(memory_store().import(&path).is_err());
+        std::fs::write(&path, "").unwrap();
+        assert!(memory_store().import(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn history_and_held_messages_share_one_order() {
+        let store = memory_store();
+        let stamped = |id: &str, lamport: u64, timestamp: &str| ChatMessage { lamport, ..message(id, "lobby", timestamp) };
+        let mut held = vec![
+            stamped("b", 2, "2026-01-01T00:00:05Z"),
+            stamped("a", 2, "2026-01-01T00:00:05Z"),
+            stamped("z", 1, "2026-01-01T00:00:09Z"),
+            stamped("c", 2, "2026-01-01T00:00:01Z"),
+            stamped("old", 0, "2026-01-01T00:00:00Z"),
+            stamped("late", 7, "2025-12-31T23:59:59Z"),
+        ];
+        for message in &held {
+            store.store(message, None).unwrap();
+        }
+        sort_for_display(&mut held);
+
+        let ids = |messages: &[ChatMessage]| messages.iter().map(|m| m.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&held), ["old", "z", "c", "a", "b", "late"]);
+        assert_eq!(ids(&store.messages(Some("lobby")).unwrap()), ids(&held));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::address_book::ADDRESS_BOOK_FILE;
+use crate::error::CommandError;
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use libp2p::identity;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const IDENTITY_FILE: &str = "identity.key";
+// Replaced identities are kept here, one directory per peer ID
+const ARCHIVE_DIR: &str = "identities";
+const EXPORT_FORMAT: &str = "p2p-chat/identity";
+const EXPORT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+// The node's keypair, kept in the app data directory so the peer ID
+// survives restarts
+pub struct IdentityStore {
+    dir: PathBuf,
+}
+
+impl IdentityStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(IDENTITY_FILE)
+    }
+
+    // Loads the saved keypair, or creates one on first run. A damaged key
+    // file is set aside rather than overwritten.
+    pub fn load_or_create(&self) -> Result<identity::Keypair, String> {
+        let path = self.key_path();
+        match std::fs::read(&path) {
+            Ok(bytes) => match identity::Keypair::from_protobuf_encoding(&bytes) {
+                Ok(keypair) => return Ok(keypair),
+                Err(e) => {
+                    warn!("Unreadable identity {}: {} - creating a new one", path.display(), e);
+                    let _ = std::fs::rename(&path, path.with_extension("key.corrupt"));
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+
+        let keypair = identity::Keypair::generate_ed25519();
+        self.save(&keypair)?;
+        info!("Created identity {}", keypair.public().to_peer_id());
+        Ok(keypair)
+    }
+
+    pub fn save(&self, keypair: &identity::Keypair) -> Result<(), String> {
+        let bytes = keypair.to_protobuf_encoding().map_err(|e| format!("Failed to encode identity: {}", e))?;
+        write_private(&self.key_path(), &bytes)
+    }
+
+    // Copies the keypair and the address book built with it into the archive
+    pub fn archive(&self, keypair: &identity::Keypair) -> Result<PathBuf, String> {
+        let dir = self.dir.join(ARCHIVE_DIR).join(keypair.public().to_peer_id().to_string());
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let bytes = keypair.to_protobuf_encoding().map_err(|e| format!("Failed to encode identity: {}", e))?;
+        write_private(&dir.join(IDENTITY_FILE), &bytes)?;
+
+        let book = self.dir.join(ADDRESS_BOOK_FILE);
+        if book.is_file() {
+            std::fs::copy(&book, dir.join(ADDRESS_BOOK_FILE)).map_err(|e| format!("Failed to archive address book: {}", e))?;
+        }
+        Ok(dir)
+    }
+}
+
+// An identity exported for another device: the keypair sealed with a key
+// derived from the user's passphrase
+#[derive(Serialize, Deserialize)]
+struct ExportedIdentity {
+    format: String,
+    version: u32,
+    // Shown before import; also bound into the ciphertext
+    peer_id: String,
+    // Base64
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub fn export(keypair: &identity::Keypair, passphrase: &str) -> Result<String, CommandError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(CommandError::invalid_input(format!(
+            "Passphrase must be at least {} characters",
+            MIN_PASSPHRASE_LEN
+        )));
+    }
+    let secret = keypair.to_protobuf_encoding().map_err(CommandError::internal)?;
+    let peer_id = keypair.public().to_peer_id().to_string();
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = derive_cipher(passphrase, &salt)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: &secret, aad: peer_id.as_bytes() })
+        .map_err(|_| CommandError::internal("Failed to encrypt identity"))?;
+
+    let exported = ExportedIdentity {
+        format: EXPORT_FORMAT.to_string(),
+        version: EXPORT_VERSION,
+        peer_id,
+        salt: BASE64.encode(salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    };
+    serde_json::to_string_pretty(&exported).map_err(CommandError::internal)
+}
+
+pub fn import(text: &str, passphrase: &str) -> Result<identity::Keypair, CommandError> {
+    let invalid = |reason: &str| CommandError::InvalidIdentityFile { reason: reason.to_string() };
+    let exported: ExportedIdentity = serde_json::from_str(text).map_err(|e| invalid(&e.to_string()))?;
+    if exported.format != EXPORT_FORMAT {
+        return Err(invalid("not an exported identity"));
+    }
+    if exported.version != EXPORT_VERSION {
+        return Err(invalid(&format!("unsupported version {}", exported.version)));
+    }
+    let salt = BASE64.decode(&exported.salt).map_err(|_| invalid("salt is not valid base64"))?;
+    let nonce: [u8; NONCE_LEN] = BASE64
+        .decode(&exported.nonce)
+        .ok()
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or_else(|| invalid("bad nonce"))?;
+    let ciphertext = BASE64.decode(&exported.ciphertext).map_err(|_| invalid("ciphertext is not valid base64"))?;
+    if salt.len() != SALT_LEN {
+        return Err(invalid("bad salt"));
+    }
+
+    // The file parsed, so a failed decryption means the passphrase is wrong
+    let secret = derive_cipher(passphrase, &salt)?
+        .decrypt(&XNonce::from(nonce), Payload { msg: &ciphertext, aad: exported.peer_id.as_bytes() })
+        .map_err(|_| CommandError::WrongPassphrase)?;
+    let keypair = identity::Keypair::from_protobuf_encoding(&secret).map_err(|e| invalid(&e.to_string()))?;
+    if keypair.public().to_peer_id().to_string() != exported.peer_id {
+        return Err(invalid("key doesn't match its peer ID"));
+    }
+    Ok(keypair)
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305, CommandError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CommandError::internal(format!("Failed to derive key: {}", e)))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+// Writes through a temp file; on Unix only the owner can read the result
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes).map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to protect {}: {}", tmp_path.display(), e))?;
+    }
+    std::fs::rename(&tmp_path, path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery";
+
+    #[test]
+    fn exported_identities_import_with_the_passphrase() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let exported = export(&keypair, PASSPHRASE).unwrap();
+        assert!(exported.contains(&keypair.public().to_peer_id().to_string()));
+        let imported = import(&exported, PASSPHRASE).unwrap();
+        assert_eq!(imported.public(), keypair.public());
+        assert!(matches!(import(&exported, "wrong passphrase"), Err(CommandError::WrongPassphrase)));
+        assert!(matches!(export(&keypair, "short"), Err(CommandError::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let exported = export(&identity::Keypair::generate_ed25519(), PASSPHRASE).unwrap();
+        let truncated = &exported[..exported.len() / 2];
+        let mut wrong_format: serde_json::Value = serde_json::from_str(&exported).unwrap();
+        wrong_format["format"] = "something/else".into();
+        let mut bad_salt: serde_json::Value = serde_json::from_str(&exported).unwrap();
+        bad_salt["salt"] = "%%%".into();
+
+        for text in [truncated, "", "garbage", "{}", &wrong_format.to_string(), &bad_salt.to_string()] {
+            assert!(
+                matches!(import(text, PASSPHRASE), Err(CommandError::InvalidIdentityFile { .. })),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn the_saved_keypair_is_reused() {
+        let dir = std::env::temp_dir().join(format!("p2p-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = IdentityStore::new(dir.clone());
+        let first = store.load_or_create().unwrap();
+        assert_eq!(store.load_or_create().unwrap().public(), first.public());
+
+        // A damaged key is set aside, not overwritten
+        std::fs::write(dir.join(IDENTITY_FILE), b"garbage").unwrap();
+        assert_ne!(store.load_or_create().unwrap().public(), first.public());
+        assert!(dir.join("identity.key.corrupt").is_file());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,341 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libp2p::kad::store::{self, MemoryStore, MemoryStoreConfig, RecordStore};
+use libp2p::kad::{ProviderRecord, Record, RecordKey};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// The Kademlia record store, kept in memory and snapshotted to a JSON file
+// in the app data directory so a restarted node rejoins the DHT warm.
+// Records and provider keys are each capped; the least recently used are
+// evicted to make room. Without a path nothing is written.
+pub struct PersistentStore {
+    local_peer_id: PeerId,
+    inner: MemoryStore,
+    path: Option<PathBuf>,
+    max_entries: usize,
+    // `get` and `providers` only take `&self` but still count as a use
+    usage: Mutex<Usage>,
+    // Set on every change so the periodic save can skip an untouched store
+    dirty: bool,
+}
+
+// Last use of each key, as ticks of a counter
+#[derive(Default)]
+struct Usage {
+    tick: u64,
+    records: HashMap<RecordKey, u64>,
+    providers: HashMap<RecordKey, u64>,
+}
+
+impl Usage {
+    fn next(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn touch_record(&mut self, key: &RecordKey) {
+        let tick = self.next();
+        if let Some(used) = self.records.get_mut(key) {
+            *used = tick;
+        }
+    }
+
+    fn touch_providers(&mut self, key: &RecordKey) {
+        let tick = self.next();
+        if let Some(used) = self.providers.get_mut(key) {
+            *used = tick;
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Snapshot {
+    // Least recently used first, so load order restores the LRU order
+    records: Vec<SavedRecord>,
+    providers: Vec<SavedProvider>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRecord {
+    // Base64
+    key: String,
+    value: String,
+    publisher: Option<String>,
+    // Unix seconds
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedProvider {
+    key: String,
+    provider: String,
+    addresses: Vec<String>,
+    expires: Option<u64>,
+}
+
+impl PersistentStore {
+    // Loads the snapshot at `path` if there is one. An unreadable snapshot
+    // doesn't stop the node: the store starts empty and the error is
+    // returned alongside it to be reported.
+    pub fn load(local_peer_id: PeerId, path: Option<PathBuf>, max_entries: usize, max_providers_per_key: usize) -> (Self, Option<String>) {
+        let config = MemoryStoreConfig {
+            max_records: max_entries,
+            max_provided_keys: max_entries,
+            max_providers_per_key,
+            ..Default::default()
+        };
+        let mut store = Self {
+            local_peer_id,
+            inner: MemoryStore::with_config(local_peer_id, config),
+            path,
+            max_entries,
+            usage: Mutex::new(Usage::default()),
+            dirty: false,
+        };
+
+        let Some(path) = &store.path else {
+            return (store, None);
+        };
+        let snapshot = match std::fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<Snapshot>(&text) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("Ignoring unreadable DHT store {}: {}", path.display(), e);
+                    return (store, Some(e.to_string()));
+                }
+            },
+            Err(_) => return (store, None),
+        };
+
+        let (mut records, mut providers) = (0, 0);
+        for record in snapshot.records.into_iter().filter_map(SavedRecord::restore) {
+            if store.put(record).is_ok() {
+                records += 1;
+            }
+        }
+        for record in snapshot.providers.into_iter().filter_map(SavedProvider::restore) {
+            if store.add_provider(record).is_ok() {
+                providers += 1;
+            }
+        }
+        info!("Loaded {} DHT records and {} provider records", records, providers);
+        store.dirty = false;
+        (store, None)
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let usage = self.usage.lock().unwrap();
+        let mut records: Vec<Cow<'_, Record>> = self
+            .inner
+            .records()
+            .filter(|record| record.expires.is_none_or(|expires| expires > now))
+            .collect();
+        records.sort_by_key(|record| usage.records.get(&record.key).copied().unwrap_or_default());
+        let mut keys: Vec<(&RecordKey, u64)> = usage.providers.iter().map(|(key, used)| (key, *used)).collect();
+        keys.sort_by_key(|(_, used)| *used);
+        // Our own provider records aren't saved: the rooms we're in are
+        // provided again when we rejoin them
+        let providers = keys
+            .into_iter()
+            .flat_map(|(key, _)| self.inner.providers(key))
+            .filter(|record| record.provider != self.local_peer_id)
+            .filter(|record| record.expires.is_none_or(|expires| expires > now))
+            .map(|record| SavedProvider::new(&record))
+            .collect();
+        let snapshot = Snapshot {
+            records: records.iter().map(|record| SavedRecord::new(record)).collect(),
+            providers,
+        };
+        drop(usage);
+
+        // Write to a temp file first so a crash can't leave half a file
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string(&snapshot)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Drop the least recently used record if a new one wouldn't fit
+    fn make_room_for_record(&mut self, key: &RecordKey) {
+        let usage = self.usage.get_mut().unwrap();
+        if usage.records.contains_key(key) || usage.records.len() < self.max_entries {
+            return;
+        }
+        let Some(oldest) = usage.records.iter().min_by_key(|(_, used)| **used).map(|(key, _)| key.clone()) else {
+            return;
+        };
+        usage.records.remove(&oldest);
+        self.inner.remove(&oldest);
+    }
+
+    // Drop the providers of the least recently used key if a new key
+    // wouldn't fit. Keys we provide ourselves are never evicted.
+    fn make_room_for_provider(&mut self, key: &RecordKey) {
+        let usage = self.usage.get_mut().unwrap();
+        if usage.providers.contains_key(key) || usage.providers.len() < self.max_entries {
+            return;
+        }
+        let local_peer_id = self.local_peer_id;
+        let inner = &self.inner;
+        let Some(oldest) = usage
+            .providers
+            .iter()
+            .filter(|(key, _)| !inner.providers(key).iter().any(|record| record.provider == local_peer_id))
+            .min_by_key(|(_, used)| **used)
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+        usage.providers.remove(&oldest);
+        for record in self.inner.providers(&oldest) {
+            self.inner.remove_provider(&oldest, &record.provider);
+        }
+    }
+}
+
+impl RecordStore for PersistentStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        let record = self.inner.get(k)?;
+        self.usage.lock().unwrap().touch_record(k);
+        Some(record)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        let key = r.key.clone();
+        self.make_room_for_record(&key);
+        self.inner.put(r)?;
+        let usage = self.usage.get_mut().unwrap();
+        let tick = usage.next();
+        usage.records.insert(key, tick);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.inner.remove(k);
+        self.usage.get_mut().unwrap().records.remove(k);
+        self.dirty = true;
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = record.key.clone();
+        self.make_room_for_provider(&key);
+        self.inner.add_provider(record)?;
+        let usage = self.usage.get_mut().unwrap();
+        let tick = usage.next();
+        usage.providers.insert(key, tick);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        let providers = self.inner.providers(key);
+        if !providers.is_empty() {
+            self.usage.lock().unwrap().touch_providers(key);
+        }
+        providers
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.usage.get_mut().unwrap().providers.remove(k);
+        }
+        self.dirty = true;
+    }
+}
+
+impl SavedRecord {
+    fn new(record: &Record) -> Self {
+        Self {
+            key: BASE64.encode(record.key.as_ref()),
+            value: BASE64.encode(&record.value),
+            publisher: record.publisher.map(|peer_id| peer_id.to_string()),
+            expires: record.expires.map(to_unix_secs),
+        }
+    }
+
+    // None for expired entries and ones that don't decode; the rest of the
+    // snapshot still loads
+    fn restore(self) -> Option<Record> {
+        if self.expires.is_some_and(is_past) {
+            return None;
+        }
+        Some(Record {
+            key: RecordKey::from(BASE64.decode(&self.key).ok()?),
+            value: BASE64.decode(&self.value).ok()?,
+            publisher: match self.publisher {
+                Some(publisher) => Some(publisher.parse().ok()?),
+                None => None,
+            },
+            expires: self.expires.map(from_unix_secs),
+        })
+    }
+}
+
+impl SavedProvider {
+    fn new(record: &ProviderRecord) -> Self {
+        Self {
+            key: BASE64.encode(record.key.as_ref()),
+            provider: record.provider.to_string(),
+            addresses: record.addresses.iter().map(|addr| addr.to_string()).collect(),
+            expires: record.expires.map(to_unix_secs),
+        }
+    }
+
+    fn restore(self) -> Option<ProviderRecord> {
+        if self.expires.is_some_and(is_past) {
+            return None;
+        }
+        Some(ProviderRecord {
+            key: RecordKey::from(BASE64.decode(&self.key).ok()?),
+            provider: self.provider.parse().ok()?,
+            addresses: self.addresses.iter().filter_map(|addr| addr.parse::<Multiaddr>().ok()).collect(),
+            expires: self.expires.map(from_unix_secs),
+        })
+    }
+}
+
+// Instants don't survive a restart, so expiry is saved as wall-clock time
+fn to_unix_secs(instant: Instant) -> u64 {
+    let at = SystemTime::now() + instant.saturating_duration_since(Instant::now());
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn from_unix_secs(secs: u64) -> Instant {
+    let at = UNIX_EPOCH + Duration::from_secs(secs);
+    Instant::now() + at.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+fn is_past(secs: u64) -> bool {
+    UNIX_EPOCH + Duration::from_secs(secs) <= SystemTime::now()
+}
@@ -0,0 +1,162 @@
+use libp2p::core::transport::PortUse;
+use libp2p::core::upgrade::DeniedUpgrade;
+use libp2p::core::{Endpoint, Multiaddr};
+use libp2p::swarm::handler::ConnectionEvent;
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm, NetworkBehaviour,
+    NotifyHandler, SubstreamProtocol, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::task::{Context, Poll, Waker};
+
+// Holds connections to chosen peers open past the swarm's idle timeout.
+// Everyone else, e.g. bootstrap nodes, is left to idle out as usual.
+#[derive(Default)]
+pub struct Behaviour {
+    keep: HashSet<PeerId>,
+    connections: HashMap<PeerId, HashSet<ConnectionId>>,
+    pending: VecDeque<ToSwarm<Infallible, bool>>,
+    waker: Option<Waker>,
+}
+
+impl Behaviour {
+    pub fn set(&mut self, peer_id: PeerId, keep_alive: bool) {
+        let changed = if keep_alive {
+            self.keep.insert(peer_id)
+        } else {
+            self.keep.remove(&peer_id)
+        };
+        if changed {
+            self.notify(peer_id, keep_alive);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for peer_id in std::mem::take(&mut self.keep) {
+            self.notify(peer_id, false);
+        }
+    }
+
+    pub fn is_kept_alive(&self, peer_id: &PeerId) -> bool {
+        self.keep.contains(peer_id)
+    }
+
+    fn notify(&mut self, peer_id: PeerId, keep_alive: bool) {
+        let Some(connections) = self.connections.get(&peer_id) else {
+            return;
+        };
+        for connection in connections {
+            self.pending.push_back(ToSwarm::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(*connection),
+                event: keep_alive,
+            });
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer_id: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler {
+            keep_alive: self.keep.contains(&peer_id),
+        })
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer_id: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler {
+            keep_alive: self.keep.contains(&peer_id),
+        })
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(established) => {
+                self.connections
+                    .entry(established.peer_id)
+                    .or_default()
+                    .insert(established.connection_id);
+            }
+            FromSwarm::ConnectionClosed(closed) => {
+                if let Some(connections) = self.connections.get_mut(&closed.peer_id) {
+                    connections.remove(&closed.connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&closed.peer_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(&mut self, _: PeerId, _: ConnectionId, event: THandlerOutEvent<Self>) {
+        match event {}
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(event);
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Speaks no protocol; it only tells the connection whether to stay open
+pub struct Handler {
+    keep_alive: bool,
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = bool;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>> {
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    fn on_connection_event(
+        &mut self,
+        _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol, Self::InboundOpenInfo, Self::OutboundOpenInfo>,
+    ) {
+    }
+}
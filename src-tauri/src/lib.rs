@@ -1,207 +1,2137 @@
+mod address_book;
+mod attachments;
+mod chunking;
+mod config;
+mod diagnostics;
+mod dial_queue;
+mod directory;
+mod error;
+mod event_log;
+mod history;
+mod identity_store;
+mod kad_store;
+mod keep_alive;
+mod log_capture;
+mod mentions;
+mod moderation;
+mod netsize;
+mod nicknames;
 mod p2p_node;
+mod retransmit;
+mod room_crypto;
+mod settings;
+mod unread;
 
-use p2p_node::{ChatMessage, P2PNode, PeerInfo};
+use address_book::{AddressBook, AddressBookPeer, ADDRESS_BOOK_FILE};
+use attachments::AttachmentStore;
+use config::{P2PConfig, PowerMode};
+use directory::PublicRoom;
+use error::CommandError;
+use event_log::{LogEntry, MAX_LOG_ENTRIES};
+use history::{ExportSummary, ImportSummary, MessageStore, SearchHit, EXPIRY_SWEEP_INTERVAL};
+use identity_store::IdentityStore;
+use log_capture::{LogBuffer, LogRecord, LOG_STREAM_CAPACITY, MAX_LOG_RECORDS};
+use mentions::MentionEvent;
+use moderation::{ModerationAction, RoomInvite};
+use p2p_node::{
+    changes_node_info, changes_peer_list, is_own_address, normalize_room_name, sanitize_nickname, sanitize_room_name, ChatBehaviour,
+    ChatMessage, ConnectionStats, ConnectivityReport, ContentType, DhtLookupResult, MeshPeers, MessageState, NetworkSizeEstimate,
+    NodeEvent, NodeIdentity, P2PNode, PeerInfo, PeerProtocols, ReachabilityResult, RecordedSystemEvent, RelayStats, RoomBroadcast,
+    RoomMember, RoutingTableSummary, PEERS_CHANGED_DEBOUNCE, PRESENCE_INTERVAL, STALE_PEER_SWEEP_INTERVAL,
+};
+use settings::{ReadMarker, SessionRoom, SettingsStore};
+use unread::UnreadCounters;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tauri::{AppHandle, Emitter, Manager, State};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use futures::StreamExt;
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId, Swarm};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
 
-type P2PState = Arc<Mutex<Option<P2PNodeHandle>>>;
+type P2PState = Arc<Mutex<NodeState>>;
+type HistoryState = Arc<Mutex<MessageStore>>;
+type SettingsState = Arc<Mutex<SettingsStore>>;
+type UnreadState = Arc<Mutex<UnreadCounters>>;
+
+// Captured log lines and the handle that changes the log level at runtime
+struct LogState {
+    buffer: LogBuffer,
+    level: reload::Handle<LevelFilter, Registry>,
+}
+
+// How long get_node_info waits for the swarm task before giving up
+const NODE_INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long saving the session waits for the swarm task; the window may be
+// closing
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long reserve_relay waits for the relay to accept
+const RELAY_RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
+// How long `test_reachability` waits for its dial to connect or fail
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Lifecycle of the node; `Initializing` keeps a second init_p2p from starting
+// another node while the first one is still being built
+#[derive(Default)]
+enum NodeState {
+    #[default]
+    Stopped,
+    Initializing,
+    Ready(P2PNodeHandle),
+}
+
+impl NodeState {
+    fn handle(&self) -> Option<&P2PNodeHandle> {
+        match self {
+            NodeState::Ready(handle) => Some(handle),
+            _ => None,
+        }
+    }
+}
 
 struct P2PNodeHandle {
-    #[allow(dead_code)]
     peer_id: String,
     command_tx: mpsc::UnboundedSender<P2PCommand>,
+    // Kept current by the swarm task so info reads never wait on it
+    info: Arc<RwLock<NodeInfo>>,
 }
 
 enum P2PCommand {
-    JoinRoom(String),
-    SendMessage(String),
+    // Room name, passphrase, whether to join passively (listen-only) and
+    // whether to list the room in the public directory
+    JoinRoom(String, Option<String>, bool, bool),
+    LeaveRoom,
+    SetStatus(Option<String>),
+    SetNickname(Option<String>, tokio::sync::oneshot::Sender<Result<(), CommandError>>),
+    SendMessage(String, ContentType, tokio::sync::oneshot::Sender<Result<(), CommandError>>),
+    BroadcastToAllRooms(String, ContentType, tokio::sync::oneshot::Sender<Result<Vec<RoomBroadcast>, CommandError>>),
+    // File bytes, MIME type, file name, caption and whether to force inline
+    SendAttachment(Vec<u8>, String, Option<String>, String, bool, tokio::sync::oneshot::Sender<Result<(), CommandError>>),
+    // Sender and hash of the attachment; replies with the local path
+    FetchAttachment(PeerId, String, tokio::sync::oneshot::Sender<Result<String, CommandError>>),
     ConnectToPeer(String),
+    ReserveRelay(Multiaddr, tokio::sync::oneshot::Sender<Result<String, CommandError>>),
+    SendToPeer(PeerId, String),
+    RefreshPeers,
+    ResetDiscovery,
+    SetPowerMode(PowerMode, bool),
+    SetMdnsEnabled(bool),
+    SetMessagesPaused(bool),
+    SetMuted(PeerId, bool),
+    SetExplicitPeer(PeerId, bool, tokio::sync::oneshot::Sender<Result<(), CommandError>>),
+    // Peer, address, and whether to dial it; replies whether the address was new
+    AddPeerAddress(PeerId, Multiaddr, bool, tokio::sync::oneshot::Sender<Result<bool, CommandError>>),
+    // Room, owner public key, and the signing key if the room is ours
+    SetRoomOwner(String, identity::PublicKey, Option<identity::Keypair>),
+    ModerateRoom(ModerationAction, tokio::sync::oneshot::Sender<Result<(), CommandError>>),
     GetInfo(tokio::sync::oneshot::Sender<NodeInfo>),
+    GetStats(tokio::sync::oneshot::Sender<NodeStats>),
+    GetMetrics(tokio::sync::oneshot::Sender<Result<String, CommandError>>),
+    GetEventLog(usize, tokio::sync::oneshot::Sender<Vec<LogEntry>>),
+    GetDiagnostics(tokio::sync::oneshot::Sender<NodeDiagnostics>),
+    PersistState(tokio::sync::oneshot::Sender<Option<SessionRoom>>),
+    GetIdentity(tokio::sync::oneshot::Sender<NodeIdentity>),
+    GetAddressBook(tokio::sync::oneshot::Sender<Vec<AddressBookPeer>>),
+    ForgetAddressBook(tokio::sync::oneshot::Sender<()>),
+    GetMeshPeers(tokio::sync::oneshot::Sender<Result<MeshPeers, CommandError>>),
+    GetRoomMembers(tokio::sync::oneshot::Sender<Result<Vec<RoomMember>, CommandError>>),
+    GetPeerProtocols(PeerId, tokio::sync::oneshot::Sender<Result<PeerProtocols, CommandError>>),
+    BenchmarkDhtLookup(String, tokio::sync::oneshot::Sender<DhtLookupResult>),
+    EstimateNetworkSize(tokio::sync::oneshot::Sender<NetworkSizeEstimate>),
+    // Rooms to leave out of the results
+    ListPublicRooms(HashSet<String>, tokio::sync::oneshot::Sender<Vec<PublicRoom>>),
+    TestReachability(Multiaddr, bool, tokio::sync::oneshot::Sender<ReachabilityResult>),
+    RunConnectivityTest(tokio::sync::oneshot::Sender<Result<ConnectivityReport, CommandError>>),
 }
 
 #[derive(serde::Serialize, Clone)]
 struct NodeInfo {
     peer_id: String,
-    addresses: Vec<String>,
+    listen_addresses: Vec<String>,
+    external_addresses: Vec<String>,
+    relay_addresses: Vec<String>,
     connected_peers: Vec<PeerInfo>,
+    power_mode: PowerMode,
+    mdns_enabled: bool,
+    private_network: bool,
+    room_passive: bool,
+    explicit_peers: Vec<String>,
+}
+
+// Payload of the `node-crashed` event
+#[derive(serde::Serialize, Clone)]
+struct NodeCrashed {
+    reason: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct NodeStats {
+    // "public", "private" or "unknown", as last reported by AutoNAT
+    nat_status: String,
+    relay: RelayStats,
+    connections: ConnectionStats,
+    // Resent copies of room messages dropped since startup
+    duplicate_messages: u64,
+}
+
+// What the swarm task contributes to a diagnostics bundle
+struct NodeDiagnostics {
+    info: NodeInfo,
+    stats: NodeStats,
+    routing_table: RoutingTableSummary,
+    system_events: Vec<RecordedSystemEvent>,
+    event_log: Vec<LogEntry>,
 }
 
 #[tauri::command]
-async fn init_p2p(app: AppHandle, state: State<'_, P2PState>) -> Result<String, String> {
-    let mut state_guard = state.lock().await;
+async fn init_p2p(
+    app: AppHandle,
+    state: State<'_, P2PState>,
+    history: State<'_, HistoryState>,
+    unread: State<'_, UnreadState>,
+    settings: State<'_, SettingsState>,
+    config: Option<P2PConfig>,
+) -> Result<String, CommandError> {
+    let mut config = config.unwrap_or_default();
+    config.validate().map_err(CommandError::invalid_input)?;
+    config.power = settings.lock().await.settings.power.clone();
+    let data_dir = app.path().app_data_dir().map_err(CommandError::internal)?;
+    config.kad_store_path = Some(data_dir.join("kad_store.json"));
+    let keypair = IdentityStore::new(data_dir.clone()).load_or_create().map_err(CommandError::internal)?;
     
-    if state_guard.is_some() {
-        return Err("P2P node already initialized".to_string());
-    }
+    claim_node_slot(&state).await?;
 
     let (message_tx, mut message_rx) = mpsc::unbounded_channel::<ChatMessage>();
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<NodeEvent>();
     let (command_tx, mut command_rx) = mpsc::unbounded_channel::<P2PCommand>();
     
-    // Create P2P node
-    let (mut node, mut swarm) = P2PNode::create(message_tx)
-        .await
-        .map_err(|e| e.to_string())?;
+    let (mut node, mut swarm) = build_in_slot(&state, start_node(message_tx, event_tx, &config, keypair)).await?;
 
     let peer_id = node.get_peer_id();
+    {
+        let mut settings = settings.lock().await;
+        // First run: a friendly name beats a raw peer ID
+        if settings.settings.nickname.is_none() {
+            settings.settings.nickname = Some(nicknames::generate());
+            settings.settings.nickname_generated = true;
+            if let Err(e) = settings.save() {
+                tracing::warn!("Failed to save generated nickname: {}", e);
+            }
+        }
+        node.muted_peers = settings
+            .settings
+            .muted_peers
+            .iter()
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        node.nickname = settings.settings.nickname.clone();
+        for (room, owner) in &settings.settings.room_owners {
+            match moderation::decode_public_key(owner) {
+                Ok(owner) => node.set_room_owner(room.clone(), owner, None),
+                Err(e) => tracing::warn!("Ignoring owner of room {}: {}", room, e),
+            }
+        }
+        for (room, keypair) in &settings.settings.owned_rooms {
+            match moderation::decode_keypair(keypair) {
+                Ok(keypair) => node.set_room_owner(room.clone(), keypair.public(), Some(keypair)),
+                Err(e) => tracing::warn!("Ignoring owner key of room {}: {}", room, e),
+            }
+        }
+    }
+    node.load_address_book(&mut swarm, AddressBook::load(data_dir.join(ADDRESS_BOOK_FILE)));
+    node.attachments = Some(AttachmentStore::new(data_dir.join("attachments")));
+    // Carry on from the last run's clock so new messages sort after stored ones
+    match history.lock().await.max_lamport() {
+        Ok(lamport) => node.seed_clock(lamport),
+        Err(e) => tracing::warn!("Failed to read the message clock: {}", e),
+    }
+    match history.lock().await.outbox() {
+        Ok(entries) => node.load_outbox(entries),
+        Err(e) => tracing::warn!("Failed to load outbox: {}", e),
+    }
+    let session_room = settings.lock().await.settings.session_room.clone();
+    restore_session(&mut node, &mut swarm, session_room);
     
-    // Store node handle
-    *state_guard = Some(P2PNodeHandle {
-        peer_id: peer_id.clone(),
-        command_tx,
-    });
-    drop(state_guard);
+    let info = Arc::new(RwLock::new(node_info(&node, &swarm)));
+    let info_snapshot = info.clone();
 
     // Send initial message
     let _ = node.message_tx.send(ChatMessage {
+        id: uuid::Uuid::new_v4().to_string(),
         from: "System".to_string(),
         content: "🚀 Node initialized - connecting to network...".to_string(),
         timestamp: chrono::Utc::now().to_rfc3339(),
         is_self: false,
+        is_direct: false,
+        room: None,
+        content_type: ContentType::Plain,
+        delivered_to: None,
+        recipients_estimate: None,
+        attachment: None,
+        lamport: node.tick_clock(),
+        recovered: false,
     });
 
-    // Send mDNS enabled message
-    node.send_system_message("✓ Local network discovery (mDNS) enabled".to_string());
+    // Send mDNS status message; a failed start was already reported by create
+    if node.mdns_enabled {
+        node.send_system_message("✓ Local network discovery (mDNS) enabled".to_string());
+    } else if !config.mdns_enabled {
+        node.send_system_message("✗ Local network discovery (mDNS) disabled".to_string());
+    }
+
+    if config.relay_server {
+        node.send_system_message("📡 Relay server mode enabled - will stop if AutoNAT finds this node behind NAT".to_string());
+    }
 
     // Bootstrap DHT
     node.bootstrap_dht(&mut swarm);
-
-    // Listen on IPv6
-    swarm.listen_on("/ip6/::/tcp/8080".parse().unwrap()).unwrap();
+    
+    // Listen for browser peers over WebSocket
+    if let Some(addr) = &config.ws_listen_addr {
+        if let Err(e) = swarm.listen_on(addr.parse().unwrap()) {
+            node.send_system_message(format!("⚠ Failed to listen on {}: {}", addr, e));
+        }
+    }
 
     // Clone for tasks
     let app_message_relay = app.clone();
+    let history_relay = history.inner().clone();
+    let unread_relay = unread.inner().clone();
+    let settings_relay = settings.inner().clone();
+    let mention_peer_id = peer_id.clone();
+    let message_ttl = config.message_ttl();
     
     // Spawn message relay task
     tokio::spawn(async move {
         while let Some(msg) = message_rx.recv().await {
+            // Persist chat messages (system notices are not history)
+            if !msg.is_system() {
+                if let Err(e) = history_relay.lock().await.store(&msg, message_ttl) {
+                    tracing::warn!("Failed to store message: {}", e);
+                }
+            }
+            let unread_changed = unread_relay.lock().await.record(&msg);
+            
+            // Check incoming chat for mentions of us
+            let mention = if msg.is_self || msg.is_system() {
+                None
+            } else {
+                let configured = settings_relay.lock().await.settings.mention_keywords.clone();
+                let keywords = if configured.is_empty() {
+                    mentions::default_keywords(&mention_peer_id)
+                } else {
+                    configured
+                };
+                mentions::find_mention(&msg.content, &keywords).map(|keyword| MentionEvent {
+                    message: msg.clone(),
+                    keyword,
+                })
+            };
+            
+            // Windows showing a single room listen on its own channel instead;
+            // direct messages only go out on the shared one
+            let scoped = if msg.is_system() {
+                Some(SYSTEM_MESSAGE_EVENT.to_string())
+            } else {
+                msg.room.as_deref().map(room_event_name)
+            };
+            if let Some(event) = scoped {
+                let _ = app_message_relay.emit(&event, msg.clone());
+            }
             let _ = app_message_relay.emit("chat-message", msg);
+            if let Some(changed) = unread_changed {
+                let _ = app_message_relay.emit("unread-changed", changed);
+            }
+            if let Some(mention) = mention {
+                let _ = app_message_relay.emit("mention", mention);
+            }
         }
     });
 
-    // Spawn command handler and node runner
+    // Delete expired messages until the node stops; runs even without a TTL
+    // so messages stored under an earlier TTL still go away
+    let app_expiry = app.clone();
+    let history_expiry = history.inner().clone();
+    let expiry_command_tx = command_tx.clone();
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = sweep.tick() => {}
+                _ = expiry_command_tx.closed() => break,
+            }
+            let expired = match history_expiry.lock().await.expire() {
+                Ok(expired) => expired,
+                Err(e) => {
+                    tracing::warn!("Failed to expire messages: {}", e);
+                    continue;
+                }
+            };
+            for message in expired {
+                let _ = app_expiry.emit("message-expired", message);
+            }
+        }
+    });
+
+    // Forward node events to the frontend
+    let app_event_relay = app.clone();
+    let history_outbox = history.inner().clone();
     tokio::spawn(async move {
-        let mut peer_discovery_interval = tokio::time::interval(Duration::from_secs(30));
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                NodeEvent::IncompatiblePeer(peer) => {
+                    let _ = app_event_relay.emit("incompatible-peer", peer);
+                }
+                NodeEvent::MessageDelivered(delivered) => {
+                    let _ = app_event_relay.emit("message-delivered", delivered);
+                }
+                NodeEvent::PeerStatus(status) => {
+                    let _ = app_event_relay.emit("peer-status", status);
+                }
+                NodeEvent::RoomSearchFinished(search) => {
+                    let _ = app_event_relay.emit("room-search-finished", search);
+                }
+                NodeEvent::PeerRenamed(renamed) => {
+                    let _ = app_event_relay.emit("peer-renamed", renamed);
+                }
+                NodeEvent::PresenceChanged(changed) => {
+                    let _ = app_event_relay.emit("presence-changed", changed);
+                }
+                NodeEvent::PeersChanged(peers) => {
+                    let _ = app_event_relay.emit("peers-changed", peers);
+                }
+                NodeEvent::RoomModerated(moderated) => {
+                    let _ = app_event_relay.emit("room-moderated", moderated);
+                }
+                NodeEvent::ModerationRejected(rejected) => {
+                    let _ = app_event_relay.emit("moderation-rejected", rejected);
+                }
+                NodeEvent::RoomAnnounced(announced) => {
+                    let _ = app_event_relay.emit("room-announced", announced);
+                }
+                NodeEvent::System(event) => {
+                    let _ = app_event_relay.emit("system-event", event);
+                }
+                NodeEvent::ConnectivityProgress(step) => {
+                    let _ = app_event_relay.emit("connectivity-test-progress", step);
+                }
+                NodeEvent::MessageStatus(status) => {
+                    if status.status != MessageState::Queued {
+                        if let Err(e) = history_outbox.lock().await.remove_outgoing(&status.message_id) {
+                            tracing::warn!("Failed to update outbox: {}", e);
+                        }
+                    }
+                    let _ = app_event_relay.emit("message-status", status);
+                }
+                NodeEvent::MessageQueued(entry) => {
+                    if let Err(e) = history_outbox.lock().await.queue_outgoing(&entry) {
+                        tracing::warn!("Failed to save queued message: {}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    // Spawn command handler and node runner
+    let swarm_task = tokio::spawn(async move {
+        let peer_discovery = tokio::time::sleep(config.discovery_delay(node.power_mode));
+        tokio::pin!(peer_discovery);
+        let mut stale_peer_sweep = tokio::time::interval(STALE_PEER_SWEEP_INTERVAL);
+        let mut presence_broadcast = tokio::time::interval(PRESENCE_INTERVAL);
+        // Set by the first peer list change after the last `peers-changed`
+        // event; later changes before then ride along
+        let mut peers_changed_at: Option<tokio::time::Instant> = None;
         
         loop {
             tokio::select! {
                 Some(cmd) = command_rx.recv() => {
                     match cmd {
-                        P2PCommand::JoinRoom(room_name) => {
-                            node.join_room(&mut swarm, room_name);
+                        P2PCommand::JoinRoom(room_name, passphrase, passive, public) => {
+                            node.join_room(&mut swarm, room_name, passphrase, passive, public);
+                        }
+                        P2PCommand::LeaveRoom => {
+                            node.leave_room(&mut swarm);
+                        }
+                        P2PCommand::SetStatus(status) => {
+                            node.set_status(&mut swarm, status);
                         }
-                        P2PCommand::SendMessage(message) => {
-                            node.send_message(&mut swarm, message).await;
+                        P2PCommand::SetNickname(nickname, tx) => {
+                            let _ = tx.send(node.set_nickname(&mut swarm, nickname));
+                        }
+                        P2PCommand::SendMessage(message, content_type, tx) => {
+                            let _ = tx.send(node.send_message(&mut swarm, message, content_type).await);
+                        }
+                        P2PCommand::BroadcastToAllRooms(message, content_type, tx) => {
+                            let _ = tx.send(node.broadcast_to_all_rooms(&mut swarm, message, content_type).await);
+                        }
+                        P2PCommand::SendAttachment(data, mime, name, caption, inline, tx) => {
+                            let _ = tx.send(node.send_attachment(&mut swarm, data, mime, name, caption, inline));
+                        }
+                        P2PCommand::FetchAttachment(peer_id, sha256, tx) => {
+                            node.fetch_attachment(&mut swarm, peer_id, sha256, tx);
                         }
                         P2PCommand::ConnectToPeer(addr) => {
                             node.connect_to_peer(&mut swarm, addr);
                         }
+                        P2PCommand::ReserveRelay(addr, tx) => {
+                            node.reserve_relay(&mut swarm, addr, tx);
+                        }
+                        P2PCommand::SendToPeer(peer_id, content) => {
+                            node.send_to_peer(&mut swarm, peer_id, content);
+                        }
+                        P2PCommand::RefreshPeers => {
+                            node.refresh_peers(&mut swarm);
+                            // Restart the periodic timer so we don't immediately search again
+                            peer_discovery.as_mut().reset(tokio::time::Instant::now() + config.discovery_delay(node.power_mode));
+                        }
+                        P2PCommand::ResetDiscovery => {
+                            node.reset_discovery(&mut swarm);
+                            peer_discovery.as_mut().reset(tokio::time::Instant::now() + config.discovery_delay(node.power_mode));
+                        }
+                        P2PCommand::SetPowerMode(mode, pause_mdns) => {
+                            node.set_power_mode(mode, pause_mdns);
+                            peer_discovery.as_mut().reset(tokio::time::Instant::now() + config.discovery_delay(mode));
+                        }
+                        P2PCommand::SetMdnsEnabled(enabled) => {
+                            node.set_mdns_enabled(&mut swarm, enabled);
+                        }
+                        P2PCommand::SetMessagesPaused(paused) => {
+                            node.set_messages_paused(paused);
+                        }
+                        P2PCommand::SetMuted(peer_id, muted) => {
+                            node.set_muted(&mut swarm, peer_id, muted);
+                        }
+                        P2PCommand::SetRoomOwner(room, owner, keypair) => {
+                            node.set_room_owner(room, owner, keypair);
+                        }
+                        P2PCommand::ModerateRoom(action, tx) => {
+                            let _ = tx.send(node.moderate_room(&mut swarm, action));
+                        }
+                        P2PCommand::AddPeerAddress(peer_id, addr, dial, tx) => {
+                            let _ = tx.send(node.add_peer_address(&mut swarm, peer_id, addr, dial));
+                        }
+                        P2PCommand::SetExplicitPeer(peer_id, explicit, tx) => {
+                            let result = if explicit {
+                                node.add_explicit_peer(&mut swarm, peer_id)
+                            } else {
+                                node.remove_explicit_peer(&mut swarm, peer_id);
+                                Ok(())
+                            };
+                            let _ = tx.send(result);
+                        }
                         P2PCommand::GetInfo(tx) => {
-                            let info = NodeInfo {
-                                peer_id: node.get_peer_id(),
-                                addresses: node.get_addresses(&swarm),
-                                connected_peers: node.get_connected_peers(),
+                            let _ = tx.send(node_info(&node, &swarm));
+                        }
+                        P2PCommand::GetStats(tx) => {
+                            let _ = tx.send(node_stats(&node, &swarm));
+                        }
+                        P2PCommand::GetDiagnostics(tx) => {
+                            let diagnostics = NodeDiagnostics {
+                                info: node_info(&node, &swarm),
+                                stats: node_stats(&node, &swarm),
+                                routing_table: node.routing_table_summary(&mut swarm),
+                                system_events: node.recent_system_events(),
+                                event_log: node.event_log.recent(MAX_LOG_ENTRIES),
                             };
-                            let _ = tx.send(info);
+                            let _ = tx.send(diagnostics);
+                        }
+                        P2PCommand::PersistState(tx) => {
+                            let _ = tx.send(node.persist_state(&mut swarm));
+                        }
+                        P2PCommand::GetEventLog(limit, tx) => {
+                            let _ = tx.send(node.event_log.recent(limit));
+                        }
+                        P2PCommand::GetMetrics(tx) => {
+                            let _ = tx.send(node.encode_metrics().map_err(CommandError::internal));
+                        }
+                        P2PCommand::GetIdentity(tx) => {
+                            let _ = tx.send(node.get_identity(&swarm));
+                        }
+                        P2PCommand::ForgetAddressBook(tx) => {
+                            node.forget_address_book();
+                            let _ = tx.send(());
+                        }
+                        P2PCommand::GetAddressBook(tx) => {
+                            let _ = tx.send(node.address_book.snapshot());
+                        }
+                        P2PCommand::GetMeshPeers(tx) => {
+                            let _ = tx.send(node.mesh_peers(&swarm));
+                        }
+                        P2PCommand::GetRoomMembers(tx) => {
+                            let _ = tx.send(node.room_members());
+                        }
+                        P2PCommand::GetPeerProtocols(peer_id, tx) => {
+                            let _ = tx.send(node.peer_protocols(&peer_id));
+                        }
+                        P2PCommand::BenchmarkDhtLookup(key, tx) => {
+                            node.benchmark_dht_lookup(&mut swarm, key, tx);
+                        }
+                        P2PCommand::EstimateNetworkSize(tx) => {
+                            node.estimate_network_size(&mut swarm, tx);
+                        }
+                        P2PCommand::ListPublicRooms(hidden, tx) => {
+                            node.list_public_rooms(&mut swarm, hidden, tx);
+                        }
+                        P2PCommand::TestReachability(addr, close, tx) => {
+                            node.test_reachability(&mut swarm, addr, close, tx);
+                        }
+                        P2PCommand::RunConnectivityTest(tx) => {
+                            node.start_connectivity_test(&mut swarm, tx);
                         }
                     }
                     // Process any pending peer dials after handling commands
                     node.process_pending_dials(&mut swarm);
+                    *info_snapshot.write().await = node_info(&node, &swarm);
                 }
                 event = swarm.select_next_some() => {
-                    node.handle_event(event).await;
+                    let info_changed = changes_node_info(&event);
+                    if changes_peer_list(&event) {
+                        peers_changed_at.get_or_insert_with(|| tokio::time::Instant::now() + PEERS_CHANGED_DEBOUNCE);
+                    }
+                    node.handle_event(&mut swarm, event).await;
                     // Process any pending peer dials after handling events
                     node.process_pending_dials(&mut swarm);
+                    if info_changed {
+                        *info_snapshot.write().await = node_info(&node, &swarm);
+                    }
                 }
-                _ = peer_discovery_interval.tick() => {
+                // Paused while we have no connections to query through; a
+                // search that came due meanwhile runs once a peer connects
+                _ = &mut peer_discovery, if swarm.network_info().num_peers() > 0 => {
                     // Periodically search for more peers in the current room
-                    if let Some(room_name) = &node.current_room_name {
-                        swarm
-                            .behaviour_mut()
-                            .kad
-                            .get_providers(room_name.as_bytes().to_vec().into());
+                    node.search_room_peers(&mut swarm, false);
+                    peer_discovery.as_mut().reset(tokio::time::Instant::now() + config.discovery_delay(node.power_mode));
+                }
+                _ = tokio::time::sleep_until(
+                    peers_changed_at.unwrap_or_else(tokio::time::Instant::now)
+                ), if peers_changed_at.is_some() => {
+                    peers_changed_at = None;
+                    node.emit_peers_changed();
+                }
+                // A room join whose subscription failed, once its backoff is up
+                _ = tokio::time::sleep_until(
+                    node.join_retry_at().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.join_retry_at().is_some() => {
+                    node.retry_pending_join(&mut swarm);
+                    *info_snapshot.write().await = node_info(&node, &swarm);
+                }
+                // Disconnects held back in case the peer comes right back
+                _ = tokio::time::sleep_until(
+                    node.disconnect_report_at().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.disconnect_report_at().is_some() => {
+                    node.report_disconnects();
+                }
+                // Network events merged into one chat line per kind
+                _ = tokio::time::sleep_until(
+                    node.system_events_flush_at().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.system_events_flush_at().is_some() => {
+                    node.flush_system_events();
+                }
+                // An empty room searched again ahead of the periodic search
+                _ = tokio::time::sleep_until(
+                    node.search_retry_at().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.search_retry_at().is_some() => {
+                    node.retry_room_search(&mut swarm);
+                }
+                // Room peer searches the user is waiting on, at their deadline
+                _ = tokio::time::sleep_until(
+                    node.provider_search_deadline().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.provider_search_deadline().is_some() => {
+                    node.expire_provider_searches(&mut swarm);
+                }
+                // A connectivity test step still waiting on the network
+                _ = tokio::time::sleep_until(
+                    node.connectivity_test_deadline().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.connectivity_test_deadline().is_some() => {
+                    node.expire_connectivity_step(&mut swarm);
+                }
+                // Network size lookups past their deadline
+                _ = tokio::time::sleep_until(
+                    node.network_size_deadline().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.network_size_deadline().is_some() => {
+                    node.expire_network_size_probe(&mut swarm);
+                }
+                // Ask authors for room messages we missed
+                _ = tokio::time::sleep_until(
+                    node.retransmit_deadline().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.retransmit_deadline().is_some() => {
+                    node.request_retransmissions(&mut swarm);
+                }
+                // Public room lookups still waiting on slow providers
+                _ = tokio::time::sleep_until(
+                    node.directory_deadline().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if node.directory_deadline().is_some() => {
+                    node.expire_directory_lookups();
+                }
+                _ = presence_broadcast.tick() => {
+                    node.publish_presence(&mut swarm);
+                    node.update_member_presence();
+                    node.resend_awaiting_mesh(&mut swarm);
+                    node.flush_outbox(&mut swarm);
+                }
+                _ = stale_peer_sweep.tick() => {
+                    if node.prune_stale_peers(&swarm) > 0 {
+                        peers_changed_at.get_or_insert_with(|| tokio::time::Instant::now() + PEERS_CHANGED_DEBOUNCE);
                     }
+                    node.expire_deliveries();
+                    node.expire_partial_messages();
+                    node.expire_reachability_tests();
+                    node.retry_relay_reservations(&mut swarm);
+                    node.save_address_book();
+                    node.save_kad_store(&mut swarm);
+                    *info_snapshot.write().await = node_info(&node, &swarm);
                 }
             }
         }
     });
 
+    // Only hand out the node once its task is running
+    *state.lock().await = NodeState::Ready(P2PNodeHandle {
+        peer_id: peer_id.clone(),
+        command_tx,
+        info,
+    });
+
+    // Watch the swarm task; if it dies the node is gone, so drop the handle
+    // and let the frontend offer a restart through init_p2p
+    let state_supervisor = state.inner().clone();
+    let app_supervisor = app.clone();
+    tokio::spawn(async move {
+        let reason = match swarm_task.await {
+            Ok(()) => "swarm task exited unexpectedly".to_string(),
+            Err(e) if e.is_panic() => panic_message(e.into_panic()),
+            Err(e) => e.to_string(),
+        };
+        tracing::error!("P2P node crashed: {}", reason);
+        
+        *state_supervisor.lock().await = NodeState::Stopped;
+        let _ = app_supervisor.emit("node-crashed", NodeCrashed { reason });
+    });
+
     Ok(peer_id)
 }
 
+// Claim the slot up front; the lock isn't held while the node is built
+async fn claim_node_slot(state: &Mutex<NodeState>) -> Result<(), CommandError> {
+    let mut state = state.lock().await;
+    match *state {
+        NodeState::Stopped => {
+            *state = NodeState::Initializing;
+            Ok(())
+        }
+        NodeState::Initializing | NodeState::Ready(_) => Err(CommandError::AlreadyInitialized),
+    }
+}
+
+// Builds the node in a claimed slot; on failure frees the slot so a retry
+// can start over
+async fn build_in_slot<T>(
+    state: &Mutex<NodeState>,
+    build: impl std::future::Future<Output = Result<T, CommandError>>,
+) -> Result<T, CommandError> {
+    let built = build.await;
+    if built.is_err() {
+        *state.lock().await = NodeState::Stopped;
+    }
+    built
+}
+
+// Build the swarm and bind the main listener, the steps of init_p2p that can fail
+async fn start_node(
+    message_tx: mpsc::UnboundedSender<ChatMessage>,
+    event_tx: mpsc::UnboundedSender<NodeEvent>,
+    config: &P2PConfig,
+    keypair: identity::Keypair,
+) -> Result<(P2PNode, Swarm<ChatBehaviour>), CommandError> {
+    let (node, mut swarm) = P2PNode::create_with_keypair(message_tx, event_tx, config, keypair)
+        .await
+        .map_err(CommandError::internal)?;
+    
+    // Listen on IPv6
+    swarm
+        .listen_on("/ip6/::/tcp/8080".parse().unwrap())
+        .map_err(|e| CommandError::internal(format!("Failed to listen on port 8080: {}", e)))?;
+    
+    Ok((node, swarm))
+}
+
+const SYSTEM_MESSAGE_EVENT: &str = "chat-message:system";
+
+// `chat-message:<room>` for a room's messages, with the normalized room name
+// as lowercase hex of its UTF-8 bytes: any name makes a valid event name,
+// different rooms never share one, and no room gets the system channel
+fn room_event_name(room: &str) -> String {
+    let hex: String = normalize_room_name(room).bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("chat-message:{}", hex)
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+// Rejoin the room recorded by the last `persist_state`
+fn restore_session(node: &mut P2PNode, swarm: &mut Swarm<ChatBehaviour>, session_room: Option<SessionRoom>) {
+    if let Some(room) = session_room {
+        tracing::info!("Rejoining '{}' from the last session", room.name);
+        node.join_room(swarm, room.name, None, room.passive, room.public);
+    }
+}
+
+fn node_info(node: &P2PNode, swarm: &Swarm<ChatBehaviour>) -> NodeInfo {
+    NodeInfo {
+        peer_id: node.get_peer_id(),
+        listen_addresses: node.get_addresses(swarm),
+        external_addresses: node.get_external_addresses(swarm),
+        relay_addresses: node.get_relay_addresses(swarm),
+        connected_peers: node.get_connected_peers(),
+        power_mode: node.power_mode,
+        mdns_enabled: node.mdns_enabled,
+        private_network: node.private_network,
+        room_passive: node.room_passive,
+        explicit_peers: {
+            let mut peers: Vec<String> = node.explicit_peers.iter().map(PeerId::to_string).collect();
+            peers.sort();
+            peers
+        },
+    }
+}
+
+fn node_stats(node: &P2PNode, swarm: &Swarm<ChatBehaviour>) -> NodeStats {
+    NodeStats {
+        nat_status: node.nat_status(swarm).to_string(),
+        relay: node.relay_stats(swarm),
+        connections: node.connection_stats(swarm),
+        duplicate_messages: node.duplicate_messages,
+    }
+}
+
+// Returns the snapshot the swarm task keeps up to date; `fresh` asks the
+// swarm task directly instead, e.g. for exact connection durations
+#[tauri::command]
+async fn get_node_info(fresh: Option<bool>, state: State<'_, P2PState>) -> Result<NodeInfo, CommandError> {
+    let (command_tx, info) = match state.lock().await.handle() {
+        Some(handle) => (handle.command_tx.clone(), handle.info.clone()),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    if !fresh.unwrap_or(false) {
+        return Ok(info.read().await.clone());
+    }
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::GetInfo(tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    
+    match tokio::time::timeout(NODE_INFO_TIMEOUT, rx).await {
+        Ok(result) => result.map_err(|_| CommandError::NotInitialized),
+        Err(_) => Err(CommandError::Timeout),
+    }
+}
+
 #[tauri::command]
-async fn get_node_info(state: State<'_, P2PState>) -> Result<NodeInfo, String> {
+async fn get_stats(state: State<'_, P2PState>) -> Result<NodeStats, CommandError> {
     let state_guard = state.lock().await;
     
-    if let Some(handle) = state_guard.as_ref() {
+    if let Some(handle) = state_guard.handle() {
         let (tx, rx) = tokio::sync::oneshot::channel();
-        handle.command_tx.send(P2PCommand::GetInfo(tx))
-            .map_err(|e| e.to_string())?;
+        handle.command_tx.send(P2PCommand::GetStats(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
         
-        rx.await.map_err(|e| e.to_string())
+        rx.await.map_err(|_| CommandError::NotInitialized)
     } else {
-        Err("P2P node not initialized".to_string())
+        Err(CommandError::NotInitialized)
     }
 }
 
+// Recent dial failures, bootstrap and search results and other notable
+// events, oldest first; `limit` defaults to everything kept
 #[tauri::command]
-async fn join_room(room_name: String, state: State<'_, P2PState>) -> Result<(), String> {
+async fn get_event_log(limit: Option<usize>, state: State<'_, P2PState>) -> Result<Vec<LogEntry>, CommandError> {
     let state_guard = state.lock().await;
     
-    if let Some(handle) = state_guard.as_ref() {
-        handle.command_tx.send(P2PCommand::JoinRoom(room_name))
-            .map_err(|e| e.to_string())?;
-        Ok(())
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetEventLog(limit.unwrap_or(MAX_LOG_ENTRIES), tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)
     } else {
-        Err("P2P node not initialized".to_string())
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Save everything a sudden exit would lose and record the current room to
+// rejoin on the next launch. History, settings and the identity are written
+// as they change, so this covers the node's own state. Also run when the
+// window is closed.
+#[tauri::command]
+async fn persist_state(app: AppHandle) -> Result<(), CommandError> {
+    save_session(&app).await
+}
+
+async fn save_session(app: &AppHandle) -> Result<(), CommandError> {
+    // Without a running node the last saved session stays as it is
+    let Some(command_tx) = app.state::<P2PState>().lock().await.handle().map(|handle| handle.command_tx.clone()) else {
+        return Ok(());
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::PersistState(tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    let session_room = match tokio::time::timeout(PERSIST_TIMEOUT, rx).await {
+        Ok(result) => result.map_err(|_| CommandError::NotInitialized)?,
+        Err(_) => return Err(CommandError::Timeout),
+    };
+    
+    let settings = app.state::<SettingsState>();
+    let mut settings = settings.lock().await;
+    settings.settings.session_room = session_room;
+    settings.save().map_err(CommandError::internal)
+}
+
+// Change how much is logged, e.g. "debug" while reproducing a connection
+// problem; one of "off", "error", "warn", "info", "debug" or "trace"
+#[tauri::command]
+async fn set_log_level(level: String, logs: State<'_, LogState>) -> Result<(), CommandError> {
+    let filter: LevelFilter = level
+        .parse()
+        .map_err(|_| CommandError::invalid_input(format!("Unknown log level '{}'", level)))?;
+    logs.level.reload(filter).map_err(CommandError::internal)?;
+    tracing::info!("Log level set to {}", filter);
+    Ok(())
+}
+
+// Recent log lines, oldest first, without message contents; works before
+// the node is started
+#[tauri::command]
+async fn get_recent_logs(limit: Option<usize>, logs: State<'_, LogState>) -> Result<Vec<LogRecord>, CommandError> {
+    Ok(logs.buffer.recent(limit.unwrap_or(MAX_LOG_RECORDS)))
+}
+
+// Start or stop sending each new log line as a `debug-log` event, for a
+// live console
+#[tauri::command]
+async fn set_debug_log_stream(enabled: bool, app: AppHandle, logs: State<'_, LogState>) -> Result<(), CommandError> {
+    if !enabled {
+        logs.buffer.set_stream(None);
+        return Ok(());
+    }
+    
+    // Replacing an earlier stream closes it, which ends its task
+    let (tx, mut rx) = mpsc::channel(LOG_STREAM_CAPACITY);
+    logs.buffer.set_stream(Some(tx));
+    tokio::spawn(async move {
+        while let Some(record) = rx.recv().await {
+            let _ = app.emit("debug-log", record);
+        }
+    });
+    Ok(())
+}
+
+// Write a zip for bug reports: recent logs, settings with secrets removed,
+// and the node's info, stats, routing table and recent events. Without a
+// running node the node's part is left out and noted in `node.txt`.
+#[tauri::command]
+async fn export_diagnostics(
+    path: String,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+    logs: State<'_, LogState>,
+) -> Result<(), CommandError> {
+    fn to_json<T: serde::Serialize>(value: &T) -> String {
+        serde_json::to_string_pretty(value).unwrap_or_default()
+    }
+    let mut files = vec![
+        ("logs.json", to_json(&logs.buffer.recent(MAX_LOG_RECORDS))),
+        ("settings.json", to_json(&diagnostics::redact_settings(&settings.lock().await.settings))),
+    ];
+    
+    let command_tx = state.lock().await.handle().map(|handle| handle.command_tx.clone());
+    let node = match command_tx {
+        Some(command_tx) => {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            match command_tx.send(P2PCommand::GetDiagnostics(tx)) {
+                Ok(()) => match tokio::time::timeout(NODE_INFO_TIMEOUT, rx).await {
+                    Ok(Ok(node)) => Ok(node),
+                    Ok(Err(_)) => Err("node stopped while gathering diagnostics"),
+                    Err(_) => Err("node didn't respond in time"),
+                },
+                Err(_) => Err("node isn't running"),
+            }
+        }
+        None => Err("node isn't running"),
+    };
+    match node {
+        Ok(node) => files.extend([
+            ("node_info.json", to_json(&node.info)),
+            ("stats.json", to_json(&node.stats)),
+            ("routing_table.json", to_json(&node.routing_table)),
+            ("system_events.json", to_json(&node.system_events)),
+            ("event_log.json", to_json(&node.event_log)),
+        ]),
+        Err(reason) => files.push(("node.txt", format!("Node details unavailable: {}\n", reason))),
     }
+    
+    diagnostics::write_bundle(&PathBuf::from(path), &files).map_err(CommandError::internal)
 }
 
+// Swarm, gossipsub, Kademlia, identify and bandwidth counters in the
+// Prometheus text format, ready to be served to a scraper
 #[tauri::command]
-async fn send_message(message: String, state: State<'_, P2PState>) -> Result<(), String> {
+async fn get_metrics(state: State<'_, P2PState>) -> Result<String, CommandError> {
     let state_guard = state.lock().await;
     
-    if let Some(handle) = state_guard.as_ref() {
-        handle.command_tx.send(P2PCommand::SendMessage(message))
-            .map_err(|e| e.to_string())?;
-        Ok(())
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetMetrics(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
     } else {
-        Err("P2P node not initialized".to_string())
+        Err(CommandError::NotInitialized)
     }
 }
 
 #[tauri::command]
-async fn connect_to_peer(addr: String, state: State<'_, P2PState>) -> Result<(), String> {
+async fn get_identity(state: State<'_, P2PState>) -> Result<NodeIdentity, CommandError> {
     let state_guard = state.lock().await;
     
-    if let Some(handle) = state_guard.as_ref() {
-        handle.command_tx.send(P2PCommand::ConnectToPeer(addr))
-            .map_err(|e| e.to_string())?;
-        Ok(())
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetIdentity(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)
     } else {
-        Err("P2P node not initialized".to_string())
+        Err(CommandError::NotInitialized)
     }
 }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tracing_subscriber::fmt::init();
+// Our peer ID after an identity import or rotation; a running node keeps
+// the old identity until the app is restarted
+#[derive(serde::Serialize, Clone)]
+struct IdentityChange {
+    peer_id: String,
+    restart_required: bool,
+}
+
+// Write our keypair to `path`, encrypted with `passphrase`, to move this
+// identity to another device. Returns the exported peer ID.
+#[tauri::command]
+async fn export_identity(app: AppHandle, path: String, passphrase: String) -> Result<String, CommandError> {
+    let data_dir = app.path().app_data_dir().map_err(CommandError::internal)?;
+    let keypair = IdentityStore::new(data_dir).load_or_create().map_err(CommandError::internal)?;
+    let exported = identity_store::export(&keypair, &passphrase)?;
+    std::fs::write(&path, exported).map_err(|e| CommandError::internal(format!("Failed to write {}: {}", path, e)))?;
+    Ok(keypair.public().to_peer_id().to_string())
+}
+
+// Replace our identity with one written by `export_identity`; the current
+// one is archived first
+#[tauri::command]
+async fn import_identity(
+    app: AppHandle,
+    path: String,
+    passphrase: String,
+    state: State<'_, P2PState>,
+) -> Result<IdentityChange, CommandError> {
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| CommandError::InvalidIdentityFile { reason: format!("can't read {}: {}", path, e) })?;
+    let keypair = identity_store::import(&text, &passphrase)?;
     
-    tauri::Builder::default()
-        .plugin(tauri_plugin_opener::init())
-        .setup(|app| {
-            app.manage(P2PState::default());
-            Ok(())
-        })
-        .invoke_handler(tauri::generate_handler![
-            init_p2p,
-            get_node_info,
-            join_room,
-            send_message,
-            connect_to_peer
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+    let data_dir = app.path().app_data_dir().map_err(CommandError::internal)?;
+    let store = IdentityStore::new(data_dir);
+    let current = store.load_or_create().map_err(CommandError::internal)?;
+    if current.public() != keypair.public() {
+        store.archive(&current).map_err(CommandError::internal)?;
+        store.save(&keypair).map_err(CommandError::internal)?;
+    }
+    
+    let peer_id = keypair.public().to_peer_id().to_string();
+    let restart_required = state.lock().await.handle().is_some_and(|handle| handle.peer_id != peer_id);
+    Ok(IdentityChange { peer_id, restart_required })
+}
+
+// Switch to a fresh keypair. The old one and the address book built with it
+// are archived, and the address book starts over so the new identity isn't
+// linked to the old one by redialing the same peers.
+#[tauri::command]
+async fn rotate_identity(
+    app: AppHandle,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<IdentityChange, CommandError> {
+    let data_dir = app.path().app_data_dir().map_err(CommandError::internal)?;
+    let store = IdentityStore::new(data_dir.clone());
+    let old = store.load_or_create().map_err(CommandError::internal)?;
+    store.archive(&old).map_err(CommandError::internal)?;
+    let keypair = identity::Keypair::generate_ed25519();
+    store.save(&keypair).map_err(CommandError::internal)?;
+    
+    // Empty the running node's book first so its periodic save can't bring
+    // the file back
+    let command_tx = state.lock().await.handle().map(|handle| handle.command_tx.clone());
+    if let Some(command_tx) = &command_tx {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        command_tx.send(P2PCommand::ForgetAddressBook(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        rx.await.map_err(|_| CommandError::NotInitialized)?;
+    }
+    match std::fs::remove_file(data_dir.join(ADDRESS_BOOK_FILE)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(CommandError::internal(format!("Failed to clear address book: {}", e))),
+    }
+    
+    // A generated nickname would link the two identities too; it's replaced
+    // on the next start like the identity itself
+    {
+        let mut settings = settings.lock().await;
+        if settings.settings.nickname_generated {
+            settings.settings.nickname = Some(nicknames::generate());
+            settings.save().map_err(CommandError::internal)?;
+        }
+    }
+    
+    let peer_id = keypair.public().to_peer_id().to_string();
+    tracing::info!("Rotated identity from {} to {}", old.public().to_peer_id(), peer_id);
+    Ok(IdentityChange { peer_id, restart_required: command_tx.is_some() })
+}
+
+// Peers remembered from earlier sessions, most recently seen first
+#[tauri::command]
+async fn get_address_book(state: State<'_, P2PState>) -> Result<Vec<AddressBookPeer>, CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetAddressBook(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Which room members we forward messages to directly, for debugging propagation
+#[tauri::command]
+async fn get_mesh_peers(state: State<'_, P2PState>) -> Result<MeshPeers, CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetMeshPeers(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Everyone heard from in the current room, with their heartbeat-based presence
+#[tauri::command]
+async fn get_room_members(state: State<'_, P2PState>) -> Result<Vec<RoomMember>, CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetRoomMembers(tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// What a peer supports (file transfer, relay, ...), so the UI can gate features
+#[tauri::command]
+async fn get_peer_protocols(peer_id: String, state: State<'_, P2PState>) -> Result<PeerProtocols, CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::GetPeerProtocols(peer_id, tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn benchmark_dht_lookup(key: String, state: State<'_, P2PState>) -> Result<DhtLookupResult, CommandError> {
+    if key.is_empty() {
+        return Err(CommandError::invalid_input("Lookup key cannot be empty"));
+    }
+    
+    // Don't hold the state lock while the query runs
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::BenchmarkDhtLookup(key, tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)
+}
+
+// Rough number of peers in the DHT, from lookups of random keys; see the
+// `netsize` module for how it's worked out and how far off it can be
+#[tauri::command]
+async fn estimate_network_size(state: State<'_, P2PState>) -> Result<NetworkSizeEstimate, CommandError> {
+    // Don't hold the state lock while the lookups run
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::EstimateNetworkSize(tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)
+}
+
+// Public rooms found in the directory, minus those the user hid
+#[tauri::command]
+async fn list_public_rooms(
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<Vec<PublicRoom>, CommandError> {
+    let hidden: HashSet<String> = settings.lock().await.settings.hidden_rooms.iter().cloned().collect();
+    
+    // Don't hold the state lock while the lookup runs
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::ListPublicRooms(hidden, tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)
+}
+
+#[tauri::command]
+async fn hide_public_room(room_name: String, settings: State<'_, SettingsState>) -> Result<(), CommandError> {
+    set_room_hidden(room_name, true, settings).await
+}
+
+#[tauri::command]
+async fn unhide_public_room(room_name: String, settings: State<'_, SettingsState>) -> Result<(), CommandError> {
+    set_room_hidden(room_name, false, settings).await
+}
+
+async fn set_room_hidden(room_name: String, hidden: bool, settings: State<'_, SettingsState>) -> Result<(), CommandError> {
+    let room_name = sanitize_room_name(&room_name).map_err(CommandError::invalid_input)?;
+    let mut settings = settings.lock().await;
+    settings.settings.hidden_rooms.retain(|r| r != &room_name);
+    if hidden {
+        settings.settings.hidden_rooms.push(room_name);
+    }
+    settings.save().map_err(CommandError::internal)
+}
+
+// Dial an address to check that it can be reached, e.g. before adding a
+// contact. The test connection is closed again unless `keep_open` is set.
+#[tauri::command]
+async fn test_reachability(
+    address: String,
+    keep_open: Option<bool>,
+    state: State<'_, P2PState>,
+) -> Result<ReachabilityResult, CommandError> {
+    let address: Multiaddr = address
+        .parse()
+        .map_err(|e: libp2p::multiaddr::Error| CommandError::InvalidAddress { reason: e.to_string() })?;
+    
+    // Don't hold the state lock while the dial runs
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::TestReachability(address, !keep_open.unwrap_or(false), tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    
+    match tokio::time::timeout(REACHABILITY_TIMEOUT, rx).await {
+        Ok(result) => result.map_err(|_| CommandError::NotInitialized),
+        Err(_) => Err(CommandError::Timeout),
+    }
+}
+
+// Check the bootstrap dial, listeners, public address and DHT in turn to
+// narrow down a connection problem; each step is also sent as a
+// `connectivity-test-progress` event as it finishes
+#[tauri::command]
+async fn run_connectivity_test(state: State<'_, P2PState>) -> Result<ConnectivityReport, CommandError> {
+    // Don't hold the state lock while the test runs
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::RunConnectivityTest(tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)?
+}
+
+// A `public` room is listed in the directory that `list_public_rooms` reads
+#[tauri::command]
+async fn join_room(
+    room_name: String,
+    passphrase: Option<String>,
+    public: Option<bool>,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<String, CommandError> {
+    let public = public.unwrap_or(false);
+    if public && passphrase.as_deref().is_some_and(|p| !p.is_empty()) {
+        return Err(CommandError::invalid_input("A passphrase protected room can't be public"));
+    }
+    send_join_room(&state, &settings, room_name, passphrase, false, public).await
+}
+
+// Subscribe to a room without advertising ourselves as a member
+#[tauri::command]
+async fn join_room_passive(
+    room_name: String,
+    passphrase: Option<String>,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<String, CommandError> {
+    send_join_room(&state, &settings, room_name, passphrase, true, false).await
+}
+
+// `room_name` may also be an invite from `create_room`, which makes us
+// follow moderation signed by the room's owner
+async fn send_join_room(
+    state: &State<'_, P2PState>,
+    settings: &State<'_, SettingsState>,
+    room_name: String,
+    passphrase: Option<String>,
+    passive: bool,
+    public: bool,
+) -> Result<String, CommandError> {
+    let (room_name, owner) = match RoomInvite::parse(&room_name) {
+        Some(invite) => {
+            let (room, owner) = invite.map_err(CommandError::invalid_input)?;
+            (room, Some(owner))
+        }
+        None => (room_name, None),
+    };
+    
+    // Reject bad names here so the frontend gets the error directly; the
+    // normalized name is returned so the UI shows the room we actually joined
+    let room_name = sanitize_room_name(&room_name).map_err(CommandError::invalid_input)?;
+    
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        if let Some(owner) = owner {
+            let mut settings = settings.lock().await;
+            settings.settings.room_owners.insert(room_name.clone(), moderation::encode_public_key(&owner));
+            settings.save().map_err(CommandError::internal)?;
+            handle.command_tx.send(P2PCommand::SetRoomOwner(room_name.clone(), owner, None))
+                .map_err(|_| CommandError::NotInitialized)?;
+        }
+        handle.command_tx.send(P2PCommand::JoinRoom(room_name.clone(), passphrase, passive, public))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(room_name)
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Create a room we own and join it. Share the returned invite so others
+// follow our moderation.
+#[tauri::command]
+async fn create_room(
+    room_name: String,
+    public: Option<bool>,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<RoomInvite, CommandError> {
+    let room_name = sanitize_room_name(&room_name).map_err(CommandError::invalid_input)?;
+    if state.lock().await.handle().is_none() {
+        return Err(CommandError::NotInitialized);
+    }
+    
+    let keypair = identity::Keypair::generate_ed25519();
+    let invite = RoomInvite::new(&room_name, &keypair.public());
+    {
+        let mut settings = settings.lock().await;
+        if settings.settings.owned_rooms.contains_key(&room_name) {
+            return Err(CommandError::invalid_input(format!("You already own room '{}'", room_name)));
+        }
+        settings.settings.owned_rooms.insert(room_name.clone(), moderation::encode_keypair(&keypair));
+        settings.save().map_err(CommandError::internal)?;
+    }
+    
+    let state_guard = state.lock().await;
+    let handle = state_guard.handle().ok_or(CommandError::NotInitialized)?;
+    handle.command_tx.send(P2PCommand::SetRoomOwner(room_name.clone(), keypair.public(), Some(keypair)))
+        .map_err(|_| CommandError::NotInitialized)?;
+    handle.command_tx.send(P2PCommand::JoinRoom(room_name, None, false, public.unwrap_or(false)))
+        .map_err(|_| CommandError::NotInitialized)?;
+    Ok(invite)
+}
+
+// Sign a moderation order (mute a peer, pin a message) for the current room,
+// which must be one we created
+#[tauri::command]
+async fn moderate_room(action: ModerationAction, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::ModerateRoom(action, tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Set a short status shown to the room; None or blank text clears it
+#[tauri::command]
+async fn set_status(status: Option<String>, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::SetStatus(status))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Set the name other room members see instead of our peer ID; None or
+// blank text clears it. Changes are limited to one every 10 seconds.
+#[tauri::command]
+async fn set_nickname(
+    nickname: Option<String>,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    let nickname = nickname.as_deref().and_then(sanitize_nickname);
+    apply_nickname(nickname, false, state, settings).await
+}
+
+// Replace the nickname with a new random one and return it
+#[tauri::command]
+async fn regenerate_nickname(state: State<'_, P2PState>, settings: State<'_, SettingsState>) -> Result<String, CommandError> {
+    let nickname = nicknames::generate();
+    apply_nickname(Some(nickname.clone()), true, state, settings).await?;
+    Ok(nickname)
+}
+
+async fn apply_nickname(
+    nickname: Option<String>,
+    generated: bool,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    // The node owns the rate limit, so only save once it accepted the change
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::SetNickname(nickname.clone(), tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)??;
+    
+    let mut settings = settings.lock().await;
+    settings.settings.nickname = nickname;
+    settings.settings.nickname_generated = generated;
+    settings.save().map_err(CommandError::internal)
+}
+
+#[tauri::command]
+async fn leave_room(state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::LeaveRoom)
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn send_message(
+    message: String,
+    content_type: Option<ContentType>,
+    state: State<'_, P2PState>,
+) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::SendMessage(message, content_type.unwrap_or_default(), tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Send one message to every joined room, e.g. an announcement. Rooms that
+// fail don't stop the rest; each room's outcome is returned.
+#[tauri::command]
+async fn broadcast_to_all_rooms(
+    content: String,
+    content_type: Option<ContentType>,
+    state: State<'_, P2PState>,
+) -> Result<Vec<RoomBroadcast>, CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::BroadcastToAllRooms(content, content_type.unwrap_or_default(), tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Share a file in the current room. Files without a known type are sent as
+// generic downloads.
+#[tauri::command]
+async fn send_attachment(
+    path: String,
+    mime: Option<String>,
+    caption: Option<String>,
+    state: State<'_, P2PState>,
+) -> Result<(), CommandError> {
+    let path = PathBuf::from(path);
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| CommandError::invalid_input(format!("Can't read {}: {}", path.display(), e)))?
+        .len();
+    if size > attachments::MAX_ATTACHMENT_SIZE as u64 {
+        return Err(CommandError::invalid_input(format!(
+            "File is too large ({} KiB, the limit is {} KiB)",
+            size / 1024,
+            attachments::MAX_ATTACHMENT_SIZE / 1024
+        )));
+    }
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(|e| CommandError::invalid_input(format!("Can't read {}: {}", path.display(), e)))?;
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string());
+    let mime = mime.unwrap_or_else(|| attachments::GENERIC_MIME.to_string());
+    send_attachment_data(&state, data, mime, name, caption.unwrap_or_default(), false).await
+}
+
+// Attach a small blob, e.g. an emoji image or a preview, straight from the
+// UI. `data` is base64; anything over the inline cap is refused.
+#[tauri::command]
+async fn send_inline_attachment(
+    data: String,
+    mime: String,
+    name: Option<String>,
+    caption: Option<String>,
+    state: State<'_, P2PState>,
+) -> Result<(), CommandError> {
+    // Base64 grows the data by a third; reject oversized input before decoding it
+    if data.len() > attachments::MAX_INLINE_SIZE.div_ceil(3) * 4 {
+        return Err(CommandError::invalid_input(format!(
+            "Inline attachment is too large (the limit is {} KiB) - send it as a file instead",
+            attachments::MAX_INLINE_SIZE / 1024
+        )));
+    }
+    let data = BASE64
+        .decode(data.trim())
+        .map_err(|e| CommandError::invalid_input(format!("Attachment is not valid base64: {}", e)))?;
+    send_attachment_data(&state, data, mime, name, caption.unwrap_or_default(), true).await
+}
+
+async fn send_attachment_data(
+    state: &State<'_, P2PState>,
+    data: Vec<u8>,
+    mime: String,
+    name: Option<String>,
+    caption: String,
+    inline: bool,
+) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::SendAttachment(data, mime, name, caption, inline, tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Download an attachment announced in a message; returns the local path
+#[tauri::command]
+async fn fetch_attachment(peer_id: String, sha256: String, state: State<'_, P2PState>) -> Result<String, CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    
+    // Don't hold the state lock while the download runs
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::FetchAttachment(peer_id, sha256, tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    rx.await.map_err(|_| CommandError::NotInitialized)?
+}
+
+#[tauri::command]
+async fn connect_to_peer(addr: String, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let multiaddr = addr.parse::<Multiaddr>()
+        .map_err(|e| CommandError::InvalidAddress { reason: e.to_string() })?;
+    
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        if handle.peer_id.parse().is_ok_and(|own: PeerId| is_own_address(&multiaddr, &own)) {
+            return Err(CommandError::InvalidAddress { reason: "that's your own address".to_string() });
+        }
+        handle.command_tx.send(P2PCommand::ConnectToPeer(addr))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Add a known address for a peer to the DHT routing table, e.g.
+// ("12D3Koo...", "/ip4/10.0.0.5/tcp/8080"), and dial it unless `dial` is false.
+// Returns true if the address was new, false if it was already there.
+#[tauri::command]
+async fn add_peer_address(
+    peer_id: String,
+    multiaddr: String,
+    dial: Option<bool>,
+    state: State<'_, P2PState>,
+) -> Result<bool, CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    let mut multiaddr: Multiaddr = multiaddr
+        .parse()
+        .map_err(|e: libp2p::multiaddr::Error| CommandError::InvalidAddress { reason: e.to_string() })?;
+    // The routing table stores addresses without the trailing peer ID
+    if let Some(Protocol::P2p(addr_peer)) = multiaddr.iter().last() {
+        if addr_peer != peer_id {
+            return Err(CommandError::InvalidAddress {
+                reason: format!("address belongs to {}, not {}", addr_peer, peer_id),
+            });
+        }
+        multiaddr.pop();
+    }
+    if multiaddr.is_empty() {
+        return Err(CommandError::InvalidAddress { reason: "address has no transport".to_string() });
+    }
+    
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::AddPeerAddress(peer_id, multiaddr, dial.unwrap_or(true), tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Reserve a circuit on the given relay, e.g. "/ip4/1.2.3.4/tcp/4001/p2p/12D3Koo...",
+// and return the address other peers can reach us at through it
+#[tauri::command]
+async fn reserve_relay(relay_addr: String, state: State<'_, P2PState>) -> Result<String, CommandError> {
+    let relay_addr: Multiaddr = relay_addr
+        .parse()
+        .map_err(|e: libp2p::multiaddr::Error| CommandError::InvalidAddress { reason: e.to_string() })?;
+    if !matches!(relay_addr.iter().last(), Some(Protocol::P2p(_))) {
+        return Err(CommandError::InvalidAddress {
+            reason: "relay address must end in /p2p/<relay peer id>".to_string(),
+        });
+    }
+    
+    // Don't hold the state lock while the relay answers
+    let command_tx = match state.lock().await.handle() {
+        Some(handle) => handle.command_tx.clone(),
+        None => return Err(CommandError::NotInitialized),
+    };
+    
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    command_tx.send(P2PCommand::ReserveRelay(relay_addr, tx))
+        .map_err(|_| CommandError::NotInitialized)?;
+    
+    match tokio::time::timeout(RELAY_RESERVATION_TIMEOUT, rx).await {
+        Ok(result) => result.map_err(|_| CommandError::NotInitialized)?,
+        Err(_) => Err(CommandError::Timeout),
+    }
+}
+
+#[tauri::command]
+async fn send_to_peer(peer_id: String, content: String, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        if handle.peer_id == peer_id.to_string() {
+            return Err(CommandError::invalid_input("Cannot send a direct message to yourself"));
+        }
+        handle.command_tx.send(P2PCommand::SendToPeer(peer_id, content))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn refresh_peers(state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::RefreshPeers)
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn reset_discovery(state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::ResetDiscovery)
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn get_unread_counts(unread: State<'_, UnreadState>) -> Result<HashMap<String, u32>, CommandError> {
+    Ok(unread.lock().await.counts())
+}
+
+#[tauri::command]
+async fn mark_room_read(
+    app: AppHandle,
+    room: String,
+    history: State<'_, HistoryState>,
+    settings: State<'_, SettingsState>,
+    unread: State<'_, UnreadState>,
+) -> Result<(), CommandError> {
+    let room = normalize_room_name(&room);
+    
+    // Remember the newest message in the room so counts survive a restart
+    let latest = history.lock().await.latest_in_room(&room).map_err(CommandError::internal)?;
+    let marker = match latest {
+        Some((message_id, timestamp)) => ReadMarker { message_id: Some(message_id), timestamp },
+        None => ReadMarker { message_id: None, timestamp: chrono::Utc::now().to_rfc3339() },
+    };
+    
+    let changed = unread.lock().await.mark_read(&room);
+    
+    let mut settings = settings.lock().await;
+    settings.settings.last_read.insert(room, marker);
+    settings.save().map_err(CommandError::internal)?;
+    
+    let _ = app.emit("unread-changed", changed);
+    Ok(())
+}
+
+// The room shown in the UI, or none while the window is hidden. Messages
+// arriving in it don't count as unread.
+#[tauri::command]
+async fn set_active_room(room: Option<String>, unread: State<'_, UnreadState>) -> Result<(), CommandError> {
+    unread.lock().await.set_active(room.map(|room| normalize_room_name(&room)));
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_mention_keywords(
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<Vec<String>, CommandError> {
+    let configured = settings.lock().await.settings.mention_keywords.clone();
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+    
+    // Defaults depend on our peer ID, so they're only known once the node is up
+    let state_guard = state.lock().await;
+    Ok(state_guard
+        .handle()
+        .map(|handle| mentions::default_keywords(&handle.peer_id))
+        .unwrap_or_default())
+}
+
+#[tauri::command]
+async fn set_mention_keywords(keywords: Vec<String>, settings: State<'_, SettingsState>) -> Result<(), CommandError> {
+    let keywords: Vec<String> = keywords
+        .into_iter()
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect();
+    
+    let mut settings = settings.lock().await;
+    settings.settings.mention_keywords = keywords;
+    settings.save().map_err(CommandError::internal)
+}
+
+#[tauri::command]
+async fn set_power_mode(
+    mode: PowerMode,
+    pause_mdns: Option<bool>,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    let pause_mdns = pause_mdns.unwrap_or(false);
+    
+    let mut settings = settings.lock().await;
+    settings.settings.power.mode = mode;
+    settings.settings.power.pause_mdns = pause_mdns;
+    settings.save().map_err(CommandError::internal)?;
+    drop(settings);
+    
+    // Apply right away if the node is running; otherwise it's picked up at init
+    let state_guard = state.lock().await;
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::SetPowerMode(mode, pause_mdns))
+            .map_err(|_| CommandError::NotInitialized)?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_mdns_enabled(enabled: bool, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::SetMdnsEnabled(enabled))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+// Do-not-disturb: room messages are held back until `resume_messages`
+#[tauri::command]
+async fn pause_messages(state: State<'_, P2PState>) -> Result<(), CommandError> {
+    set_messages_paused(true, state).await
+}
+
+#[tauri::command]
+async fn resume_messages(state: State<'_, P2PState>) -> Result<(), CommandError> {
+    set_messages_paused(false, state).await
+}
+
+async fn set_messages_paused(paused: bool, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::SetMessagesPaused(paused))
+            .map_err(|_| CommandError::NotInitialized)?;
+        Ok(())
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn mute_peer(
+    peer_id: String,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    set_peer_muted(peer_id, true, state, settings).await
+}
+
+#[tauri::command]
+async fn unmute_peer(
+    peer_id: String,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    set_peer_muted(peer_id, false, state, settings).await
+}
+
+async fn set_peer_muted(
+    peer_id: String,
+    muted: bool,
+    state: State<'_, P2PState>,
+    settings: State<'_, SettingsState>,
+) -> Result<(), CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    let key = peer_id.to_string();
+    
+    let mut settings = settings.lock().await;
+    settings.settings.muted_peers.retain(|p| p != &key);
+    if muted {
+        settings.settings.muted_peers.push(key);
+    }
+    settings.save().map_err(CommandError::internal)?;
+    drop(settings);
+    
+    // Apply right away if the node is running; otherwise it's picked up at init
+    let state_guard = state.lock().await;
+    if let Some(handle) = state_guard.handle() {
+        handle.command_tx.send(P2PCommand::SetMuted(peer_id, muted))
+            .map_err(|_| CommandError::NotInitialized)?;
+    }
+    Ok(())
+}
+
+// Friends stay in the room mesh regardless of gossipsub scoring and are
+// dialed at startup
+#[tauri::command]
+async fn add_explicit_peer(peer_id: String, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    set_explicit_peer(peer_id, true, state).await
+}
+
+#[tauri::command]
+async fn remove_explicit_peer(peer_id: String, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    set_explicit_peer(peer_id, false, state).await
+}
+
+async fn set_explicit_peer(peer_id: String, explicit: bool, state: State<'_, P2PState>) -> Result<(), CommandError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| CommandError::invalid_input(format!("Invalid peer ID: {}", e)))?;
+    let state_guard = state.lock().await;
+    
+    if let Some(handle) = state_guard.handle() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        handle.command_tx.send(P2PCommand::SetExplicitPeer(peer_id, explicit, tx))
+            .map_err(|_| CommandError::NotInitialized)?;
+        
+        rx.await.map_err(|_| CommandError::NotInitialized)?
+    } else {
+        Err(CommandError::NotInitialized)
+    }
+}
+
+#[tauri::command]
+async fn export_history(
+    room: Option<String>,
+    path: String,
+    history: State<'_, HistoryState>,
+) -> Result<ExportSummary, CommandError> {
+    let store = history.lock().await;
+    store
+        .export(room.as_deref(), &PathBuf::from(path))
+        .map_err(CommandError::internal)
+}
+
+#[tauri::command]
+async fn import_history(path: String, history: State<'_, HistoryState>) -> Result<ImportSummary, CommandError> {
+    let mut store = history.lock().await;
+    store
+        .import(&PathBuf::from(path))
+        .map_err(CommandError::internal)
+}
+
+#[tauri::command]
+async fn search_messages(
+    query: String,
+    room: Option<String>,
+    limit: u32,
+    history: State<'_, HistoryState>,
+) -> Result<Vec<SearchHit>, CommandError> {
+    let store = history.lock().await;
+    store
+        .search(&query, room.as_deref(), limit)
+        .map_err(CommandError::internal)
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    // Logs go to stdout as before and into a buffer the UI can read back
+    let log_buffer = LogBuffer::default();
+    let (level, level_handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_buffer.layer())
+        .init();
+    
+    tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .manage(LogState { buffer: log_buffer, level: level_handle })
+        .on_window_event(|window, event| {
+            // The app may be gone before the frontend gets to call persist_state
+            if let tauri::WindowEvent::CloseRequested { .. } = event {
+                let app = window.app_handle().clone();
+                if let Err(e) = tauri::async_runtime::block_on(save_session(&app)) {
+                    tracing::warn!("Failed to save session on close: {}", e);
+                }
+            }
+        })
+        .setup(|app| {
+            app.manage(P2PState::default());
+            
+            // Open the local message store in the app data directory
+            let data_dir = app.path().app_data_dir()?;
+            std::fs::create_dir_all(&data_dir)?;
+            let store = MessageStore::open(&data_dir.join("history.db"))?;
+            let settings = SettingsStore::load(data_dir.join("settings.json"));
+            let unread = UnreadCounters::seed(&store, &settings.settings)?;
+            app.manage(HistoryState::new(Mutex::new(store)));
+            app.manage(SettingsState::new(Mutex::new(settings)));
+            app.manage(UnreadState::new(Mutex::new(unread)));
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            init_p2p,
+            get_node_info,
+            get_stats,
+            get_metrics,
+            get_event_log,
+            set_log_level,
+            persist_state,
+            get_recent_logs,
+            export_diagnostics,
+            set_debug_log_stream,
+            get_identity,
+            export_identity,
+            import_identity,
+            rotate_identity,
+            get_mesh_peers,
+            get_room_members,
+            get_peer_protocols,
+            get_address_book,
+            benchmark_dht_lookup,
+            estimate_network_size,
+            test_reachability,
+            run_connectivity_test,
+            list_public_rooms,
+            hide_public_room,
+            unhide_public_room,
+            join_room,
+            join_room_passive,
+            create_room,
+            moderate_room,
+            leave_room,
+            set_status,
+            set_nickname,
+            regenerate_nickname,
+            send_message,
+            broadcast_to_all_rooms,
+            send_attachment,
+            send_inline_attachment,
+            fetch_attachment,
+            connect_to_peer,
+            reserve_relay,
+            send_to_peer,
+            refresh_peers,
+            reset_discovery,
+            set_power_mode,
+            set_mdns_enabled,
+            pause_messages,
+            resume_messages,
+            mute_peer,
+            unmute_peer,
+            add_explicit_peer,
+            add_peer_address,
+            remove_explicit_peer,
+            export_history,
+            import_history,
+            search_messages,
+            get_unread_counts,
+            set_active_room,
+            mark_room_read,
+            get_mention_keywords,
+            set_mention_keywords
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_inits_claim_the_slot_once() {
+        let state = Arc::new(Mutex::new(NodeState::Stopped));
+        let (first, second) = tokio::join!(claim_node_slot(&state), claim_node_slot(&state));
+        let mut results = [first, second];
+        results.sort_by_key(|result| result.is_err());
+        assert_eq!(results, [Ok(()), Err(CommandError::AlreadyInitialized)]);
+        assert!(matches!(*state.lock().await, NodeState::Initializing));
+    }
+
+    #[tokio::test]
+    async fn failed_start_frees_the_slot() {
+        let state = Mutex::new(NodeState::Stopped);
+        claim_node_slot(&state).await.unwrap();
+        let failed = build_in_slot(&state, async { Err::<(), _>(CommandError::internal("no transport")) }).await;
+        assert!(failed.is_err());
+        assert!(matches!(*state.lock().await, NodeState::Stopped));
+        assert_eq!(claim_node_slot(&state).await, Ok(()));
+
+        let built = build_in_slot(&state, async { Ok::<_, CommandError>(7) }).await;
+        assert_eq!(built, Ok(7));
+        assert!(matches!(*state.lock().await, NodeState::Initializing));
+    }
+
+    #[test]
+    fn room_event_names_never_collide() {
+        assert_eq!(room_event_name("lobby"), "chat-message:6c6f626279");
+        let rooms = ["a b", "a_b", "a.b", "a/b", "ab", "日本", "system", "café"];
+        let names: HashSet<String> = rooms.iter().map(|room| room_event_name(room)).collect();
+        assert_eq!(names.len(), rooms.len());
+        assert!(!names.contains(SYSTEM_MESSAGE_EVENT));
+        assert!(names.iter().all(|name| name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b':' || b == b'-')));
+
+        // Spellings of the same room share its channel
+        assert_eq!(room_event_name(" Cafe\u{301}"), room_event_name("café"));
+        assert_eq!(room_event_name("LOBBY"), room_event_name("lobby"));
+    }
 }
@@ -0,0 +1,105 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+// Log lines kept for `get_recent_logs`; the oldest are dropped past this
+pub const MAX_LOG_RECORDS: usize = 2000;
+// Lines waiting to go out as `debug-log` events; more are dropped so a slow
+// UI can't make logging block or grow
+pub const LOG_STREAM_CAPACITY: usize = 256;
+// Events that carry message contents log under this target and are never
+// captured, so logs can be shared without leaking chat
+pub const CONTENT_LOG_TARGET: &str = "p2p_chat::content";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Captured {
+    records: VecDeque<LogRecord>,
+    // Set while the UI has the live console open
+    stream: Option<mpsc::Sender<LogRecord>>,
+}
+
+// Recent tracing output kept in memory for bug reports, filled by the
+// layer from `layer()`
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    captured: Arc<Mutex<Captured>>,
+}
+
+impl LogBuffer {
+    pub fn layer(&self) -> CaptureLayer {
+        CaptureLayer { buffer: self.clone() }
+    }
+
+    // The newest `limit` records, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<LogRecord> {
+        let captured = self.captured.lock().unwrap();
+        let skip = captured.records.len().saturating_sub(limit);
+        captured.records.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn set_stream(&self, stream: Option<mpsc::Sender<LogRecord>>) {
+        self.captured.lock().unwrap().stream = stream;
+    }
+
+    fn push(&self, record: LogRecord) {
+        let mut captured = self.captured.lock().unwrap();
+        if let Some(stream) = &captured.stream {
+            // Full or closed: the live view misses the line, the buffer doesn't
+            let _ = stream.try_send(record.clone());
+        }
+        if captured.records.len() >= MAX_LOG_RECORDS {
+            captured.records.pop_front();
+        }
+        captured.records.push_back(record);
+    }
+}
+
+pub struct CaptureLayer {
+    buffer: LogBuffer,
+}
+
+impl<S: Subscriber> Layer<S> for CaptureLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target() == CONTENT_LOG_TARGET {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.buffer.push(LogRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+        });
+    }
+}
+
+// The formatted message followed by any other fields as `name=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
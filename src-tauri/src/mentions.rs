@@ -0,0 +1,90 @@
+use crate::p2p_node::ChatMessage;
+use serde::Serialize;
+
+// Payload of the `mention` event
+#[derive(Debug, Clone, Serialize)]
+pub struct MentionEvent {
+    pub message: ChatMessage,
+    pub keyword: String,
+}
+
+// Keywords used when the user hasn't configured any: the distinctive tail of
+// our peer ID, as shown in the UI's shortened form
+pub fn default_keywords(peer_id: &str) -> Vec<String> {
+    let tail = &peer_id[peer_id.len().saturating_sub(6)..];
+    vec![tail.to_string()]
+}
+
+// First keyword that appears in the content as a whole word, ignoring case
+pub fn find_mention(content: &str, keywords: &[String]) -> Option<String> {
+    let content = content.to_lowercase();
+
+    keywords
+        .iter()
+        .find(|keyword| {
+            let keyword = keyword.trim().to_lowercase();
+            !keyword.is_empty() && contains_word(&content, &keyword)
+        })
+        .cloned()
+}
+
+fn contains_word(haystack: &str, word: &str) -> bool {
+    haystack.match_indices(word).any(|(start, _)| {
+        let end = start + word.len();
+        let before = haystack[..start].chars().next_back();
+        let after = haystack[end..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_whole_words_only() {
+        let ben = keywords(&["ben"]);
+        assert_eq!(find_mention("hey ben, lunch?", &ben).as_deref(), Some("ben"));
+        assert_eq!(find_mention("running the benchmark now", &ben), None);
+        assert_eq!(find_mention("ask reuben", &ben), None);
+        assert_eq!(find_mention("ben_2 is here", &ben), None);
+    }
+
+    #[test]
+    fn matches_at_start_and_end() {
+        let ben = keywords(&["ben"]);
+        assert!(find_mention("ben: are you there", &ben).is_some());
+        assert!(find_mention("thanks ben", &ben).is_some());
+        assert!(find_mention("ben", &ben).is_some());
+    }
+
+    #[test]
+    fn punctuation_is_a_word_boundary() {
+        let ben = keywords(&["ben"]);
+        for content in ["@ben look", "(ben)", "ben!", "ben?", "\"ben\"", "ben's turn", "ben…"] {
+            assert!(find_mention(content, &ben).is_some(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn ignores_case_and_returns_the_configured_keyword() {
+        let configured = keywords(&["Ben"]);
+        assert_eq!(find_mention("BEN are you up", &configured).as_deref(), Some("Ben"));
+        assert_eq!(find_mention("hi Ünal", &keywords(&["ünal"])).as_deref(), Some("ünal"));
+    }
+
+    #[test]
+    fn blank_keywords_never_match() {
+        assert_eq!(find_mention("anything at all", &keywords(&["", "   "])), None);
+        assert_eq!(find_mention("", &keywords(&["ben"])), None);
+        assert_eq!(find_mention("hi ben", &keywords(&["", "ben"])).as_deref(), Some("ben"));
+    }
+}
@@ -0,0 +1,211 @@
+use libp2p::identity;
+use serde::{Deserialize, Serialize};
+
+const INVITE_PREFIX: &str = "p2p-chat-invite:";
+// Orders older than this are ignored, so a recorded one can't be replayed later
+const MODERATION_MAX_AGE_SECS: i64 = 10 * 60;
+// Allowed clock skew for orders that claim to come from the future
+const MODERATION_MAX_SKEW_SECS: i64 = 60;
+const MAX_MUTE_MINUTES: u64 = 7 * 24 * 60;
+
+// A room name together with its owner's public key. Clients that join
+// through an invite only obey moderation signed with that key.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInvite {
+    pub room: String,
+    // Base58 protobuf encoding of the owner's public key
+    pub owner: String,
+    // `p2p-chat-invite:<owner>/<room>`, for sharing
+    pub invite: String,
+}
+
+impl RoomInvite {
+    pub fn new(room: &str, owner: &identity::PublicKey) -> Self {
+        let owner = encode_public_key(owner);
+        Self {
+            room: room.to_string(),
+            invite: format!("{}{}/{}", INVITE_PREFIX, owner, room),
+            owner,
+        }
+    }
+
+    // None if `text` isn't an invite at all; Err if it is one but malformed
+    pub fn parse(text: &str) -> Option<Result<(String, identity::PublicKey), String>> {
+        let rest = text.trim().strip_prefix(INVITE_PREFIX)?;
+        Some(
+            rest.split_once('/')
+                .ok_or_else(|| "Invite is missing the room name".to_string())
+                .and_then(|(owner, room)| Ok((room.to_string(), decode_public_key(owner)?))),
+        )
+    }
+}
+
+pub fn encode_public_key(key: &identity::PublicKey) -> String {
+    bs58::encode(key.encode_protobuf()).into_string()
+}
+
+pub fn decode_public_key(encoded: &str) -> Result<identity::PublicKey, String> {
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| format!("Invalid owner key: {}", e))?;
+    identity::PublicKey::try_decode_protobuf(&bytes).map_err(|e| format!("Invalid owner key: {}", e))
+}
+
+pub fn encode_keypair(keypair: &identity::Keypair) -> String {
+    let bytes = keypair
+        .to_protobuf_encoding()
+        .expect("ed25519 keypairs can always be encoded");
+    bs58::encode(bytes).into_string()
+}
+
+pub fn decode_keypair(encoded: &str) -> Result<identity::Keypair, String> {
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| format!("Invalid room owner key: {}", e))?;
+    identity::Keypair::from_protobuf_encoding(&bytes).map_err(|e| format!("Invalid room owner key: {}", e))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ModerationAction {
+    // Hide the peer's messages in the room for a while
+    Mute { peer_id: String, minutes: u64 },
+    // Pin a message, by the ID its author sent it with
+    Pin { message_id: String },
+}
+
+impl ModerationAction {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ModerationAction::Mute { peer_id, minutes } => {
+                peer_id
+                    .parse::<libp2p::PeerId>()
+                    .map_err(|e| format!("Invalid peer ID: {}", e))?;
+                if *minutes == 0 || *minutes > MAX_MUTE_MINUTES {
+                    return Err(format!("Mute duration must be between 1 and {} minutes", MAX_MUTE_MINUTES));
+                }
+            }
+            ModerationAction::Pin { message_id } => {
+                if message_id.is_empty() || message_id.len() > 64 {
+                    return Err("Invalid message ID".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Moderation order as sent on a room's moderation topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationMessage {
+    pub action: ModerationAction,
+    // Unix seconds
+    pub issued_at: i64,
+    // Base58 ed25519 signature by the room owner over `signed_bytes`
+    pub signature: String,
+}
+
+impl ModerationMessage {
+    pub fn sign(room: &str, action: ModerationAction, owner: &identity::Keypair) -> Result<Self, String> {
+        let issued_at = chrono::Utc::now().timestamp();
+        let signature = owner
+            .sign(&signed_bytes(room, &action, issued_at))
+            .map_err(|e| format!("Failed to sign moderation: {}", e))?;
+        Ok(Self {
+            action,
+            issued_at,
+            signature: bs58::encode(signature).into_string(),
+        })
+    }
+
+    // Checks the signature against the owner from the room's invite, and
+    // that the order is recent
+    pub fn verify(&self, room: &str, owner: &identity::PublicKey) -> Result<(), String> {
+        let signature = bs58::decode(&self.signature)
+            .into_vec()
+            .map_err(|_| "signature is not valid base58".to_string())?;
+        if !owner.verify(&signed_bytes(room, &self.action, self.issued_at), &signature) {
+            return Err("signature does not match the room owner".to_string());
+        }
+
+        let age = chrono::Utc::now().timestamp() - self.issued_at;
+        if !(-MODERATION_MAX_SKEW_SECS..=MODERATION_MAX_AGE_SECS).contains(&age) {
+            return Err(format!("order is {} seconds old", age));
+        }
+        self.action.validate()
+    }
+}
+
+// The room is signed along with the order so it can't be replayed elsewhere
+fn signed_bytes(room: &str, action: &ModerationAction, issued_at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("p2p-chat/moderation", room, action, issued_at)).expect("moderation serialization cannot fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mute() -> ModerationAction {
+        ModerationAction::Mute { peer_id: identity::Keypair::generate_ed25519().public().to_peer_id().to_string(), minutes: 5 }
+    }
+
+    #[test]
+    fn signed_orders_verify_against_the_owner() {
+        let owner = identity::Keypair::generate_ed25519();
+        let message = ModerationMessage::sign("room", mute(), &owner).unwrap();
+        assert_eq!(message.verify("room", &owner.public()), Ok(()));
+
+        let pin = ModerationMessage::sign("room", ModerationAction::Pin { message_id: "abc".into() }, &owner).unwrap();
+        assert_eq!(pin.verify("room", &owner.public()), Ok(()));
+    }
+
+    #[test]
+    fn tampered_orders_are_rejected() {
+        let owner = identity::Keypair::generate_ed25519();
+        let message = ModerationMessage::sign("room", mute(), &owner).unwrap();
+
+        let mut changed_action = message.clone();
+        changed_action.action = mute();
+        assert!(changed_action.verify("room", &owner.public()).is_err());
+
+        let mut changed_time = message.clone();
+        changed_time.issued_at -= 1;
+        assert!(changed_time.verify("room", &owner.public()).is_err());
+
+        let mut garbage = message.clone();
+        garbage.signature = "not base58 0OIl".into();
+        assert!(garbage.verify("room", &owner.public()).is_err());
+
+        // An order for one room can't be replayed in another
+        assert!(message.verify("other", &owner.public()).is_err());
+    }
+
+    #[test]
+    fn orders_from_another_key_are_rejected() {
+        let owner = identity::Keypair::generate_ed25519();
+        let impostor = identity::Keypair::generate_ed25519();
+        let message = ModerationMessage::sign("room", mute(), &impostor).unwrap();
+        assert!(message.verify("room", &owner.public()).is_err());
+    }
+
+    #[test]
+    fn stale_orders_are_rejected() {
+        let owner = identity::Keypair::generate_ed25519();
+        let action = mute();
+        let issued_at = chrono::Utc::now().timestamp() - MODERATION_MAX_AGE_SECS - 5;
+        let signature = owner.sign(&signed_bytes("room", &action, issued_at)).unwrap();
+        let message = ModerationMessage { action, issued_at, signature: bs58::encode(signature).into_string() };
+        assert!(message.verify("room", &owner.public()).is_err());
+    }
+
+    #[test]
+    fn invites_round_trip() {
+        let owner = identity::Keypair::generate_ed25519();
+        let invite = RoomInvite::new("my room", &owner.public());
+        let (room, key) = RoomInvite::parse(&invite.invite).unwrap().unwrap();
+        assert_eq!(room, "my room");
+        assert_eq!(key, owner.public());
+        assert!(RoomInvite::parse("hello").is_none());
+        assert!(RoomInvite::parse("p2p-chat-invite:nokey").unwrap().is_err());
+    }
+}
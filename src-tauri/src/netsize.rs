@@ -0,0 +1,76 @@
+use libp2p::PeerId;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// Rough size of the DHT from closest-peer lookups for random keys.
+//
+// Peer IDs hash to uniformly random points of the keyspace, so in a network
+// of N peers the i-th closest peer to any key is about i / (N + 1) of the
+// keyspace away. A lookup returns up to k = 20 of these distances, and a
+// least-squares fit of distance against rank through the origin has a slope
+// of about 1 / (N + 1).
+//
+// A single lookup is off by around 1/sqrt(k), so ±25% or so; pooling
+// several narrows that, and the smallest and largest single-lookup figures
+// are reported as the range. Peers that don't answer leave gaps in the
+// closest set, which pushes the estimate low, so treat it as a lower bound
+// of the same order of magnitude rather than a count.
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeEstimate {
+    pub estimate: u64,
+    // Smallest and largest single-lookup estimates
+    pub low: u64,
+    pub high: u64,
+}
+
+// Distance of each peer from the lookup key as a fraction of the keyspace,
+// closest first. Kademlia keys are SHA-256 hashes, so only the top 64 bits
+// of the XOR distance matter at this precision.
+pub fn distances(key: &[u8], peers: &[PeerId]) -> Vec<f64> {
+    let target = Sha256::digest(key);
+    let mut distances: Vec<f64> = peers
+        .iter()
+        .map(|peer| {
+            let hash = Sha256::digest(peer.to_bytes());
+            let mut prefix = [0u8; 8];
+            for (i, byte) in prefix.iter_mut().enumerate() {
+                *byte = hash[i] ^ target[i];
+            }
+            u64::from_be_bytes(prefix) as f64 / 2f64.powi(64)
+        })
+        .collect();
+    distances.sort_by(f64::total_cmp);
+    distances
+}
+
+// Pools the distances of all lookups; None if no lookup found a peer
+pub fn estimate(samples: &[Vec<f64>]) -> Option<SizeEstimate> {
+    let per_lookup: Vec<u64> = samples.iter().filter_map(|distances| size_from_fit(fit(distances))).collect();
+    let pooled = samples
+        .iter()
+        .map(|distances| fit(distances))
+        .fold((0.0, 0.0), |(xy, xx), (sample_xy, sample_xx)| (xy + sample_xy, xx + sample_xx));
+    let estimate = size_from_fit(pooled)?;
+    Some(SizeEstimate {
+        estimate,
+        low: per_lookup.iter().copied().min().unwrap_or(estimate).min(estimate),
+        high: per_lookup.iter().copied().max().unwrap_or(estimate).max(estimate),
+    })
+}
+
+// Sums for the fit: Σ rank·distance and Σ rank²
+fn fit(distances: &[f64]) -> (f64, f64) {
+    distances.iter().enumerate().fold((0.0, 0.0), |(xy, xx), (i, distance)| {
+        let rank = (i + 1) as f64;
+        (xy + rank * distance, xx + rank * rank)
+    })
+}
+
+fn size_from_fit((xy, xx): (f64, f64)) -> Option<u64> {
+    if xy <= 0.0 {
+        return None;
+    }
+    let slope = xy / xx;
+    Some((1.0 / slope - 1.0).max(1.0).round() as u64)
+}
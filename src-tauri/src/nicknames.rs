@@ -0,0 +1,29 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "breezy", "bright", "calm", "clever", "cosmic", "crisp", "curious", "dapper", "daring",
+    "dusty", "eager", "fancy", "fuzzy", "gentle", "glad", "golden", "happy", "hidden", "humble", "jolly", "keen",
+    "kind", "lively", "lucky", "mellow", "merry", "mighty", "misty", "nimble", "noble", "patient", "plucky", "polite",
+    "proud", "quick", "quiet", "rapid", "rosy", "rustic", "shiny", "silent", "silver", "sleepy", "snowy", "solar",
+    "sparkly", "spry", "steady", "stormy", "sunny", "swift", "tidy", "tiny", "velvet", "vivid", "wandering", "warm",
+    "whimsical", "wild", "witty", "zesty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beacon", "bear", "beetle", "bison", "canyon", "cedar", "comet", "coral", "crane", "cricket", "dolphin",
+    "eagle", "ember", "falcon", "fern", "finch", "fox", "gecko", "glacier", "harbor", "hawk", "hedgehog", "heron",
+    "ibis", "jaguar", "kestrel", "koala", "lantern", "lark", "lemur", "lynx", "maple", "meadow", "moose", "moth",
+    "nebula", "newt", "orca", "otter", "owl", "panda", "pebble", "pelican", "pine", "puffin", "quail", "raven",
+    "reef", "river", "robin", "salmon", "sparrow", "spruce", "squirrel", "stork", "thistle", "tiger", "toucan",
+    "walrus", "willow", "wombat", "wren", "yak",
+];
+
+// A friendly name like "swift-otter-427" for users who haven't picked one.
+// The number keeps two people with the same words apart.
+pub fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    let adjective = ADJECTIVES.choose(&mut rng).copied().unwrap_or("quiet");
+    let noun = NOUNS.choose(&mut rng).copied().unwrap_or("otter");
+    format!("{}-{}-{}", adjective, noun, rng.gen_range(100..1000))
+}
@@ -1,39 +1,947 @@
 use libp2p::{
-    identify, kad, mdns, noise, gossipsub,
-    swarm::{NetworkBehaviour, SwarmEvent}, tcp, yamux, 
-    Multiaddr, PeerId, Swarm, StreamProtocol, SwarmBuilder,
+    autonat, connection_limits,
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{self, ListenerId, PortUse, TransportError, TransportEvent},
+        upgrade,
+    },
+    dns, identify, identity, kad, mdns, noise, gossipsub, ping, request_response,
+    metrics::{Metrics, Recorder},
+    multiaddr::Protocol,
+    pnet::{PnetConfig, PreSharedKey}, relay, rendezvous,
+    swarm::{behaviour::toggle::Toggle, dial_opts::{DialOpts, PeerCondition}, ConnectionDenied, ConnectionId, DialError, ListenError, NetworkBehaviour, SwarmEvent}, tcp, websocket, yamux, 
+    Multiaddr, PeerId, Swarm, StreamProtocol, SwarmBuilder, Transport,
 };
+use crate::address_book::AddressBook;
+use crate::attachments::{self, Attachment, AttachmentStore, FileRequest, FileResponse};
+use crate::chunking::{self, ChunkHeader, Reassembler};
+use crate::dial_queue::DialQueue;
+use crate::directory::{self, DirectoryRequest, DirectoryResponse, PublicRoom, RoomListing};
+use crate::error::CommandError;
+use crate::event_log::{EventLog, LogLevel};
+use crate::kad_store::PersistentStore;
+use crate::keep_alive;
+use crate::log_capture::CONTENT_LOG_TARGET;
+use crate::moderation::{ModerationAction, ModerationMessage};
+use crate::netsize::{self, SizeEstimate};
+use crate::retransmit::{self, GapTracker, RetransmitRequest, RetransmitResponse, SentLog, Sequence};
+use crate::config::{RENDEZVOUS_MAX_TTL_SECS, RENDEZVOUS_MIN_TTL_SECS, ConnectionLimitSettings, GossipsubSettings, P2PConfig, PowerMode, RelayLimits, TransportSettings};
+use crate::room_crypto::RoomKey;
+use crate::settings::SessionRoom;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use prometheus_client::registry::Registry;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::io::{Read, Write};
 use std::net::IpAddr;
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tracing::{info, warn};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+use unicode_normalization::UnicodeNormalization;
 
 const CHAT_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/1.0.0");
+// Announced over identify; bump the major version on incompatible changes
+const PROTOCOL_VERSION: &str = "p2p-chat/1";
+pub const AGENT_VERSION: &str = concat!("p2p-chat/", env!("CARGO_PKG_VERSION"));
+// Longest room name we accept, in characters
+const MAX_ROOM_NAME_LEN: usize = 64;
+// Longer statuses and nicknames are cut to this many characters
+const MAX_STATUS_LEN: usize = 80;
+const MAX_NICKNAME_LEN: usize = 32;
+// A peer, us included, may change nickname at most once per this interval
+const NICKNAME_CHANGE_INTERVAL: Duration = Duration::from_secs(10);
+// How often presence is broadcast. It doubles as a heartbeat, and lets
+// newcomers to the room see a set status.
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+// Missed heartbeats before a room member shows as away, then offline
+const MISSED_BEATS_AWAY: u32 = 2;
+const MISSED_BEATS_OFFLINE: u32 = 5;
+
+// Bump when the room message envelope changes incompatibly
+const ENVELOPE_VERSION: u32 = 1;
+// Room left in each message for gossipsub framing, signature and room encryption
+const PUBLISH_OVERHEAD: usize = 1024;
+// Message content longer than this is deflated on the wire
+const COMPRESSION_THRESHOLD: usize = 1024;
+// Inflating stops here, so a small payload can't expand into gigabytes
+const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024;
+// What clients that predate compression show instead of deflated content
+const COMPRESSED_PLACEHOLDER: &str = "[Compressed message, update p2p-chat to read it]";
+// Shown for envelopes from newer clients that carry no text we can read
+const UNSUPPORTED_PLACEHOLDER: &str = "[Message from a newer version of p2p-chat, update to read it]";
+
+const DIRECT_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/direct/1.0.0");
+const RECEIPT_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/receipt/1.0.0");
+
+// Delivery receipts are counted for at most this many of our latest room
+// messages, and for no longer than the window
+const MAX_TRACKED_DELIVERIES: usize = 256;
+const DELIVERY_TRACKING_WINDOW: Duration = Duration::from_secs(10 * 60);
+// Room messages remembered by sender and message ID, so a resent copy of
+// one we've already shown is dropped
+const MAX_SEEN_MESSAGES: usize = 4096;
+// Hex digits of SHA-256 kept in IDs of unsigned gossipsub messages (128 bits)
+const MESSAGE_ID_HASH_LEN: usize = 32;
+// System events kept for the diagnostics bundle
+const MAX_RECENT_SYSTEM_EVENTS: usize = 50;
+
+// How often the select loop checks for stale peer entries
+pub const STALE_PEER_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// Peer list changes within this window go out as one `peers-changed` event,
+// so bootstrap doesn't flood the frontend
+pub const PEERS_CHANGED_DEBOUNCE: Duration = Duration::from_millis(500);
+// Entries without a live connection are dropped after this long without activity
+const STALE_PEER_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+// Identify re-runs this rarely in low-power mode (the libp2p default is 5 minutes)
+const LOW_POWER_IDENTIFY_INTERVAL: Duration = Duration::from_secs(20 * 60);
+
+// Losing every peer, at least this many of them within the window, is taken
+// as a network change (e.g. WiFi to cellular) rather than peers leaving
+const NETWORK_LOSS_MIN_PEERS: usize = 2;
+const NETWORK_LOSS_WINDOW: Duration = Duration::from_secs(10);
+// Minimum time between two automatic reconnects
+const RECONNECT_COOLDOWN: Duration = Duration::from_secs(30);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// Pings go out less often in low-power mode, still often enough that a live
+// connection is heard from within STALE_PEER_TIMEOUT
+const LOW_POWER_PING_INTERVAL: Duration = Duration::from_secs(90);
+// Room messages held back while messages are paused
+const MAX_HELD_MESSAGES: usize = 500;
+
+// Known members redialed when joining a room
+const AUTO_DIAL_LIMIT: usize = 8;
+
+// Failed room subscriptions are retried after 1s, 2s, 4s, ... up to the limit
+const SUBSCRIBE_RETRY_BASE: Duration = Duration::from_secs(1);
+const SUBSCRIBE_MAX_ATTEMPTS: u32 = 4;
+
+// Consecutive empty room searches before suggesting a connectivity check
+const EMPTY_SEARCHES_BEFORE_HINT: u32 = 3;
+
+// An empty room search is repeated after 2s, 4s, 8s, ... up to the maximum,
+// until a member turns up
+const SEARCH_RETRY_BASE: Duration = Duration::from_secs(2);
+const SEARCH_RETRY_MAX: Duration = Duration::from_secs(30);
+
+// Normal-priority system events of one kind arriving within this window
+// become a single chat line
+const SYSTEM_EVENT_COALESCE_WINDOW: Duration = Duration::from_secs(1);
+// System chat lines shown per second; the rest are counted and reported
+const MAX_SYSTEM_MESSAGES_PER_SEC: u32 = 5;
+
+// At most one "connection limit reached" system message per this interval
+const CONNECTION_LIMIT_NOTICE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Public room lookups answer with whatever arrived by then
+const DIRECTORY_LOOKUP_TIMEOUT: Duration = Duration::from_secs(15);
+// The bootstrap dial and DHT lookup of `run_connectivity_test` each fail
+// after this long
+const CONNECTIVITY_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+// Random-key lookups behind each network size estimate
+const NETWORK_SIZE_LOOKUPS: usize = 4;
+
+// Public libp2p bootstrap nodes; the connectivity test dials the first
+const BOOTSTRAP_ADDRESSES: &[&str] = &[
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+    "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+];
+// Directory providers asked per lookup
+const MAX_DIRECTORY_REQUESTS: usize = 50;
 
 // Network behaviour combining all protocols
 #[derive(NetworkBehaviour)]
 pub struct ChatBehaviour {
-    pub kad: kad::Behaviour<kad::store::MemoryStore>,
-    pub mdns: mdns::tokio::Behaviour,
+    pub limits: connection_limits::Behaviour,
+    pub kad: kad::Behaviour<PersistentStore>,
+    // Disabled when mDNS is turned off at init
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub identify: identify::Behaviour,
     pub gossipsub: gossipsub::Behaviour,
+    pub direct: request_response::json::Behaviour<DirectMessage, DirectAck>,
+    pub receipts: request_response::json::Behaviour<DeliveryReceipt, DirectAck>,
+    pub files: request_response::json::Behaviour<FileRequest, FileResponse>,
+    pub directory: request_response::json::Behaviour<DirectoryRequest, DirectoryResponse>,
+    pub retransmit: request_response::json::Behaviour<RetransmitRequest, RetransmitResponse>,
+    pub autonat: autonat::Behaviour,
+    // Relay server; only present when enabled and not known to be behind NAT
+    pub relay: Toggle<relay::Behaviour>,
+    // Reservations on other peers' relays, so NATed nodes can be reached
+    pub relay_client: relay::client::Behaviour,
+    pub rendezvous: rendezvous::client::Behaviour,
+    // Finds dead connections to room members, which `keep_alive` holds open
+    pub ping: ping::Behaviour,
+    pub keep_alive: keep_alive::Behaviour,
+}
+
+// One-off message sent straight to a peer, outside any room topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub content: String,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectAck {}
+
+// Sent to the author of a room message once we've received it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+}
+
+// Broadcast on a room's presence topic; a missing status or nickname means
+// it was cleared. Older clients only read `status`, so a rename still carries
+// the current status and doesn't wipe it for them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PresenceUpdate {
+    status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
+    pub id: String,
     pub from: String,
     pub content: String,
     pub timestamp: String,
     pub is_self: bool,
+    pub is_direct: bool,
+    pub room: Option<String>,
+    // Messages from before content types existed are plain text
+    #[serde(default)]
+    pub content_type: ContentType,
+    // Peers that acknowledged one of our room messages; updates arrive as
+    // `message-delivered` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered_to: Option<u32>,
+    // Mesh peers one of our room messages was published to. Zero means it
+    // waits for the mesh to form and is sent again, reported by a
+    // `message-status` event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients_estimate: Option<u32>,
+    // A file sent along with the message; `content` is its caption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    // Lamport clock of the message; messages stored before it existed are 0
+    #[serde(default)]
+    pub lamport: u64,
+    // Missed at first and fetched from its author afterwards
+    #[serde(default)]
+    pub recovered: bool,
+}
+
+impl ChatMessage {
+    pub fn is_system(&self) -> bool {
+        self.from == "System"
+    }
+
+    // Order messages are shown in: after everything the sender had seen,
+    // then by the sender's send time, with the ID breaking ties. History
+    // sorts by the same columns.
+    pub fn sort_key(&self) -> (u64, &str, &str) {
+        (self.lamport, &self.timestamp, &self.id)
+    }
+}
+
+// Puts messages in the order they're shown, the order history returns them in
+pub fn sort_for_display(messages: &mut [ChatMessage]) {
+    messages.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+}
+
+// How the UI should render a message's content
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContentType {
+    #[serde(rename = "text/markdown")]
+    Markdown,
+    // Unknown types from newer clients fall back to plain text
+    #[default]
+    #[serde(rename = "text/plain", other)]
+    Plain,
+}
+
+impl ContentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentType::Plain => "text/plain",
+            ContentType::Markdown => "text/markdown",
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Self {
+        match mime {
+            "text/markdown" => ContentType::Markdown,
+            _ => ContentType::Plain,
+        }
+    }
+}
+
+// Wire format of room messages. Payloads that don't parse as an envelope come
+// from clients that send raw text and are shown as plain text. Envelopes from
+// newer clients are read as far as their fields match ours; unknown fields
+// are ignored.
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageEnvelope {
+    v: u32,
+    // Sender's message ID, echoed back in delivery receipts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    content_type: ContentType,
+    content: String,
+    // Base64 of the deflated content for large messages. `content` then holds
+    // COMPRESSED_PLACEHOLDER, which older clients ignore this field and show.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deflate: Option<String>,
+    // Set on each part of a message split by `encode_parts`. Older clients
+    // show the parts as separate messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk: Option<ChunkHeader>,
+    // Older clients show only `content`, which describes the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attachment: Option<Attachment>,
+    // Sender's Lamport clock and RFC 3339 send time, for ordering. Missing
+    // from older clients, whose messages are ordered as they arrive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lamport: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sent_at: Option<String>,
+    // Sender's numbering of their messages in the room, for noticing ones
+    // we missed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<Sequence>,
+}
+
+// Where a message falls in the room's order; every part of a split message
+// carries the same one
+#[derive(Debug, Clone)]
+struct MessageStamp {
+    lamport: u64,
+    sent_at: String,
+    sequence: Sequence,
+}
+
+impl MessageEnvelope {
+    // One envelope per part, splitting the content when a single envelope
+    // would be larger than `max_len`
+    fn encode_parts(
+        id: &str,
+        content_type: ContentType,
+        content: &str,
+        stamp: &MessageStamp,
+        max_len: usize,
+    ) -> Result<Vec<Vec<u8>>, CommandError> {
+        let whole = Self::encode(id, content_type, content, stamp, None);
+        if whole.len() <= max_len {
+            return Ok(vec![whole]);
+        }
+        
+        // Escaping can grow a part past the limit; retry with smaller parts until all fit
+        let mut part_len = max_len * 3 / 4;
+        loop {
+            let parts = chunking::split(content, part_len);
+            if parts.len() > chunking::MAX_CHUNKS as usize {
+                return Err(CommandError::invalid_input(format!(
+                    "Message is too long ({} KiB, the limit is about {} KiB), split it up and try again",
+                    content.len() / 1024,
+                    chunking::MAX_CHUNKS as usize * max_len / 1024
+                )));
+            }
+            let total = parts.len() as u32;
+            let encoded: Vec<Vec<u8>> = parts
+                .iter()
+                .enumerate()
+                .map(|(index, part)| Self::encode(id, content_type, part, stamp, Some(ChunkHeader { index: index as u32, total })))
+                .collect();
+            if encoded.iter().all(|envelope| envelope.len() <= max_len) {
+                return Ok(encoded);
+            }
+            part_len /= 2;
+        }
+    }
+
+    fn encode(id: &str, content_type: ContentType, content: &str, stamp: &MessageStamp, chunk: Option<ChunkHeader>) -> Vec<u8> {
+        // Only worth it when the result is actually smaller
+        let deflate = (content.len() > COMPRESSION_THRESHOLD)
+            .then(|| deflate(content))
+            .filter(|compressed| compressed.len() < content.len());
+        let envelope = MessageEnvelope {
+            v: ENVELOPE_VERSION,
+            id: Some(id.to_string()),
+            content_type,
+            content: match deflate {
+                Some(_) => COMPRESSED_PLACEHOLDER.to_string(),
+                None => content.to_string(),
+            },
+            deflate,
+            chunk,
+            attachment: None,
+            lamport: Some(stamp.lamport),
+            sent_at: Some(stamp.sent_at.clone()),
+            sequence: Some(stamp.sequence),
+        };
+        serde_json::to_vec(&envelope).expect("envelope serialization cannot fail")
+    }
+
+    // Attachment messages are never split; inline data is kept small enough
+    // that they fit in one gossipsub message
+    fn encode_attachment(id: &str, caption: &str, stamp: &MessageStamp, attachment: Attachment) -> Vec<u8> {
+        let envelope = MessageEnvelope {
+            v: ENVELOPE_VERSION,
+            id: Some(id.to_string()),
+            content_type: ContentType::Plain,
+            content: caption.to_string(),
+            deflate: None,
+            chunk: None,
+            attachment: Some(attachment),
+            lamport: Some(stamp.lamport),
+            sent_at: Some(stamp.sent_at.clone()),
+            sequence: Some(stamp.sequence),
+        };
+        serde_json::to_vec(&envelope).expect("envelope serialization cannot fail")
+    }
+
+    // None when compressed content is corrupt or inflates past MAX_DECOMPRESSED_SIZE
+    fn decode(data: &[u8]) -> Option<MessageEnvelope> {
+        let mut envelope = match serde_json::from_slice::<MessageEnvelope>(data) {
+            Ok(envelope) if envelope.v >= 1 => envelope,
+            Ok(_) => Self::legacy(data),
+            Err(e) => match serde_json::from_slice::<serde_json::Value>(data) {
+                Ok(value) if value.get("v").and_then(|v| v.as_u64()).is_some_and(|v| v > ENVELOPE_VERSION as u64) => {
+                    info!("Reading what we can of a newer message envelope: {}", e);
+                    Self::from_newer(&value)
+                }
+                _ => Self::legacy(data),
+            },
+        };
+        if let Some(compressed) = envelope.deflate.take() {
+            envelope.content = inflate(&compressed)
+                .map_err(|e| warn!("Dropping compressed message: {}", e))
+                .ok()?;
+        }
+        Some(envelope)
+    }
+
+    // Raw bytes from clients that predate envelopes
+    fn legacy(data: &[u8]) -> MessageEnvelope {
+        MessageEnvelope {
+            v: 0,
+            id: None,
+            content_type: ContentType::Plain,
+            content: String::from_utf8_lossy(data).to_string(),
+            deflate: None,
+            chunk: None,
+            attachment: None,
+            lamport: None,
+            sent_at: None,
+            sequence: None,
+        }
+    }
+
+    // Picks the fields we understand out of an envelope whose layout changed
+    // after our version; any that moved or changed type are left out
+    fn from_newer(value: &serde_json::Value) -> MessageEnvelope {
+        fn field<T: serde::de::DeserializeOwned>(value: &serde_json::Value, name: &str) -> Option<T> {
+            value.get(name).cloned().and_then(|v| serde_json::from_value(v).ok())
+        }
+        let deflate: Option<String> = field(value, "deflate");
+        let content: Option<String> = field(value, "content");
+        MessageEnvelope {
+            v: value.get("v").and_then(|v| v.as_u64()).unwrap_or_default() as u32,
+            id: field(value, "id"),
+            content_type: field(value, "content_type").unwrap_or_default(),
+            content: match (&deflate, content) {
+                (None, Some(content)) => content,
+                _ => UNSUPPORTED_PLACEHOLDER.to_string(),
+            },
+            deflate,
+            chunk: field(value, "chunk"),
+            attachment: field(value, "attachment"),
+            lamport: field(value, "lamport"),
+            sent_at: field(value, "sent_at"),
+            sequence: field(value, "sequence"),
+        }
+    }
+}
+
+// Sender and its sequence number: copies of one message arriving over
+// several paths are deduplicated, while the same text sent twice is two
+// messages. Unsigned messages fall back to a hash of the data, which has to
+// come out the same on every build and platform for peers to agree on it.
+fn gossip_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    if let (Some(source), Some(seq)) = (message.source, message.sequence_number) {
+        return gossipsub::MessageId::from(format!("{}/{}", source, seq));
+    }
+    let mut id = attachments::sha256_hex(&message.data);
+    id.truncate(MESSAGE_ID_HASH_LEN);
+    gossipsub::MessageId::from(id)
+}
+
+// The sender's send time, but never later than now so a fast clock can't
+// keep a message at the bottom; messages without one are stamped on arrival
+fn sender_timestamp(sent_at: Option<String>) -> String {
+    let now = chrono::Utc::now();
+    sent_at
+        .and_then(|sent_at| chrono::DateTime::parse_from_rfc3339(&sent_at).ok())
+        .map(|sent_at| sent_at.with_timezone(&chrono::Utc).min(now))
+        .unwrap_or(now)
+        .to_rfc3339()
+}
+
+fn deflate(content: &str) -> String {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content.as_bytes()).expect("writing to a Vec cannot fail");
+    BASE64.encode(encoder.finish().expect("writing to a Vec cannot fail"))
+}
+
+fn inflate(encoded: &str) -> Result<String, String> {
+    let compressed = BASE64.decode(encoded).map_err(|e| format!("bad base64: {}", e))?;
+    let mut content = Vec::new();
+    DeflateDecoder::new(compressed.as_slice())
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut content)
+        .map_err(|e| format!("bad deflate stream: {}", e))?;
+    if content.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(format!("content expands past {} bytes", MAX_DECOMPRESSED_SIZE));
+    }
+    String::from_utf8(content).map_err(|e| format!("content is not UTF-8: {}", e))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub peer_id: String,
     pub addresses: Vec<String>,
+    pub connected_since: Option<String>,
+    pub connected_secs: Option<i64>,
+    pub direction: Option<ConnectionDirection>,
+    pub connections: Vec<PeerConnection>,
+    // From identify; empty until the peer has been identified
+    pub protocol_version: Option<String>,
+    pub agent_version: Option<String>,
+    pub protocols: Vec<String>,
+    // Speaks our chat protocol rather than being some other DHT node
+    pub is_chat_peer: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub established: u32,
+    pub pending_incoming: u32,
+    pub pending_outgoing: u32,
+    pub max_established: u32,
+    pub max_established_per_peer: u32,
+    pub max_pending: u32,
+    // Our own dials to discovered peers, in flight and waiting for a slot
+    pub dials_in_flight: usize,
+    pub dials_queued: usize,
+    // Connections and dials refused by the limits since startup
+    pub denied: u64,
+}
+
+// Kademlia routing table by bucket, for diagnostics
+#[derive(Debug, Clone, Serialize)]
+pub struct RoutingTableSummary {
+    pub peers: usize,
+    pub buckets: Vec<BucketSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketSummary {
+    // log2 of the bucket's distance from us
+    pub index: u32,
+    pub peers: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedSystemEvent {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: SystemEvent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayStats {
+    pub enabled: bool,
+    pub active: bool,
+    // Peers currently holding a reservation with us
+    pub reservations: usize,
+    pub open_circuits: usize,
+    pub total_circuits: u64,
+}
+
+// Gossipsub view of the current room. Only `mesh` peers get our messages
+// forwarded in full; `subscribed` peers are in the room but outside the mesh
+// and only hear about messages through gossip
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshPeers {
+    pub room: String,
+    pub mesh: Vec<String>,
+    pub subscribed: Vec<String>,
+}
+
+// Protocols a connected peer announced via identify, as returned by
+// `get_peer_protocols`; `identified` stays false until identify completes
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerProtocols {
+    pub peer_id: String,
+    pub identified: bool,
+    pub protocols: Vec<String>,
+}
+
+// One open connection to a peer, as reported in PeerInfo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerConnection {
+    pub remote_addr: String,
+    pub direction: ConnectionDirection,
+    pub connected_since: String,
+    pub connected_secs: i64,
+}
+
+// Everything we track about a connected peer
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    // Listen addresses the peer reported via identify
+    pub listen_addrs: Vec<String>,
+    pub connections: HashMap<ConnectionId, ConnectionDetails>,
+    // Last swarm event involving this peer
+    pub last_seen: Instant,
+    pub identity: Option<PeerIdentity>,
+}
+
+// What a peer told us about itself via identify
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub protocol_version: String,
+    pub agent_version: String,
+    pub protocols: Vec<StreamProtocol>,
+}
+
+impl PeerIdentity {
+    pub fn is_chat_peer(&self) -> bool {
+        self.protocols.contains(&CHAT_PROTOCOL) && !self.is_incompatible()
+    }
+
+    // A chat node whose protocol major version differs from ours
+    pub fn is_incompatible(&self) -> bool {
+        protocol_major(&self.protocol_version).is_some_and(|major| Some(major) != protocol_major(PROTOCOL_VERSION))
+    }
+}
+
+// Major version of a chat identify protocol version such as "p2p-chat/1",
+// or None for other kinds of nodes. Builds before PROTOCOL_VERSION existed
+// announced the stream protocol "/p2p-chat/1.0.0" instead.
+pub fn protocol_major(protocol_version: &str) -> Option<u32> {
+    let version = protocol_version.strip_prefix('/').unwrap_or(protocol_version);
+    let version = version.strip_prefix("p2p-chat/")?;
+    version.split('.').next()?.parse().ok()
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionDetails {
+    pub remote_addr: Multiaddr,
+    pub direction: ConnectionDirection,
+    pub connected_since: chrono::DateTime<chrono::Utc>,
+}
+
+impl ConnectionInfo {
+    fn new() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            connections: HashMap::new(),
+            last_seen: Instant::now(),
+            identity: None,
+        }
+    }
+
+    fn is_chat_peer(&self) -> bool {
+        self.identity.as_ref().is_some_and(PeerIdentity::is_chat_peer)
+    }
+
+    // The longest-lived connection stands for the peer as a whole
+    fn oldest(&self) -> Option<&ConnectionDetails> {
+        self.connections.values().min_by_key(|c| c.connected_since)
+    }
+}
+
+// Derive a fixed keypair from a 32-byte seed so tests and tutorials get a
+// stable peer ID. NOT FOR PRODUCTION: anyone who knows the seed owns the identity.
+#[cfg(any(test, feature = "test-identity"))]
+pub fn keypair_from_seed(seed: [u8; 32]) -> identity::Keypair {
+    identity::Keypair::ed25519_from_bytes(seed).expect("a 32-byte seed is always a valid ed25519 key")
+}
+
+// Trimmed and truncated status without control characters; None clears it
+pub fn sanitize_status(text: &str) -> Option<String> {
+    clean_text(text, MAX_STATUS_LEN)
+}
+
+// Same rules as statuses, with a shorter limit
+pub fn sanitize_nickname(text: &str) -> Option<String> {
+    clean_text(text, MAX_NICKNAME_LEN)
+}
+
+fn clean_text(text: &str, max_len: usize) -> Option<String> {
+    let text: String = text
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_len)
+        .collect();
+    let text = text.trim_end();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+// Canonical form of a room name, used for the gossipsub topic, the DHT
+// provider key and the stored history so peers typing the "same" name always
+// meet: trimmed, lowercase, and NFC so composed and decomposed accents match
+pub fn normalize_room_name(name: &str) -> String {
+    name.trim().to_lowercase().nfc().collect()
+}
+
+// Normalizes a room name typed by the user and rejects unusable ones
+pub fn sanitize_room_name(name: &str) -> Result<String, String> {
+    let name = normalize_room_name(name);
+    
+    if name.is_empty() {
+        return Err("Room name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(format!("Room name is too long (max {} characters)", MAX_ROOM_NAME_LEN));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("Room name cannot contain control characters".to_string());
+    }
+    
+    Ok(name)
+}
+
+// Load a pre-shared key in the standard swarm.key format
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read swarm key {}: {}", path.display(), e))?;
+    text.trim()
+        .parse::<PreSharedKey>()
+        .map_err(|e| format!("Invalid swarm key {}: {}", path.display(), e))
+}
+
+// TCP with noise and yamux, optionally behind a pnet handshake so only nodes
+// holding the same pre-shared key can complete a connection
+fn build_transport(
+    key: &identity::Keypair,
+    psk: Option<PreSharedKey>,
+    settings: &TransportSettings,
+) -> Result<transport::Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let tcp = tcp_transport(settings);
+    let noise = noise::Config::new(key)?;
+    
+    let transport = match psk {
+        Some(psk) => {
+            let transport = tcp
+                .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+                .upgrade(upgrade::Version::V1Lazy)
+                .authenticate(noise)
+                .multiplex(yamux_config(settings))
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+            
+            // Browsers can't do the pnet handshake, so private networks stay TCP-only
+            return Ok(dns::tokio::Transport::system(transport)?.boxed());
+        }
+        None => tcp
+            .upgrade(upgrade::Version::V1Lazy)
+            .authenticate(noise)
+            .multiplex(yamux_config(settings))
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer))),
+    };
+    
+    // WebSocket has to see /dns addresses before they are resolved so /wss
+    // can verify the certificate against the hostname
+    let ws = websocket::WsConfig::new(dns::tokio::Transport::system(tcp_transport(settings))?)
+        .upgrade(upgrade::Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?)
+        .multiplex(yamux_config(settings))
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+    
+    let transport = ws
+        .or_transport(dns::tokio::Transport::system(transport)?)
+        .map(|either, _| either.into_inner())
+        .boxed();
+    
+    Ok(transport)
+}
+
+fn tcp_transport(settings: &TransportSettings) -> TcpPortPolicy<tcp::tokio::Transport> {
+    TcpPortPolicy {
+        inner: tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)),
+        reuse: settings.tcp_port_reuse,
+    }
+}
+
+// The yamux setters are deprecated upstream but remain the only way to pin
+// window sizes; they switch yamux to its fixed-window implementation
+#[allow(deprecated)]
+fn yamux_config(settings: &TransportSettings) -> yamux::Config {
+    let mut config = yamux::Config::default();
+    if let Some(window) = settings.yamux_receive_window {
+        config.set_receive_window_size(window);
+    }
+    if let Some(buffer) = settings.yamux_max_buffer_size {
+        config.set_max_buffer_size(buffer);
+    }
+    if let Some(streams) = settings.yamux_max_num_streams {
+        config.set_max_num_streams(streams);
+    }
+    config
+}
+
+// Port reuse is decided per dial by whoever dials; this makes every outgoing
+// connection take a fresh port when reuse is turned off
+struct TcpPortPolicy<T> {
+    inner: T,
+    reuse: bool,
+}
+
+impl<T: Transport + Unpin> Transport for TcpPortPolicy<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(&mut self, id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr, mut opts: transport::DialOpts) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if !self.reuse {
+            opts.port_use = PortUse::New;
+        }
+        self.inner.dial(addr, opts)
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll(cx)
+    }
+}
+
+// Whether an event can change what `get_node_info` reports
+pub fn changes_node_info(event: &SwarmEvent<ChatBehaviourEvent>) -> bool {
+    matches!(
+        event,
+        SwarmEvent::NewListenAddr { .. }
+            | SwarmEvent::ExpiredListenAddr { .. }
+            | SwarmEvent::ListenerClosed { .. }
+            | SwarmEvent::ExternalAddrConfirmed { .. }
+            | SwarmEvent::ExternalAddrExpired { .. }
+            | SwarmEvent::ConnectionEstablished { .. }
+            | SwarmEvent::ConnectionClosed { .. }
+            | SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Received { .. }))
+    )
+}
+
+// Whether the event adds, removes or re-identifies an entry in
+// `connected_peers`, so the frontend's peer list needs refreshing
+pub fn changes_peer_list(event: &SwarmEvent<ChatBehaviourEvent>) -> bool {
+    matches!(
+        event,
+        SwarmEvent::ConnectionEstablished { .. }
+            | SwarmEvent::ConnectionClosed { .. }
+            | SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Received { .. }))
+    )
+}
+
+// The remote peer an event is about, if any
+fn event_peer(event: &SwarmEvent<ChatBehaviourEvent>) -> Option<PeerId> {
+    match event {
+        SwarmEvent::ConnectionEstablished { peer_id, .. } => Some(*peer_id),
+        SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(e)) => match e {
+            gossipsub::Event::Message { propagation_source, .. } => Some(*propagation_source),
+            gossipsub::Event::Subscribed { peer_id, .. }
+            | gossipsub::Event::Unsubscribed { peer_id, .. }
+            | gossipsub::Event::GossipsubNotSupported { peer_id } => Some(*peer_id),
+        },
+        SwarmEvent::Behaviour(ChatBehaviourEvent::Direct(request_response::Event::Message { peer, .. })) => Some(*peer),
+        SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(
+            identify::Event::Received { peer_id, .. }
+            | identify::Event::Sent { peer_id, .. }
+            | identify::Event::Pushed { peer_id, .. },
+        )) => Some(*peer_id),
+        SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(kad::Event::RoutingUpdated { peer, .. })) => Some(*peer),
+        _ => None,
+    }
+}
+
+fn relay_config(limits: &RelayLimits) -> relay::Config {
+    relay::Config {
+        max_reservations: limits.max_reservations,
+        max_reservations_per_peer: limits.max_reservations_per_peer,
+        reservation_duration: Duration::from_secs(limits.reservation_duration_secs),
+        max_circuits: limits.max_circuits,
+        max_circuits_per_peer: limits.max_circuits_per_peer,
+        max_circuit_duration: Duration::from_secs(limits.max_circuit_duration_secs),
+        max_circuit_bytes: limits.max_circuit_bytes,
+        ..Default::default()
+    }
+}
+
+// What to do with one peer returned by a room provider search
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProviderAction {
+    // Our own provider record
+    Ignore,
+    // Identified as a plain DHT node rather than a chat peer
+    NotChat,
+    // A room peer we're already connected to, dialing or about to dial
+    Known,
+    Dial,
+}
+
+// `queued` is whether a dial of the peer is already queued or running
+fn classify_provider(peer_id: &PeerId, local: &PeerId, connected: Option<&ConnectionInfo>, queued: bool) -> ProviderAction {
+    if peer_id == local {
+        return ProviderAction::Ignore;
+    }
+    match connected {
+        Some(info) if info.identity.is_some() && !info.is_chat_peer() => ProviderAction::NotChat,
+        Some(_) => ProviderAction::Known,
+        None if queued => ProviderAction::Known,
+        None => ProviderAction::Dial,
+    }
+}
+
+// Dial options for a test dial, on a connection of its own even when
+// we're already connected to the peer
+fn test_dial_opts(address: &Multiaddr) -> DialOpts {
+    match address.iter().last() {
+        Some(Protocol::P2p(peer_id)) => DialOpts::peer_id(peer_id)
+            .addresses(vec![address.clone()])
+            .condition(PeerCondition::Always)
+            .build(),
+        _ => DialOpts::unknown_peer_id().address(address.clone()).build(),
+    }
 }
 
 // Helper function to check if an IP is private/local
@@ -60,6 +968,7 @@ fn is_private_ip(ip: &IpAddr) -> bool {
 }
 
 // Filter addresses to only IPv6 public addresses
+// Only the IP component is checked; /ws and /wss suffixes are kept as-is
 fn filter_ipv6_public_addrs(addrs: &[Multiaddr]) -> Vec<Multiaddr> {
     let mut filtered = Vec::new();
 
@@ -95,106 +1004,914 @@ fn filter_ipv6_public_addrs(addrs: &[Multiaddr]) -> Vec<Multiaddr> {
     filtered
 }
 
-pub struct P2PNode {
-    pub peer_id: PeerId,
-    pub connected_peers: HashMap<PeerId, Vec<String>>,
-    pub message_tx: mpsc::UnboundedSender<ChatMessage>,
-    pub discovered_peers: HashSet<PeerId>,
-    pub current_room: Option<gossipsub::IdentTopic>,
-    pub current_room_name: Option<String>,
-    pub bootstrap_peers: HashSet<PeerId>,
-    pub peers_to_dial: Vec<PeerId>,
+// Events for the frontend that aren't chat messages
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    IncompatiblePeer(IncompatiblePeer),
+    MessageDelivered(MessageDelivered),
+    PeerStatus(PeerStatus),
+    RoomSearchFinished(RoomSearchFinished),
+    PeerRenamed(PeerRenamed),
+    PresenceChanged(PresenceChanged),
+    // The full peer list, sent at most once per PEERS_CHANGED_DEBOUNCE
+    PeersChanged(Vec<PeerInfo>),
+    RoomModerated(RoomModerated),
+    ModerationRejected(ModerationRejected),
+    RoomAnnounced(RoomAnnounced),
+    MessageStatus(MessageStatus),
+    // A message went to the outbox; only persisted, the UI gets a MessageStatus
+    MessageQueued(OutboxEntry),
+    System(SystemEvent),
+    ConnectivityProgress(ConnectivityStepResult),
 }
 
-impl P2PNode {
-    pub async fn create(
-        message_tx: mpsc::UnboundedSender<ChatMessage>,
-    ) -> Result<(Self, Swarm<ChatBehaviour>), Box<dyn Error>> {
-        // Create swarm following the tutorial pattern
-        let swarm = SwarmBuilder::with_new_identity()
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default().nodelay(true),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_behaviour(|key| {
-                let local_peer_id = key.public().to_peer_id();
-                
-                // Create Kademlia DHT
-                let store = kad::store::MemoryStore::new(local_peer_id);
-                let mut kad_config = kad::Config::new(CHAT_PROTOCOL.clone());
-                kad_config.set_query_timeout(Duration::from_secs(60));
-                let mut kad = kad::Behaviour::with_config(local_peer_id, store, kad_config);
-                
-                // Add bootstrap peers
-                let bootstrap_peers = vec![
-                    ("QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN", "/dnsaddr/bootstrap.libp2p.io"),
-                    ("QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa", "/dnsaddr/bootstrap.libp2p.io"),
-                    ("QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb", "/dnsaddr/bootstrap.libp2p.io"),
-                    ("QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt", "/dnsaddr/bootstrap.libp2p.io"),
-                ];
-                
-                for (peer_id_str, _) in bootstrap_peers {
-                    if let Ok(peer_id) = peer_id_str.parse::<PeerId>() {
-                        let addr: Multiaddr = format!("/dnsaddr/bootstrap.libp2p.io/p2p/{}", peer_id_str)
-                            .parse()
-                            .unwrap();
-                        kad.add_address(&peer_id, addr);
-                    }
-                }
-                
-                // Enable server mode for DHT
-                kad.set_mode(Some(kad::Mode::Server));
-                
-                // Create mDNS behaviour
-                let mdns = mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    local_peer_id,
-                )?;
-                
-                // Create identify behaviour
-                let identify = identify::Behaviour::new(identify::Config::new(
-                    CHAT_PROTOCOL.to_string(),
-                    key.public(),
-                ));
-                
-                // Create Gossipsub behaviour
-                let gossipsub_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_secs(1))
-                    .validation_mode(gossipsub::ValidationMode::Strict)
-                    .message_id_fn(|message| {
-                        // Use content hash as message ID to deduplicate
-                        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-                        std::hash::Hash::hash(&message.data, &mut hasher);
-                        gossipsub::MessageId::from(std::hash::Hasher::finish(&hasher).to_string())
-                    })
-                    .build()
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                
-                let gossipsub = gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(key.clone()),
-                    gossipsub_config,
-                )
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                
-                Ok(ChatBehaviour { kad, mdns, identify, gossipsub })
-            })?
-            .with_swarm_config(|cfg| {
-                cfg.with_idle_connection_timeout(Duration::from_secs(60))
-            })
-            .build();
-        
-        let peer_id = *swarm.local_peer_id();
-        
-        let node = Self::new(peer_id, message_tx);
-        
-        Ok((node, swarm))
-    }
+// Routine network happenings, sent to the UI as `system-event`. Normal
+// ones are also shown in the chat, merged per kind; low-priority ones are
+// only sent as events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemEvent {
+    BootstrapConnected { peer_id: String },
+    PeerConnected { peer_id: String },
+    PeerDisconnected { peer_id: String },
+    MdnsDiscovered { peer_id: String },
+    MdnsExpired { peer_id: String },
+    RoutingUpdated { peer_id: String },
+    // Connection setup feedback; a dial may not name its peer, and an
+    // incoming connection's peer is only known after the handshake
+    Dialing { peer_id: Option<String> },
+    IncomingConnection { address: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemPriority {
+    Low,
+    Normal,
+}
+
+impl SystemEvent {
+    pub fn priority(&self) -> SystemPriority {
+        match self {
+            SystemEvent::MdnsExpired { .. }
+            | SystemEvent::RoutingUpdated { .. }
+            | SystemEvent::Dialing { .. }
+            | SystemEvent::IncomingConnection { .. } => SystemPriority::Low,
+            _ => SystemPriority::Normal,
+        }
+    }
+
+    // The peer the event is about, or the remote address when that's all we know
+    fn subject(&self) -> &str {
+        match self {
+            SystemEvent::BootstrapConnected { peer_id }
+            | SystemEvent::PeerConnected { peer_id }
+            | SystemEvent::PeerDisconnected { peer_id }
+            | SystemEvent::MdnsDiscovered { peer_id }
+            | SystemEvent::MdnsExpired { peer_id }
+            | SystemEvent::RoutingUpdated { peer_id } => peer_id,
+            SystemEvent::Dialing { peer_id } => peer_id.as_deref().unwrap_or("unknown peer"),
+            SystemEvent::IncomingConnection { address } => address,
+        }
+    }
+
+    // Chat line for `count` events of this kind, naming the peer when there's one
+    fn summary(&self, count: usize, peer: &str) -> String {
+        match (self, count) {
+            (SystemEvent::BootstrapConnected { .. }, 1) => format!("✓ Connected to bootstrap node {}", peer),
+            (SystemEvent::BootstrapConnected { .. }, n) => format!("✓ Connected to {} bootstrap nodes", n),
+            (SystemEvent::PeerConnected { .. }, 1) => format!("✓ Connected to {}", peer),
+            (SystemEvent::PeerConnected { .. }, n) => format!("✓ Connected to {} peers", n),
+            (SystemEvent::PeerDisconnected { .. }, 1) => format!("✗ Disconnected from {}", peer),
+            (SystemEvent::PeerDisconnected { .. }, n) => format!("✗ Disconnected from {} peers", n),
+            (SystemEvent::MdnsDiscovered { .. }, 1) => format!("🔍 mDNS discovered peer: {}", peer),
+            (SystemEvent::MdnsDiscovered { .. }, n) => format!("🔍 mDNS discovered {} peers", n),
+            (SystemEvent::MdnsExpired { .. }, 1) => format!("mDNS peer expired: {}", peer),
+            (SystemEvent::MdnsExpired { .. }, n) => format!("{} mDNS peers expired", n),
+            (SystemEvent::RoutingUpdated { .. }, 1) => format!("Routing updated for {}", peer),
+            (SystemEvent::RoutingUpdated { .. }, n) => format!("Routing updated for {} peers", n),
+            (SystemEvent::Dialing { .. }, 1) => format!("Dialing {}...", peer),
+            (SystemEvent::Dialing { .. }, n) => format!("Dialing {} peers...", n),
+            (SystemEvent::IncomingConnection { .. }, 1) => format!("Incoming connection from {}", peer),
+            (SystemEvent::IncomingConnection { .. }, n) => format!("{} incoming connections", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageState {
+    // Waiting in the outbox for room peers
+    Queued,
+    Sent,
+    // Still queued after `outbox_max_age`
+    Failed,
+}
+
+// Payload of the `message-status` event, sent as a message moves through the
+// outbox or goes out again after being published with nobody in the mesh
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageStatus {
+    pub message_id: String,
+    pub room: Option<String>,
+    pub status: MessageState,
+    pub recipients_estimate: u32,
+}
+
+// A room message that couldn't be published for lack of peers, kept in the
+// message store so it survives restarts
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub room: String,
+    pub content: String,
+    pub content_type: ContentType,
+    // RFC 3339
+    pub created_at: String,
+    // Kept so the message is ordered where it was written, not where it was sent
+    pub lamport: u64,
+}
+
+impl OutboxEntry {
+    fn is_expired(&self, max_age: Duration) -> bool {
+        let Ok(created_at) = chrono::DateTime::parse_from_rfc3339(&self.created_at) else {
+            return true;
+        };
+        chrono::Utc::now()
+            .signed_duration_since(created_at)
+            .to_std()
+            .is_ok_and(|age| age >= max_age)
+    }
+}
+
+// Payload of the `room-announced` event, sent when a room joined before the
+// DHT was reachable is announced to the network after all
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomAnnounced {
+    pub room: String,
+}
+
+// Payload of the `room-moderated` event, sent for each owner order applied
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomModerated {
+    pub room: String,
+    pub action: ModerationAction,
+}
+
+// Payload of the `moderation-rejected` event: a moderation order that wasn't
+// signed by the room owner, or was too old to trust
+#[derive(Debug, Clone, Serialize)]
+pub struct ModerationRejected {
+    pub room: String,
+    pub peer_id: Option<String>,
+    pub reason: String,
+}
+
+// Payload of the `peer-renamed` event; `nickname` is None once cleared
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerRenamed {
+    pub peer_id: String,
+    pub nickname: Option<String>,
+}
+
+// Payload of the `room-search-finished` event, sent when a DHT provider
+// search ends; `error` is set when the query failed or timed out
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomSearchFinished {
+    pub room: String,
+    pub providers: usize,
+    pub interactive: bool,
+    pub error: Option<String>,
+}
+
+// How recently a room member was heard from, judged by missed heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    Online,
+    Away,
+    Offline,
+}
+
+impl PresenceState {
+    fn after_silence(silence: Duration) -> Self {
+        if silence >= PRESENCE_INTERVAL * MISSED_BEATS_OFFLINE {
+            PresenceState::Offline
+        } else if silence >= PRESENCE_INTERVAL * MISSED_BEATS_AWAY {
+            PresenceState::Away
+        } else {
+            PresenceState::Online
+        }
+    }
+}
+
+// A peer seen in the current room, as returned by `get_room_members`
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomMember {
+    pub peer_id: String,
+    pub nickname: Option<String>,
+    pub presence: PresenceState,
+    pub last_seen: String,
+}
+
+// Payload of the `presence-changed` event
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceChanged {
+    pub peer_id: String,
+    pub room: String,
+    pub presence: PresenceState,
+    pub last_seen: String,
+}
+
+// When a room member was last heard from, on any of the room's topics
+struct MemberPresence {
+    heard_at: Instant,
+    last_seen: String,
+    state: PresenceState,
+}
+
+// Payload of the `peer-status` event; `status` is None once cleared
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStatus {
+    pub peer_id: String,
+    pub status: Option<String>,
+}
+
+// Payload of the `message-delivered` event; `delivered_to` is the new total
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDelivered {
+    pub message_id: String,
+    pub room: Option<String>,
+    pub delivered_to: u32,
+}
+
+// The last system message shown, and how many copies were held back since
+struct LastSystemMessage {
+    content: String,
+    shown_at: Instant,
+    suppressed: u32,
+}
+
+// System chat lines shown in the current one-second window
+struct SystemMessageRate {
+    window_start: Instant,
+    shown: u32,
+    dropped: u32,
+}
+
+// One of our room messages waiting for delivery receipts
+pub struct PendingDelivery {
+    pub room: Option<String>,
+    pub topic: gossipsub::TopicHash,
+    // Room members when it was sent; later joiners may still get it via gossip
+    pub recipients: HashSet<PeerId>,
+    pub acked: HashSet<PeerId>,
+    pub sent_at: Instant,
+    // Unencrypted payloads of a message published with no mesh peers, kept
+    // to publish again once the mesh forms
+    pub awaiting_mesh: Vec<Vec<u8>>,
+}
+
+impl PendingDelivery {
+    // Payloads to publish again now that the room's mesh has formed. They're
+    // given up either way, so an acknowledged message isn't sent twice.
+    fn take_resend(&mut self, topic: &gossipsub::TopicHash) -> Option<Vec<Vec<u8>>> {
+        if self.awaiting_mesh.is_empty() || self.topic != *topic {
+            return None;
+        }
+        let encoded = std::mem::take(&mut self.awaiting_mesh);
+        self.acked.is_empty().then_some(encoded)
+    }
+}
+
+// Registrations are renewed halfway through the TTL the server granted. The
+// server's value is kept to the range servers accept, so a huge one can't
+// overflow the deadline and a tiny one can't have us re-register in a loop.
+fn rendezvous_refresh_delay(granted_ttl_secs: u64) -> Duration {
+    Duration::from_secs(granted_ttl_secs.clamp(RENDEZVOUS_MIN_TTL_SECS, RENDEZVOUS_MAX_TTL_SECS) / 2)
+}
+
+// Our own messages can come back through the mesh in some topologies.
+// Unsigned messages carry no source, so they are never taken for ours.
+fn is_own_message(source: Option<PeerId>, local: &PeerId) -> bool {
+    source.as_ref() == Some(local)
+}
+
+// Payloads worth keeping for `resend_awaiting_mesh`: only those of a message
+// published while the mesh had nobody in it
+fn awaiting_mesh(recipients_estimate: u32, encoded: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    match recipients_estimate {
+        0 => encoded,
+        _ => Vec::new(),
+    }
+}
+
+// A chat peer announcing a protocol major version other than ours
+#[derive(Debug, Clone, Serialize)]
+pub struct IncompatiblePeer {
+    pub peer_id: String,
+    pub protocol_version: String,
+    pub agent_version: String,
+}
+
+// Local identity details for diagnostics
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeIdentity {
+    pub peer_id: String,
+    // Protobuf-encoded public key in base58
+    pub public_key: String,
+    pub protocol_version: String,
+    pub agent_version: String,
+    pub behaviours: Vec<String>,
+}
+
+// Outcome of a timed `get_closest_peers` query
+#[derive(Debug, Clone, Serialize)]
+pub struct DhtLookupResult {
+    pub key: String,
+    pub elapsed_ms: u64,
+    pub peers_found: usize,
+    // Requests sent to other peers while the query ran
+    pub requests: u32,
+    // The query hit the Kademlia timeout; the other fields cover what it got so far
+    pub timed_out: bool,
+}
+
+// A benchmark lookup in flight, answered when its query finishes
+pub struct DhtBenchmark {
+    pub key: String,
+    pub started: Instant,
+    pub reply: oneshot::Sender<DhtLookupResult>,
+}
+
+// Outcome of `broadcast_to_all_rooms` for one room
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomBroadcast {
+    pub room: String,
+    // Published to room peers; false if it failed or went to the outbox
+    pub delivered: bool,
+    pub error: Option<String>,
+}
+
+// Result of `estimate_network_size`; see `netsize` for the method
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkSizeEstimate {
+    // None when no lookup found any peers
+    pub size: Option<SizeEstimate>,
+    pub lookups: usize,
+    pub peers_sampled: usize,
+    pub routing_table_peers: usize,
+    pub elapsed_ms: u64,
+}
+
+// The lookups of a network size estimate in flight. Callers that ask while
+// it runs wait for the same answer.
+pub struct NetworkSizeProbe {
+    started: Instant,
+    // Cleared once the lookups have been told to finish
+    deadline: Option<Instant>,
+    pending: HashSet<kad::QueryId>,
+    samples: Vec<Vec<f64>>,
+    replies: Vec<oneshot::Sender<NetworkSizeEstimate>>,
+}
+
+// Outcome of a `test_reachability` dial; `error` is set when it failed
+#[derive(Debug, Clone, Serialize)]
+pub struct ReachabilityResult {
+    pub address: String,
+    pub reachable: bool,
+    // The peer that answered, which may differ from the one in the address
+    pub peer_id: Option<String>,
+    pub dial_ms: u64,
+    pub error: Option<String>,
+}
+
+// A test dial in flight, answered when its connection is established or fails
+pub struct ReachabilityTest {
+    pub address: Multiaddr,
+    pub started: Instant,
+    // Close the connection again once it's up
+    pub close: bool,
+    pub reply: oneshot::Sender<ReachabilityResult>,
+}
+
+// Steps of `run_connectivity_test`, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectivityStep {
+    // Resolve and dial a public bootstrap node
+    BootstrapDial,
+    // At least one listener is bound
+    Listeners,
+    // A confirmed external address or a public IPv6 one
+    PublicAddress,
+    // A closest-peers lookup finds someone
+    DhtQuery,
+}
+
+// Sent as `connectivity-test-progress` when a step finishes
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectivityStepResult {
+    pub step: ConnectivityStep,
+    pub passed: bool,
+    pub duration_ms: u64,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectivityReport {
+    // Every step passed
+    pub passed: bool,
+    pub total_ms: u64,
+    pub steps: Vec<ConnectivityStepResult>,
+}
+
+// A connectivity test in progress. Steps that need the network wait for
+// their swarm event or deadline, so the node keeps running meanwhile.
+pub struct ConnectivityTest {
+    started: Instant,
+    step_started: Instant,
+    deadline: Option<Instant>,
+    // What the current step is waiting for, if anything
+    dial: Option<(ConnectionId, Multiaddr)>,
+    query: Option<kad::QueryId>,
+    steps: Vec<ConnectivityStepResult>,
+    reply: oneshot::Sender<Result<ConnectivityReport, CommandError>>,
+}
+
+// A room-peer lookup in flight, tracked so its outcome can be reported
+pub struct ProviderQuery {
+    pub room: String,
+    pub found: usize,
+    // Queries started by the user report empty results; periodic ones stay quiet
+    pub interactive: bool,
+    // Interactive searches are cut short here instead of running the full
+    // query timeout
+    pub deadline: Option<Instant>,
+}
+
+// An attachment being downloaded from the peer that sent it
+pub struct AttachmentFetch {
+    pub sha256: String,
+    pub reply: oneshot::Sender<Result<String, CommandError>>,
+}
+
+// A public room lookup: a search for directory providers, then a request
+// to each provider found
+pub struct DirectoryLookup {
+    pub listings: Vec<RoomListing>,
+    pub requests: HashSet<request_response::OutboundRequestId>,
+    // Rooms the user chose not to see
+    pub hidden: HashSet<String>,
+    // Cleared once the provider search is done
+    pub searching: bool,
+    pub deadline: Instant,
+    pub reply: oneshot::Sender<Vec<PublicRoom>>,
+}
+
+// A room join whose gossipsub subscription failed and is waiting to retry.
+// Nothing about the room is applied to the node until the subscription works.
+pub struct PendingJoin {
+    pub room_name: String,
+    pub room_key: Option<RoomKey>,
+    pub topic: gossipsub::IdentTopic,
+    pub passive: bool,
+    pub public: bool,
+    pub attempts: u32,
+    pub retry_at: Instant,
+}
+
+fn is_relay_addr(addr: &Multiaddr) -> bool {
+    addr.iter()
+        .any(|p| matches!(p, libp2p::multiaddr::Protocol::P2pCircuit))
+}
+
+// Whether dialing `addr` would reach `peer_id`, judged by the address's last
+// /p2p/ component; relayed addresses name the relay first. Addresses without
+// one can't be told apart from anyone else's.
+pub fn is_own_address(addr: &Multiaddr, peer_id: &PeerId) -> bool {
+    addr.iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(target) => Some(target),
+            _ => None,
+        })
+        .last()
+        .is_some_and(|target| target == *peer_id)
+}
+
+pub struct P2PNode {
+    pub peer_id: PeerId,
+    pub connected_peers: HashMap<PeerId, ConnectionInfo>,
+    pub message_tx: mpsc::UnboundedSender<ChatMessage>,
+    pub discovered_peers: HashSet<PeerId>,
+    pub current_room: Option<gossipsub::IdentTopic>,
+    pub current_room_name: Option<String>,
+    // Names of the rooms joined this session by topic, kept after leaving so
+    // messages still arriving for a room are tagged with its name
+    pub room_topics: HashMap<gossipsub::TopicHash, String>,
+    // Listen-only: subscribed to the topic but not advertised as a provider
+    pub room_passive: bool,
+    // Listed in the public room directory
+    pub room_public: bool,
+    // Set when the current room is protected by a passphrase
+    pub room_key: Option<RoomKey>,
+    pub pending_join: Option<PendingJoin>,
+    // Side topic carrying status updates for the current room; kept apart
+    // so older clients don't show them as chat
+    pub presence_topic: Option<gossipsub::IdentTopic>,
+    pub status: Option<String>,
+    pub nickname: Option<String>,
+    pub nickname_changed_at: Option<Instant>,
+    // Nicknames announced by other peers, and when each last changed
+    pub peer_nicknames: HashMap<PeerId, String>,
+    pub peer_renamed_at: HashMap<PeerId, Instant>,
+    // Members of the current room and when each was last heard from
+    room_members: HashMap<PeerId, MemberPresence>,
+    // Owner keys by room, from invites and rooms we created. Rooms with an
+    // owner get a moderation topic; only orders signed by the owner count.
+    pub room_owners: HashMap<String, identity::PublicKey>,
+    // Signing keys of the rooms we own
+    pub owned_rooms: HashMap<String, identity::Keypair>,
+    pub moderation_topic: Option<gossipsub::IdentTopic>,
+    // Peers the room owner muted in the current room, and until when
+    pub moderated_mutes: HashMap<PeerId, Instant>,
+    // Set once a DHT bootstrap has finished all its queries
+    pub dht_bootstrapped: bool,
+    // The current room was announced while the routing table was empty, so
+    // the provider record only reached our local store
+    pub announce_pending: bool,
+    // Parts of long room messages still waiting for the rest
+    reassembler: Reassembler,
+    // Gossipsub's limit on one message; longer messages are sent in parts
+    pub max_transmit_size: usize,
+    // Sent to peers over identify
+    pub agent_version: String,
+    // Room messages waiting for peers, oldest first
+    pub outbox: Vec<OutboxEntry>,
+    // Do-not-disturb: room messages are held back until resumed. We stay in
+    // the mesh and keep relaying meanwhile.
+    pub messages_paused: bool,
+    pub held_messages: VecDeque<ChatMessage>,
+    pub held_messages_dropped: usize,
+    pub outbox_max_age: Duration,
+    // Chat peers from earlier runs, saved on the stale peer sweep
+    pub address_book: AddressBook,
+    pub address_book_ttl: Duration,
+    pub auto_dial_known_peers: bool,
+    pub bootstrap_peers: HashSet<PeerId>,
+    // Peers to dial and dials in flight, so repeated discoveries don't dial
+    // the same peer again
+    pub dials: DialQueue,
+    // Addresses learned from mDNS and identify, used when dialing by peer ID
+    pub known_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    // DHT lookups for peers we wanted to dial but had no address for
+    pub address_lookups: HashMap<kad::QueryId, PeerId>,
+    pub looked_up_peers: HashSet<PeerId>,
+    pub provider_queries: HashMap<kad::QueryId, ProviderQuery>,
+    // Provider searches in the current room that found nobody, in a row
+    pub empty_provider_searches: u32,
+    // When to search an empty room again, ahead of the periodic search
+    pub search_retry_at: Option<Instant>,
+    pub interactive_search_timeout: Duration,
+    pub dht_benchmarks: HashMap<kad::QueryId, DhtBenchmark>,
+    network_size_probe: Option<NetworkSizeProbe>,
+    pub directory_lookups: HashMap<kad::QueryId, DirectoryLookup>,
+    // Where attachment bytes are kept; set by the app once the node is built
+    pub attachments: Option<AttachmentStore>,
+    pub attachment_fetches: HashMap<request_response::OutboundRequestId, AttachmentFetch>,
+    // Numbers our room messages and keeps the latest to send again; the
+    // epoch tells this run's numbering from earlier ones
+    sequence_epoch: u64,
+    sent_log: SentLog,
+    // Gaps in other members' numbering, and the topic each request for the
+    // missing messages was about
+    gap_tracker: GapTracker,
+    retransmit_requests: HashMap<request_response::OutboundRequestId, String>,
+    // Our recent room messages by ID
+    pub pending_deliveries: HashMap<String, PendingDelivery>,
+    pub power_mode: PowerMode,
+    pub discovery_target_peers: usize,
+    pub pause_mdns_in_low_power: bool,
+    pub mdns_enabled: bool,
+    // Running with a pre-shared swarm key
+    pub private_network: bool,
+    // Peers whose messages are still relayed but not shown
+    pub muted_peers: HashSet<PeerId>,
+    // Friends gossipsub always forwards to, whatever their score; persisted
+    // in the address book
+    pub explicit_peers: HashSet<PeerId>,
+    // Set when relay server mode was requested at init
+    pub relay_limits: Option<RelayLimits>,
+    pub relay_reservations: HashSet<PeerId>,
+    pub relay_circuits: usize,
+    pub relay_circuits_total: u64,
+    // Circuit listeners on relays picked with `reserve_relay`, by relay address
+    pub relay_listeners: HashMap<ListenerId, Multiaddr>,
+    pub pending_reservations: HashMap<ListenerId, oneshot::Sender<Result<String, CommandError>>>,
+    pub reachability_tests: HashMap<ConnectionId, ReachabilityTest>,
+    connectivity_test: Option<ConnectivityTest>,
+    // Relays whose reservation was lost; retried on the stale peer sweep
+    pub lost_relays: Vec<Multiaddr>,
+    pub rendezvous_server: Option<(PeerId, Multiaddr)>,
+    pub rendezvous_ttl: u64,
+    // Namespace of the current room; None when not using rendezvous for it
+    pub rendezvous_namespace: Option<rendezvous::Namespace>,
+    pub rendezvous_cookie: Option<rendezvous::Cookie>,
+    pub rendezvous_refresh_at: Option<Instant>,
+    pub public_key: Option<identity::PublicKey>,
+    // Signs our public room listings
+    pub keypair: Option<identity::Keypair>,
+    pub connection_limits: ConnectionLimitSettings,
+    pub connections_denied: u64,
+    // "<sender>/<message id>" of recent room messages, oldest first in the queue
+    seen_messages: HashSet<String>,
+    seen_message_order: VecDeque<String>,
+    // Resent copies of room messages dropped since startup
+    pub duplicate_messages: u64,
+    pub event_log: EventLog,
+    pub connection_limit_noticed_at: Option<Instant>,
+    // Peers that fully disconnected within the last NETWORK_LOSS_WINDOW
+    pub recent_disconnects: VecDeque<(PeerId, Instant)>,
+    pub reconnected_at: Option<Instant>,
+    // Disconnects not yet shown, so a peer that's back within
+    // `reconnect_grace` doesn't flood the feed
+    pub unreported_disconnects: HashMap<PeerId, Instant>,
+    pub reconnect_grace: Duration,
+    // Identical system messages within this window are collapsed
+    pub system_message_window: Duration,
+    // Behind a lock because system messages are sent from `&self` methods
+    last_system_message: Mutex<Option<LastSystemMessage>>,
+    system_message_rate: Mutex<SystemMessageRate>,
+    // Lamport clock over the messages we show, locked for the same reason
+    lamport_clock: Mutex<u64>,
+    // Normal-priority system events waiting to be merged into chat lines,
+    // each with how many of its kind arrived, in order of arrival
+    pending_system_events: Vec<(SystemEvent, usize)>,
+    system_events_flush_at: Option<Instant>,
+    // Newest last, capped by MAX_RECENT_SYSTEM_EVENTS
+    recent_system_events: VecDeque<RecordedSystemEvent>,
+    // Prometheus counters for the swarm and its protocols; None for nodes
+    // built without `create`
+    metrics: Option<NodeMetrics>,
+    pub event_tx: mpsc::UnboundedSender<NodeEvent>,
+}
+
+struct NodeMetrics {
+    registry: Registry,
+    recorder: Metrics,
+}
+
+impl P2PNode {
+    pub async fn create_with_keypair(
+        message_tx: mpsc::UnboundedSender<ChatMessage>,
+        event_tx: mpsc::UnboundedSender<NodeEvent>,
+        config: &P2PConfig,
+        keypair: identity::Keypair,
+    ) -> Result<(Self, Swarm<ChatBehaviour>), Box<dyn Error>> {
+        // A swarm key puts us on a private network, cut off from public peers
+        let psk = match &config.swarm_key_path {
+            Some(path) => Some(load_swarm_key(Path::new(path))?),
+            None => None,
+        };
+        let private_network = psk.is_some();
+        let public_key = keypair.public();
+        let signing_key = keypair.clone();
+        
+        // Set when mDNS can't start (e.g. no usable interface); the node then
+        // runs without local discovery instead of failing
+        let mut mdns_error = None;
+        // Set when the saved DHT store can't be read; it then starts empty
+        let mut kad_store_error = None;
+        
+        // Bandwidth counters are registered by the transport, the rest by `Metrics`
+        let mut registry = Registry::default();
+        
+        // Create swarm following the tutorial pattern
+        let swarm = SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_other_transport(|key| build_transport(key, psk, &config.transport))?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_bandwidth_metrics(&mut registry)
+            .with_behaviour(|key, relay_client| {
+                let local_peer_id = key.public().to_peer_id();
+                
+                // Create Kademlia DHT
+                let (store, store_error) = PersistentStore::load(
+                    local_peer_id,
+                    config.kad_store_path.clone(),
+                    config.kad.max_records,
+                    config.kad.replication_factor,
+                );
+                kad_store_error = store_error;
+                let mut kad_config = kad::Config::new(CHAT_PROTOCOL.clone());
+                let replication_factor = std::num::NonZeroUsize::new(config.kad.replication_factor).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "kad replication_factor must be at least 1")
+                })?;
+                let parallelism = std::num::NonZeroUsize::new(config.kad.parallelism).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "kad parallelism must be at least 1")
+                })?;
+                kad_config.set_query_timeout(config.kad.query_timeout());
+                kad_config.set_replication_factor(replication_factor);
+                kad_config.set_parallelism(parallelism);
+                // Republished at the same fraction of their TTL as the libp2p
+                // defaults (22 of 48 hours for records, 12 of 48 for providers)
+                kad_config.set_record_ttl(Some(config.kad.record_ttl()));
+                kad_config.set_publication_interval(Some(config.kad.record_ttl() * 11 / 24));
+                kad_config.set_provider_record_ttl(Some(config.kad.provider_record_ttl()));
+                kad_config.set_provider_publication_interval(Some(config.kad.provider_record_ttl() / 4));
+                let mut kad = kad::Behaviour::with_config(local_peer_id, store, kad_config);
+                
+                // Add bootstrap peers
+                let bootstrap_peers = vec![
+                    ("QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN", "/dnsaddr/bootstrap.libp2p.io"),
+                    ("QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa", "/dnsaddr/bootstrap.libp2p.io"),
+                    ("QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb", "/dnsaddr/bootstrap.libp2p.io"),
+                    ("QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt", "/dnsaddr/bootstrap.libp2p.io"),
+                ];
+                
+                // Public bootstrap nodes can't speak to a private network
+                let bootstrap_peers = if private_network { Vec::new() } else { bootstrap_peers };
+                
+                for (peer_id_str, _) in bootstrap_peers {
+                    if let Ok(peer_id) = peer_id_str.parse::<PeerId>() {
+                        let addr: Multiaddr = format!("/dnsaddr/bootstrap.libp2p.io/p2p/{}", peer_id_str)
+                            .parse()
+                            .unwrap();
+                        kad.add_address(&peer_id, addr);
+                    }
+                }
+                
+                // Enable server mode for DHT
+                kad.set_mode(Some(kad::Mode::Server));
+                
+                // Create mDNS behaviour
+                let mdns = if config.mdns_enabled {
+                    match mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id) {
+                        Ok(behaviour) => Some(behaviour),
+                        Err(e) => {
+                            warn!("Failed to start mDNS, continuing without local discovery: {}", e);
+                            mdns_error = Some(e.to_string());
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let mdns = Toggle::from(mdns);
+                
+                // Create identify behaviour
+                let identify_config = identify::Config::new(
+                    PROTOCOL_VERSION.to_string(),
+                    key.public(),
+                )
+                .with_agent_version(config.agent_version.clone());
+                let identify_config = match config.power.mode {
+                    PowerMode::LowPower => identify_config.with_interval(LOW_POWER_IDENTIFY_INTERVAL),
+                    PowerMode::Normal => identify_config,
+                };
+                let identify = identify::Behaviour::new(identify_config);
+                let ping_interval = match config.power.mode {
+                    PowerMode::LowPower => LOW_POWER_PING_INTERVAL,
+                    PowerMode::Normal => PING_INTERVAL,
+                };
+                
+                // Create Gossipsub behaviour
+                let settings = &config.gossipsub;
+                let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(settings.heartbeat_interval(config.power.mode))
+                    .history_length(settings.history_length)
+                    .history_gossip(settings.history_gossip)
+                    .mesh_n(settings.mesh_n)
+                    .mesh_n_low(settings.mesh_n_low)
+                    .mesh_n_high(settings.mesh_n_high)
+                    // Keep the outbound quota valid for small meshes
+                    .mesh_outbound_min(2.min(settings.mesh_n_low).min(settings.mesh_n / 2))
+                    .flood_publish(settings.flood_publish)
+                    .max_transmit_size(settings.max_transmit_size)
+                    .validation_mode(gossipsub::ValidationMode::Strict)
+                    .message_id_fn(gossip_message_id)
+                    .build()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                
+                let gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                
+                // Create request-response behaviour for direct messages
+                let direct = request_response::json::Behaviour::new(
+                    [(DIRECT_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let receipts = request_response::json::Behaviour::new(
+                    [(RECEIPT_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let files = request_response::json::Behaviour::new(
+                    [(attachments::FILE_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let directory = request_response::json::Behaviour::new(
+                    [(directory::DIRECTORY_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let retransmit = request_response::json::Behaviour::new(
+                    [(retransmit::RETRANSMIT_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                
+                // Learn whether other peers can reach us, which decides if we may relay
+                let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
+                
+                let relay = config
+                    .relay_server
+                    .then(|| relay::Behaviour::new(local_peer_id, relay_config(&config.relay_limits)));
+                let relay = Toggle::from(relay);
+                
+                // Does nothing unless a rendezvous server is configured
+                let rendezvous = rendezvous::client::Behaviour::new(key.clone());
+                
+                let limits = &config.connection_limits;
+                let limits = connection_limits::Behaviour::new(
+                    connection_limits::ConnectionLimits::default()
+                        .with_max_established(Some(limits.max_established))
+                        .with_max_established_per_peer(Some(limits.max_established_per_peer))
+                        .with_max_pending_incoming(Some(limits.max_pending))
+                        .with_max_pending_outgoing(Some(limits.max_pending)),
+                );
+                
+                Ok(ChatBehaviour {
+                    limits,
+                    kad,
+                    mdns,
+                    identify,
+                    gossipsub,
+                    direct,
+                    receipts,
+                    files,
+                    directory,
+                    retransmit,
+                    autonat,
+                    relay,
+                    relay_client,
+                    rendezvous,
+                    ping: ping::Behaviour::new(ping::Config::new().with_interval(ping_interval)),
+                    keep_alive: keep_alive::Behaviour::default(),
+                })
+            })?
+            .with_swarm_config(|cfg| {
+                cfg.with_idle_connection_timeout(Duration::from_secs(config.idle_connection_timeout_secs))
+            })
+            .build();
+        
+        let peer_id = *swarm.local_peer_id();
+        
+        let recorder = Metrics::new(&mut registry);
+        
+        let mut node = Self::new(peer_id, message_tx, event_tx);
+        node.metrics = Some(NodeMetrics { registry, recorder });
+        node.power_mode = config.power.mode;
+        node.discovery_target_peers = config.discovery_target_peers;
+        node.interactive_search_timeout = config.kad.interactive_search_timeout();
+        node.pause_mdns_in_low_power = config.power.pause_mdns;
+        node.mdns_enabled = config.mdns_enabled && mdns_error.is_none();
+        if let Some(e) = mdns_error {
+            node.event_log.push(LogLevel::Error, "mdns", format!("mDNS unavailable: {}", e));
+            node.send_system_message(format!("⚠ Local network discovery (mDNS) unavailable: {} - using DHT only", e));
+        }
+        if let Some(e) = kad_store_error {
+            node.event_log.push(LogLevel::Warning, "kad_store", format!("Saved DHT records unreadable: {}", e));
+            node.send_system_message(format!("⚠ Saved DHT records couldn't be read ({}) - starting with an empty store", e));
+        }
+        node.private_network = private_network;
+        node.public_key = Some(public_key);
+        node.keypair = Some(signing_key);
+        node.connection_limits = config.connection_limits.clone();
+        node.system_message_window = Duration::from_secs(config.system_message_dedup_secs);
+        node.reconnect_grace = Duration::from_secs(config.reconnect_grace_secs);
+        node.address_book_ttl = config.address_book_ttl();
+        node.auto_dial_known_peers = config.auto_dial_known_peers;
+        node.max_transmit_size = config.gossipsub.max_transmit_size;
+        node.agent_version = config.agent_version.clone();
+        node.outbox_max_age = config.outbox_max_age();
+        node.relay_limits = config.relay_server.then(|| config.relay_limits.clone());
+        node.rendezvous_ttl = config.rendezvous_ttl_secs;
+        
+        // Validated by P2PConfig, so the address always ends in /p2p/<peer id>
+        if let Some(addr) = &config.rendezvous_server {
+            let addr: Multiaddr = addr.parse()?;
+            if let Some(Protocol::P2p(server)) = addr.iter().last() {
+                node.rendezvous_server = Some((server, addr));
+            }
+        }
+        
+        Ok((node, swarm))
+    }
 
     fn new(
         peer_id: PeerId,
         message_tx: mpsc::UnboundedSender<ChatMessage>,
+        event_tx: mpsc::UnboundedSender<NodeEvent>,
     ) -> Self {
         // Parse bootstrap peer IDs
         let bootstrap_peer_ids = vec![
@@ -219,8 +1936,101 @@ impl P2PNode {
             discovered_peers: HashSet::new(),
             current_room: None,
             current_room_name: None,
+            room_topics: HashMap::new(),
+            room_passive: false,
+            room_public: false,
+            room_key: None,
+            pending_join: None,
+            presence_topic: None,
+            status: None,
+            nickname: None,
+            nickname_changed_at: None,
+            peer_nicknames: HashMap::new(),
+            room_members: HashMap::new(),
+            room_owners: HashMap::new(),
+            owned_rooms: HashMap::new(),
+            moderation_topic: None,
+            moderated_mutes: HashMap::new(),
+            dht_bootstrapped: false,
+            announce_pending: false,
+            reassembler: Reassembler::default(),
+            max_transmit_size: GossipsubSettings::default().max_transmit_size,
+            agent_version: AGENT_VERSION.to_string(),
+            outbox: Vec::new(),
+            messages_paused: false,
+            held_messages: VecDeque::new(),
+            held_messages_dropped: 0,
+            outbox_max_age: Duration::MAX,
+            peer_renamed_at: HashMap::new(),
+            address_book: AddressBook::default(),
+            address_book_ttl: Duration::MAX,
+            auto_dial_known_peers: false,
             bootstrap_peers,
-            peers_to_dial: Vec::new(),
+            dials: DialQueue::default(),
+            known_addresses: HashMap::new(),
+            address_lookups: HashMap::new(),
+            looked_up_peers: HashSet::new(),
+            provider_queries: HashMap::new(),
+            empty_provider_searches: 0,
+            search_retry_at: None,
+            interactive_search_timeout: Duration::from_secs(15),
+            dht_benchmarks: HashMap::new(),
+            network_size_probe: None,
+            directory_lookups: HashMap::new(),
+            attachments: None,
+            attachment_fetches: HashMap::new(),
+            sequence_epoch: rand::random(),
+            sent_log: SentLog::default(),
+            gap_tracker: GapTracker::default(),
+            retransmit_requests: HashMap::new(),
+            pending_deliveries: HashMap::new(),
+            power_mode: PowerMode::Normal,
+            discovery_target_peers: usize::MAX,
+            pause_mdns_in_low_power: false,
+            mdns_enabled: true,
+            private_network: false,
+            muted_peers: HashSet::new(),
+            explicit_peers: HashSet::new(),
+            relay_limits: None,
+            relay_reservations: HashSet::new(),
+            relay_circuits: 0,
+            relay_circuits_total: 0,
+            relay_listeners: HashMap::new(),
+            pending_reservations: HashMap::new(),
+            reachability_tests: HashMap::new(),
+            connectivity_test: None,
+            lost_relays: Vec::new(),
+            rendezvous_server: None,
+            rendezvous_ttl: 0,
+            rendezvous_namespace: None,
+            rendezvous_cookie: None,
+            rendezvous_refresh_at: None,
+            public_key: None,
+            keypair: None,
+            connection_limits: ConnectionLimitSettings::default(),
+            connections_denied: 0,
+            seen_messages: HashSet::new(),
+            seen_message_order: VecDeque::new(),
+            duplicate_messages: 0,
+            event_log: EventLog::default(),
+            connection_limit_noticed_at: None,
+            recent_disconnects: VecDeque::new(),
+            reconnected_at: None,
+            unreported_disconnects: HashMap::new(),
+            reconnect_grace: Duration::ZERO,
+            system_message_window: Duration::ZERO,
+            last_system_message: Mutex::new(None),
+            lamport_clock: Mutex::new(0),
+            system_message_rate: Mutex::new(SystemMessageRate {
+                window_start: Instant::now(),
+                shown: 0,
+                dropped: 0,
+            }),
+            pending_system_events: Vec::new(),
+            system_events_flush_at: None,
+            recent_system_events: VecDeque::new(),
+            metrics: None,
+            event_tx,
         }
     }
 
@@ -231,23 +2041,136 @@ impl P2PNode {
     pub fn get_connected_peers(&self) -> Vec<PeerInfo> {
         self.connected_peers
             .iter()
-            .map(|(peer_id, addrs)| PeerInfo {
-                peer_id: peer_id.to_string(),
-                addresses: addrs.clone(),
+            .map(|(peer_id, info)| {
+                let now = chrono::Utc::now();
+                let oldest = info.oldest();
+                let mut connections: Vec<PeerConnection> = info
+                    .connections
+                    .values()
+                    .map(|c| PeerConnection {
+                        remote_addr: c.remote_addr.to_string(),
+                        direction: c.direction,
+                        connected_since: c.connected_since.to_rfc3339(),
+                        connected_secs: (now - c.connected_since).num_seconds(),
+                    })
+                    .collect();
+                connections.sort_by(|a, b| a.connected_since.cmp(&b.connected_since));
+                
+                PeerInfo {
+                    peer_id: peer_id.to_string(),
+                    addresses: info.listen_addrs.clone(),
+                    connected_since: oldest.map(|c| c.connected_since.to_rfc3339()),
+                    connected_secs: oldest.map(|c| (now - c.connected_since).num_seconds()),
+                    direction: oldest.map(|c| c.direction),
+                    connections,
+                    protocol_version: info.identity.as_ref().map(|i| i.protocol_version.clone()),
+                    agent_version: info.identity.as_ref().map(|i| i.agent_version.clone()),
+                    protocols: info
+                        .identity
+                        .as_ref()
+                        .map(|i| i.protocols.iter().map(|p| p.to_string()).collect())
+                        .unwrap_or_default(),
+                    is_chat_peer: info.is_chat_peer(),
+                }
             })
             .collect()
     }
 
+    // Repeats of the previous message within the window are held back; the
+    // next copy after the window carries the count instead
     pub fn send_system_message(&self, content: String) {
+        let content = {
+            let mut last = self.last_system_message.lock().unwrap_or_else(|e| e.into_inner());
+            let suppressed = match last.as_mut() {
+                Some(prev) if prev.content == content => {
+                    if prev.shown_at.elapsed() < self.system_message_window {
+                        prev.suppressed += 1;
+                        return;
+                    }
+                    prev.suppressed
+                }
+                _ => 0,
+            };
+            *last = Some(LastSystemMessage {
+                content: content.clone(),
+                shown_at: Instant::now(),
+                suppressed: 0,
+            });
+            
+            if suppressed > 0 {
+                format!("{} (×{})", content, suppressed + 1)
+            } else {
+                content
+            }
+        };
+        
+        // Past the per-second cap lines are only counted; the first line of
+        // the next window says how many were skipped
+        let skipped = {
+            let mut rate = self.system_message_rate.lock().unwrap_or_else(|e| e.into_inner());
+            if rate.window_start.elapsed() >= Duration::from_secs(1) {
+                rate.window_start = Instant::now();
+                rate.shown = 0;
+            }
+            if rate.shown >= MAX_SYSTEM_MESSAGES_PER_SEC {
+                rate.dropped += 1;
+                info!("System message over the rate limit: {}", content);
+                return;
+            }
+            rate.shown += 1;
+            std::mem::take(&mut rate.dropped)
+        };
+        if skipped > 0 {
+            self.emit_system_line(format!("… {} more system messages skipped", skipped));
+        }
+        self.emit_system_line(content);
+    }
+
+    fn emit_system_line(&self, content: String) {
         let _ = self.message_tx.send(ChatMessage {
+            id: uuid::Uuid::new_v4().to_string(),
             from: "System".to_string(),
             content,
             timestamp: chrono::Utc::now().to_rfc3339(),
             is_self: false,
+            is_direct: false,
+            room: None,
+            content_type: ContentType::Plain,
+            delivered_to: None,
+            recipients_estimate: None,
+            attachment: None,
+            lamport: self.tick_clock(),
+            recovered: false,
         });
     }
 
+    // Next Lamport time for a message we send or show
+    pub fn tick_clock(&self) -> u64 {
+        let mut clock = self.lamport_clock.lock().unwrap_or_else(|e| e.into_inner());
+        *clock += 1;
+        *clock
+    }
+
+    // Moves the clock past a peer's message so whatever we send next is
+    // ordered after it
+    fn observe_clock(&self, remote: u64) -> u64 {
+        let mut clock = self.lamport_clock.lock().unwrap_or_else(|e| e.into_inner());
+        *clock = (*clock).max(remote).saturating_add(1);
+        *clock
+    }
+
+    // Continue from the newest message of an earlier run
+    pub fn seed_clock(&self, value: u64) {
+        let mut clock = self.lamport_clock.lock().unwrap_or_else(|e| e.into_inner());
+        *clock = (*clock).max(value);
+    }
+
     pub fn bootstrap_dht(&self, swarm: &mut Swarm<ChatBehaviour>) {
+        if self.private_network {
+            self.send_system_message("🔒 Private network mode - public bootstrap nodes skipped".to_string());
+            return;
+        }
+        
         // Bootstrap the DHT
         if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
             warn!("DHT bootstrap failed: {}", e);
@@ -258,16 +2181,8 @@ impl P2PNode {
         self.send_system_message("✓ DHT bootstrap initiated".to_string());
         
         // Connect to bootstrap peers
-        let bootstrap_peers = vec![
-            "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
-            "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
-            "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
-            "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
-            "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
-        ];
-        
         let mut connected = 0;
-        for addr_str in bootstrap_peers {
+        for addr_str in BOOTSTRAP_ADDRESSES {
             if let Ok(addr) = addr_str.parse::<Multiaddr>() {
                 info!("Attempting to dial bootstrap peer: {}", addr);
                 if swarm.dial(addr.clone()).is_ok() {
@@ -283,236 +2198,3467 @@ impl P2PNode {
         }
     }
 
-    pub fn join_room(&mut self, swarm: &mut Swarm<ChatBehaviour>, room_name: String) {
-        info!("Joining room: {}", room_name);
-        
-        // Create gossipsub topic from room name
-        let topic = gossipsub::IdentTopic::new(room_name.clone());
-        
-        // Subscribe to the topic
-        if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
-            warn!("Failed to subscribe to topic: {}", e);
-            self.send_system_message(format!("⚠ Failed to join room '{}': {}", room_name, e));
+    // A passive join receives the room's messages without announcing us in the
+    // DHT or rendezvous, so other members won't find and dial us. A public
+    // room is also listed in the room directory.
+    pub fn join_room(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        room_name: String,
+        passphrase: Option<String>,
+        passive: bool,
+        public: bool,
+    ) {
+        let room_name = match sanitize_room_name(&room_name) {
+            Ok(name) => name,
+            Err(e) => {
+                self.send_system_message(format!("⚠ {}", e));
+                return;
+            }
+        };
+        let passphrase = passphrase.filter(|p| !p.is_empty());
+        if public && (passive || passphrase.is_some()) {
+            self.send_system_message(format!("⚠ Room '{}' can't be public when joined passively or with a passphrase", room_name));
             return;
         }
+        info!("Joining room: {}", room_name);
         
-        self.current_room = Some(topic);
-        self.current_room_name = Some(room_name.clone());
+        // Private rooms use a topic derived from the passphrase; public rooms use the name
+        let room_key = match passphrase {
+            Some(passphrase) => match RoomKey::derive(&room_name, &passphrase) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("{}", e);
+                    self.send_system_message(format!("⚠ Failed to join room '{}': {}", room_name, e));
+                    return;
+                }
+            },
+            None => None,
+        };
         
-        self.send_system_message(format!("📢 Announcing in room '{}'...", room_name));
+        // Create gossipsub topic from room name
+        let topic = match &room_key {
+            Some(key) => gossipsub::IdentTopic::new(key.topic_name()),
+            None => gossipsub::IdentTopic::new(room_name.clone()),
+        };
         
-        // Announce ourselves in Kademlia for peer discovery
-        if let Err(e) = swarm
-            .behaviour_mut()
-            .kad
-            .start_providing(room_name.as_bytes().to_vec().into())
-        {
-            warn!("Failed to start providing: {}", e);
-            self.send_system_message(format!("⚠ Failed to announce in room: {}", e));
-            return;
-        }
+        // A newer join replaces one still waiting to retry
+        self.pending_join = None;
+        self.subscribe_room(swarm, PendingJoin {
+            room_name,
+            room_key,
+            topic,
+            passive,
+            public,
+            attempts: 0,
+            retry_at: Instant::now(),
+        });
+    }
+
+    // When the pending join should be retried, for the node loop's timer
+    pub fn join_retry_at(&self) -> Option<Instant> {
+        self.pending_join.as_ref().map(|join| join.retry_at)
+    }
+
+    pub fn retry_pending_join(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        match self.pending_join.take() {
+            Some(join) if Instant::now() >= join.retry_at => self.subscribe_room(swarm, join),
+            pending => self.pending_join = pending,
+        }
+    }
+
+    fn subscribe_room(&mut self, swarm: &mut Swarm<ChatBehaviour>, mut join: PendingJoin) {
+        match swarm.behaviour_mut().gossipsub.subscribe(&join.topic) {
+            Ok(true) => {}
+            // Still subscribed from an earlier join, which is as good as a new subscription
+            Ok(false) => {
+                info!("Already subscribed to topic for room {}", join.room_name);
+                self.send_system_message(format!("✓ Already subscribed to room '{}'", join.room_name));
+            }
+            // The subscription filter will refuse this topic every time
+            Err(e @ gossipsub::SubscriptionError::NotAllowed) => {
+                warn!("Failed to subscribe to topic: {}", e);
+                self.send_system_message(format!("⚠ Failed to join room '{}': {}", join.room_name, e));
+                return;
+            }
+            Err(e) => {
+                join.attempts += 1;
+                warn!("Failed to subscribe to topic (attempt {}): {}", join.attempts, e);
+                if join.attempts >= SUBSCRIBE_MAX_ATTEMPTS {
+                    self.send_system_message(format!(
+                        "⚠ Failed to join room '{}' after {} attempts: {}",
+                        join.room_name, join.attempts, e
+                    ));
+                    return;
+                }
+
+                let delay = SUBSCRIBE_RETRY_BASE * 2u32.pow(join.attempts - 1);
+                self.send_system_message(format!(
+                    "⏳ Couldn't join room '{}' ({}), retrying in {}s...",
+                    join.room_name,
+                    e,
+                    delay.as_secs()
+                ));
+                join.retry_at = Instant::now() + delay;
+                self.pending_join = Some(join);
+                return;
+            }
+        }
+
+        if join.attempts > 0 {
+            self.send_system_message(format!("✓ Joined room '{}' after {} retries", join.room_name, join.attempts));
+        }
+        self.enter_room(swarm, join);
+    }
+
+    // Applies a joined room to the node once its topic is subscribed
+    fn enter_room(&mut self, swarm: &mut Swarm<ChatBehaviour>, join: PendingJoin) {
+        let PendingJoin { room_name, room_key, topic, passive, public, .. } = join;
+        // The provider key matches the topic so private room names never reach the DHT
+        let provider_key = kad::RecordKey::new(&topic.hash().as_str());
+        let topic_hash = topic.hash();
+        
+        if room_key.is_some() {
+            self.send_system_message(format!("🔒 Room '{}' is passphrase protected", room_name));
+        }
+        
+        self.room_topics.insert(topic_hash.clone(), room_name.clone());
+        self.current_room = Some(topic);
+        self.current_room_name = Some(room_name.clone());
+        self.room_key = room_key;
+        self.room_passive = passive;
+        self.room_public = public;
+        self.empty_provider_searches = 0;
+        self.search_retry_at = None;
+        self.room_members.clear();
+        self.moderated_mutes.clear();
+        self.join_presence(swarm);
+        self.join_moderation(swarm);
+        self.dial_known_room_peers(&room_name);
+        
+        // Members already connected stay connected while the room is quiet
+        let members: Vec<PeerId> = swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic_hash))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in members {
+            swarm.behaviour_mut().keep_alive.set(peer_id, true);
+        }
+        
+        if passive {
+            self.send_system_message(format!("👂 Listening in room '{}' without announcing", room_name));
+            self.join_rendezvous(swarm);
+            self.search_room_peers(swarm, true);
+            return;
+        }
+        
+        self.send_system_message(format!("📢 Announcing in room '{}'...", room_name));
+        
+        // Announce ourselves in Kademlia for peer discovery
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .kad
+            .start_providing(provider_key)
+        {
+            warn!("Failed to start providing: {}", e);
+            self.send_system_message(format!("⚠ Failed to announce in room: {}", e));
+            return;
+        }
+        if public {
+            self.provide_directory(swarm);
+        }
+
+        // With nobody in the routing table the record stays local; announce
+        // again once the DHT is reachable
+        self.announce_pending = !self.dht_bootstrapped || Self::dht_peer_count(swarm) == 0;
+        if self.announce_pending {
+            self.send_system_message(format!("⏳ DHT not ready yet - '{}' will be announced to the network once it is", room_name));
+        } else {
+            self.send_system_message(format!("✓ Announced! Searching for peers in '{}'...", room_name));
+        }
+
+        self.join_rendezvous(swarm);
+
+        // Search for peers in the room via DHT
+        self.search_room_peers(swarm, true);
+    }
+
+    pub fn recent_system_events(&self) -> Vec<RecordedSystemEvent> {
+        self.recent_system_events.iter().cloned().collect()
+    }
+
+    pub fn routing_table_summary(&self, swarm: &mut Swarm<ChatBehaviour>) -> RoutingTableSummary {
+        let buckets: Vec<BucketSummary> = swarm
+            .behaviour_mut()
+            .kad
+            .kbuckets()
+            .map(|bucket| BucketSummary {
+                index: bucket.range().0.ilog2().unwrap_or_default(),
+                peers: bucket.num_entries(),
+            })
+            .collect();
+        RoutingTableSummary {
+            peers: buckets.iter().map(|bucket| bucket.peers).sum(),
+            buckets,
+        }
+    }
+
+    fn dht_peer_count(swarm: &mut Swarm<ChatBehaviour>) -> usize {
+        swarm
+            .behaviour_mut()
+            .kad
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum()
+    }
+
+    // Repeats the provider announcement and search for a room that was
+    // joined before the DHT could carry them
+    fn flush_pending_announce(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if !self.announce_pending {
+            return;
+        }
+        // An early peer gets a first announcement out; bootstrap completion
+        // still repeats it to reach the rest of the network
+        self.announce_pending = !self.dht_bootstrapped;
+        let (Some(topic), Some(room_name)) = (self.current_room.clone(), self.current_room_name.clone()) else {
+            return;
+        };
+        if self.room_passive {
+            return;
+        }
+
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .kad
+            .start_providing(kad::RecordKey::new(&topic.hash().as_str()))
+        {
+            warn!("Failed to re-announce room: {}", e);
+            self.send_system_message(format!("⚠ Failed to announce in room: {}", e));
+            return;
+        }
+
+        if self.room_public {
+            self.provide_directory(swarm);
+        }
+
+        info!("Re-announced room {} after DHT became reachable", room_name);
+        self.send_system_message(format!("✓ Room '{}' announced to the network - searching for peers...", room_name));
+        let _ = self.event_tx.send(NodeEvent::RoomAnnounced(RoomAnnounced { room: room_name }));
+        self.search_room_peers(swarm, true);
+    }
+
+    pub fn leave_room(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if let Some(join) = self.pending_join.take() {
+            self.send_system_message(format!("✗ Stopped trying to join room '{}'", join.room_name));
+            if self.current_room.is_none() {
+                return;
+            }
+        }
+        let (Some(topic), Some(room_name)) = (self.current_room.take(), self.current_room_name.take()) else {
+            self.send_system_message("⚠ Not in a room".to_string());
+            return;
+        };
+        info!("Leaving room: {}", room_name);
+        self.announce_pending = false;
+        
+        if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+            warn!("Failed to unsubscribe from topic: {}", e);
+        }
+        self.sent_log.forget(topic.hash().as_str());
+        self.gap_tracker.forget_topic(topic.hash().as_str());
+        self.leave_presence(swarm);
+        self.leave_moderation(swarm);
+        swarm.behaviour_mut().keep_alive.clear();
+        
+        // Passive rooms were never announced, so there is nothing to withdraw
+        if !self.room_passive {
+            swarm
+                .behaviour_mut()
+                .kad
+                .stop_providing(&kad::RecordKey::new(&topic.hash().as_str()));
+            if let (Some((server, _)), Some(namespace)) = (&self.rendezvous_server, self.rendezvous_namespace.clone()) {
+                swarm.behaviour_mut().rendezvous.unregister(namespace, *server);
+            }
+        }
+        if self.room_public {
+            swarm
+                .behaviour_mut()
+                .kad
+                .stop_providing(&kad::RecordKey::new(&directory::DIRECTORY_KEY));
+        }
+        
+        self.room_key = None;
+        self.room_passive = false;
+        self.room_public = false;
+        self.room_members.clear();
+        self.moderated_mutes.clear();
+        self.rendezvous_namespace = None;
+        self.rendezvous_cookie = None;
+        self.rendezvous_refresh_at = None;
+        self.search_retry_at = None;
+        self.cancel_provider_searches(swarm, Some(&room_name));
+        // Receipts for a room we've left can no longer be shown against it
+        self.pending_deliveries.retain(|_, pending| pending.topic != topic.hash());
+        
+        self.send_system_message(format!("👋 Left room '{}'", room_name));
+    }
+
+    // Follow status updates in the current room, and announce ours unless
+    // the room was joined passively
+    fn join_presence(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        self.leave_presence(swarm);
+        let Some(room) = &self.current_room else {
+            return;
+        };
+        
+        let topic = gossipsub::IdentTopic::new(format!("{}/presence", room.hash()));
+        if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+            warn!("Failed to subscribe to presence topic: {}", e);
+            return;
+        }
+        self.presence_topic = Some(topic);
+        self.publish_presence(swarm);
+    }
+
+    fn leave_presence(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if let Some(topic) = self.presence_topic.take() {
+            if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+                warn!("Failed to unsubscribe from presence topic: {}", e);
+            }
+        }
+    }
+
+    // Follow the owner's moderation orders in rooms that have one
+    fn join_moderation(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        self.leave_moderation(swarm);
+        let (Some(room), Some(name)) = (&self.current_room, &self.current_room_name) else {
+            return;
+        };
+        if !self.room_owners.contains_key(name) {
+            return;
+        }
+        
+        let topic = gossipsub::IdentTopic::new(format!("{}/moderation", room.hash()));
+        if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+            warn!("Failed to subscribe to moderation topic: {}", e);
+            return;
+        }
+        self.moderation_topic = Some(topic);
+    }
+
+    fn leave_moderation(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if let Some(topic) = self.moderation_topic.take() {
+            if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+                warn!("Failed to unsubscribe from moderation topic: {}", e);
+            }
+        }
+    }
+
+    // Takes effect from the next join of the room
+    pub fn set_room_owner(&mut self, room: String, owner: identity::PublicKey, keypair: Option<identity::Keypair>) {
+        if let Some(keypair) = keypair {
+            self.owned_rooms.insert(room.clone(), keypair);
+        }
+        self.room_owners.insert(room, owner);
+    }
+
+    // Sign an order with the current room's owner key, publish it and apply it locally
+    pub fn moderate_room(&mut self, swarm: &mut Swarm<ChatBehaviour>, action: ModerationAction) -> Result<(), CommandError> {
+        action.validate().map_err(CommandError::invalid_input)?;
+        let room = self.current_room_name.clone().ok_or(CommandError::RoomNotJoined)?;
+        let owner = self
+            .owned_rooms
+            .get(&room)
+            .ok_or_else(|| CommandError::invalid_input("Only the room owner can moderate this room"))?;
+        let topic = self
+            .moderation_topic
+            .clone()
+            .ok_or_else(|| CommandError::internal("Not subscribed to the room's moderation topic"))?;
+        
+        let order = ModerationMessage::sign(&room, action, owner).map_err(CommandError::internal)?;
+        let payload = serde_json::to_vec(&order).expect("moderation serialization cannot fail");
+        let payload = match &self.room_key {
+            Some(key) => key.encrypt(&payload).map_err(|e| CommandError::PublishFailed { reason: e })?,
+            None => payload,
+        };
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic, payload)
+            .map_err(|e| CommandError::PublishFailed { reason: e.to_string() })?;
+        
+        self.apply_moderation(room, order);
+        Ok(())
+    }
+
+    fn is_moderation_topic(&self, topic: &gossipsub::TopicHash) -> bool {
+        self.moderation_topic.as_ref().is_some_and(|t| t.hash() == *topic)
+    }
+
+    fn is_moderator_muted(&self, peer_id: &PeerId) -> bool {
+        self.moderated_mutes.get(peer_id).is_some_and(|until| Instant::now() < *until)
+    }
+
+    fn handle_moderation(&mut self, source: Option<PeerId>, data: &[u8]) {
+        let Some(room) = self.current_room_name.clone() else {
+            return;
+        };
+        let Some(owner) = self.room_owners.get(&room) else {
+            return;
+        };
+        
+        let verified = match &self.room_key {
+            Some(key) => key.decrypt(data).ok_or_else(|| "not encrypted with the room key".to_string()),
+            None => Ok(data.to_vec()),
+        }
+        .and_then(|data| {
+            serde_json::from_slice::<ModerationMessage>(&data).map_err(|e| format!("malformed order: {}", e))
+        })
+        .and_then(|order| order.verify(&room, owner).map(|()| order));
+        
+        match verified {
+            Ok(order) => self.apply_moderation(room, order),
+            Err(reason) => {
+                warn!("Ignoring moderation order from {:?} in {}: {}", source, room, reason);
+                let _ = self.event_tx.send(NodeEvent::ModerationRejected(ModerationRejected {
+                    room,
+                    peer_id: source.map(|p| p.to_string()),
+                    reason,
+                }));
+            }
+        }
+    }
+
+    fn apply_moderation(&mut self, room: String, order: ModerationMessage) {
+        match &order.action {
+            ModerationAction::Mute { peer_id, minutes } => {
+                let Ok(peer) = peer_id.parse::<PeerId>() else {
+                    return;
+                };
+                // Counted from when the order was issued, so a late copy doesn't extend it
+                let ends_at = order.issued_at + (*minutes as i64) * 60;
+                let remaining = ends_at - chrono::Utc::now().timestamp();
+                if remaining <= 0 {
+                    return;
+                }
+                self.moderated_mutes.insert(peer, Instant::now() + Duration::from_secs(remaining as u64));
+                self.send_system_message(format!(
+                    "🛡 {} was muted by the room owner for {} minutes",
+                    self.display_name(&peer),
+                    minutes
+                ));
+            }
+            ModerationAction::Pin { message_id } => {
+                self.send_system_message(format!("📌 The room owner pinned message {}", message_id));
+            }
+        }
+        let _ = self.event_tx.send(NodeEvent::RoomModerated(RoomModerated {
+            room,
+            action: order.action,
+        }));
+    }
+
+    pub fn set_status(&mut self, swarm: &mut Swarm<ChatBehaviour>, status: Option<String>) {
+        let status = status.as_deref().and_then(sanitize_status);
+        if status == self.status {
+            return;
+        }
+        
+        match &status {
+            Some(text) => self.send_system_message(format!("💬 Status set to '{}'", text)),
+            None => self.send_system_message("💬 Status cleared".to_string()),
+        }
+        self.status = status;
+        self.publish_presence(swarm);
+    }
+
+    pub fn set_nickname(&mut self, swarm: &mut Swarm<ChatBehaviour>, nickname: Option<String>) -> Result<(), CommandError> {
+        let nickname = nickname.as_deref().and_then(sanitize_nickname);
+        if nickname == self.nickname {
+            return Ok(());
+        }
+        if let Some(at) = self.nickname_changed_at {
+            let elapsed = at.elapsed();
+            if elapsed < NICKNAME_CHANGE_INTERVAL {
+                return Err(CommandError::RateLimited {
+                    retry_after_secs: (NICKNAME_CHANGE_INTERVAL - elapsed).as_secs().max(1),
+                });
+            }
+        }
+        
+        match &nickname {
+            Some(name) => self.send_system_message(format!("🏷 Nickname set to '{}'", name)),
+            None => self.send_system_message("🏷 Nickname cleared".to_string()),
+        }
+        self.nickname = nickname;
+        self.nickname_changed_at = Some(Instant::now());
+        self.publish_presence(swarm);
+        Ok(())
+    }
+
+    // Any sign of life from a room member: a heartbeat, a chat message or a
+    // fresh subscription
+    fn note_member_alive(&mut self, peer_id: PeerId) {
+        self.search_retry_at = None;
+        let last_seen = chrono::Utc::now().to_rfc3339();
+        let changed = match self.room_members.get_mut(&peer_id) {
+            Some(member) => {
+                member.heard_at = Instant::now();
+                member.last_seen = last_seen.clone();
+                std::mem::replace(&mut member.state, PresenceState::Online) != PresenceState::Online
+            }
+            None => {
+                self.room_members.insert(peer_id, MemberPresence {
+                    heard_at: Instant::now(),
+                    last_seen: last_seen.clone(),
+                    state: PresenceState::Online,
+                });
+                true
+            }
+        };
+        if changed {
+            self.emit_presence_changed(peer_id, PresenceState::Online, last_seen);
+        }
+    }
+
+    // Mark members away or offline once their heartbeats stop; run on the
+    // presence interval
+    pub fn update_member_presence(&mut self) {
+        let mut changes = Vec::new();
+        for (peer_id, member) in &mut self.room_members {
+            let state = PresenceState::after_silence(member.heard_at.elapsed());
+            if state != member.state {
+                member.state = state;
+                changes.push((*peer_id, state, member.last_seen.clone()));
+            }
+        }
+        for (peer_id, state, last_seen) in changes {
+            info!("Room member {} is now {:?}", peer_id, state);
+            self.emit_presence_changed(peer_id, state, last_seen);
+        }
+    }
+
+    fn emit_presence_changed(&self, peer_id: PeerId, presence: PresenceState, last_seen: String) {
+        let Some(room) = self.current_room_name.clone() else {
+            return;
+        };
+        let _ = self.event_tx.send(NodeEvent::PresenceChanged(PresenceChanged {
+            peer_id: peer_id.to_string(),
+            room,
+            presence,
+            last_seen,
+        }));
+    }
+
+    pub fn room_members(&self) -> Result<Vec<RoomMember>, CommandError> {
+        if self.current_room.is_none() {
+            return Err(CommandError::RoomNotJoined);
+        }
+        let mut members: Vec<RoomMember> = self
+            .room_members
+            .iter()
+            .map(|(peer_id, member)| RoomMember {
+                peer_id: peer_id.to_string(),
+                nickname: self.peer_nicknames.get(peer_id).cloned(),
+                presence: member.state,
+                last_seen: member.last_seen.clone(),
+            })
+            .collect();
+        members.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        Ok(members)
+    }
+
+    // Heartbeat carrying the current status and nickname, sent periodically
+    // and whenever either changes
+    pub fn publish_presence(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some(topic) = &self.presence_topic else {
+            return;
+        };
+        if self.room_passive {
+            return;
+        }
+        
+        let update = PresenceUpdate {
+            status: self.status.clone(),
+            peer_id: self.nickname.is_some().then(|| self.peer_id.to_string()),
+            nickname: self.nickname.clone(),
+        };
+        let payload = serde_json::to_vec(&update).expect("presence serialization cannot fail");
+        let payload = match &self.room_key {
+            Some(key) => match key.encrypt(&payload) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Failed to encrypt presence: {}", e);
+                    return;
+                }
+            },
+            None => payload,
+        };
+        
+        // Fails with InsufficientPeers while we're alone in the room; the next
+        // broadcast catches up once someone joins
+        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), payload) {
+            info!("Presence not published: {}", e);
+        }
+    }
+
+    fn is_presence_topic(&self, topic: &gossipsub::TopicHash) -> bool {
+        self.presence_topic.as_ref().is_some_and(|t| t.hash() == *topic)
+    }
+
+    fn is_current_room(&self, topic: &gossipsub::TopicHash) -> bool {
+        self.current_room.as_ref().is_some_and(|t| t.hash() == *topic)
+    }
+
+    // Presence never reaches the message channel, so heartbeats stay out of
+    // history and unread counts
+    fn handle_presence(&mut self, source: Option<PeerId>, data: &[u8]) {
+        let Some(source) = source else {
+            return;
+        };
+        let data = match &self.room_key {
+            Some(key) => match key.decrypt(data) {
+                Some(plaintext) => plaintext,
+                None => return,
+            },
+            None => data.to_vec(),
+        };
+        let Ok(update) = serde_json::from_slice::<PresenceUpdate>(&data) else {
+            info!("Ignoring malformed presence update from {}", source);
+            return;
+        };
+        if update.peer_id.as_ref().is_some_and(|peer_id| *peer_id != source.to_string()) {
+            info!("Ignoring presence update from {} claiming another peer ID", source);
+            return;
+        }
+        
+        self.note_member_alive(source);
+        let _ = self.event_tx.send(NodeEvent::PeerStatus(PeerStatus {
+            peer_id: source.to_string(),
+            status: update.status.as_deref().and_then(sanitize_status),
+        }));
+        self.update_peer_nickname(source, update.nickname.as_deref().and_then(sanitize_nickname));
+    }
+
+    // Renames faster than NICKNAME_CHANGE_INTERVAL are dropped; the periodic
+    // presence broadcast delivers the latest name once the interval has passed
+    fn update_peer_nickname(&mut self, peer_id: PeerId, nickname: Option<String>) {
+        if self.peer_nicknames.get(&peer_id) == nickname.as_ref() {
+            return;
+        }
+        if self
+            .peer_renamed_at
+            .get(&peer_id)
+            .is_some_and(|at| at.elapsed() < NICKNAME_CHANGE_INTERVAL)
+        {
+            info!("Ignoring rapid rename from {}", peer_id);
+            return;
+        }
+        self.peer_renamed_at.insert(peer_id, Instant::now());
+        
+        let old_name = self.display_name(&peer_id);
+        self.address_book.set_nickname(&peer_id, nickname.clone());
+        match &nickname {
+            Some(name) => {
+                self.peer_nicknames.insert(peer_id, name.clone());
+            }
+            None => {
+                self.peer_nicknames.remove(&peer_id);
+            }
+        }
+        self.send_system_message(format!("🏷 {} is now known as {}", old_name, self.display_name(&peer_id)));
+        let _ = self.event_tx.send(NodeEvent::PeerRenamed(PeerRenamed {
+            peer_id: peer_id.to_string(),
+            nickname,
+        }));
+    }
+
+    // Move our rendezvous registration to the current room. The namespace is
+    // derived from the topic so private room names aren't sent to the server.
+    // Passive rooms only discover through it and never register.
+    fn join_rendezvous(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let (Some((server, _)), Some(topic)) = (self.rendezvous_server.clone(), &self.current_room) else {
+            return;
+        };
+        
+        if let Some(old) = self.rendezvous_namespace.take() {
+            swarm.behaviour_mut().rendezvous.unregister(old, server);
+        }
+        self.rendezvous_cookie = None;
+        self.rendezvous_refresh_at = None;
+        
+        match rendezvous::Namespace::new(format!("p2p-chat/{}", topic.hash())) {
+            Ok(namespace) => {
+                self.rendezvous_namespace = Some(namespace);
+                if !self.room_passive {
+                    self.register_rendezvous(swarm);
+                }
+            }
+            Err(e) => self.rendezvous_fallback(format!("namespace rejected: {}", e)),
+        }
+    }
+
+    fn register_rendezvous(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let (Some((server, _)), Some(namespace)) = (self.rendezvous_server.clone(), self.rendezvous_namespace.clone()) else {
+            return;
+        };
+        self.ensure_rendezvous_connection(swarm);
+        
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .rendezvous
+            .register(namespace, server, Some(self.rendezvous_ttl))
+        {
+            self.rendezvous_fallback(format!("registration failed: {}", e));
+        }
+    }
+
+    fn discover_rendezvous(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let (Some((server, _)), Some(namespace)) = (self.rendezvous_server.clone(), self.rendezvous_namespace.clone()) else {
+            return;
+        };
+        self.ensure_rendezvous_connection(swarm);
+        
+        // The cookie makes the server only return registrations we haven't seen
+        swarm
+            .behaviour_mut()
+            .rendezvous
+            .discover(Some(namespace), self.rendezvous_cookie.clone(), None, server);
+    }
+
+    // Requests to the server wait for this connection; idle timeouts close it between uses
+    fn ensure_rendezvous_connection(&self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some((server, addr)) = &self.rendezvous_server else {
+            return;
+        };
+        if swarm.is_connected(server) {
+            return;
+        }
+        let opts = DialOpts::peer_id(*server).addresses(vec![addr.clone()]).build();
+        if let Err(e) = swarm.dial(opts) {
+            warn!("Failed to dial rendezvous server: {}", e);
+        }
+    }
+
+    // Stop using rendezvous for the current room; DHT discovery carries on alone
+    fn rendezvous_fallback(&mut self, reason: String) {
+        warn!("Rendezvous {}", reason);
+        self.rendezvous_namespace = None;
+        self.rendezvous_refresh_at = None;
+        self.send_system_message(format!("⚠ Rendezvous {} - using DHT discovery only", reason));
+    }
+
+    pub fn set_power_mode(&mut self, mode: PowerMode, pause_mdns: bool) {
+        self.power_mode = mode;
+        self.pause_mdns_in_low_power = pause_mdns;
+        
+        match mode {
+            PowerMode::LowPower => {
+                let mdns = if self.mdns_paused() { ", mDNS paused" } else { "" };
+                self.send_system_message(format!("🔋 Low-power mode on: slower peer discovery, no re-dial lookups{}", mdns));
+            }
+            PowerMode::Normal => {
+                self.send_system_message("⚡ Normal power mode restored".to_string());
+            }
+        }
+        // These are fixed when the swarm is built
+        self.send_system_message("ℹ Identify, ping and gossipsub heartbeat intervals change after restart".to_string());
+    }
+
+    fn mdns_paused(&self) -> bool {
+        self.power_mode == PowerMode::LowPower && self.pause_mdns_in_low_power
+    }
+
+    pub fn get_identity(&self, swarm: &Swarm<ChatBehaviour>) -> NodeIdentity {
+        NodeIdentity {
+            peer_id: self.peer_id.to_string(),
+            public_key: self
+                .public_key
+                .as_ref()
+                .map(|key| bs58::encode(key.encode_protobuf()).into_string())
+                .unwrap_or_default(),
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            agent_version: self.agent_version.clone(),
+            behaviours: self.enabled_behaviours(swarm),
+        }
+    }
+
+    fn enabled_behaviours(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<String> {
+        let behaviour = swarm.behaviour();
+        let mut names = vec!["kad", "identify", "gossipsub", "direct", "receipts", "retransmit", "autonat", "ping"];
+        if behaviour.mdns.is_enabled() && self.mdns_enabled {
+            names.push("mdns");
+        }
+        if behaviour.relay.is_enabled() {
+            names.push("relay-server");
+        }
+        if self.rendezvous_server.is_some() {
+            names.push("rendezvous");
+        }
+        names.into_iter().map(String::from).collect()
+    }
+
+    pub fn nat_status(&self, swarm: &Swarm<ChatBehaviour>) -> &'static str {
+        match swarm.behaviour().autonat.nat_status() {
+            autonat::NatStatus::Public(_) => "public",
+            autonat::NatStatus::Private => "private",
+            autonat::NatStatus::Unknown => "unknown",
+        }
+    }
+
+    // False if the message was already seen
+    fn note_message_seen(&mut self, key: String) -> bool {
+        if !self.seen_messages.insert(key.clone()) {
+            return false;
+        }
+        self.seen_message_order.push_back(key);
+        if self.seen_message_order.len() > MAX_SEEN_MESSAGES {
+            if let Some(oldest) = self.seen_message_order.pop_front() {
+                self.seen_messages.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn connection_stats(&self, swarm: &Swarm<ChatBehaviour>) -> ConnectionStats {
+        let counters = swarm.network_info().connection_counters().clone();
+        ConnectionStats {
+            established: counters.num_established(),
+            pending_incoming: counters.num_pending_incoming(),
+            pending_outgoing: counters.num_pending_outgoing(),
+            max_established: self.connection_limits.max_established,
+            max_established_per_peer: self.connection_limits.max_established_per_peer,
+            max_pending: self.connection_limits.max_pending,
+            dials_in_flight: self.dials.in_flight_len(),
+            dials_queued: self.dials.queued_len(),
+            denied: self.connections_denied,
+        }
+    }
+
+    // Returns whether the denial came from our connection limits; a busy room
+    // can hit them constantly, so the user only hears about it once a minute
+    fn note_connection_denied(&mut self, cause: &ConnectionDenied) -> bool {
+        let Some(exceeded) = cause.downcast_ref::<connection_limits::Exceeded>() else {
+            return false;
+        };
+        self.connections_denied += 1;
+        info!("Connection denied: {}", exceeded);
+        self.event_log.push(LogLevel::Warning, "connection_limit", format!("Connection denied: {}", exceeded));
+        
+        if self
+            .connection_limit_noticed_at
+            .is_none_or(|at| at.elapsed() >= CONNECTION_LIMIT_NOTICE_INTERVAL)
+        {
+            self.connection_limit_noticed_at = Some(Instant::now());
+            self.send_system_message(format!("⚠ Refusing new connections: {}", exceeded));
+        }
+        true
+    }
+
+    fn record_metrics(&self, event: &SwarmEvent<ChatBehaviourEvent>) {
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+        let recorder = &metrics.recorder;
+        recorder.record(event);
+        match event {
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(e)) => recorder.record(e),
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(e)) => recorder.record(e),
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(e)) => recorder.record(e),
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Relay(e)) => recorder.record(e),
+            _ => {}
+        }
+    }
+
+    // Registry contents in the Prometheus text exposition format
+    pub fn encode_metrics(&self) -> Result<String, String> {
+        let metrics = self.metrics.as_ref().ok_or("Metrics are not enabled")?;
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &metrics.registry)
+            .map_err(|e| format!("Failed to encode metrics: {}", e))?;
+        Ok(text)
+    }
+
+    // Periodic discovery only runs while we have peers, so after a network
+    // change nothing would bring us back; detect it and reconnect right away
+    fn note_disconnect(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer_id: PeerId) {
+        let now = Instant::now();
+        self.recent_disconnects.push_back((peer_id, now));
+        while self
+            .recent_disconnects
+            .front()
+            .is_some_and(|(_, at)| now.duration_since(*at) > NETWORK_LOSS_WINDOW)
+        {
+            self.recent_disconnects.pop_front();
+        }
+        
+        if swarm.network_info().num_peers() > 0 || self.recent_disconnects.len() < NETWORK_LOSS_MIN_PEERS {
+            return;
+        }
+        if self.reconnected_at.is_some_and(|at| at.elapsed() < RECONNECT_COOLDOWN) {
+            return;
+        }
+        self.reconnect(swarm);
+    }
+
+    pub fn disconnect_report_at(&self) -> Option<Instant> {
+        self.unreported_disconnects.values().min().map(|at| *at + self.reconnect_grace)
+    }
+
+    // Show the disconnects of peers that didn't come back within the grace period
+    pub fn report_disconnects(&mut self) {
+        let grace = self.reconnect_grace;
+        let gone: Vec<PeerId> = self
+            .unreported_disconnects
+            .iter()
+            .filter(|(_, at)| at.elapsed() >= grace)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in gone {
+            self.unreported_disconnects.remove(&peer_id);
+            self.report_system_event(SystemEvent::PeerDisconnected { peer_id: peer_id.to_string() });
+        }
+    }
+
+    fn reconnect(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        info!("Lost all {} peers within {:?}, reconnecting", self.recent_disconnects.len(), NETWORK_LOSS_WINDOW);
+        self.send_system_message("🔄 Network changed, reconnecting...".to_string());
+        self.reconnected_at = Some(Instant::now());
+        
+        // Dials started on the old network won't complete
+        self.dials.clear_in_flight();
+        for (peer_id, _) in std::mem::take(&mut self.recent_disconnects) {
+            self.queue_dial(peer_id);
+        }
+        
+        self.bootstrap_dht(swarm);
+        self.search_room_peers(swarm, false);
+    }
+
+    // Replaced on every identify, so protocols a peer adds or drops show up
+    // after its next identify push
+    pub fn peer_protocols(&self, peer_id: &PeerId) -> Result<PeerProtocols, CommandError> {
+        let info = self
+            .connected_peers
+            .get(peer_id)
+            .ok_or_else(|| CommandError::invalid_input(format!("Peer {} is not connected", peer_id)))?;
+        let mut protocols: Vec<String> = info
+            .identity
+            .as_ref()
+            .map(|i| i.protocols.iter().map(|p| p.to_string()).collect())
+            .unwrap_or_default();
+        protocols.sort();
+        
+        Ok(PeerProtocols {
+            peer_id: peer_id.to_string(),
+            identified: info.identity.is_some(),
+            protocols,
+        })
+    }
+
+    pub fn mesh_peers(&self, swarm: &Swarm<ChatBehaviour>) -> Result<MeshPeers, CommandError> {
+        let (Some(topic), Some(room)) = (&self.current_room, &self.current_room_name) else {
+            return Err(CommandError::RoomNotJoined);
+        };
+        let topic = topic.hash();
+        let gossipsub = &swarm.behaviour().gossipsub;
+        
+        let mesh: HashSet<PeerId> = gossipsub.mesh_peers(&topic).copied().collect();
+        let subscribed = gossipsub
+            .all_peers()
+            .filter(|(peer_id, topics)| topics.contains(&&topic) && !mesh.contains(peer_id))
+            .map(|(peer_id, _)| peer_id.to_string())
+            .collect();
+        
+        Ok(MeshPeers {
+            room: room.clone(),
+            mesh: mesh.iter().map(PeerId::to_string).collect(),
+            subscribed,
+        })
+    }
+
+    pub fn relay_stats(&self, swarm: &Swarm<ChatBehaviour>) -> RelayStats {
+        RelayStats {
+            enabled: self.relay_limits.is_some(),
+            active: swarm.behaviour().relay.is_enabled(),
+            reservations: self.relay_reservations.len(),
+            open_circuits: self.relay_circuits,
+            total_circuits: self.relay_circuits_total,
+        }
+    }
+
+    // Only relay while other peers can actually reach us; a NATed relay would
+    // hand out reservations nobody can use
+    fn update_relay_server(&mut self, swarm: &mut Swarm<ChatBehaviour>, status: &autonat::NatStatus) {
+        let Some(limits) = &self.relay_limits else {
+            return;
+        };
+        let active = swarm.behaviour().relay.is_enabled();
+        
+        match status {
+            autonat::NatStatus::Private if active => {
+                swarm.behaviour_mut().relay = Toggle::from(None);
+                self.relay_reservations.clear();
+                self.relay_circuits = 0;
+                warn!("Relay server disabled: node is not publicly reachable");
+                self.send_system_message("⚠ Relay server disabled: AutoNAT reports this node is behind NAT".to_string());
+            }
+            autonat::NatStatus::Public(_) if !active => {
+                let relay = relay::Behaviour::new(self.peer_id, relay_config(limits));
+                swarm.behaviour_mut().relay = Toggle::from(Some(relay));
+                info!("Relay server re-enabled: node is publicly reachable");
+                self.send_system_message("📡 Relay server enabled: node is publicly reachable".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_muted(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer_id: PeerId, muted: bool) {
+        let short_id = self.short_peer_id(&peer_id.to_string());
+        if muted {
+            if self.muted_peers.insert(peer_id) {
+                self.send_system_message(format!("🔇 Muted peer {}", short_id));
+            }
+            // Someone we've muted is no longer a friend worth keeping in the mesh
+            if self.explicit_peers.contains(&peer_id) {
+                self.remove_explicit_peer(swarm, peer_id);
+            }
+        } else if self.muted_peers.remove(&peer_id) {
+            self.send_system_message(format!("🔊 Unmuted peer {}", short_id));
+        }
+    }
+
+    pub fn add_explicit_peer(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer_id: PeerId) -> Result<(), CommandError> {
+        if peer_id == self.peer_id {
+            return Err(CommandError::invalid_input("Cannot add yourself as an explicit peer"));
+        }
+        if self.muted_peers.contains(&peer_id) {
+            return Err(CommandError::invalid_input("Unmute this peer before adding it as an explicit peer"));
+        }
+        if !self.explicit_peers.insert(peer_id) {
+            return Ok(());
+        }
+        
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        self.address_book.set_explicit(&peer_id, true);
+        self.save_address_book();
+        self.queue_dial(peer_id);
+        self.send_system_message(format!("🤝 Always keeping {} in the room mesh", self.display_name(&peer_id)));
+        Ok(())
+    }
+
+    pub fn remove_explicit_peer(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer_id: PeerId) {
+        if !self.explicit_peers.remove(&peer_id) {
+            return;
+        }
+        
+        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+        self.address_book.set_explicit(&peer_id, false);
+        self.save_address_book();
+        self.send_system_message(format!("✗ No longer keeping {} in the room mesh", self.display_name(&peer_id)));
+    }
+
+    pub fn set_mdns_enabled(&mut self, swarm: &mut Swarm<ChatBehaviour>, enabled: bool) {
+        if enabled == self.mdns_enabled {
+            return;
+        }
+        
+        if enabled && !swarm.behaviour().mdns.is_enabled() {
+            // mDNS was off or failed to start at init; try it now
+            match mdns::tokio::Behaviour::new(mdns::Config::default(), self.peer_id) {
+                Ok(behaviour) => swarm.behaviour_mut().mdns = Toggle::from(Some(behaviour)),
+                Err(e) => {
+                    warn!("Failed to start mDNS: {}", e);
+                    self.send_system_message(format!("⚠ Failed to enable local network discovery: {}", e));
+                    return;
+                }
+            }
+        }
+        
+        self.mdns_enabled = enabled;
+        if enabled {
+            self.send_system_message("✓ Local network discovery (mDNS) enabled".to_string());
+        } else {
+            // The running mDNS tasks can't be stopped without rebuilding the swarm
+            self.send_system_message("✗ Local network discovery (mDNS) disabled - restart with mDNS off to stop all mDNS traffic".to_string());
+        }
+    }
+
+    pub fn refresh_peers(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some(room_name) = self.current_room_name.clone() else {
+            self.send_system_message("⚠ Join a room first (Ctrl+J)".to_string());
+            return;
+        };
+        
+        self.send_system_message(format!("🔄 Refreshing peers in '{}'...", room_name));
+        self.search_room_peers(swarm, true);
+    }
+
+    // Forget everything discovery has seen so peers that were found but never
+    // connected get found and dialed again
+    pub fn reset_discovery(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let forgotten = self.discovered_peers.len() + self.dials.queued_len() + self.looked_up_peers.len();
+        self.discovered_peers.clear();
+        self.dials.clear();
+        self.looked_up_peers.clear();
+        self.address_lookups.clear();
+        self.empty_provider_searches = 0;
+        // Searches already under way are superseded by the one started below
+        self.cancel_provider_searches(swarm, None);
+        info!("Discovery reset, {} cached peers forgotten", forgotten);
+
+        let Some(room_name) = self.current_room_name.clone() else {
+            self.send_system_message("🔄 Discovery state cleared".to_string());
+            return;
+        };
+        self.send_system_message(format!("🔄 Discovery state cleared - searching for peers in '{}' again...", room_name));
+        self.dial_known_room_peers(&room_name);
+        self.search_room_peers(swarm, true);
+    }
+
+    pub fn search_room_peers(&mut self, swarm: &mut Swarm<ChatBehaviour>, interactive: bool) {
+        let (Some(topic), Some(room_name)) = (self.current_room.clone(), self.current_room_name.clone()) else {
+            return;
+        };
+        
+        // Re-register before the server drops us, even if we skip the search
+        if self.rendezvous_refresh_at.is_some_and(|at| Instant::now() >= at) {
+            self.rendezvous_refresh_at = None;
+            self.register_rendezvous(swarm);
+        }
+        
+        // Periodic searches stop once the room is well connected
+        let members = self.room_member_count(swarm, &topic.hash());
+        if !interactive && members >= self.discovery_target_peers {
+            info!("Skipping provider search in '{}': {} members connected", room_name, members);
+            return;
+        }
+        
+        let query_id = swarm
+            .behaviour_mut()
+            .kad
+            .get_providers(kad::RecordKey::new(&topic.hash().as_str()));
+        
+        self.provider_queries.insert(query_id, ProviderQuery {
+            room: room_name,
+            found: 0,
+            interactive,
+            deadline: interactive.then(|| Instant::now() + self.interactive_search_timeout),
+        });
+        
+        // Look for newcomers registered since the last search
+        self.discover_rendezvous(swarm);
+    }
+
+    // Stop the searches for `room`, or all of them; anything they still
+    // report is ignored
+    fn cancel_provider_searches(&mut self, swarm: &mut Swarm<ChatBehaviour>, room: Option<&str>) {
+        let cancelled: Vec<kad::QueryId> = self
+            .provider_queries
+            .iter()
+            .filter(|(_, query)| room.is_none_or(|room| query.room == room))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in cancelled {
+            self.provider_queries.remove(&id);
+            if let Some(mut running) = swarm.behaviour_mut().kad.query_mut(&id) {
+                running.finish();
+            }
+        }
+    }
+
+    pub fn provider_search_deadline(&self) -> Option<Instant> {
+        self.provider_queries.values().filter_map(|query| query.deadline).min()
+    }
+
+    // Ends interactive searches that ran out of time; each then finishes
+    // with whatever it found through the usual query result
+    pub fn expire_provider_searches(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let now = Instant::now();
+        for (id, query) in &mut self.provider_queries {
+            if query.deadline.is_some_and(|deadline| now >= deadline) {
+                query.deadline = None;
+                if let Some(mut running) = swarm.behaviour_mut().kad.query_mut(id) {
+                    info!("Ending provider search in '{}' with {} peers", query.room, query.found);
+                    running.finish();
+                }
+            }
+        }
+    }
+
+    // Reports a finished search to the UI and keeps the streak of empty ones
+    fn finish_provider_query(&mut self, query: ProviderQuery, error: Option<String>) {
+        match &error {
+            Some(e) => self.event_log.push(
+                LogLevel::Warning,
+                "provider_search",
+                format!("Search in '{}' failed after {} peers: {}", query.room, query.found, e),
+            ),
+            None => self.event_log.push(
+                LogLevel::Info,
+                "provider_search",
+                format!("Search in '{}' found {} peers", query.room, query.found),
+            ),
+        }
+        
+        // A timeout after finding peers is expected; only report user-started
+        // searches that came up empty
+        if query.interactive && query.found == 0 {
+            if let Some(e) = &error {
+                self.send_system_message(format!("⚠ Peer search in room '{}' failed: {} - will retry", query.room, e));
+            }
+        }
+        
+        // Results for a room we already left don't count towards the streak
+        if self.current_room_name.as_deref() == Some(query.room.as_str()) {
+            if query.found > 0 {
+                self.empty_provider_searches = 0;
+            } else {
+                self.empty_provider_searches += 1;
+                self.schedule_search_retry(&query.room);
+                if self.empty_provider_searches == EMPTY_SEARCHES_BEFORE_HINT {
+                    self.send_system_message(format!(
+                        "⚠ Still no peers found in room '{}' after {} searches - check your internet connection",
+                        query.room, EMPTY_SEARCHES_BEFORE_HINT
+                    ));
+                }
+            }
+        }
+        
+        let _ = self.event_tx.send(NodeEvent::RoomSearchFinished(RoomSearchFinished {
+            room: query.room,
+            providers: query.found,
+            interactive: query.interactive,
+            error,
+        }));
+    }
+
+    // Search again soon while nobody else is in the room
+    fn schedule_search_retry(&mut self, room: &str) {
+        if !self.room_members.is_empty() {
+            return;
+        }
+        let delay = SEARCH_RETRY_BASE
+            .saturating_mul(2u32.saturating_pow(self.empty_provider_searches.saturating_sub(1)))
+            .min(SEARCH_RETRY_MAX);
+        self.search_retry_at = Some(Instant::now() + delay);
+        self.send_system_message(format!(
+            "🔍 No peers in room '{}' yet - still searching, next try in {}s",
+            room,
+            delay.as_secs()
+        ));
+    }
+
+    // When an empty room is searched again, for the node loop's timer
+    pub fn search_retry_at(&self) -> Option<Instant> {
+        self.search_retry_at
+    }
+
+    pub fn retry_room_search(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if self.search_retry_at.take().is_none() {
+            return;
+        }
+        // Someone showed up since the retry was scheduled
+        if !self.room_members.is_empty() {
+            return;
+        }
+        self.search_room_peers(swarm, false);
+    }
+
+    // Connected peers subscribed to the room's topic
+    fn room_member_count(&self, swarm: &Swarm<ChatBehaviour>, topic: &gossipsub::TopicHash) -> usize {
+        swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&topic))
+            .count()
+    }
+
+    fn provide_directory(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .kad
+            .start_providing(kad::RecordKey::new(&directory::DIRECTORY_KEY))
+        {
+            warn!("Failed to list room in the directory: {}", e);
+            self.send_system_message(format!("⚠ Failed to list room in the public directory: {}", e));
+        }
+    }
+
+    // Our signed listing for the current room, if it is public
+    fn directory_listing(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<RoomListing> {
+        let (true, Some(topic), Some(room), Some(keypair)) =
+            (self.room_public, &self.current_room, &self.current_room_name, &self.keypair)
+        else {
+            return Vec::new();
+        };
+        let members = self.room_member_count(swarm, &topic.hash()) as u32 + 1;
+        match RoomListing::sign(room, members, keypair) {
+            Ok(listing) => vec![listing],
+            Err(e) => {
+                warn!("{}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    // Find public rooms through the peers listing them in the DHT; the merged
+    // rooms are sent on `reply` once every provider answered or the lookup timed out
+    pub fn list_public_rooms(&mut self, swarm: &mut Swarm<ChatBehaviour>, hidden: HashSet<String>, reply: oneshot::Sender<Vec<PublicRoom>>) {
+        let query_id = swarm
+            .behaviour_mut()
+            .kad
+            .get_providers(kad::RecordKey::new(&directory::DIRECTORY_KEY));
+        self.directory_lookups.insert(query_id, DirectoryLookup {
+            listings: self.directory_listing(swarm),
+            requests: HashSet::new(),
+            hidden,
+            searching: true,
+            deadline: Instant::now() + DIRECTORY_LOOKUP_TIMEOUT,
+            reply,
+        });
+    }
+
+    fn handle_directory_providers(&mut self, swarm: &mut Swarm<ChatBehaviour>, id: kad::QueryId, result: kad::GetProvidersResult) {
+        let Some(lookup) = self.directory_lookups.get_mut(&id) else {
+            return;
+        };
+        match result {
+            Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                for peer_id in providers {
+                    if peer_id == self.peer_id || lookup.requests.len() >= MAX_DIRECTORY_REQUESTS {
+                        continue;
+                    }
+                    let request_id = swarm.behaviour_mut().directory.send_request(&peer_id, DirectoryRequest {});
+                    lookup.requests.insert(request_id);
+                }
+            }
+            Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => lookup.searching = false,
+            Err(e) => {
+                warn!("Public room search failed: {}", e);
+                lookup.searching = false;
+            }
+        }
+        self.finish_directory_lookups(false);
+    }
+
+    fn handle_directory_response(&mut self, peer: PeerId, request_id: request_response::OutboundRequestId, response: Option<DirectoryResponse>) {
+        let Some(lookup) = self.directory_lookups.values_mut().find(|lookup| lookup.requests.contains(&request_id)) else {
+            return;
+        };
+        lookup.requests.remove(&request_id);
+        for listing in response.into_iter().flat_map(|response| response.rooms).take(directory::MAX_PUBLIC_ROOMS) {
+            match listing.verify(&peer) {
+                Ok(()) => lookup.listings.push(listing),
+                Err(e) => info!("Ignoring room listing from {}: {}", peer, e),
+            }
+        }
+        self.finish_directory_lookups(false);
+    }
+
+    // When the next public room lookup gives up waiting, for the node loop's timer
+    pub fn directory_deadline(&self) -> Option<Instant> {
+        self.directory_lookups.values().map(|lookup| lookup.deadline).min()
+    }
+
+    pub fn expire_directory_lookups(&mut self) {
+        self.finish_directory_lookups(true);
+    }
+
+    // Answers lookups with nothing left to wait for, or every overdue one
+    // when `expired` is set
+    fn finish_directory_lookups(&mut self, expired: bool) {
+        let now = Instant::now();
+        let done: Vec<kad::QueryId> = self
+            .directory_lookups
+            .iter()
+            .filter(|(_, lookup)| (!lookup.searching && lookup.requests.is_empty()) || (expired && now >= lookup.deadline))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in done {
+            if let Some(lookup) = self.directory_lookups.remove(&id) {
+                let rooms = directory::merge_listings(&lookup.listings, &lookup.hidden);
+                info!("Public room lookup found {} rooms", rooms.len());
+                let _ = lookup.reply.send(rooms);
+            }
+        }
+    }
+
+    // Time a closest-peers lookup for `key`; the result is sent on `reply`
+    // once the query completes or times out
+    pub fn benchmark_dht_lookup(&mut self, swarm: &mut Swarm<ChatBehaviour>, key: String, reply: oneshot::Sender<DhtLookupResult>) {
+        let query_id = swarm.behaviour_mut().kad.get_closest_peers(key.as_bytes().to_vec());
+        self.dht_benchmarks.insert(query_id, DhtBenchmark {
+            key,
+            started: Instant::now(),
+            reply,
+        });
+    }
+
+    // Estimate how many peers are in the DHT from lookups of random keys;
+    // the result is sent on `reply` once they all finish
+    pub fn estimate_network_size(&mut self, swarm: &mut Swarm<ChatBehaviour>, reply: oneshot::Sender<NetworkSizeEstimate>) {
+        if let Some(probe) = &mut self.network_size_probe {
+            probe.replies.push(reply);
+            return;
+        }
+        
+        let pending = (0..NETWORK_SIZE_LOOKUPS)
+            .map(|_| swarm.behaviour_mut().kad.get_closest_peers(PeerId::random()))
+            .collect();
+        let now = Instant::now();
+        self.network_size_probe = Some(NetworkSizeProbe {
+            started: now,
+            deadline: Some(now + self.interactive_search_timeout),
+            pending,
+            samples: Vec::new(),
+            replies: vec![reply],
+        });
+    }
+
+    fn record_network_size_sample(&mut self, swarm: &mut Swarm<ChatBehaviour>, id: kad::QueryId, key: &[u8], peers: &[PeerId]) {
+        let Some(probe) = &mut self.network_size_probe else {
+            return;
+        };
+        probe.pending.remove(&id);
+        probe.samples.push(netsize::distances(key, peers));
+        if !probe.pending.is_empty() {
+            return;
+        }
+        
+        let Some(probe) = self.network_size_probe.take() else {
+            return;
+        };
+        let result = NetworkSizeEstimate {
+            size: netsize::estimate(&probe.samples),
+            lookups: probe.samples.len(),
+            peers_sampled: probe.samples.iter().map(Vec::len).sum(),
+            routing_table_peers: Self::dht_peer_count(swarm),
+            elapsed_ms: probe.started.elapsed().as_millis() as u64,
+        };
+        info!("Network size estimate: {:?}", result);
+        if let Some(size) = &result.size {
+            self.event_log.push(
+                LogLevel::Info,
+                "network_size",
+                format!("DHT size estimated at {} peers ({} to {})", size.estimate, size.low, size.high),
+            );
+        }
+        for reply in probe.replies {
+            let _ = reply.send(result.clone());
+        }
+    }
+
+    pub fn network_size_deadline(&self) -> Option<Instant> {
+        self.network_size_probe.as_ref().and_then(|probe| probe.deadline)
+    }
+
+    // Cut the estimate's lookups short; each then reports what it found so far
+    pub fn expire_network_size_probe(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some(probe) = &mut self.network_size_probe else {
+            return;
+        };
+        if probe.deadline.is_none_or(|deadline| deadline > Instant::now()) {
+            return;
+        }
+        probe.deadline = None;
+        for id in &probe.pending {
+            if let Some(mut query) = swarm.behaviour_mut().kad.query_mut(id) {
+                query.finish();
+            }
+        }
+    }
+
+    pub async fn send_message(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        message: String,
+        content_type: ContentType,
+    ) -> Result<(), CommandError> {
+        // Check if we're in a room
+        let topic = self.current_room.clone().ok_or(CommandError::RoomNotJoined)?;
+        
+        let id = uuid::Uuid::new_v4().to_string();
+        let stamp = MessageStamp {
+            lamport: self.tick_clock(),
+            sent_at: chrono::Utc::now().to_rfc3339(),
+            sequence: self.next_sequence(&topic.hash()),
+        };
+        let encoded = MessageEnvelope::encode_parts(&id, content_type, &message, &stamp, self.max_transmit_size - PUBLISH_OVERHEAD)?;
+        let payloads = self.seal_payloads(&encoded)?;
+        
+        // Publish message to gossipsub topic, one part at a time for long ones
+        match self.publish_parts(swarm, &topic, stamp.sequence, payloads) {
+            Ok(()) => {
+                let topic = topic.hash();
+                let recipients = swarm
+                    .behaviour()
+                    .gossipsub
+                    .all_peers()
+                    .filter(|(_, topics)| topics.contains(&&topic))
+                    .map(|(peer_id, _)| *peer_id)
+                    .collect();
+                
+                // Publishing succeeds right after joining even before the mesh
+                // has formed; keep such messages to send again
+                let recipients_estimate = swarm.behaviour().gossipsub.mesh_peers(&topic).count() as u32;
+                if recipients_estimate == 0 {
+                    info!("Message {} published with no mesh peers yet", id);
+                }
+                self.track_delivery(id.clone(), topic, recipients, awaiting_mesh(recipients_estimate, encoded));
+                
+                // Echo message back to UI as sent
+                let _ = self.message_tx.send(ChatMessage {
+                    id,
+                    from: "You".to_string(),
+                    content: message,
+                    timestamp: stamp.sent_at,
+                    is_self: true,
+                    is_direct: false,
+                    room: self.current_room_name.clone(),
+                    content_type,
+                    delivered_to: Some(0),
+                    recipients_estimate: Some(recipients_estimate),
+                    attachment: None,
+                    lamport: stamp.lamport,
+                    recovered: false,
+                });
+                Ok(())
+            }
+            // Nobody to send to yet; keep it until the room has peers
+            Err(gossipsub::PublishError::InsufficientPeers) => {
+                let entry = OutboxEntry {
+                    id: id.clone(),
+                    room: self.current_room_name.clone().unwrap_or_default(),
+                    content: message.clone(),
+                    content_type,
+                    created_at: stamp.sent_at.clone(),
+                    lamport: stamp.lamport,
+                };
+                info!("No peers in room '{}', queueing message {}", entry.room, id);
+                self.queue_outgoing(entry);
+                
+                let _ = self.message_tx.send(ChatMessage {
+                    id,
+                    from: "You".to_string(),
+                    content: message,
+                    timestamp: stamp.sent_at,
+                    is_self: true,
+                    is_direct: false,
+                    room: self.current_room_name.clone(),
+                    content_type,
+                    delivered_to: Some(0),
+                    recipients_estimate: Some(0),
+                    attachment: None,
+                    lamport: stamp.lamport,
+                    recovered: false,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Failed to publish message: {}", e);
+                Err(CommandError::PublishFailed { reason: e.to_string() })
+            }
+        }
+    }
+
+    // Send the same message to every room we're in, carrying on past rooms
+    // that fail. The node is in one room at a time for now, so this covers
+    // at most one room until several can be joined.
+    pub async fn broadcast_to_all_rooms(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        message: String,
+        content_type: ContentType,
+    ) -> Result<Vec<RoomBroadcast>, CommandError> {
+        let rooms: Vec<(String, gossipsub::TopicHash)> = self
+            .current_room_name
+            .clone()
+            .zip(self.current_room.as_ref().map(|topic| topic.hash()))
+            .into_iter()
+            .collect();
+        if rooms.is_empty() {
+            return Err(CommandError::RoomNotJoined);
+        }
+        
+        let mut results = Vec::new();
+        for (room, topic) in rooms {
+            let has_peers = swarm.behaviour().gossipsub.all_peers().any(|(_, topics)| topics.contains(&&topic));
+            let (delivered, error) = match self.send_message(swarm, message.clone(), content_type).await {
+                Ok(()) if has_peers => (true, None),
+                Ok(()) => (false, Some("No peers in the room; queued to send later".to_string())),
+                Err(e) => (false, Some(e.to_string())),
+            };
+            results.push(RoomBroadcast { room, delivered, error });
+        }
+        Ok(results)
+    }
+
+    // Share a file in the current room. Small files go inline, as do ones up
+    // to MAX_INLINE_SIZE when `inline` is set; peers fetch larger ones from
+    // us, so they stay in the attachment store.
+    pub fn send_attachment(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        data: Vec<u8>,
+        mime: String,
+        name: Option<String>,
+        caption: String,
+        inline: bool,
+    ) -> Result<(), CommandError> {
+        let topic = self.current_room.clone().ok_or(CommandError::RoomNotJoined)?;
+        if inline && data.len() > attachments::MAX_INLINE_SIZE {
+            return Err(CommandError::invalid_input(format!(
+                "Inline attachment is too large ({} KiB, the limit is {} KiB) - send it as a file instead",
+                data.len().div_ceil(1024),
+                attachments::MAX_INLINE_SIZE / 1024
+            )));
+        }
+        if data.len() > attachments::MAX_ATTACHMENT_SIZE {
+            return Err(CommandError::invalid_input(format!(
+                "File is too large ({} KiB, the limit is {} KiB)",
+                data.len() / 1024,
+                attachments::MAX_ATTACHMENT_SIZE / 1024
+            )));
+        }
+        let store = self
+            .attachments
+            .as_ref()
+            .ok_or_else(|| CommandError::internal("Attachment storage is not available"))?;
+        let mut attachment = Attachment::new(&data, &mime, name, inline);
+        let path = store.save(&attachment.sha256, &data).map_err(CommandError::internal)?;
+        let caption = if caption.trim().is_empty() { attachment.placeholder() } else { caption };
+        
+        let id = uuid::Uuid::new_v4().to_string();
+        let stamp = MessageStamp {
+            lamport: self.tick_clock(),
+            sent_at: chrono::Utc::now().to_rfc3339(),
+            sequence: self.next_sequence(&topic.hash()),
+        };
+        let encoded = MessageEnvelope::encode_attachment(&id, &caption, &stamp, attachment.for_wire());
+        if encoded.len() > self.max_transmit_size - PUBLISH_OVERHEAD {
+            return Err(CommandError::invalid_input(format!(
+                "Attachment and caption don't fit in one {} KiB message",
+                self.max_transmit_size / 1024
+            )));
+        }
+        let payloads = self.seal_payloads(std::slice::from_ref(&encoded))?;
+        self.publish_parts(swarm, &topic, stamp.sequence, payloads).map_err(|e| {
+            warn!("Failed to publish attachment: {}", e);
+            CommandError::PublishFailed { reason: e.to_string() }
+        })?;
+        
+        let topic = topic.hash();
+        let recipients = swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        let recipients_estimate = swarm.behaviour().gossipsub.mesh_peers(&topic).count() as u32;
+        self.track_delivery(id.clone(), topic, recipients, awaiting_mesh(recipients_estimate, vec![encoded]));
+        
+        attachment.path = Some(path.display().to_string());
+        let _ = self.message_tx.send(ChatMessage {
+            id,
+            from: "You".to_string(),
+            content: caption,
+            timestamp: stamp.sent_at,
+            is_self: true,
+            is_direct: false,
+            room: self.current_room_name.clone(),
+            content_type: ContentType::Plain,
+            delivered_to: Some(0),
+            recipients_estimate: Some(recipients_estimate),
+            attachment: Some(attachment),
+            lamport: stamp.lamport,
+            recovered: false,
+        });
+        Ok(())
+    }
+
+    // Saves the bytes of an inline attachment; one whose bytes don't match
+    // its hash is dropped from the message
+    fn cache_inline_attachment(&self, mut attachment: Attachment) -> Option<Attachment> {
+        let Some(encoded) = &attachment.data else {
+            attachment.path = self
+                .attachments
+                .as_ref()
+                .and_then(|store| store.cached(&attachment.sha256))
+                .map(|path| path.display().to_string());
+            return Some(attachment);
+        };
+        let data = BASE64
+            .decode(encoded)
+            .ok()
+            .filter(|data| data.len() <= attachments::MAX_INLINE_SIZE && data.len() as u64 == attachment.size);
+        let Some(data) = data else {
+            warn!("Dropping malformed inline attachment {}", attachment.sha256);
+            return None;
+        };
+        if attachments::sha256_hex(&data) != attachment.sha256 {
+            warn!("Dropping inline attachment whose content doesn't match {}", attachment.sha256);
+            return None;
+        }
+        if let Some(store) = &self.attachments {
+            match store.save(&attachment.sha256, &data) {
+                Ok(path) => attachment.path = Some(path.display().to_string()),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        Some(attachment)
+    }
+
+    // Download an attachment from the peer that sent it; replies with the
+    // path of the local copy, right away if we already have one
+    pub fn fetch_attachment(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        peer_id: PeerId,
+        sha256: String,
+        reply: oneshot::Sender<Result<String, CommandError>>,
+    ) {
+        let Some(store) = &self.attachments else {
+            let _ = reply.send(Err(CommandError::internal("Attachment storage is not available")));
+            return;
+        };
+        if let Some(path) = store.cached(&sha256) {
+            let _ = reply.send(Ok(path.display().to_string()));
+            return;
+        }
+        let request_id = swarm.behaviour_mut().files.send_request(&peer_id, FileRequest { sha256: sha256.clone() });
+        self.attachment_fetches.insert(request_id, AttachmentFetch { sha256, reply });
+    }
+
+    fn finish_attachment_fetch(&mut self, peer: PeerId, request_id: request_response::OutboundRequestId, response: Result<FileResponse, String>) {
+        let Some(fetch) = self.attachment_fetches.remove(&request_id) else {
+            return;
+        };
+        let result = response
+            .and_then(|response| response.data.ok_or_else(|| "the peer no longer has the file".to_string()))
+            .and_then(|data| BASE64.decode(data).map_err(|e| format!("bad base64: {}", e)))
+            .and_then(|data| {
+                if data.len() > attachments::MAX_ATTACHMENT_SIZE {
+                    return Err("file is larger than allowed".to_string());
+                }
+                let store = self.attachments.as_ref().ok_or("attachment storage is not available")?;
+                store.save(&fetch.sha256, &data)
+            });
+        let result = match result {
+            Ok(path) => {
+                info!("Fetched attachment {} from {}", fetch.sha256, peer);
+                Ok(path.display().to_string())
+            }
+            Err(reason) => {
+                warn!("Failed to fetch attachment {} from {}: {}", fetch.sha256, peer, reason);
+                Err(CommandError::AttachmentUnavailable { reason })
+            }
+        };
+        let _ = fetch.reply.send(result);
+    }
+
+    pub fn set_messages_paused(&mut self, paused: bool) {
+        if paused == self.messages_paused {
+            return;
+        }
+        if paused {
+            self.send_system_message("🔕 Messages paused - new room messages will show when you resume".to_string());
+            self.messages_paused = true;
+            return;
+        }
+        
+        self.messages_paused = false;
+        let held = self.held_messages.len();
+        sort_for_display(self.held_messages.make_contiguous());
+        for message in std::mem::take(&mut self.held_messages) {
+            let _ = self.message_tx.send(message);
+        }
+        let dropped = std::mem::take(&mut self.held_messages_dropped);
+        if dropped > 0 {
+            self.send_system_message(format!(
+                "🔔 Messages resumed - {} received while paused, {} older ones dropped",
+                held, dropped
+            ));
+        } else {
+            self.send_system_message(format!("🔔 Messages resumed - {} received while paused", held));
+        }
+    }
+
+    // Oldest held messages make way once MAX_HELD_MESSAGES is reached
+    fn hold_message(&mut self, message: ChatMessage) {
+        if self.held_messages.len() >= MAX_HELD_MESSAGES {
+            self.held_messages.pop_front();
+            self.held_messages_dropped += 1;
+        }
+        self.held_messages.push_back(message);
+    }
+
+    fn queue_outgoing(&mut self, entry: OutboxEntry) {
+        let _ = self.event_tx.send(NodeEvent::MessageStatus(MessageStatus {
+            message_id: entry.id.clone(),
+            room: Some(entry.room.clone()),
+            status: MessageState::Queued,
+            recipients_estimate: 0,
+        }));
+        let _ = self.event_tx.send(NodeEvent::MessageQueued(entry.clone()));
+        self.outbox.push(entry);
+    }
+
+    // Messages queued by an earlier run, oldest first
+    pub fn load_outbox(&mut self, entries: Vec<OutboxEntry>) {
+        if !entries.is_empty() {
+            self.send_system_message(format!("📤 {} unsent message(s) will be sent once their room has peers", entries.len()));
+        }
+        self.outbox = entries;
+    }
+
+    // Publish queued messages for the current room once it has mesh peers,
+    // and give up on ones older than `outbox_max_age`
+    pub fn flush_outbox(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let max_age = self.outbox_max_age;
+        let (expired, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.outbox)
+            .into_iter()
+            .partition(|entry| entry.is_expired(max_age));
+        self.outbox = waiting;
+        for entry in expired {
+            info!("Giving up on queued message {} in '{}'", entry.id, entry.room);
+            let _ = self.event_tx.send(NodeEvent::MessageStatus(MessageStatus {
+                message_id: entry.id,
+                room: Some(entry.room),
+                status: MessageState::Failed,
+                recipients_estimate: 0,
+            }));
+        }
+        
+        let (Some(topic), Some(room)) = (self.current_room.clone(), self.current_room_name.clone()) else {
+            return;
+        };
+        let mesh_size = swarm.behaviour().gossipsub.mesh_peers(&topic.hash()).count() as u32;
+        if mesh_size == 0 || !self.outbox.iter().any(|entry| entry.room == room) {
+            return;
+        }
+        
+        // Send in the order they were written; stop at the first failure so
+        // later messages don't overtake it
+        let (pending, other_rooms): (Vec<_>, Vec<_>) = std::mem::take(&mut self.outbox)
+            .into_iter()
+            .partition(|entry| entry.room == room);
+        self.outbox = other_rooms;
+        let mut pending = pending.into_iter();
+        for entry in pending.by_ref() {
+            let stamp = MessageStamp {
+                lamport: entry.lamport,
+                sent_at: entry.created_at.clone(),
+                sequence: self.next_sequence(&topic.hash()),
+            };
+            let published = MessageEnvelope::encode_parts(&entry.id, entry.content_type, &entry.content, &stamp, self.max_transmit_size - PUBLISH_OVERHEAD)
+                .and_then(|encoded| self.seal_payloads(&encoded))
+                .and_then(|payloads| {
+                    self.publish_parts(swarm, &topic, stamp.sequence, payloads)
+                        .map_err(|e| CommandError::PublishFailed { reason: e.to_string() })
+                });
+            if let Err(e) = published {
+                info!("Couldn't send queued message {} yet: {}", entry.id, e);
+                self.outbox.push(entry);
+                break;
+            }
+            
+            info!("Sent queued message {} to {} mesh peers", entry.id, mesh_size);
+            let recipients = swarm
+                .behaviour()
+                .gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&&topic.hash()))
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            self.track_delivery(entry.id.clone(), topic.hash(), recipients, Vec::new());
+            let _ = self.event_tx.send(NodeEvent::MessageStatus(MessageStatus {
+                message_id: entry.id,
+                room: Some(room.clone()),
+                status: MessageState::Sent,
+                recipients_estimate: mesh_size,
+            }));
+        }
+        self.outbox.extend(pending);
+    }
+
+    // Encrypt the payloads for passphrase-protected rooms
+    fn seal_payloads(&self, payloads: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, CommandError> {
+        let Some(key) = &self.room_key else {
+            return Ok(payloads.to_vec());
+        };
+        payloads
+            .iter()
+            .map(|payload| key.encrypt(payload))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                warn!("{}", e);
+                CommandError::PublishFailed { reason: e.to_string() }
+            })
+    }
+
+    // Publish again the messages that went out before the room's mesh formed,
+    // unless someone acknowledged them in the meantime
+    pub fn resend_awaiting_mesh(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some(topic) = self.current_room.clone() else {
+            return;
+        };
+        let mesh_size = swarm.behaviour().gossipsub.mesh_peers(&topic.hash()).count() as u32;
+        if mesh_size == 0 {
+            return;
+        }
+        
+        let waiting: Vec<_> = self
+            .pending_deliveries
+            .iter_mut()
+            .filter_map(|(id, pending)| Some((id.clone(), pending.room.clone(), pending.take_resend(&topic.hash())?)))
+            .collect();
+        for (id, room, encoded) in waiting {
+            // Peers that already got the first copy drop this one by its
+            // message ID
+            let published = self.seal_payloads(&encoded).and_then(|payloads| {
+                payloads.into_iter().try_for_each(|payload| {
+                    swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(topic.clone(), payload)
+                        .map(|_| ())
+                        .map_err(|e| CommandError::PublishFailed { reason: e.to_string() })
+                })
+            });
+            if let Err(e) = published {
+                info!("Couldn't resend message {} yet: {}", id, e);
+                if let Some(pending) = self.pending_deliveries.get_mut(&id) {
+                    pending.awaiting_mesh = encoded;
+                }
+                continue;
+            }
+            
+            info!("Resent message {} to {} mesh peers", id, mesh_size);
+            let _ = self.event_tx.send(NodeEvent::MessageStatus(MessageStatus {
+                message_id: id,
+                room,
+                status: MessageState::Sent,
+                recipients_estimate: mesh_size,
+            }));
+        }
+    }
+
+    fn track_delivery(&mut self, id: String, topic: gossipsub::TopicHash, recipients: HashSet<PeerId>, awaiting_mesh: Vec<Vec<u8>>) {
+        if self.pending_deliveries.len() >= MAX_TRACKED_DELIVERIES {
+            let oldest = self
+                .pending_deliveries
+                .iter()
+                .min_by_key(|(_, pending)| pending.sent_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.pending_deliveries.remove(&oldest);
+            }
+        }
+        self.pending_deliveries.insert(id, PendingDelivery {
+            room: self.current_room_name.clone(),
+            topic,
+            recipients,
+            acked: HashSet::new(),
+            sent_at: Instant::now(),
+            awaiting_mesh,
+        });
+    }
+
+    // Count each peer once, and only peers that were in the room when we sent
+    // the message or are in it now; members leaving later keep their receipt
+    fn record_delivery(&mut self, swarm: &Swarm<ChatBehaviour>, peer: PeerId, message_id: String) {
+        let Some(pending) = self.pending_deliveries.get_mut(&message_id) else {
+            return;
+        };
+        let member = pending.recipients.contains(&peer)
+            || swarm
+                .behaviour()
+                .gossipsub
+                .all_peers()
+                .any(|(peer_id, topics)| *peer_id == peer && topics.contains(&&pending.topic));
+        if !member || !pending.acked.insert(peer) {
+            return;
+        }
+        
+        let _ = self.event_tx.send(NodeEvent::MessageDelivered(MessageDelivered {
+            message_id,
+            room: pending.room.clone(),
+            delivered_to: pending.acked.len() as u32,
+        }));
+    }
+
+    // Drop long messages whose remaining parts never arrived, telling the
+    // user rather than losing them silently
+    pub fn expire_partial_messages(&mut self) {
+        let dropped = self.reassembler.expire();
+        if dropped > 0 {
+            info!("Dropped {} incomplete messages", dropped);
+            self.send_system_message(format!(
+                "⚠ {} long message(s) never fully arrived and were dropped",
+                dropped
+            ));
+        }
+    }
+
+    pub fn expire_deliveries(&mut self) {
+        self.pending_deliveries
+            .retain(|_, pending| pending.sent_at.elapsed() < DELIVERY_TRACKING_WINDOW);
+    }
+
+    // The caller has already checked that `peer` isn't us
+    pub fn send_to_peer(&self, swarm: &mut Swarm<ChatBehaviour>, peer: PeerId, content: String) {
+        let peer_id = peer.to_string();
+        
+        // The request-response behaviour dials the peer itself and queues the
+        // request until the connection is up
+        if !swarm.is_connected(&peer) {
+            info!("Not connected to {}, dialing before direct message", peer);
+            self.send_system_message(format!("🔗 Dialing {} for direct message...", self.short_peer_id(&peer_id)));
+        }
+        
+        let timestamp = chrono::Utc::now().to_rfc3339();
+        swarm.behaviour_mut().direct.send_request(&peer, DirectMessage {
+            content: content.clone(),
+            timestamp: timestamp.clone(),
+        });
+        
+        // Echo message back to UI as sent
+        let _ = self.message_tx.send(ChatMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            from: format!("You → {}", self.short_peer_id(&peer_id)),
+            content,
+            timestamp,
+            is_self: true,
+            is_direct: true,
+            room: None,
+            content_type: ContentType::Plain,
+            delivered_to: None,
+            recipients_estimate: None,
+            attachment: None,
+            lamport: self.tick_clock(),
+            recovered: false,
+        });
+    }
+
+    pub fn get_addresses(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<String> {
+        let addrs: Vec<Multiaddr> = swarm
+            .listeners()
+            .filter(|addr| !is_relay_addr(addr))
+            .cloned()
+            .collect();
+        
+        // Filter to only public IPv6 addresses (removes fe80::, ::1, etc.)
+        let filtered = filter_ipv6_public_addrs(&addrs);
+        
+        filtered
+            .iter()
+            .map(|addr| self.with_peer_id(addr))
+            .collect()
+    }
+
+    pub fn get_external_addresses(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<String> {
+        // Addresses confirmed reachable from outside
+        swarm
+            .external_addresses()
+            .map(|addr| self.with_peer_id(addr))
+            .collect()
+    }
+
+    pub fn get_relay_addresses(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<String> {
+        // Listeners backed by a circuit reservation on a relay
+        swarm
+            .listeners()
+            .filter(|addr| is_relay_addr(addr))
+            .map(|addr| self.with_peer_id(addr))
+            .collect()
+    }
+
+    fn with_peer_id(&self, addr: &Multiaddr) -> String {
+        // Relay and external addresses may already end in our peer ID
+        if matches!(addr.iter().last(), Some(libp2p::multiaddr::Protocol::P2p(_))) {
+            addr.to_string()
+        } else {
+            format!("{}/p2p/{}", addr, self.peer_id)
+        }
+    }
+
+    pub fn connect_to_peer(&mut self, swarm: &mut Swarm<ChatBehaviour>, addr: String) {
+        info!("Attempting to connect to peer at: {}", addr);
+        
+        // Parse the multiaddr
+        match addr.parse::<Multiaddr>() {
+            Ok(multiaddr) if is_own_address(&multiaddr, &self.peer_id) => {
+                self.send_system_message(format!("⚠ {} is your own address", addr));
+            }
+            Ok(multiaddr) => {
+                // Try to dial the address
+                match swarm.dial(multiaddr.clone()) {
+                    Ok(_) => {
+                        self.send_system_message(format!("🔗 Dialing peer at {}...", addr));
+                    }
+                    Err(e) => {
+                        warn!("Failed to dial peer: {}", e);
+                        self.send_system_message(format!("⚠ Failed to dial peer: {}", e));
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Invalid multiaddr: {}", e);
+                self.send_system_message(format!("⚠ Invalid address format: {}", e));
+            }
+        }
+    }
+
+    // Seeds the DHT routing table with a known peer, e.g. on a self-hosted
+    // network without bootstrap nodes. Returns false if the routing table
+    // already had the address.
+    pub fn add_peer_address(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        peer_id: PeerId,
+        addr: Multiaddr,
+        dial: bool,
+    ) -> Result<bool, CommandError> {
+        if peer_id == self.peer_id {
+            return Err(CommandError::invalid_input("Cannot add your own peer ID"));
+        }
+        
+        let mut present = false;
+        if let Some(bucket) = swarm.behaviour_mut().kad.kbucket(peer_id) {
+            present = bucket
+                .iter()
+                .any(|entry| entry.node.key.preimage() == &peer_id && entry.node.value.iter().any(|a| *a == addr));
+        }
+        match swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone()) {
+            kad::RoutingUpdate::Success | kad::RoutingUpdate::Pending => {}
+            kad::RoutingUpdate::Failed => {
+                return Err(CommandError::internal("the routing table has no room for this peer"));
+            }
+        }
+        self.remember_address(peer_id, addr.clone());
+        
+        if !present {
+            self.send_system_message(format!("📇 Added {} for {} to the routing table", addr, self.display_name(&peer_id)));
+        }
+        if dial {
+            self.queue_dial(peer_id);
+        }
+        Ok(!present)
+    }
+
+    // Drop peers we no longer hold a connection to, in case a close event was
+    // missed or identify raced a disconnect
+    // Returns how many entries were removed
+    pub fn prune_stale_peers(&mut self, swarm: &Swarm<ChatBehaviour>) -> usize {
+        let stale: Vec<PeerId> = self
+            .connected_peers
+            .iter()
+            .filter(|(peer_id, info)| {
+                info.last_seen.elapsed() > STALE_PEER_TIMEOUT && !swarm.is_connected(peer_id)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        
+        for peer_id in &stale {
+            info!("Pruning stale peer entry for {}", peer_id);
+            self.connected_peers.remove(peer_id);
+        }
+        stale.len()
+    }
+
+    pub fn emit_peers_changed(&self) {
+        let _ = self.event_tx.send(NodeEvent::PeersChanged(self.get_connected_peers()));
+    }
+
+    // Number for our next message in `topic`, used up only once it's published
+    fn next_sequence(&self, topic: &gossipsub::TopicHash) -> Sequence {
+        Sequence {
+            epoch: self.sequence_epoch,
+            seq: self.sent_log.next_seq(topic.as_str()),
+        }
+    }
+
+    // Publishes each part of a message, stopping at the first failure. Once
+    // any part is out, peers have seen its sequence number, so it's used up
+    // even if a later part fails; keeping every part lets a retransmission
+    // fill in the rest.
+    fn publish_parts(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        topic: &gossipsub::IdentTopic,
+        sequence: Sequence,
+        payloads: Vec<Vec<u8>>,
+    ) -> Result<(), gossipsub::PublishError> {
+        let mut published = 0;
+        let result = payloads.iter().try_for_each(|payload| {
+            swarm.behaviour_mut().gossipsub.publish(topic.clone(), payload.clone())?;
+            published += 1;
+            Ok(())
+        });
+        if published > 0 {
+            self.sent_log.record(topic.hash().as_str(), sequence.seq, payloads);
+        }
+        result
+    }
+
+    pub fn retransmit_deadline(&self) -> Option<Instant> {
+        self.gap_tracker.next_deadline()
+    }
+
+    // Ask authors for room messages still missing after GAP_GRACE. The
+    // request-response behaviour dials them if the connection dropped.
+    pub fn request_retransmissions(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        for (peer, request) in self.gap_tracker.take_due(Instant::now()) {
+            info!(
+                "Asking {} for missed messages {}..={} in {}",
+                peer, request.from, request.to, request.topic
+            );
+            let topic = request.topic.clone();
+            let request_id = swarm.behaviour_mut().retransmit.send_request(&peer, request);
+            self.retransmit_requests.insert(request_id, topic);
+        }
+    }
+
+    // Only room members get our messages again, and only from this run's
+    // numbering; ranges we no longer have come back empty
+    fn answer_retransmit(&self, swarm: &Swarm<ChatBehaviour>, peer: &PeerId, request: &RetransmitRequest) -> RetransmitResponse {
+        let is_member = swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .any(|(member, topics)| member == peer && topics.iter().any(|topic| topic.as_str() == request.topic));
+        if !is_member || request.epoch != self.sequence_epoch || request.from > request.to {
+            info!("Ignoring retransmission request from {} for {}", peer, request.topic);
+            return RetransmitResponse { messages: Vec::new() };
+        }
+        self.sent_log.respond(request)
+    }
+
+    fn handle_retransmit_response(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        peer: PeerId,
+        request_id: request_response::OutboundRequestId,
+        response: RetransmitResponse,
+    ) {
+        let Some(topic) = self.retransmit_requests.remove(&request_id) else {
+            return;
+        };
+        let topic = gossipsub::TopicHash::from_raw(topic);
+        if !self.is_current_room(&topic) || self.muted_peers.contains(&peer) {
+            return;
+        }
+        info!("{} sent {} missed message part(s) again", peer, response.messages.len());
+        for message in response.messages {
+            match BASE64.decode(message) {
+                Ok(data) => self.receive_room_message(swarm, Some(peer), peer, &topic, data, true),
+                Err(e) => warn!("Dropping malformed retransmitted message from {}: {}", peer, e),
+            }
+        }
+    }
+
+    // A message for a room topic, from gossipsub or sent again by its author
+    // when we missed it
+    fn receive_room_message(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        source: Option<PeerId>,
+        propagation_source: PeerId,
+        topic: &gossipsub::TopicHash,
+        data: Vec<u8>,
+        recovered: bool,
+    ) {
+        // Private rooms are tagged with their display name rather than the topic hash
+        let Some(room) = self.room_topics.get(topic).cloned() else {
+            info!("Dropping message for a topic we never joined: {}", topic);
+            return;
+        };
+        let in_current_room = self.is_current_room(topic);
+        if in_current_room && source.is_some_and(|source| self.is_moderator_muted(&source)) {
+            info!("Hiding message from peer {:?} muted by the room owner", source);
+            return;
+        }
+        if let (Some(source), true) = (source, in_current_room) {
+            self.note_member_alive(source);
+        }
+        
+        // Decrypt messages in passphrase-protected rooms; anything that
+        // doesn't open with our key is dropped without telling the user
+        let data = match (&self.room_key, in_current_room) {
+            (Some(key), true) => match key.decrypt(&data) {
+                Some(plaintext) => plaintext,
+                None => {
+                    info!("Dropping undecryptable message from {}", propagation_source);
+                    return;
+                }
+            },
+            _ => data,
+        };
+        
+        let Some(MessageEnvelope { id, content_type, content, chunk, attachment, lamport, sent_at, sequence, .. }) = MessageEnvelope::decode(&data) else {
+            return;
+        };
+        
+        // Gaps in the author's numbering are asked for once GAP_GRACE passes
+        if let (Some(source), Some(sequence), true) = (source, sequence, in_current_room) {
+            self.gap_tracker.observe(topic.as_str(), source, sequence);
+        }
+        
+        // Hold parts of a long message back until all of them are in
+        let content = match (chunk, &id) {
+            (Some(header), Some(message_id)) => {
+                let key = format!("{}/{}", source.unwrap_or(propagation_source), message_id);
+                match self.reassembler.insert(&key, header, content) {
+                    Ok(Some(content)) => content,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Dropping part of message {}: {}", key, e);
+                        return;
+                    }
+                }
+            }
+            (Some(_), None) => {
+                info!("Dropping message part without a message ID from {}", propagation_source);
+                return;
+            }
+            (None, _) => content,
+        };
+        info!(target: CONTENT_LOG_TARGET, "Received {} message from {}: {}", content_type.as_str(), propagation_source, content);
+        let attachment = attachment
+            .and_then(|attachment| attachment.received(source.map(|source| source.to_string())))
+            .and_then(|attachment| self.cache_inline_attachment(attachment));
+        
+        // Acknowledge to the author, but don't dial them just for that
+        if let (Some(message_id), Some(source), true) = (&id, source, in_current_room) {
+            if swarm.is_connected(&source) {
+                swarm.behaviour_mut().receipts.send_request(&source, DeliveryReceipt { message_id: message_id.clone() });
+            }
+        }
+        
+        // A resend of a message we already showed, e.g. because our
+        // receipt didn't reach the author; acknowledged above but
+        // shown only once
+        if let Some(message_id) = &id {
+            let key = format!("{}/{}", source.unwrap_or(propagation_source), message_id);
+            if !self.note_message_seen(key) {
+                self.duplicate_messages += 1;
+                info!("Dropping duplicate of message {} from {}", message_id, propagation_source);
+                return;
+            }
+        }
+        
+        // Ordered by the sender's clock; messages from older clients
+        // go after everything we've seen
+        let local_time = self.observe_clock(lamport.unwrap_or_default());
+        
+        // Keep the author's ID so every member stores, orders and pins the
+        // message by the same one; only legacy envelopes lack it
+        let chat_message = ChatMessage {
+            id: id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            from: source.map_or_else(|| "Unknown".to_string(), |source| self.display_name(&source)),
+            content,
+            timestamp: sender_timestamp(sent_at),
+            is_self: false,
+            is_direct: false,
+            room: Some(room),
+            content_type,
+            delivered_to: None,
+            recipients_estimate: None,
+            attachment,
+            lamport: lamport.unwrap_or(local_time),
+            recovered,
+        };
+        // Send to frontend, or hold it back while messages are paused
+        if self.messages_paused {
+            self.hold_message(chat_message);
+        } else {
+            let _ = self.message_tx.send(chat_message);
+        }
+    }
+
+    pub async fn handle_event(&mut self, swarm: &mut Swarm<ChatBehaviour>, event: SwarmEvent<ChatBehaviourEvent>) {
+        if let Some(info) = event_peer(&event).and_then(|peer| self.connected_peers.get_mut(&peer)) {
+            info.last_seen = Instant::now();
+        }
+        self.record_metrics(&event);
+        
+        match event {
+            SwarmEvent::NewListenAddr { listener_id, address } => {
+                info!("Listening on {}", address);
+                self.send_system_message(format!("🎧 Listening on {}", address));
+                if let Some(reply) = self.pending_reservations.remove(&listener_id) {
+                    let _ = reply.send(Ok(self.with_peer_id(&address)));
+                }
+            }
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                let Some(relay_addr) = self.relay_listeners.remove(&listener_id) else {
+                    return;
+                };
+                let reason = match reason {
+                    Ok(()) => "listener closed".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                warn!("Relay listener on {} closed: {}", relay_addr, reason);
+                self.event_log.push(LogLevel::Warning, "relay", format!("Relay listener on {} closed: {}", relay_addr, reason));
+                
+                // A reservation that never came up is reported to the caller;
+                // one that was working is retried later
+                if let Some(reply) = self.pending_reservations.remove(&listener_id) {
+                    let _ = reply.send(Err(CommandError::RelayReservationFailed { reason }));
+                } else {
+                    self.send_system_message(format!("⚠ Lost relay reservation on {}: {} - will retry", relay_addr, reason));
+                    self.lost_relays.push(relay_addr);
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => {
+                // The local echo has already shown our own messages
+                if is_own_message(message.source, &self.peer_id) {
+                    info!("Dropping our own message {} received back from {}", message_id, propagation_source);
+                    return;
+                }
+                
+                // Gossipsub has already forwarded the message to the mesh by now,
+                // so muting only hides it locally
+                if message.source.is_some_and(|source| self.muted_peers.contains(&source)) {
+                    info!("Hiding message from muted peer {:?}", message.source);
+                    return;
+                }
+                
+                if self.is_presence_topic(&message.topic) {
+                    self.handle_presence(message.source, &message.data);
+                    return;
+                }
+                if self.is_moderation_topic(&message.topic) {
+                    self.handle_moderation(message.source, &message.data);
+                    return;
+                }
+                
+                self.receive_room_message(swarm, message.source, propagation_source, &message.topic, message.data, false);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                info!("Peer {} subscribed to topic: {}", peer_id, topic);
+                if self.is_presence_topic(&topic) || self.is_moderation_topic(&topic) {
+                    return;
+                }
+                if let Some(room) = self.current_room_name.clone().filter(|_| self.is_current_room(&topic)) {
+                    self.address_book.set_room(&peer_id, &room);
+                    self.note_member_alive(peer_id);
+                    swarm.behaviour_mut().keep_alive.set(peer_id, true);
+                }
+                self.send_system_message(format!("✓ Peer {} joined the room", self.display_name(&peer_id)));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                info!("Peer {} unsubscribed from topic: {}", peer_id, topic);
+                if self.is_presence_topic(&topic) || self.is_moderation_topic(&topic) {
+                    return;
+                }
+                // A clean leave needs no missed heartbeats to go offline
+                if self.is_current_room(&topic) {
+                    if let Some(member) = self.room_members.remove(&peer_id) {
+                        self.emit_presence_changed(peer_id, PresenceState::Offline, member.last_seen);
+                    }
+                    swarm.behaviour_mut().keep_alive.set(peer_id, false);
+                }
+                self.send_system_message(format!("✗ Peer {} left the room", self.display_name(&peer_id)));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Direct(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                info!(target: CONTENT_LOG_TARGET, "Received direct message from {}: {}", peer, request.content);
+                let _ = swarm.behaviour_mut().direct.send_response(channel, DirectAck {});
+                if self.muted_peers.contains(&peer) {
+                    return;
+                }
+                
+                let _ = self.message_tx.send(ChatMessage {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    from: self.display_name(&peer),
+                    content: request.content,
+                    timestamp: request.timestamp,
+                    is_self: false,
+                    is_direct: true,
+                    room: None,
+                    content_type: ContentType::Plain,
+                    delivered_to: None,
+                    recipients_estimate: None,
+                    attachment: None,
+                    lamport: self.tick_clock(),
+                    recovered: false,
+                });
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Receipts(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                let _ = swarm.behaviour_mut().receipts.send_response(channel, DirectAck {});
+                self.record_delivery(swarm, peer, request.message_id);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Files(request_response::Event::Message {
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            })) => {
+                let data = self
+                    .attachments
+                    .as_ref()
+                    .and_then(|store| store.load(&request.sha256))
+                    .filter(|data| data.len() <= attachments::MAX_ATTACHMENT_SIZE)
+                    .map(|data| BASE64.encode(data));
+                let _ = swarm.behaviour_mut().files.send_response(channel, FileResponse { data });
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Files(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            })) => {
+                self.finish_attachment_fetch(peer, request_id, Ok(response));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Files(request_response::Event::OutboundFailure { peer, request_id, error })) => {
+                self.finish_attachment_fetch(peer, request_id, Err(error.to_string()));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Directory(request_response::Event::Message {
+                message: request_response::Message::Request { channel, .. },
+                ..
+            })) => {
+                let rooms = self.directory_listing(swarm);
+                let _ = swarm.behaviour_mut().directory.send_response(channel, DirectoryResponse { rooms });
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Directory(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            })) => {
+                self.handle_directory_response(peer, request_id, Some(response));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Directory(request_response::Event::OutboundFailure { peer, request_id, error })) => {
+                info!("Directory request to {} failed: {}", peer, error);
+                self.handle_directory_response(peer, request_id, None);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Retransmit(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                let response = self.answer_retransmit(swarm, &peer, &request);
+                let _ = swarm.behaviour_mut().retransmit.send_response(channel, response);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Retransmit(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            })) => {
+                self.handle_retransmit_response(swarm, peer, request_id, response);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Retransmit(request_response::Event::OutboundFailure { peer, request_id, error })) => {
+                info!("Retransmission request to {} failed: {}", peer, error);
+                self.retransmit_requests.remove(&request_id);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Direct(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                warn!("Direct message to {} failed: {}", peer, error);
+                self.send_system_message(format!("⚠ Direct message to {} failed: {}", self.short_peer_id(&peer.to_string()), error));
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                if !self.mdns_enabled || self.mdns_paused() {
+                    info!("Ignoring {} mDNS discoveries while mDNS is off", peers.len());
+                    return;
+                }
+                for (peer_id, multiaddr) in peers {
+                    if peer_id == self.peer_id {
+                        continue;
+                    }
+                    info!("mDNS discovered peer: {} at {}", peer_id, multiaddr);
+                    self.remember_address(peer_id, multiaddr);
+                    
+                    // Queue this peer for dialing
+                    if self.queue_dial(peer_id) {
+                        self.report_system_event(SystemEvent::MdnsDiscovered { peer_id: peer_id.to_string() });
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
+                for (peer_id, multiaddr) in peers {
+                    self.discovered_peers.remove(&peer_id);
+                    if let Some(addrs) = self.known_addresses.get_mut(&peer_id) {
+                        addrs.retain(|a| a != &multiaddr);
+                    }
+                    info!("mDNS peer expired: {}", peer_id);
+                    self.report_system_event(SystemEvent::MdnsExpired { peer_id: peer_id.to_string() });
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                info!("Identified peer: {}", peer_id);
+                // Identify can land after the connection already closed
+                let identity = PeerIdentity {
+                    protocol_version: info.protocol_version.clone(),
+                    agent_version: info.agent_version.clone(),
+                    protocols: info.protocols.clone(),
+                };
+                
+                // Keep incompatible peers out of the room mesh; their messages
+                // may not be in a format we understand
+                if identity.is_incompatible() {
+                    warn!("Peer {} runs incompatible protocol {}", peer_id, identity.protocol_version);
+                    swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+                    self.send_system_message(format!(
+                        "⚠ Peer {} runs an incompatible chat version ({})",
+                        self.short_peer_id(&peer_id.to_string()),
+                        identity.agent_version
+                    ));
+                    let _ = self.event_tx.send(NodeEvent::IncompatiblePeer(IncompatiblePeer {
+                        peer_id: peer_id.to_string(),
+                        protocol_version: identity.protocol_version.clone(),
+                        agent_version: identity.agent_version.clone(),
+                    }));
+                } else if identity.is_chat_peer() {
+                    // They may have upgraded since we last saw them
+                    swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer_id);
+                }
+                
+                if identity.is_chat_peer() {
+                    self.address_book.record_seen(&peer_id, &info.listen_addrs);
+                }
+                if let Some(connection) = self.connected_peers.get_mut(&peer_id) {
+                    connection.listen_addrs = info.listen_addrs.iter().map(|a| a.to_string()).collect();
+                    connection.identity = Some(identity);
+                }
+                for addr in info.listen_addrs {
+                    self.remember_address(peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Error { peer_id, error, .. })) => {
+                warn!("Identify with {} failed: {}", peer_id, error);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                info!("Connected to peer: {} via {}", peer_id, endpoint.get_remote_address());
+                if let Some(test) = self.reachability_tests.remove(&connection_id) {
+                    self.finish_reachability_test(swarm, connection_id, test, Ok(peer_id));
+                }
+                if let Some(address) = self.take_connectivity_dial(connection_id) {
+                    swarm.close_connection(connection_id);
+                    self.finish_connectivity_step(swarm, ConnectivityStep::BootstrapDial, true, format!("Reached {} at {}", peer_id, address));
+                }
+                self.looked_up_peers.remove(&peer_id);
+                self.dials.connected(&peer_id);
+                
+                let details = ConnectionDetails {
+                    remote_addr: endpoint.get_remote_address().clone(),
+                    direction: if endpoint.is_dialer() {
+                        ConnectionDirection::Outbound
+                    } else {
+                        ConnectionDirection::Inbound
+                    },
+                    connected_since: chrono::Utc::now(),
+                };
+                self.connected_peers
+                    .entry(peer_id)
+                    .or_insert_with(ConnectionInfo::new)
+                    .connections
+                    .insert(connection_id, details);
+                
+                // Only an address we dialed is one we can dial again
+                if self.address_book.contains(&peer_id) {
+                    let addrs = endpoint.is_dialer().then(|| endpoint.get_remote_address());
+                    self.address_book.record_seen(&peer_id, addrs);
+                }
+                
+                // Check if this is a bootstrap peer
+                if self.unreported_disconnects.remove(&peer_id).is_some() {
+                    info!("Peer {} reconnected within the grace period", peer_id);
+                } else if self.bootstrap_peers.contains(&peer_id) {
+                    self.report_system_event(SystemEvent::BootstrapConnected { peer_id: peer_id.to_string() });
+                } else {
+                    self.report_system_event(SystemEvent::PeerConnected { peer_id: peer_id.to_string() });
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
+                info!("Connection {:?} to {} closed ({} remaining)", connection_id, peer_id, num_established);
+                if let Some(info) = self.connected_peers.get_mut(&peer_id) {
+                    info.connections.remove(&connection_id);
+                }
+                
+                // Only the last connection going away disconnects the peer
+                if num_established == 0 {
+                    self.connected_peers.remove(&peer_id);
+                    if self.reconnect_grace.is_zero() {
+                        self.report_system_event(SystemEvent::PeerDisconnected { peer_id: peer_id.to_string() });
+                    } else {
+                        self.unreported_disconnects.insert(peer_id, Instant::now());
+                    }
+                    self.note_disconnect(swarm, peer_id);
+                    // Redial explicit peers right away instead of waiting for
+                    // gossipsub's own check, which runs only every few minutes
+                    if self.explicit_peers.contains(&peer_id) && self.queue_dial(peer_id) {
+                        info!("Redialing explicit peer {}", peer_id);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Ping(ping::Event { peer, result: Err(e), .. })) => {
+                info!("Ping to {} failed: {}", peer, e);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(kad::Event::RoutingUpdated { peer, is_new_peer, .. })) => {
+                info!("Routing updated for peer: {}", peer);
+                if is_new_peer {
+                    self.report_system_event(SystemEvent::RoutingUpdated { peer_id: peer.to_string() });
+                }
+                // The first routable peer is enough to carry a queued announcement
+                if is_new_peer && Self::dht_peer_count(swarm) == 1 {
+                    self.flush_pending_announce(swarm);
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { id, result, stats, .. })) => {
+                match result {
+                    kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, num_remaining })) => {
+                        info!("Bootstrap successful with peer: {} ({} remaining)", peer, num_remaining);
+                        if num_remaining == 0 {
+                            self.event_log.push(LogLevel::Info, "bootstrap", "DHT bootstrap complete".to_string());
+                            self.dht_bootstrapped = true;
+                            self.send_system_message("✓ DHT bootstrap complete - internet discovery enabled".to_string());
+                            self.flush_pending_announce(swarm);
+                        }
+                    }
+                    kad::QueryResult::Bootstrap(Err(e)) => {
+                        warn!("Bootstrap error: {:?}", e);
+                        self.event_log.push(LogLevel::Error, "bootstrap", format!("DHT bootstrap failed: {}", e));
+                        self.send_system_message(format!("⚠ DHT bootstrap failed ({}) - internet discovery may be limited", e));
+                    }
+                    kad::QueryResult::GetProviders(result) if self.directory_lookups.contains_key(&id) => {
+                        self.handle_directory_providers(swarm, id, result);
+                    }
+                    // Late results of a search for a room we've left, or one
+                    // superseded by a discovery reset
+                    kad::QueryResult::GetProviders(_) if !self.provider_queries.contains_key(&id) => {
+                        info!("Ignoring result of cancelled provider search {:?}", id);
+                    }
+                    kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                        let room = self.provider_queries.get(&id).map(|query| query.room.clone()).unwrap_or_default();
+                        for peer_id in providers {
+                            let queued = self.is_dial_queued(&peer_id);
+                            match classify_provider(&peer_id, &self.peer_id, self.connected_peers.get(&peer_id), queued) {
+                                ProviderAction::Ignore => continue,
+                                // Providers identified as plain DHT nodes aren't room peers
+                                ProviderAction::NotChat => {
+                                    info!("Ignoring provider {} that doesn't speak {}", peer_id, CHAT_PROTOCOL);
+                                    continue;
+                                }
+                                ProviderAction::Known | ProviderAction::Dial => {}
+                            }
+                            
+                            if let Some(query) = self.provider_queries.get_mut(&id) {
+                                query.found += 1;
+                            }
+                            
+                            // Queue this peer for dialing
+                            if self.queue_dial(peer_id) {
+                                info!("Found provider (peer) in room '{}': {}", room, peer_id);
+                                self.send_system_message(format!(
+                                    "🔍 Found peer {} in room '{}', connecting...",
+                                    self.short_peer_id(&peer_id.to_string()),
+                                    room
+                                ));
+                            }
+                        }
+                    }
+                    kad::QueryResult::GetClosestPeers(lookup)
+                        if self.connectivity_test.as_ref().is_some_and(|test| test.query == Some(id)) =>
+                    {
+                        let (passed, detail) = match lookup {
+                            Ok(ok) if !ok.peers.is_empty() => {
+                                (true, format!("Found {} peers with {} requests", ok.peers.len(), stats.num_requests()))
+                            }
+                            Ok(_) => (false, "The lookup found no peers".to_string()),
+                            Err(kad::GetClosestPeersError::Timeout { peers, .. }) if !peers.is_empty() => {
+                                (true, format!("Found {} peers before the lookup timed out", peers.len()))
+                            }
+                            Err(e) => (false, format!("The lookup failed: {}", e)),
+                        };
+                        self.finish_connectivity_step(swarm, ConnectivityStep::DhtQuery, passed, detail);
+                    }
+                    kad::QueryResult::GetClosestPeers(lookup)
+                        if self.network_size_probe.as_ref().is_some_and(|probe| probe.pending.contains(&id)) =>
+                    {
+                        let (key, peers) = match lookup {
+                            Ok(kad::GetClosestPeersOk { key, peers }) => (key, peers),
+                            Err(kad::GetClosestPeersError::Timeout { key, peers }) => (key, peers),
+                        };
+                        let peers: Vec<PeerId> = peers.into_iter().map(|peer| peer.peer_id).collect();
+                        self.record_network_size_sample(swarm, id, &key, &peers);
+                    }
+                    kad::QueryResult::GetClosestPeers(lookup) if self.dht_benchmarks.contains_key(&id) => {
+                        if let Some(benchmark) = self.dht_benchmarks.remove(&id) {
+                            let (peers_found, timed_out) = match lookup {
+                                Ok(ok) => (ok.peers.len(), false),
+                                Err(kad::GetClosestPeersError::Timeout { peers, .. }) => (peers.len(), true),
+                            };
+                            let result = DhtLookupResult {
+                                key: benchmark.key,
+                                elapsed_ms: benchmark.started.elapsed().as_millis() as u64,
+                                peers_found,
+                                requests: stats.num_requests(),
+                                timed_out,
+                            };
+                            info!("DHT lookup benchmark: {:?}", result);
+                            let _ = benchmark.reply.send(result);
+                        }
+                    }
+                    kad::QueryResult::GetClosestPeers(Ok(kad::GetClosestPeersOk { peers, .. }))
+                    | kad::QueryResult::GetClosestPeers(Err(kad::GetClosestPeersError::Timeout { peers, .. })) => {
+                        if let Some(target) = self.address_lookups.remove(&id) {
+                            if let Some(found) = peers.into_iter().find(|p| p.peer_id == target) {
+                                info!("DHT lookup found {} addresses for {}", found.addrs.len(), target);
+                                for addr in found.addrs {
+                                    self.remember_address(target, addr);
+                                }
+                            }
+                            // Retry the dial once, with whatever addresses we have now
+                            self.queue_dial(target);
+                        }
+                    }
+                    kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. })) => {
+                        if let Some(query) = self.provider_queries.remove(&id) {
+                            info!("Provider search in '{}' finished with {} peers", query.room, query.found);
+                            self.finish_provider_query(query, None);
+                        }
+                    }
+                    kad::QueryResult::GetProviders(Err(e)) => {
+                        if let Some(query) = self.provider_queries.remove(&id) {
+                            warn!("Provider search in '{}' failed: {}", query.room, e);
+                            self.finish_provider_query(query, Some(e.to_string()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                info!("NAT status changed from {:?} to {:?}", old, new);
+                self.event_log.push(LogLevel::Info, "nat", format!("NAT status changed from {:?} to {:?}", old, new));
+                self.update_relay_server(swarm, &new);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Relay(event)) => {
+                self.handle_relay_event(event);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::RelayClient(event)) => {
+                self.handle_relay_client_event(event);
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(event)) => {
+                self.handle_rendezvous_event(event);
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                let target = peer_id.map_or_else(|| "unknown peer".to_string(), |peer_id| peer_id.to_string());
+                self.event_log.push(LogLevel::Warning, "dial", format!("Dial to {} failed: {}", target, error));
+                if let Some(test) = self.reachability_tests.remove(&connection_id) {
+                    self.finish_reachability_test(swarm, connection_id, test, Err(error.to_string()));
+                }
+                if let Some(address) = self.take_connectivity_dial(connection_id) {
+                    let detail = format!("Could not reach {}: {}", address, error);
+                    self.finish_connectivity_step(swarm, ConnectivityStep::BootstrapDial, false, detail);
+                }
+                if let Some(peer_id) = peer_id {
+                    self.dials.failed(&peer_id);
+                }
+                if let DialError::Denied { cause } = &error {
+                    self.note_connection_denied(cause);
+                }
+            }
+            SwarmEvent::IncomingConnectionError { error: ListenError::Denied { cause }, .. } => {
+                self.note_connection_denied(&cause);
+            }
+            SwarmEvent::Dialing { peer_id, connection_id } => {
+                debug!("Dialing {:?} on connection {:?}", peer_id, connection_id);
+                self.report_system_event(SystemEvent::Dialing { peer_id: peer_id.map(|peer_id| peer_id.to_string()) });
+            }
+            SwarmEvent::IncomingConnection { send_back_addr, .. } => {
+                debug!("Incoming connection from {}", send_back_addr);
+                self.report_system_event(SystemEvent::IncomingConnection { address: send_back_addr.to_string() });
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_rendezvous_event(&mut self, event: rendezvous::client::Event) {
+        match event {
+            rendezvous::client::Event::Registered { namespace, ttl, .. } => {
+                if self.rendezvous_namespace.as_ref() != Some(&namespace) {
+                    return;
+                }
+                info!("Registered in rendezvous namespace {} for {}s", namespace, ttl);
+                if self.rendezvous_refresh_at.is_none() {
+                    self.send_system_message("📍 Registered with rendezvous server".to_string());
+                }
+                self.rendezvous_refresh_at = Some(Instant::now() + rendezvous_refresh_delay(ttl));
+            }
+            rendezvous::client::Event::RegisterFailed { namespace, error, .. } => {
+                if self.rendezvous_namespace.as_ref() == Some(&namespace) {
+                    self.rendezvous_fallback(format!("registration failed: {:?}", error));
+                }
+            }
+            rendezvous::client::Event::Discovered { registrations, cookie, .. } => {
+                if cookie.namespace() != self.rendezvous_namespace.as_ref() {
+                    return;
+                }
+                self.rendezvous_cookie = Some(cookie);
+                
+                for registration in registrations {
+                    let peer_id = registration.record.peer_id();
+                    if peer_id == self.peer_id {
+                        continue;
+                    }
+                    for addr in registration.record.addresses() {
+                        self.remember_address(peer_id, addr.clone());
+                    }
+                    if self.queue_dial(peer_id) {
+                        info!("Rendezvous found peer {} in room", peer_id);
+                        self.send_system_message(format!("🔍 Found peer {} in room via rendezvous, connecting...", self.short_peer_id(&peer_id.to_string())));
+                    }
+                }
+            }
+            rendezvous::client::Event::DiscoverFailed { namespace, error, .. } => {
+                if namespace.is_some() && namespace == self.rendezvous_namespace {
+                    self.rendezvous_fallback(format!("discovery failed: {:?}", error));
+                }
+            }
+            rendezvous::client::Event::Expired { peer } => {
+                info!("Rendezvous registration of {} expired", peer);
+            }
+        }
+    }
+
+    fn handle_relay_event(&mut self, event: relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, renewed } => {
+                let is_new = self.relay_reservations.insert(src_peer_id);
+                info!("Accepted relay reservation from {} (renewed: {})", src_peer_id, renewed);
+                if is_new && !renewed {
+                    self.send_system_message(format!("📡 Accepted relay reservation from {}", self.short_peer_id(&src_peer_id.to_string())));
+                }
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                info!("Relay reservation of {} expired", src_peer_id);
+                self.relay_reservations.remove(&src_peer_id);
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                info!("Denied relay reservation from {}", src_peer_id);
+            }
+            relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                self.relay_circuits += 1;
+                self.relay_circuits_total += 1;
+                info!("Opened relay circuit {} -> {}", src_peer_id, dst_peer_id);
+                self.send_system_message(format!(
+                    "🔀 Relaying circuit {} → {}",
+                    self.short_peer_id(&src_peer_id.to_string()),
+                    self.short_peer_id(&dst_peer_id.to_string())
+                ));
+            }
+            relay::Event::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+                info!("Denied relay circuit {} -> {}", src_peer_id, dst_peer_id);
+            }
+            relay::Event::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+                self.relay_circuits = self.relay_circuits.saturating_sub(1);
+                info!("Relay circuit {} -> {} closed: {:?}", src_peer_id, dst_peer_id, error);
+            }
+            _ => {}
+        }
+    }
+
+    // Listen through a relay of the user's choice. The reply carries our
+    // circuit address once the relay accepts the reservation; the relay
+    // client renews it before it lapses for as long as the connection lives.
+    pub fn reserve_relay(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        relay_addr: Multiaddr,
+        reply: oneshot::Sender<Result<String, CommandError>>,
+    ) {
+        info!("Requesting relay reservation on {}", relay_addr);
+        match swarm.listen_on(relay_addr.clone().with(Protocol::P2pCircuit)) {
+            Ok(listener_id) => {
+                self.send_system_message(format!("📡 Requesting a reservation on relay {}...", relay_addr));
+                self.relay_listeners.insert(listener_id, relay_addr);
+                self.pending_reservations.insert(listener_id, reply);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(CommandError::RelayReservationFailed { reason: e.to_string() }));
+            }
+        }
+    }
+
+    // Dial `address` on a connection of its own, so it is tested even when
+    // we're already connected to that peer
+    pub fn test_reachability(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        address: Multiaddr,
+        close: bool,
+        reply: oneshot::Sender<ReachabilityResult>,
+    ) {
+        let opts = test_dial_opts(&address);
+        let connection_id = opts.connection_id();
+        let test = ReachabilityTest {
+            address,
+            started: Instant::now(),
+            close,
+            reply,
+        };
+        
+        info!("Testing reachability of {}", test.address);
+        match swarm.dial(opts) {
+            Ok(()) => {
+                self.reachability_tests.insert(connection_id, test);
+            }
+            Err(e) => self.finish_reachability_test(swarm, connection_id, test, Err(e.to_string())),
+        }
+    }
+
+    fn finish_reachability_test(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        connection_id: ConnectionId,
+        test: ReachabilityTest,
+        outcome: Result<PeerId, String>,
+    ) {
+        let dial_ms = test.started.elapsed().as_millis() as u64;
+        let result = match outcome {
+            Ok(peer_id) => {
+                info!("Reached {} at {} in {} ms", peer_id, test.address, dial_ms);
+                if test.close {
+                    swarm.close_connection(connection_id);
+                }
+                ReachabilityResult {
+                    address: test.address.to_string(),
+                    reachable: true,
+                    peer_id: Some(peer_id.to_string()),
+                    dial_ms,
+                    error: None,
+                }
+            }
+            Err(error) => {
+                info!("Could not reach {}: {}", test.address, error);
+                ReachabilityResult {
+                    address: test.address.to_string(),
+                    reachable: false,
+                    peer_id: None,
+                    dial_ms,
+                    error: Some(error),
+                }
+            }
+        };
+        let _ = test.reply.send(result);
+    }
+
+    // Forget test dials whose caller already gave up waiting
+    pub fn expire_reachability_tests(&mut self) {
+        self.reachability_tests.retain(|_, test| !test.reply.is_closed());
+    }
+
+    // Check, one step at a time, that we can reach a bootstrap node, are
+    // listening, have a public address and can query the DHT. Each finished
+    // step is sent as an event; the whole report goes to `reply`.
+    pub fn start_connectivity_test(
+        &mut self,
+        swarm: &mut Swarm<ChatBehaviour>,
+        reply: oneshot::Sender<Result<ConnectivityReport, CommandError>>,
+    ) {
+        if self.connectivity_test.as_ref().is_some_and(|test| !test.reply.is_closed()) {
+            let _ = reply.send(Err(CommandError::invalid_input("A connectivity test is already running")));
+            return;
+        }
+        
+        info!("Starting connectivity test");
+        let now = Instant::now();
+        self.connectivity_test = Some(ConnectivityTest {
+            started: now,
+            step_started: now,
+            deadline: None,
+            dial: None,
+            query: None,
+            steps: Vec::new(),
+            reply,
+        });
+        self.start_bootstrap_dial_step(swarm);
+    }
+
+    fn start_bootstrap_dial_step(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let step = ConnectivityStep::BootstrapDial;
+        if self.private_network {
+            let detail = "Private network mode doesn't use public bootstrap nodes".to_string();
+            self.finish_connectivity_step(swarm, step, false, detail);
+            return;
+        }
+        let Some(address) = BOOTSTRAP_ADDRESSES.first().and_then(|addr| addr.parse::<Multiaddr>().ok()) else {
+            self.finish_connectivity_step(swarm, step, false, "No bootstrap address".to_string());
+            return;
+        };
+        
+        let opts = test_dial_opts(&address);
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                if let Some(test) = &mut self.connectivity_test {
+                    test.dial = Some((connection_id, address));
+                    test.deadline = Some(Instant::now() + CONNECTIVITY_STEP_TIMEOUT);
+                }
+            }
+            Err(e) => self.finish_connectivity_step(swarm, step, false, format!("Could not dial {}: {}", address, e)),
+        }
+    }
+
+    fn check_listeners(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let (passed, detail) = match swarm.listeners().count() {
+            0 => (false, "Not listening on any address".to_string()),
+            count => (true, format!("Listening on {} addresses", count)),
+        };
+        self.finish_connectivity_step(swarm, ConnectivityStep::Listeners, passed, detail);
+    }
+
+    fn check_public_address(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let listen_addrs: Vec<Multiaddr> = swarm.listeners().cloned().collect();
+        let (passed, detail) = match (swarm.external_addresses().next(), filter_ipv6_public_addrs(&listen_addrs).first()) {
+            (Some(addr), _) => (true, format!("Confirmed external address {}", addr)),
+            (None, Some(addr)) => (true, format!("Public IPv6 address {}", addr)),
+            (None, None) => (false, "No confirmed external or public IPv6 address; peers may need a relay to reach us".to_string()),
+        };
+        self.finish_connectivity_step(swarm, ConnectivityStep::PublicAddress, passed, detail);
+    }
 
-        self.send_system_message(format!("✓ Announced! Searching for peers in '{}'...", room_name));
+    fn start_dht_query_step(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        if Self::dht_peer_count(swarm) == 0 {
+            let detail = "The DHT routing table is empty".to_string();
+            self.finish_connectivity_step(swarm, ConnectivityStep::DhtQuery, false, detail);
+            return;
+        }
+        let query_id = swarm.behaviour_mut().kad.get_closest_peers(PeerId::random());
+        if let Some(test) = &mut self.connectivity_test {
+            test.query = Some(query_id);
+            test.deadline = Some(Instant::now() + CONNECTIVITY_STEP_TIMEOUT);
+        }
+    }
 
-        // Search for peers in the room via DHT
-        swarm
-            .behaviour_mut()
-            .kad
-            .get_providers(room_name.as_bytes().to_vec().into());
+    // The address of the test's bootstrap dial, if that's what `connection_id` is
+    fn take_connectivity_dial(&mut self, connection_id: ConnectionId) -> Option<Multiaddr> {
+        let test = self.connectivity_test.as_mut()?;
+        if test.dial.as_ref().is_some_and(|(id, _)| *id == connection_id) {
+            return test.dial.take().map(|(_, address)| address);
+        }
+        None
     }
 
-    pub async fn send_message(&self, swarm: &mut Swarm<ChatBehaviour>, message: String) {
-        // Check if we're in a room
-        let topic = match &self.current_room {
-            Some(t) => t,
-            None => {
-                self.send_system_message("⚠ Join a room first (Ctrl+J)".to_string());
-                return;
-            }
+    // Record the outcome of `step` and run the next one
+    fn finish_connectivity_step(&mut self, swarm: &mut Swarm<ChatBehaviour>, step: ConnectivityStep, passed: bool, detail: String) {
+        let Some(test) = &mut self.connectivity_test else {
+            return;
         };
+        let result = ConnectivityStepResult {
+            step,
+            passed,
+            duration_ms: test.step_started.elapsed().as_millis() as u64,
+            detail,
+        };
+        info!("Connectivity test {:?} {}: {}", step, if passed { "passed" } else { "failed" }, result.detail);
+        let _ = self.event_tx.send(NodeEvent::ConnectivityProgress(result.clone()));
+        test.steps.push(result);
+        test.step_started = Instant::now();
+        test.deadline = None;
+        test.dial = None;
+        test.query = None;
         
-        // Publish message to gossipsub topic
-        match swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.as_bytes()) {
-            Ok(_) => {
-                // Echo message back to UI as sent
-                let _ = self.message_tx.send(ChatMessage {
-                    from: "You".to_string(),
-                    content: message,
-                    timestamp: chrono::Utc::now().to_rfc3339(),
-                    is_self: true,
-                });
-            }
-            Err(e) => {
-                warn!("Failed to publish message: {}", e);
-                self.send_system_message(format!("⚠ Failed to send message: {}", e));
-            }
+        match step {
+            ConnectivityStep::BootstrapDial => self.check_listeners(swarm),
+            ConnectivityStep::Listeners => self.check_public_address(swarm),
+            ConnectivityStep::PublicAddress => self.start_dht_query_step(swarm),
+            ConnectivityStep::DhtQuery => self.finish_connectivity_test(),
         }
     }
 
-    pub fn get_addresses(&self, swarm: &Swarm<ChatBehaviour>) -> Vec<String> {
-        let addrs: Vec<Multiaddr> = swarm.listeners().cloned().collect();
-        
-        // Filter to only public IPv6 addresses (removes fe80::, ::1, etc.)
-        let filtered = filter_ipv6_public_addrs(&addrs);
-        
-        filtered
-            .iter()
-            .map(|addr| format!("{}/p2p/{}", addr, self.peer_id))
-            .collect()
+    fn finish_connectivity_test(&mut self) {
+        let Some(test) = self.connectivity_test.take() else {
+            return;
+        };
+        let report = ConnectivityReport {
+            passed: test.steps.iter().all(|step| step.passed),
+            total_ms: test.started.elapsed().as_millis() as u64,
+            steps: test.steps,
+        };
+        let failed: Vec<String> = report.steps.iter().filter(|step| !step.passed).map(|step| format!("{:?}", step.step)).collect();
+        if failed.is_empty() {
+            self.event_log.push(LogLevel::Info, "connectivity_test", "Connectivity test passed".to_string());
+        } else {
+            self.event_log.push(LogLevel::Warning, "connectivity_test", format!("Connectivity test failed: {}", failed.join(", ")));
+        }
+        let _ = test.reply.send(Ok(report));
     }
 
-    pub fn connect_to_peer(&mut self, swarm: &mut Swarm<ChatBehaviour>, addr: String) {
-        info!("Attempting to connect to peer at: {}", addr);
-        
-        // Parse the multiaddr
-        match addr.parse::<Multiaddr>() {
-            Ok(multiaddr) => {
-                // Try to dial the address
-                match swarm.dial(multiaddr.clone()) {
-                    Ok(_) => {
-                        self.send_system_message(format!("🔗 Dialing peer at {}...", addr));
-                    }
-                    Err(e) => {
-                        warn!("Failed to dial peer: {}", e);
-                        self.send_system_message(format!("⚠ Failed to dial peer: {}", e));
-                    }
-                }
+    // When the current connectivity test step gives up, for the node loop's timer
+    pub fn connectivity_test_deadline(&self) -> Option<Instant> {
+        self.connectivity_test.as_ref().and_then(|test| test.deadline)
+    }
+
+    pub fn expire_connectivity_step(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        let Some(test) = &mut self.connectivity_test else {
+            return;
+        };
+        if test.deadline.is_none_or(|deadline| deadline > Instant::now()) {
+            return;
+        }
+        let waited = CONNECTIVITY_STEP_TIMEOUT.as_secs();
+        if let Some((_, address)) = test.dial.take() {
+            let detail = format!("No connection to {} within {} s", address, waited);
+            self.finish_connectivity_step(swarm, ConnectivityStep::BootstrapDial, false, detail);
+        } else if let Some(query_id) = test.query.take() {
+            if let Some(mut query) = swarm.behaviour_mut().kad.query_mut(&query_id) {
+                query.finish();
             }
-            Err(e) => {
-                warn!("Invalid multiaddr: {}", e);
-                self.send_system_message(format!("⚠ Invalid address format: {}", e));
+            let detail = format!("The lookup didn't finish within {} s", waited);
+            self.finish_connectivity_step(swarm, ConnectivityStep::DhtQuery, false, detail);
+        }
+    }
+
+    pub fn retry_relay_reservations(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+        for relay_addr in std::mem::take(&mut self.lost_relays) {
+            match swarm.listen_on(relay_addr.clone().with(Protocol::P2pCircuit)) {
+                Ok(listener_id) => {
+                    info!("Retrying relay reservation on {}", relay_addr);
+                    self.relay_listeners.insert(listener_id, relay_addr);
+                }
+                Err(e) => {
+                    warn!("Failed to retry relay reservation on {}: {}", relay_addr, e);
+                    self.lost_relays.push(relay_addr);
+                }
             }
         }
     }
 
-    pub async fn handle_event(&mut self, event: SwarmEvent<ChatBehaviourEvent>) {
+    fn handle_relay_client_event(&mut self, event: relay::client::Event) {
         match event {
-            SwarmEvent::NewListenAddr { address, .. } => {
-                info!("Listening on {}", address);
-                self.send_system_message(format!("🎧 Listening on {}", address));
-            }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                propagation_source,
-                message_id: _,
-                message,
-            })) => {
-                // Received a message from gossipsub
-                let msg_str = String::from_utf8_lossy(&message.data);
-                info!("Received message from {}: {}", propagation_source, msg_str);
-                
-                // Send to frontend
-                let _ = self.message_tx.send(ChatMessage {
-                    from: self.short_peer_id(&message.source.map(|s| s.to_string()).unwrap_or_else(|| "Unknown".to_string())),
-                    content: msg_str.to_string(),
-                    timestamp: chrono::Utc::now().to_rfc3339(),
-                    is_self: false,
-                });
-            }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
-                info!("Peer {} subscribed to topic: {}", peer_id, topic);
-                self.send_system_message(format!("✓ Peer {} joined the room", self.short_peer_id(&peer_id.to_string())));
-            }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
-                info!("Peer {} unsubscribed from topic: {}", peer_id, topic);
-                self.send_system_message(format!("✗ Peer {} left the room", self.short_peer_id(&peer_id.to_string())));
-            }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
-                for (peer_id, multiaddr) in peers {
-                    if peer_id == self.peer_id {
-                        continue;
-                    }
-                    info!("mDNS discovered peer: {} at {}", peer_id, multiaddr);
-                    
-                    // Check if already connected
-                    if self.connected_peers.contains_key(&peer_id) {
-                        continue;
-                    }
-                    
-                    self.send_system_message(format!("🔍 mDNS discovered peer: {}", self.short_peer_id(&peer_id.to_string())));
-                    
-                    // Queue this peer for dialing
-                    self.peers_to_dial.push(peer_id);
+            relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. } => {
+                info!("Relay {} accepted our reservation (renewal: {})", relay_peer_id, renewal);
+                if !renewal {
+                    self.send_system_message(format!("📡 Reserved a circuit on relay {}", self.short_peer_id(&relay_peer_id.to_string())));
                 }
             }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
-                for (peer_id, _) in peers {
-                    self.discovered_peers.remove(&peer_id);
-                    info!("mDNS peer expired: {}", peer_id);
-                }
+            relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                info!("Opened circuit through relay {}", relay_peer_id);
             }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
-                info!("Identified peer: {}", peer_id);
-                let addrs: Vec<String> = info.listen_addrs.iter().map(|a| a.to_string()).collect();
-                self.connected_peers.insert(peer_id, addrs);
+            relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                info!("Peer {} reached us through a relay", src_peer_id);
             }
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                info!("Connected to peer: {}", peer_id);
-                
-                // Check if this is a bootstrap peer
-                if self.bootstrap_peers.contains(&peer_id) {
-                    self.send_system_message(format!("✓ Connected to bootstrap node {}", self.short_peer_id(&peer_id.to_string())));
-                } else {
-                    self.send_system_message(format!("✓ Connected to {}", self.short_peer_id(&peer_id.to_string())));
-                }
+        }
+    }
+
+    // Take over the persisted book, forgetting stale peers and seeding the
+    // rest into the DHT so they are reachable before any discovery runs
+    pub fn load_address_book(&mut self, swarm: &mut Swarm<ChatBehaviour>, mut book: AddressBook) {
+        book.prune(self.address_book_ttl);
+        
+        let mut seeded = 0;
+        let mut explicit = Vec::new();
+        for (peer_id, entry) in book.peers() {
+            for addr in entry.addresses.iter().filter_map(|a| a.parse::<Multiaddr>().ok()) {
+                swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+                self.remember_address(peer_id, addr);
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                info!("Disconnected from peer: {}", peer_id);
-                self.connected_peers.remove(&peer_id);
-                self.send_system_message(format!("✗ Disconnected from {}", self.short_peer_id(&peer_id.to_string())));
+            if let Some(nickname) = &entry.nickname {
+                self.peer_nicknames.insert(peer_id, nickname.clone());
             }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(kad::Event::RoutingUpdated { peer, .. })) => {
-                info!("Routing updated for peer: {}", peer);
+            if entry.explicit {
+                explicit.push(peer_id);
             }
-            SwarmEvent::Behaviour(ChatBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { result, .. })) => {
-                match result {
-                    kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, num_remaining })) => {
-                        info!("Bootstrap successful with peer: {} ({} remaining)", peer, num_remaining);
-                        if num_remaining == 0 {
-                            self.send_system_message("✓ DHT bootstrap complete - internet discovery enabled".to_string());
-                        }
-                    }
-                    kad::QueryResult::Bootstrap(Err(e)) => {
-                        warn!("Bootstrap error: {:?}", e);
-                    }
-                    kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
-                        for peer_id in providers {
-                            if peer_id == self.peer_id {
-                                continue;
-                            }
-                            
-                            // Skip if already connected
-                            if self.connected_peers.contains_key(&peer_id) {
-                                continue;
-                            }
-                            
-                            info!("Found provider (peer) in room: {}", peer_id);
-                            self.send_system_message(format!("🔍 Found peer {} in room, connecting...", self.short_peer_id(&peer_id.to_string())));
-                            
-                            // Queue this peer for dialing
-                            self.peers_to_dial.push(peer_id);
-                        }
-                    }
-                    _ => {}
-                }
+            seeded += 1;
+        }
+        info!("Loaded {} peers from the address book", seeded);
+        
+        // Peers muted while the node was down lose their explicit status
+        for peer_id in explicit {
+            if self.muted_peers.contains(&peer_id) {
+                book.set_explicit(&peer_id, false);
+                continue;
+            }
+            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+            self.explicit_peers.insert(peer_id);
+            self.queue_dial(peer_id);
+        }
+        self.address_book = book;
+    }
+
+    // Saves what the periodic sweep would, and returns the room to rejoin
+    // next time
+    pub fn persist_state(&mut self, swarm: &mut Swarm<ChatBehaviour>) -> Option<SessionRoom> {
+        self.save_address_book();
+        self.save_kad_store(swarm);
+        if self.room_key.is_some() {
+            return None;
+        }
+        self.current_room_name.clone().map(|name| SessionRoom {
+            name,
+            passive: self.room_passive,
+            public: self.room_public,
+        })
+    }
+
+    pub fn save_kad_store(&self, swarm: &mut Swarm<ChatBehaviour>) {
+        if let Err(e) = swarm.behaviour_mut().kad.store_mut().save() {
+            warn!("Failed to save DHT store: {}", e);
+        }
+    }
+
+    // Drops every remembered peer, e.g. after the identity they knew us by
+    // was replaced; nothing is saved again until the next start
+    pub fn forget_address_book(&mut self) {
+        self.address_book = AddressBook::default();
+        info!("Address book cleared");
+    }
+
+    pub fn save_address_book(&mut self) {
+        self.address_book.prune(self.address_book_ttl);
+        if let Err(e) = self.address_book.save() {
+            warn!("Failed to save address book: {}", e);
+        }
+    }
+
+    fn dial_known_room_peers(&mut self, room: &str) {
+        if !self.auto_dial_known_peers {
+            return;
+        }
+        let mut queued = 0;
+        for peer_id in self.address_book.recent_room_peers(room, AUTO_DIAL_LIMIT) {
+            if self.queue_dial(peer_id) {
+                queued += 1;
             }
-            _ => {}
         }
+        if queued > 0 {
+            self.send_system_message(format!("🔁 Reconnecting to {} peers last seen in '{}'", queued, room));
+        }
+    }
+
+    // Queue a discovered peer for dialing; false if it's already connected,
+    // queued or being dialed, so callers only announce new finds
+    pub fn queue_dial(&mut self, peer_id: PeerId) -> bool {
+        if peer_id == self.peer_id || self.connected_peers.contains_key(&peer_id) || self.is_dial_queued(&peer_id) {
+            return false;
+        }
+        self.dials.queue(peer_id)
     }
 
+    // Queued, being dialed, or waiting on a DHT lookup of its addresses
+    fn is_dial_queued(&self, peer_id: &PeerId) -> bool {
+        self.dials.contains(peer_id) || self.address_lookups.values().any(|target| target == peer_id)
+    }
+
+    // Dials queued peers, at most `max_concurrent_dials` at a time; the rest
+    // stay queued until running dials connect or fail
     pub fn process_pending_dials(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
-        // Dial any pending peers (skip duplicates and already connected)
-        while let Some(peer_id) = self.peers_to_dial.pop() {
-            // Skip if already connected
-            if self.connected_peers.contains_key(&peer_id) {
+        while let Some(peer_id) = self.dials.next_due(self.connection_limits.max_concurrent_dials) {
+            // May have connected since it was queued
+            if peer_id == self.peer_id || self.connected_peers.contains_key(&peer_id) {
+                continue;
+            }
+            
+            let mut addrs = self.addresses_for(swarm, &peer_id);
+            addrs.retain(|addr| !is_own_address(addr, &self.peer_id));
+            
+            // Freshly discovered providers often have no cached address; look
+            // them up in the DHT first and retry once the lookup completes
+            // (skipped in low-power mode to save the extra queries)
+            if addrs.is_empty() && self.power_mode == PowerMode::Normal && self.looked_up_peers.insert(peer_id) {
+                info!("No known addresses for {}, looking them up in the DHT", peer_id);
+                let query_id = swarm.behaviour_mut().kad.get_closest_peers(peer_id);
+                self.address_lookups.insert(query_id, peer_id);
                 continue;
             }
             
-            info!("Dialing discovered peer: {}", peer_id);
-            if let Err(e) = swarm.dial(peer_id) {
+            info!("Dialing discovered peer: {} ({} known addresses)", peer_id, addrs.len());
+            let opts = DialOpts::peer_id(peer_id)
+                .addresses(addrs)
+                .extend_addresses_through_behaviour()
+                .build();
+            if let Err(e) = swarm.dial(opts) {
+                if let DialError::Denied { cause } = &e {
+                    if self.note_connection_denied(cause) {
+                        continue;
+                    }
+                }
                 warn!("Failed to dial peer {}: {}", peer_id, e);
                 self.send_system_message(format!("⚠ Failed to connect to {}: {}", self.short_peer_id(&peer_id.to_string()), e));
+                continue;
+            }
+            self.dials.started(peer_id);
+        }
+    }
+
+    fn remember_address(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let addrs = self.known_addresses.entry(peer_id).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        self.dials.address_learned(peer_id);
+    }
+
+    // Every address we know for a peer: our own records plus the DHT routing table
+    fn addresses_for(&self, swarm: &mut Swarm<ChatBehaviour>, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let mut addrs = self.known_addresses.get(peer_id).cloned().unwrap_or_default();
+        
+        if let Some(bucket) = swarm.behaviour_mut().kad.kbucket(*peer_id) {
+            for entry in bucket.iter() {
+                if entry.node.key.preimage() == peer_id {
+                    for addr in entry.node.value.iter() {
+                        if !addrs.contains(addr) {
+                            addrs.push(addr.clone());
+                        }
+                    }
+                }
             }
         }
+        
+        addrs
+    }
+
+    // Announced nickname if we know one, otherwise the shortened peer ID
+    fn display_name(&self, peer_id: &PeerId) -> String {
+        match self.peer_nicknames.get(peer_id) {
+            Some(nickname) => nickname.clone(),
+            None => self.short_peer_id(&peer_id.to_string()),
+        }
+    }
+
+    // Sends the event to the UI, and queues normal-priority ones to be
+    // merged into a chat line by `flush_system_events`
+    fn report_system_event(&mut self, event: SystemEvent) {
+        let _ = self.event_tx.send(NodeEvent::System(event.clone()));
+        if self.recent_system_events.len() >= MAX_RECENT_SYSTEM_EVENTS {
+            self.recent_system_events.pop_front();
+        }
+        self.recent_system_events.push_back(RecordedSystemEvent {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            event: event.clone(),
+        });
+        if event.priority() == SystemPriority::Low {
+            return;
+        }
+        let kind = std::mem::discriminant(&event);
+        match self.pending_system_events.iter_mut().find(|(pending, _)| std::mem::discriminant(pending) == kind) {
+            Some((_, count)) => *count += 1,
+            None => self.pending_system_events.push((event, 1)),
+        }
+        self.system_events_flush_at
+            .get_or_insert_with(|| Instant::now() + SYSTEM_EVENT_COALESCE_WINDOW);
+    }
+
+    // When queued system events are shown, for the node loop's timer
+    pub fn system_events_flush_at(&self) -> Option<Instant> {
+        self.system_events_flush_at
+    }
+
+    pub fn flush_system_events(&mut self) {
+        self.system_events_flush_at = None;
+        for (event, count) in std::mem::take(&mut self.pending_system_events) {
+            let peer = self.short_peer_id(event.subject());
+            self.send_system_message(event.summary(count, &peer));
+        }
     }
 
     fn short_peer_id(&self, peer_id: &str) -> String {
@@ -523,3 +5669,269 @@ impl P2PNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(protocol_version: &str) -> PeerIdentity {
+        PeerIdentity {
+            protocol_version: protocol_version.to_string(),
+            agent_version: "test".to_string(),
+            protocols: vec![CHAT_PROTOCOL],
+        }
+    }
+
+    fn connected(protocol_version: Option<&str>) -> ConnectionInfo {
+        let mut info = ConnectionInfo::new();
+        info.identity = protocol_version.map(identity);
+        info
+    }
+
+    #[test]
+    fn provider_results_are_classified() {
+        let local = PeerId::random();
+        let peer = PeerId::random();
+        assert_eq!(classify_provider(&local, &local, None, false), ProviderAction::Ignore);
+        assert_eq!(classify_provider(&peer, &local, None, false), ProviderAction::Dial);
+        assert_eq!(classify_provider(&peer, &local, None, true), ProviderAction::Known);
+        // Connected but not identified yet: give it the benefit of the doubt
+        assert_eq!(classify_provider(&peer, &local, Some(&connected(None)), false), ProviderAction::Known);
+        assert_eq!(classify_provider(&peer, &local, Some(&connected(Some("p2p-chat/1"))), false), ProviderAction::Known);
+        assert_eq!(classify_provider(&peer, &local, Some(&connected(Some("p2p-chat/2"))), false), ProviderAction::NotChat);
+        let mut dht_node = connected(Some("ipfs/0.1.0"));
+        dht_node.identity.as_mut().unwrap().protocols = vec![StreamProtocol::new("/ipfs/kad/1.0.0")];
+        assert_eq!(classify_provider(&peer, &local, Some(&dht_node), true), ProviderAction::NotChat);
+    }
+
+    fn stamp() -> MessageStamp {
+        MessageStamp {
+            lamport: 3,
+            sent_at: "2026-01-01T00:00:00+00:00".to_string(),
+            sequence: Sequence { epoch: 1, seq: 1 },
+        }
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        let encoded = MessageEnvelope::encode("m1", ContentType::Markdown, "**hi**", &stamp(), None);
+        let envelope = MessageEnvelope::decode(&encoded).unwrap();
+        assert_eq!(envelope.id.as_deref(), Some("m1"));
+        assert_eq!(envelope.content_type, ContentType::Markdown);
+        assert_eq!(envelope.content, "**hi**");
+        assert_eq!(envelope.lamport, Some(3));
+        assert_eq!(envelope.sequence, Some(Sequence { epoch: 1, seq: 1 }));
+    }
+
+    #[test]
+    fn large_content_is_compressed_and_inflated() {
+        let content = "all work and no play ".repeat(500);
+        let encoded = MessageEnvelope::encode("m1", ContentType::Plain, &content, &stamp(), None);
+        assert!(encoded.len() < content.len() / 4);
+        assert!(String::from_utf8_lossy(&encoded).contains(COMPRESSED_PLACEHOLDER));
+        assert_eq!(MessageEnvelope::decode(&encoded).unwrap().content, content);
+    }
+
+    #[test]
+    fn decompression_bombs_are_dropped() {
+        // Compresses to about 1 KiB but inflates past the cap
+        let bomb = "a".repeat(MAX_DECOMPRESSED_SIZE as usize + 1);
+        let compressed = deflate(&bomb);
+        assert!(compressed.len() < 16 * 1024);
+        assert!(inflate(&compressed).is_err());
+        let envelope = serde_json::json!({
+            "v": ENVELOPE_VERSION,
+            "id": "bomb",
+            "content_type": "text/plain",
+            "content": COMPRESSED_PLACEHOLDER,
+            "deflate": compressed,
+        });
+        assert!(MessageEnvelope::decode(envelope.to_string().as_bytes()).is_none());
+
+        // Right at the cap is still fine
+        let largest = "a".repeat(MAX_DECOMPRESSED_SIZE as usize);
+        assert_eq!(inflate(&deflate(&largest)).unwrap().len(), largest.len());
+        assert!(inflate("not base64!").is_err());
+    }
+
+    #[test]
+    fn plain_payloads_from_old_clients_still_decode() {
+        for raw in ["hello from an old build", "{\"not\": \"an envelope\"}", "{\"v\": 0, \"content\": \"x\"}"] {
+            let envelope = MessageEnvelope::decode(raw.as_bytes()).unwrap();
+            assert_eq!(envelope.v, 0);
+            assert_eq!(envelope.id, None);
+            assert_eq!(envelope.content_type, ContentType::Plain);
+            assert_eq!(envelope.content, raw);
+            assert_eq!(envelope.sequence, None);
+        }
+    }
+
+    #[test]
+    fn newer_envelopes_keep_the_fields_we_know() {
+        let newer = serde_json::json!({
+            "v": ENVELOPE_VERSION + 1,
+            "id": "m2",
+            "content_type": "text/markdown",
+            "content": "still readable",
+            "lamport": 9,
+            "chunk": "changed shape",
+        });
+        let envelope = MessageEnvelope::decode(newer.to_string().as_bytes()).unwrap();
+        assert_eq!(envelope.id.as_deref(), Some("m2"));
+        assert_eq!(envelope.content, "still readable");
+        assert_eq!(envelope.lamport, Some(9));
+        assert_eq!(envelope.chunk, None);
+    }
+
+    #[test]
+    fn room_names_normalize_to_one_spelling() {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        assert_ne!(composed, decomposed);
+        assert_eq!(normalize_room_name(composed), normalize_room_name(decomposed));
+        assert_eq!(normalize_room_name(decomposed), composed);
+        assert_eq!(normalize_room_name("  CAFE\u{301} "), composed);
+        assert_eq!(normalize_room_name("Rust-Lang"), "rust-lang");
+        assert_eq!(normalize_room_name("\tlobby\n"), "lobby");
+        // Non-Latin names are kept, not stripped
+        assert_eq!(normalize_room_name("日本"), "日本");
+        assert_eq!(normalize_room_name("ΣΟΦΙΑ"), "σοφια");
+        // Inner whitespace is part of the name
+        assert_eq!(normalize_room_name("Two  Words"), "two  words");
+        assert_eq!(sanitize_room_name("   "), Err("Room name cannot be empty".to_string()));
+    }
+
+    #[test]
+    fn protocol_major_reads_chat_versions() {
+        assert_eq!(protocol_major("p2p-chat/1"), Some(1));
+        assert_eq!(protocol_major("p2p-chat/2.3"), Some(2));
+        // Builds from before PROTOCOL_VERSION announced the stream protocol
+        assert_eq!(protocol_major("/p2p-chat/1.0.0"), Some(1));
+    }
+
+    #[test]
+    fn protocol_major_ignores_malformed_and_foreign_versions() {
+        for version in ["", "p2p-chat/", "p2p-chat/x", "p2p-chat/-1", "p2p-chat", "ipfs/0.1.0", "/ipfs/kad/1.0.0"] {
+            assert_eq!(protocol_major(version), None, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn only_a_different_major_is_incompatible() {
+        assert!(!identity(PROTOCOL_VERSION).is_incompatible());
+        assert!(!identity("p2p-chat/1.7").is_incompatible());
+        assert!(identity("p2p-chat/2").is_incompatible());
+        assert!(identity("p2p-chat/0").is_incompatible());
+        // Missing or unreadable versions aren't held against the peer
+        assert!(!identity("").is_incompatible());
+        assert!(!identity("p2p-chat/next").is_incompatible());
+
+        assert!(identity("p2p-chat/1").is_chat_peer());
+        assert!(!identity("p2p-chat/2").is_chat_peer());
+        let mut not_chat = identity("p2p-chat/1");
+        not_chat.protocols.clear();
+        assert!(!not_chat.is_chat_peer());
+    }
+
+    fn pending(topic: &gossipsub::TopicHash, awaiting_mesh: Vec<Vec<u8>>) -> PendingDelivery {
+        PendingDelivery {
+            room: Some("room".to_string()),
+            topic: topic.clone(),
+            recipients: HashSet::new(),
+            acked: HashSet::new(),
+            sent_at: Instant::now(),
+            awaiting_mesh,
+        }
+    }
+
+    #[test]
+    fn only_messages_sent_to_an_empty_mesh_are_kept() {
+        let encoded = vec![b"part 1".to_vec(), b"part 2".to_vec()];
+        assert_eq!(awaiting_mesh(0, encoded.clone()), encoded);
+        assert!(awaiting_mesh(1, encoded.clone()).is_empty());
+        assert!(awaiting_mesh(6, encoded).is_empty());
+    }
+
+    #[test]
+    fn messages_awaiting_the_mesh_are_resent_once() {
+        let topic = gossipsub::IdentTopic::new("room").hash();
+        let other = gossipsub::IdentTopic::new("other").hash();
+        let encoded = vec![b"payload".to_vec()];
+
+        let mut waiting = pending(&topic, encoded.clone());
+        assert_eq!(waiting.take_resend(&other), None);
+        assert_eq!(waiting.take_resend(&topic), Some(encoded.clone()));
+        assert_eq!(waiting.take_resend(&topic), None);
+
+        // Someone already got it, so it isn't sent again
+        let mut acked = pending(&topic, encoded);
+        acked.acked.insert(PeerId::random());
+        assert_eq!(acked.take_resend(&topic), None);
+        assert!(acked.awaiting_mesh.is_empty());
+
+        assert_eq!(pending(&topic, Vec::new()).take_resend(&topic), None);
+    }
+
+    #[test]
+    fn only_messages_we_signed_are_our_own() {
+        let local = PeerId::random();
+        assert!(is_own_message(Some(local), &local));
+        assert!(!is_own_message(Some(PeerId::random()), &local));
+        assert!(!is_own_message(None, &local));
+    }
+
+    #[test]
+    fn own_addresses_are_recognised_by_their_last_peer_id() {
+        let local = PeerId::random();
+        let relay = PeerId::random();
+        let addr = |text: String| text.parse::<Multiaddr>().unwrap();
+
+        assert!(is_own_address(&addr(format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", local)), &local));
+        assert!(!is_own_address(&addr("/ip4/10.0.0.1/tcp/4001".to_string()), &local));
+        assert!(!is_own_address(&addr(format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", PeerId::random())), &local));
+
+        // Through a relay: only the peer after the circuit counts
+        let through_relay = format!("/ip4/10.0.0.2/tcp/4001/p2p/{}/p2p-circuit/p2p/{}", relay, local);
+        assert!(is_own_address(&addr(through_relay), &local));
+        let to_someone_else = format!("/ip4/10.0.0.2/tcp/4001/p2p/{}/p2p-circuit/p2p/{}", local, relay);
+        assert!(!is_own_address(&addr(to_someone_else), &local));
+    }
+
+    fn gossip(data: &str, topic: &str, source: Option<PeerId>, sequence_number: Option<u64>) -> gossipsub::Message {
+        gossipsub::Message {
+            source,
+            data: data.as_bytes().to_vec(),
+            sequence_number,
+            topic: gossipsub::IdentTopic::new(topic).hash(),
+        }
+    }
+
+    #[test]
+    fn unsigned_message_ids_depend_only_on_the_data() {
+        let id = gossip_message_id(&gossip("hello", "room", None, None));
+        assert_eq!(id, gossip_message_id(&gossip("hello", "room", None, None)));
+        assert_ne!(id, gossip_message_id(&gossip("hello!", "room", None, None)));
+        // Pinned so every build agrees: the first half of the SHA-256 in hex
+        assert_eq!(id, gossipsub::MessageId::from("2cf24dba5fb0a30e26e83b2ac5b9e29e"));
+    }
+
+    #[test]
+    fn signed_message_ids_use_source_and_sequence() {
+        let source = PeerId::random();
+        let id = gossip_message_id(&gossip("hello", "room", Some(source), Some(7)));
+        assert_eq!(id, gossipsub::MessageId::from(format!("{}/7", source)));
+        // The same text sent twice is two messages
+        assert_ne!(id, gossip_message_id(&gossip("hello", "room", Some(source), Some(8))));
+        assert_eq!(id, gossip_message_id(&gossip("other", "room", Some(source), Some(7))));
+    }
+
+    #[test]
+    fn rendezvous_refreshes_stay_in_the_accepted_range() {
+        assert_eq!(rendezvous_refresh_delay(4 * 60 * 60), Duration::from_secs(2 * 60 * 60));
+        for ttl in [0, 1] {
+            assert_eq!(rendezvous_refresh_delay(ttl), Duration::from_secs(RENDEZVOUS_MIN_TTL_SECS / 2));
+        }
+        assert_eq!(rendezvous_refresh_delay(u64::MAX), Duration::from_secs(RENDEZVOUS_MAX_TTL_SECS / 2));
+        assert!(Instant::now().checked_add(rendezvous_refresh_delay(u64::MAX)).is_some());
+    }
+}
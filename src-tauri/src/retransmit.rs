@@ -0,0 +1,275 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub const RETRANSMIT_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-chat/retransmit/1.0.0");
+// Messages each sender keeps per room to send again, and the most we ask
+// one sender for after a gap
+pub const MAX_RETRANSMIT_RANGE: u64 = 200;
+// Gossipsub doesn't keep order, so a missing message may still be on its
+// way; only ask for what's still missing after this long
+pub const GAP_GRACE: Duration = Duration::from_secs(3);
+// Keeps a response well inside the request-response size limit; later
+// messages of the range are left out
+const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+
+// Place of a room message in its sender's stream. The epoch is picked at
+// startup, so numbering restarts with each run without looking like a gap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence {
+    pub epoch: u64,
+    pub seq: u64,
+}
+
+// Asks the author of a room message for their messages `from..=to`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetransmitRequest {
+    // Topic hash, since private room names are only known to members
+    pub topic: String,
+    pub epoch: u64,
+    pub from: u64,
+    pub to: u64,
+}
+
+// Base64 of each payload as it was published, so messages in passphrase
+// rooms stay encrypted with the room key. Ones we no longer have are left out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetransmitResponse {
+    pub messages: Vec<String>,
+}
+
+// Payloads of one published message, every part of a split one
+struct SentMessage {
+    seq: u64,
+    payloads: Vec<Vec<u8>>,
+}
+
+// Our latest room messages by topic, to answer retransmission requests
+#[derive(Default)]
+pub struct SentLog {
+    next_seq: HashMap<String, u64>,
+    sent: HashMap<String, VecDeque<SentMessage>>,
+}
+
+impl SentLog {
+    // Number for the next message published to `topic`
+    pub fn next_seq(&self, topic: &str) -> u64 {
+        self.next_seq.get(topic).copied().unwrap_or(1)
+    }
+
+    pub fn record(&mut self, topic: &str, seq: u64, payloads: Vec<Vec<u8>>) {
+        self.next_seq.insert(topic.to_string(), seq + 1);
+        let sent = self.sent.entry(topic.to_string()).or_default();
+        sent.push_back(SentMessage { seq, payloads });
+        while sent.len() > MAX_RETRANSMIT_RANGE as usize {
+            sent.pop_front();
+        }
+    }
+
+    // Numbering carries on if we come back, so a peer still tracking us
+    // doesn't mistake new messages for old ones
+    pub fn forget(&mut self, topic: &str) {
+        self.sent.remove(topic);
+    }
+
+    pub fn respond(&self, request: &RetransmitRequest) -> RetransmitResponse {
+        let mut messages = Vec::new();
+        let mut size = 0;
+        let sent = self.sent.get(&request.topic).into_iter().flatten();
+        for message in sent.filter(|message| (request.from..=request.to).contains(&message.seq)) {
+            size += message.payloads.iter().map(Vec::len).sum::<usize>();
+            if size > MAX_RESPONSE_BYTES {
+                break;
+            }
+            messages.extend(message.payloads.iter().map(|payload| BASE64.encode(payload)));
+        }
+        RetransmitResponse { messages }
+    }
+}
+
+struct SenderStream {
+    epoch: u64,
+    highest: u64,
+    missing: BTreeSet<u64>,
+    // When the oldest still-missing message was first noticed
+    gap_since: Option<Instant>,
+}
+
+// Missing sequence numbers per (topic, sender)
+#[derive(Default)]
+pub struct GapTracker {
+    streams: HashMap<(String, PeerId), SenderStream>,
+}
+
+impl GapTracker {
+    // The first message seen from a sender, or from a new run of theirs,
+    // starts tracking; we don't ask for what came before it
+    pub fn observe(&mut self, topic: &str, sender: PeerId, sequence: Sequence) {
+        let stream = self
+            .streams
+            .entry((topic.to_string(), sender))
+            .or_insert(SenderStream { epoch: sequence.epoch, highest: sequence.seq, missing: BTreeSet::new(), gap_since: None });
+        if stream.epoch != sequence.epoch {
+            *stream = SenderStream { epoch: sequence.epoch, highest: sequence.seq, missing: BTreeSet::new(), gap_since: None };
+            return;
+        }
+        if sequence.seq > stream.highest {
+            let from = (stream.highest + 1).max(sequence.seq.saturating_sub(MAX_RETRANSMIT_RANGE));
+            stream.missing.extend(from..sequence.seq);
+            stream.highest = sequence.seq;
+        } else {
+            stream.missing.remove(&sequence.seq);
+        }
+        // Only the latest MAX_RETRANSMIT_RANGE are worth asking for
+        while stream.missing.len() > MAX_RETRANSMIT_RANGE as usize {
+            stream.missing.pop_first();
+        }
+        stream.gap_since = match stream.missing.is_empty() {
+            true => None,
+            false => stream.gap_since.or_else(|| Some(Instant::now())),
+        };
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.streams.values().filter_map(|stream| stream.gap_since).min().map(|since| since + GAP_GRACE)
+    }
+
+    // Requests for gaps open longer than GAP_GRACE, with the sender to ask.
+    // Each gap is asked for once; what doesn't come back stays missed.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(PeerId, RetransmitRequest)> {
+        let mut due = Vec::new();
+        for ((topic, sender), stream) in &mut self.streams {
+            if stream.gap_since.is_none_or(|since| since + GAP_GRACE > now) {
+                continue;
+            }
+            let missing = std::mem::take(&mut stream.missing);
+            stream.gap_since = None;
+            if let (Some(from), Some(to)) = (missing.first(), missing.last()) {
+                due.push((*sender, RetransmitRequest { topic: topic.clone(), epoch: stream.epoch, from: *from, to: *to }));
+            }
+        }
+        due
+    }
+
+    pub fn forget_topic(&mut self, topic: &str) {
+        self.streams.retain(|(stream_topic, _), _| stream_topic != topic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: &str = "topic";
+
+    fn at(epoch: u64, seq: u64) -> Sequence {
+        Sequence { epoch, seq }
+    }
+
+    fn request(from: u64, to: u64) -> RetransmitRequest {
+        RetransmitRequest { topic: TOPIC.to_string(), epoch: 1, from, to }
+    }
+
+    fn after_grace() -> Instant {
+        Instant::now() + GAP_GRACE
+    }
+
+    #[test]
+    fn gaps_are_asked_for_once_after_the_grace_period() {
+        let mut tracker = GapTracker::default();
+        let sender = PeerId::random();
+        tracker.observe(TOPIC, sender, at(1, 1));
+        tracker.observe(TOPIC, sender, at(1, 4));
+        assert!(tracker.next_deadline().is_some());
+        assert!(tracker.take_due(Instant::now()).is_empty());
+
+        let due = tracker.take_due(after_grace());
+        assert_eq!(due.len(), 1);
+        let (peer, request) = &due[0];
+        assert_eq!(*peer, sender);
+        assert_eq!((request.topic.as_str(), request.epoch, request.from, request.to), (TOPIC, 1, 2, 3));
+        assert!(tracker.take_due(after_grace()).is_empty());
+        assert_eq!(tracker.next_deadline(), None);
+    }
+
+    #[test]
+    fn late_arrivals_close_the_gap() {
+        let mut tracker = GapTracker::default();
+        let sender = PeerId::random();
+        for seq in [1, 3, 2] {
+            tracker.observe(TOPIC, sender, at(1, seq));
+        }
+        assert_eq!(tracker.next_deadline(), None);
+        assert!(tracker.take_due(after_grace()).is_empty());
+    }
+
+    #[test]
+    fn a_new_epoch_starts_tracking_over() {
+        let mut tracker = GapTracker::default();
+        let sender = PeerId::random();
+        tracker.observe(TOPIC, sender, at(1, 5));
+        tracker.observe(TOPIC, sender, at(1, 7));
+        // The sender restarted: neither the old gap nor numbers before the
+        // first message of the new run are asked for
+        tracker.observe(TOPIC, sender, at(2, 4));
+        assert!(tracker.take_due(after_grace()).is_empty());
+
+        tracker.observe(TOPIC, sender, at(2, 6));
+        let due = tracker.take_due(after_grace());
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].1.epoch, due[0].1.from, due[0].1.to), (2, 5, 5));
+    }
+
+    #[test]
+    fn only_the_latest_messages_of_a_long_gap_are_asked_for() {
+        let mut tracker = GapTracker::default();
+        let sender = PeerId::random();
+        tracker.observe(TOPIC, sender, at(1, 1));
+        tracker.observe(TOPIC, sender, at(1, 1000));
+        let due = tracker.take_due(after_grace());
+        assert_eq!((due[0].1.from, due[0].1.to), (1000 - MAX_RETRANSMIT_RANGE, 999));
+
+        // Forgetting the room drops its gaps
+        tracker.observe(TOPIC, sender, at(1, 1002));
+        tracker.forget_topic(TOPIC);
+        assert!(tracker.take_due(after_grace()).is_empty());
+    }
+
+    #[test]
+    fn sent_log_answers_from_what_it_kept() {
+        let mut log = SentLog::default();
+        assert_eq!(log.next_seq(TOPIC), 1);
+        for seq in 1..=250 {
+            log.record(TOPIC, seq, vec![format!("m{}", seq).into_bytes()]);
+        }
+        assert_eq!(log.next_seq(TOPIC), 251);
+
+        // Only the latest MAX_RETRANSMIT_RANGE are kept
+        let all = log.respond(&request(1, 250)).messages;
+        assert_eq!(all.len(), MAX_RETRANSMIT_RANGE as usize);
+        assert_eq!(all[0], BASE64.encode("m51"));
+        assert_eq!(log.respond(&request(240, 241)).messages, [BASE64.encode("m240"), BASE64.encode("m241")]);
+
+        // Ranges we don't have come back empty
+        assert!(log.respond(&request(1, 50)).messages.is_empty());
+        assert!(log.respond(&request(251, 300)).messages.is_empty());
+        let mut other_topic = request(1, 250);
+        other_topic.topic = "other".to_string();
+        assert!(log.respond(&other_topic).messages.is_empty());
+
+        // Forgetting a room keeps its numbering going
+        log.forget(TOPIC);
+        assert!(log.respond(&request(1, 250)).messages.is_empty());
+        assert_eq!(log.next_seq(TOPIC), 251);
+    }
+
+    #[test]
+    fn every_part_of_a_split_message_is_sent_again() {
+        let mut log = SentLog::default();
+        log.record(TOPIC, 1, vec![b"part 1".to_vec(), b"part 2".to_vec()]);
+        assert_eq!(log.respond(&request(1, 1)).messages.len(), 2);
+    }
+}
@@ -0,0 +1,61 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+const NONCE_LEN: usize = 24;
+
+// Secrets derived from a room name and passphrase. Peers with a different
+// passphrase end up on a different topic and can't decrypt our payloads.
+pub struct RoomKey {
+    cipher: XChaCha20Poly1305,
+    topic: String,
+}
+
+impl RoomKey {
+    pub fn derive(room_name: &str, passphrase: &str) -> Result<Self, String> {
+        // Argon2 makes guessing the passphrase from the topic name expensive;
+        // the room name acts as salt so equal passphrases don't collide
+        let salt = format!("p2p-chat/room/{}", room_name);
+        let mut secret = [0u8; 64];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut secret)
+            .map_err(|e| format!("Failed to derive room key: {}", e))?;
+
+        let (key, topic_seed) = secret.split_at(32);
+        let topic_id: String = topic_seed[..16].iter().map(|b| format!("{:02x}", b)).collect();
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            topic: format!("p2p-chat/private/{}", topic_id),
+        })
+    }
+
+    pub fn topic_name(&self) -> &str {
+        &self.topic
+    }
+
+    // Returns the random nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: self.topic.as_bytes() })
+            .map_err(|_| "Failed to encrypt message".to_string())?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(payload)
+    }
+
+    // None if the payload was not sealed with this key
+    pub fn decrypt(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+        self.cipher
+            .decrypt(&XNonce::from(nonce), Payload { msg: ciphertext, aad: self.topic.as_bytes() })
+            .ok()
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::PowerSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use tracing::warn;
+
+// Persisted user settings; unknown or missing fields fall back to defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Last message the user has seen in each room
+    pub last_read: HashMap<String, ReadMarker>,
+    // Words that trigger a `mention` event; empty means use the defaults
+    pub mention_keywords: Vec<String>,
+    pub power: PowerSettings,
+    // Peers whose chat is hidden; they stay connected and relayed
+    pub muted_peers: Vec<String>,
+    // Announced to the rooms we join
+    pub nickname: Option<String>,
+    // Set while the nickname is one we generated rather than one the user
+    // chose; it's then replaced along with the identity
+    pub nickname_generated: bool,
+    // Owner signing keys of rooms we created, by room name
+    pub owned_rooms: HashMap<String, String>,
+    // Owner public keys of rooms joined through an invite, by room name
+    pub room_owners: HashMap<String, String>,
+    // Public rooms left out of `list_public_rooms`
+    pub hidden_rooms: Vec<String>,
+    // Room we were in when the session was last saved; rejoined at startup
+    pub session_room: Option<SessionRoom>,
+}
+
+// Passphrase rooms are never recorded, since that would mean writing the
+// passphrase to disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRoom {
+    pub name: String,
+    pub passive: bool,
+    pub public: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadMarker {
+    pub message_id: Option<String>,
+    pub timestamp: String,
+}
+
+// Settings backed by a JSON file in the app data directory
+pub struct SettingsStore {
+    path: PathBuf,
+    pub settings: Settings,
+}
+
+impl SettingsStore {
+    pub fn load(path: PathBuf) -> Self {
+        let settings = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable settings file {}: {}", path.display(), e);
+                Settings::default()
+            }),
+            Err(_) => Settings::default(),
+        };
+
+        Self { path, settings }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        // Write to a temp file first so a crash can't leave half a settings file
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&self.settings)?)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use crate::history::MessageStore;
+use crate::p2p_node::ChatMessage;
+use crate::settings::Settings;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Payload of the `unread-changed` event
+#[derive(Debug, Clone, Serialize)]
+pub struct UnreadChanged {
+    pub room: String,
+    pub count: u32,
+}
+
+// Per-room count of messages received since the room was last marked read
+#[derive(Default)]
+pub struct UnreadCounters {
+    counts: HashMap<String, u32>,
+    // Room the user is looking at; its messages are read as they arrive
+    active: Option<String>,
+}
+
+impl UnreadCounters {
+    // Rebuild the counters from stored history and the persisted read markers
+    pub fn seed(store: &MessageStore, settings: &Settings) -> rusqlite::Result<Self> {
+        let mut counts = HashMap::new();
+        for room in store.rooms()? {
+            let since = settings.last_read.get(&room).map(|m| m.timestamp.as_str());
+            let count = store.count_received_since(&room, since)?;
+            if count > 0 {
+                counts.insert(room, count);
+            }
+        }
+        Ok(Self { counts, active: None })
+    }
+
+    // Count an incoming message; returns the event to emit if a counter changed
+    pub fn record(&mut self, msg: &ChatMessage) -> Option<UnreadChanged> {
+        if msg.is_self || msg.is_system() {
+            return None;
+        }
+        let room = msg.room.as_ref()?;
+        if self.active.as_ref() == Some(room) {
+            return None;
+        }
+
+        let count = self.counts.entry(room.clone()).or_insert(0);
+        *count += 1;
+        Some(UnreadChanged {
+            room: room.clone(),
+            count: *count,
+        })
+    }
+
+    pub fn mark_read(&mut self, room: &str) -> UnreadChanged {
+        self.counts.remove(room);
+        UnreadChanged {
+            room: room.to_string(),
+            count: 0,
+        }
+    }
+
+    pub fn set_active(&mut self, room: Option<String>) {
+        self.active = room;
+    }
+
+    pub fn counts(&self) -> HashMap<String, u32> {
+        self.counts.clone()
+    }
+}